@@ -2,7 +2,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
-    mycap_server::setup_log();
+    mycap_server::setup_tracing(&mycap_server::TracingConfig::default());
     tauri::async_runtime::spawn(async {
         if let Err(error) = mycap_server::start_server().await {
             log::error!("Server error: {error:?}");
@@ -1,18 +1,24 @@
 import { writable } from "svelte/store";
 
 const WEBSOCKET_PORT = 8298;
+// The server rejects clients that don't offer this
+const WEBSOCKET_PROTOCOL = "mycap.v1";
 
-export type TrackerStatus = "Ok" | "Error" | "Off" | "TimedOut";
+export type TrackerStatus = "Ok" | "Error" | "Off" | "TimedOut" | "Unknown";
 
 export interface TrackerConfig {
     name: string;
 }
 
 export interface TrackerInfo {
+    // Stable identifier for the physical tracker, the index can change
+    id: string;
     index: number;
     status: TrackerStatus;
     config: TrackerConfig;
     latency_ms: number;
+    // Set while the data looks like it's from a broken IMU
+    suspect: "Stuck" | "Noisy" | null;
 }
 
 export interface TrackerData {
@@ -20,6 +26,12 @@ export interface TrackerData {
     acceleration: [number, number, number];
     velocity: [number, number, number];
     position: [number, number, number];
+    timestamp_us: number;
+    stale: boolean;
+}
+
+export interface ServerStatus {
+    epoch_unix_us: number;
 }
 
 export interface Tracker {
@@ -30,18 +42,26 @@ export interface Tracker {
 export const websocket = writable<WebSocket | undefined>();
 export const trackers = writable<Tracker[]>([]);
 export const websocketError = writable("");
+export const serverStatus = writable<ServerStatus | undefined>();
+// Settings stored on each device by mac address
+export const deviceConfigs = writable<Record<string, Record<string, string>>>({});
 
 function connectWebsocket() {
     if (typeof window !== "undefined") {
         const protocol = location.protocol === "https" ? "wss" : "ws";
-        websocket.set(new WebSocket(`${protocol}://localhost:${WEBSOCKET_PORT}`));
+        const url = `${protocol}://localhost:${WEBSOCKET_PORT}`;
+        websocket.set(new WebSocket(url, WEBSOCKET_PROTOCOL));
     }
 }
 
 websocket.subscribe((ws) => {
     if (ws) {
+        // Every message from the server is numbered from 0 for each connection
+        let nextSeq = 0;
+
         ws.onopen = () => {
             console.log("Connected to websocket");
+            ws.send(JSON.stringify({ type: "SetLocale", locale: navigator.language }));
         };
 
         ws.onclose = () => {
@@ -57,6 +77,11 @@ websocket.subscribe((ws) => {
         ws.onmessage = (event) => {
             const message = JSON.parse(event.data);
             if (message) {
+                if (message.seq !== nextSeq) {
+                    console.warn(`Expected message ${nextSeq} but got ${message.seq}`);
+                }
+
+                nextSeq = message.seq + 1;
                 handleMessage(message);
             }
         };
@@ -67,9 +92,30 @@ websocket.subscribe((ws) => {
 
 function handleMessage(message: Record<string, any>) {
     switch (message.type) {
+        case "SyncChunk":
+            for (const payloadMessage of message.payload) {
+                handleMessage(payloadMessage);
+            }
+
+            break;
         case "Error":
             websocketError.set(message.error);
             break;
+        case "DeviceError":
+            websocketError.set(
+                `Device ${message.mac}: ${message.advice ?? "reported an unknown error"}`,
+            );
+            break;
+        case "DeviceConfig":
+            deviceConfigs.update((configs) => {
+                configs[message.mac] = message.entries;
+                return configs;
+            });
+
+            break;
+        case "ServerStatus":
+            serverStatus.set(message.status);
+            break;
         case "TrackerInfo":
             trackers.update((trackers) => {
                 if (trackers[message.info.index]) {
@@ -82,6 +128,8 @@ function handleMessage(message: Record<string, any>) {
                             orientation: [0, 0, 0, 1],
                             position: [0, 0, 0],
                             velocity: [0, 0, 0],
+                            timestamp_us: 0,
+                            stale: true,
                         },
                     };
 
@@ -91,6 +139,13 @@ function handleMessage(message: Record<string, any>) {
                 return trackers;
             });
 
+            break;
+        case "TrackerRemoved":
+            trackers.update((trackers) => {
+                delete trackers[message.index];
+                return trackers;
+            });
+
             break;
         case "TrackerData":
             trackers.update((trackers) => {
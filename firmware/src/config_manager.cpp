@@ -7,6 +7,7 @@
 #include <cstring>
 
 #define WIFI_FILE "/wifi"
+#define SETTINGS_FILE "/settings"
 
 void ConfigManager::setup() {
     if (!LittleFS.begin()) {
@@ -25,6 +26,14 @@ void ConfigManager::setup() {
         file.read((uint8_t*)&m_wifi_entries, sizeof(WifiEntries));
         file.close();
     }
+
+    memset(&m_settings, 0, sizeof(Settings));
+
+    if (LittleFS.exists(SETTINGS_FILE)) {
+        File file = LittleFS.open(SETTINGS_FILE, "r");
+        file.read((uint8_t*)&m_settings, sizeof(Settings));
+        file.close();
+    }
 }
 
 void ConfigManager::reset() {
@@ -57,6 +66,33 @@ void ConfigManager::wifi_entry_save(const char* ssid, const char* password) {
     file.close();
 }
 
+bool ConfigManager::setting_set(const char* key, const char* value) {
+    int i = 0;
+    for (; i < m_settings.count; i++) {
+        if (strncmp(m_settings.array[i].key, key, MAX_SETTING_KEY_LENGTH) == 0) {
+            break;
+        }
+    }
+
+    if (i == m_settings.count) {
+        if (m_settings.count >= MAX_SETTINGS) {
+            LOG_ERROR("No space left to save setting %s", key);
+            return false;
+        }
+
+        strncpy(m_settings.array[i].key, key, MAX_SETTING_KEY_LENGTH);
+        m_settings.count += 1;
+    }
+
+    LOG_INFO("Saving setting %s = %s", key, value);
+    strncpy(m_settings.array[i].value, value, MAX_SETTING_VALUE_LENGTH);
+
+    File file = LittleFS.open(SETTINGS_FILE, "w");
+    file.write((uint8_t*)&m_settings, sizeof(Settings));
+    file.close();
+    return true;
+}
+
 bool ConfigManager::wifi_entry_exists(const char* ssid) {
     return find_wifi_entry_index(ssid) >= 0;
 }
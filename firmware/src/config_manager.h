@@ -5,6 +5,9 @@
 
 constexpr size_t MAX_PASSWORD_LENGTH = 64;
 constexpr size_t MAX_SSID_LENGTH = 32;
+// Needs to fit inside a single set config packet
+constexpr size_t MAX_SETTING_KEY_LENGTH = 16;
+constexpr size_t MAX_SETTING_VALUE_LENGTH = 32;
 
 struct WifiEntry {
     char ssid[MAX_SSID_LENGTH];
@@ -16,6 +19,16 @@ struct WifiEntries {
     std::array<WifiEntry, MAX_WIFI_ENTRIES> array;
 };
 
+struct SettingEntry {
+    char key[MAX_SETTING_KEY_LENGTH + 1];
+    char value[MAX_SETTING_VALUE_LENGTH + 1];
+};
+
+struct Settings {
+    uint8_t count;
+    std::array<SettingEntry, MAX_SETTINGS> array;
+};
+
 class ConfigManager {
 public:
     void setup();
@@ -25,9 +38,13 @@ public:
     const char* wifi_password_get(const char* ssid);
     bool wifi_entry_exists(const char* ssid);
 
+    bool setting_set(const char* key, const char* value);
+    const Settings& get_settings() { return m_settings; }
+
 private:
     int find_wifi_entry_index(const char* ssid);
 
 private:
     WifiEntries m_wifi_entries;
+    Settings m_settings;
 };
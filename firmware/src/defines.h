@@ -17,4 +17,8 @@
 // Each wifi entry takes 96 bytes
 #define MAX_WIFI_ENTRIES 4
 
+// Number of key value settings that can be set from the server
+// Each setting takes 51 bytes
+#define MAX_SETTINGS 16
+
 // #define CUSTOM_LED_PIN D8
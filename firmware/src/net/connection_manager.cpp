@@ -55,6 +55,12 @@ void ConnectionManager::receive_packets() {
     LOG_TRACE("Received %d bytes from %s", len, m_udp.remoteIP().toString().c_str());
     m_last_received_time = millis();
 
+    // Packets only come from the server so this follows it when it changes port
+    if (m_connected && m_udp.remoteIP() == m_server_ip && m_udp.remotePort() != m_server_port) {
+        LOG_INFO("Server moved to port %d", m_udp.remotePort());
+        m_server_port = m_udp.remotePort();
+    }
+
     switch (m_buffer[0]) {
     case PACKET_HANDSHAKE: {
         // MCSVR indicates mycap server response
@@ -66,6 +72,7 @@ void ConnectionManager::receive_packets() {
             LOG_INFO("Successfully handshaked with %s", m_udp.remoteIP().toString().c_str());
             m_connected = true;
             m_server_ip = m_udp.remoteIP();
+            m_server_port = m_udp.remotePort();
             m_next_packet_number = 1; // Use 1 since handshake would use packet number 0
 
             // Set the tracker statuses to off so they can be resent
@@ -73,12 +80,74 @@ void ConnectionManager::receive_packets() {
                 m_tracker_statuses_on_server.begin(), m_tracker_statuses_on_server.end(),
                 TrackerStatus::Off
             );
+            report_device_errors();
         } else {
             // Ignore later handshake packets
             LOG_WARN("Received handshake while already connected");
         }
         break;
     }
+    case PACKET_SERVER_ANNOUNCE: {
+        if (m_connected || strncmp((const char*)m_buffer + 1, "MCSVR", 5) != 0) {
+            break;
+        }
+
+        // Server announced itself so handshake with it directly
+        LOG_TRACE("Sending handshake packet to announced ip %s", m_udp.remoteIP().toString().c_str());
+        m_server_ip = m_udp.remoteIP();
+        m_server_port = m_udp.remotePort();
+        begin_packet(PACKET_HANDSHAKE);
+        write_handshake_body();
+        break;
+    }
+    case PACKET_HANDSHAKE_REQUEST: {
+        if (strncmp((const char*)m_buffer + 1, "MCSVR", 5) != 0) {
+            break;
+        }
+
+        // Server lost track of us so handshake again to get reconnected
+        LOG_INFO("Server requested handshake, reconnecting to %s", m_udp.remoteIP().toString().c_str());
+        m_connected = false;
+        m_server_ip = m_udp.remoteIP();
+        m_server_port = m_udp.remotePort();
+        begin_packet(PACKET_HANDSHAKE);
+        write_handshake_body();
+        break;
+    }
+    case PACKET_SERVER_FULL: {
+        if (m_connected || strncmp((const char*)m_buffer + 1, "MCSVR", 5) != 0) {
+            break;
+        }
+
+        // Wait longer before the next handshake and hold the led on so it's obvious
+        LOG_WARN("Server %s is full, trying again later", m_udp.remoteIP().toString().c_str());
+        m_last_sent_handshake_time = millis() + SERVER_FULL_BACKOFF_MS;
+        g_internal_led.blink(1000);
+        break;
+    }
+    case PACKET_SERVER_SHUTDOWN: {
+        if (!m_connected || strncmp((const char*)m_buffer + 1, "MCSVR", 5) != 0) {
+            break;
+        }
+
+        // Go back to looking for a server but without spamming handshakes while it's down
+        LOG_INFO("Server %s is shutting down", m_udp.remoteIP().toString().c_str());
+        m_connected = false;
+        m_last_sent_handshake_time = millis() + SERVER_SHUTDOWN_BACKOFF_MS;
+        g_internal_led.blink(1000);
+        break;
+    }
+    case PACKET_REQUEST_STATUS: {
+        if (!m_connected || strncmp((const char*)m_buffer + 1, "MCSVR", 5) != 0) {
+            break;
+        }
+
+        // Send them all even if the server acknowledged them since it might not have them anymore
+        for (Tracker* tracker : g_tracker_manager.get_trackers()) {
+            send_tracker_status(tracker);
+        }
+        break;
+    }
     case PACKET_TRACKER_STATUS: {
         uint8_t id = m_buffer[1];
         if (id < m_tracker_statuses_on_server.size()) {
@@ -87,9 +156,34 @@ void ConnectionManager::receive_packets() {
         break;
     }
     case PACKET_PING_PONG:
+        // Newer servers send their clock after the id
+        if (len >= 10) {
+            uint64_t server_time;
+            memcpy(&server_time, m_buffer + 2, sizeof(server_time));
+            m_server_clock_offset = (int64_t)(server_time - micros64());
+        }
+
         // Pong back ping
         send_pong(m_buffer[1]);
         break;
+    case PACKET_GET_CONFIG:
+        send_config();
+        break;
+    case PACKET_SET_CONFIG_KV: {
+        char key[MAX_SETTING_KEY_LENGTH + 1];
+        char value[MAX_SETTING_VALUE_LENGTH + 1];
+        int offset = 1;
+        if (!read_length_prefixed_str(offset, len, key, MAX_SETTING_KEY_LENGTH) ||
+            !read_length_prefixed_str(offset, len, value, MAX_SETTING_VALUE_LENGTH)) {
+            LOG_WARN("Received invalid set config packet");
+            break;
+        }
+
+        // Replying with the settings acknowledges the set
+        g_config_manager.setting_set(key, value);
+        send_config();
+        break;
+    }
     default:
         LOG_WARN("Received invalid packet id %d", m_buffer[0]);
         break;
@@ -111,6 +205,7 @@ void ConnectionManager::send_handshake() {
     // Hardcoded server ip
     LOG_TRACE("Sending handshake to hardcoded ip %s", SERVER_IP.toString().c_str());
     m_server_ip = SERVER_IP;
+    m_server_port = UDP_PORT;
     begin_packet(PACKET_HANDSHAKE);
 #else
     // Start using multicast to find the server by sending handshake packets
@@ -120,6 +215,10 @@ void ConnectionManager::send_handshake() {
     m_udp.write(PACKET_HANDSHAKE);
 #endif
 
+    write_handshake_body();
+}
+
+void ConnectionManager::write_handshake_body() {
     write_str("MCDEV"); // mark as mycap handshake
 
     // Send mac adresss as unique id
@@ -134,6 +233,23 @@ void ConnectionManager::send_pong(uint8_t id) {
     g_internal_led.blink(20);
     begin_packet(PACKET_PING_PONG);
     m_udp.write(id);
+    // Send our clock so the server can work out the offset to its clock
+    uint64_t time = micros64();
+    m_udp.write((uint8_t*)&time, sizeof(time));
+    end_packet();
+}
+
+void ConnectionManager::send_config() {
+    const Settings& settings = g_config_manager.get_settings();
+    begin_packet(PACKET_GET_CONFIG);
+    write_packet_number();
+    m_udp.write(settings.count);
+
+    for (uint8_t i = 0; i < settings.count; i++) {
+        write_length_prefixed_str(settings.array[i].key);
+        write_length_prefixed_str(settings.array[i].value);
+    }
+
     end_packet();
 }
 
@@ -164,6 +280,42 @@ void ConnectionManager::send_tracker_status(Tracker* tracker) {
     end_packet();
 }
 
+void ConnectionManager::send_device_error(uint8_t code, const char* detail) {
+    begin_packet(PACKET_DEVICE_ERROR);
+    write_packet_number();
+    m_udp.write(code);
+    uint8_t length = std::min(strlen(detail), MAX_DEVICE_ERROR_DETAIL_LENGTH);
+    m_udp.write(length);
+    m_udp.write(detail, length);
+    end_packet();
+}
+
+void ConnectionManager::send_input_event(uint8_t input, uint8_t kind, float value) {
+    begin_packet(PACKET_INPUT_EVENT);
+    write_packet_number();
+    m_udp.write(input);
+    m_udp.write(kind);
+    m_udp.write((uint8_t*)&value, sizeof(value));
+    end_packet();
+}
+
+// Tells the server about problems that happened before it could be reached
+void ConnectionManager::report_device_errors() {
+    char detail[MAX_DEVICE_ERROR_DETAIL_LENGTH + 1];
+    for (Tracker* tracker : g_tracker_manager.get_trackers()) {
+        if (tracker->status == TrackerStatus::Error) {
+            snprintf(detail, sizeof(detail), "tracker %d", tracker->get_index());
+            send_device_error(DEVICE_ERROR_IMU_INIT_FAIL, detail);
+        }
+    }
+
+    int32_t rssi = WiFi.RSSI();
+    if (rssi < -80) {
+        snprintf(detail, sizeof(detail), "rssi %d dBm", rssi);
+        send_device_error(DEVICE_ERROR_WIFI_WEAK, detail);
+    }
+}
+
 bool ConnectionManager::has_acked_tracker(Tracker* tracker) {
     return m_tracker_statuses_on_server[tracker->get_index()] == tracker->status;
 }
@@ -172,8 +324,33 @@ void ConnectionManager::write_str(const char* str) {
     m_udp.write(str, strlen(str));
 }
 
+void ConnectionManager::write_length_prefixed_str(const char* str) {
+    uint8_t length = strlen(str);
+    m_udp.write(length);
+    m_udp.write(str, length);
+}
+
+bool ConnectionManager::read_length_prefixed_str(
+    int& offset, int len, char* out, size_t max_length
+) {
+    if (offset >= len) {
+        return false;
+    }
+
+    size_t length = m_buffer[offset];
+    offset += 1;
+    if (length > max_length || offset + (int)length > len) {
+        return false;
+    }
+
+    memcpy(out, m_buffer + offset, length);
+    out[length] = '\0';
+    offset += length;
+    return true;
+}
+
 void ConnectionManager::begin_packet(uint8_t packet_type) {
-    m_udp.beginPacket(m_server_ip, UDP_PORT);
+    m_udp.beginPacket(m_server_ip, m_server_port);
     m_udp.write(packet_type);
 }
 
@@ -16,6 +16,38 @@ constexpr uint8_t PACKET_PING_PONG = 0x00;
 constexpr uint8_t PACKET_HANDSHAKE = 0x01;
 constexpr uint8_t PACKET_TRACKER_STATUS = 0x02;
 constexpr uint8_t PACKET_TRACKER_DATA = 0x03;
+// Sent by the server over broadcast when multicast discovery doesn't work
+constexpr uint8_t PACKET_SERVER_ANNOUNCE = 0x04;
+// Server asks for the settings and the device replies with them as length prefixed key values
+constexpr uint8_t PACKET_GET_CONFIG = 0x05;
+// Server sets a setting and the device replies with its settings to acknowledge it
+constexpr uint8_t PACKET_SET_CONFIG_KV = 0x06;
+// Server doesn't know the device (e.g. after restarting) so it asks for a new handshake
+constexpr uint8_t PACKET_HANDSHAKE_REQUEST = 0x07;
+// Something went wrong on the device, sent with an error code and a short detail string
+constexpr uint8_t PACKET_DEVICE_ERROR = 0x08;
+// Server has reached its device limit so the handshake was rejected
+constexpr uint8_t PACKET_SERVER_FULL = 0x09;
+// How long to wait before handshaking again after the server was full
+constexpr uint64_t SERVER_FULL_BACKOFF_MS = 30000;
+// Server is shutting down on purpose so there's no point handshaking with it straight away
+constexpr uint8_t PACKET_SERVER_SHUTDOWN = 0x0f;
+// How long to wait before handshaking again after the server shut down
+constexpr uint64_t SERVER_SHUTDOWN_BACKOFF_MS = 10000;
+// Server wants the status of every tracker again, sent after each handshake
+constexpr uint8_t PACKET_REQUEST_STATUS = 0x10;
+
+constexpr uint8_t DEVICE_ERROR_IMU_INIT_FAIL = 0x01;
+constexpr uint8_t DEVICE_ERROR_BROWNOUT = 0x02;
+constexpr uint8_t DEVICE_ERROR_WIFI_WEAK = 0x03;
+constexpr uint8_t DEVICE_ERROR_FLASH_FULL = 0x04;
+constexpr size_t MAX_DEVICE_ERROR_DETAIL_LENGTH = 48;
+
+// A button or axis on the device changed, sent with the input id, kind and a float value
+constexpr uint8_t PACKET_INPUT_EVENT = 0x0a;
+
+constexpr uint8_t INPUT_KIND_BUTTON = 0;
+constexpr uint8_t INPUT_KIND_AXIS = 1;
 
 const IPAddress MULTICAST_IP = IPAddress(239, 255, 0, 123);
 
@@ -28,6 +60,9 @@ public:
     void send_tracker_status(Tracker* tracker);
     void send_handshake();
     void send_pong(uint8_t id);
+    void send_config();
+    void send_device_error(uint8_t code, const char* detail = "");
+    void send_input_event(uint8_t input, uint8_t kind, float value);
 
     bool has_acked_tracker(Tracker* tracker);
 
@@ -38,15 +73,21 @@ private:
     void begin_packet(uint8_t packet_type);
     void write_packet_number();
     void write_str(const char* str);
+    void write_length_prefixed_str(const char* str);
+    bool read_length_prefixed_str(int& offset, int len, char* out, size_t max_length);
+    void write_handshake_body();
     void end_packet();
 
     void receive_packets();
+    void report_device_errors();
     void update_tracker_statuses();
 
 private:
     WiFiUDP m_udp;
     bool m_connected = false;
     IPAddress m_server_ip = INADDR_NONE;
+    // The server can move to another port so follow the port its packets come from
+    uint16_t m_server_port = UDP_PORT;
     WifiManager m_wifi;
     uint8_t m_buffer[64];
 
@@ -56,4 +97,6 @@ private:
     uint64_t m_last_sent_handshake_time = 0;
     uint64_t m_last_received_time = 0;
     uint64_t m_last_tracker_status_sent_time = 0;
+    // Server clock minus our clock in microseconds, roughly since it ignores the network delay
+    int64_t m_server_clock_offset = 0;
 };
@@ -151,6 +151,8 @@ void WifiManager::on_connect() {
         "Connected to WiFi %s with ip %s", WiFi.SSID().c_str(), WiFi.localIP().toString().c_str()
     );
     m_connected = true;
+    // Lets the server know that the credentials it sent over serial work
+    Serial.print("WifiConnected\n");
 
     if (m_has_manually_set_creds) {
         struct station_config config;
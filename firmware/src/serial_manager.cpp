@@ -1,4 +1,5 @@
 #include <Arduino.h>
+#include <ESP8266WiFi.h>
 
 #include "globals.h"
 #include "log.h"
@@ -43,5 +44,8 @@ void SerialManager::parse_incomming_command() {
         g_connection_manager.get_wifi().use_credentials(ssid_ptr, password_ptr);
     } else if (strcmp(m_buffer, "FactoryReset") == 0) {
         g_config_manager.reset();
+    } else if (strcmp(m_buffer, "Mac") == 0) {
+        // Printed without the log prefix since the server parses it even when logging is off
+        Serial.printf("Mac %s\n", WiFi.macAddress().c_str());
     }
 }
@@ -0,0 +1,40 @@
+//! Time spent under the main lock sending the tracker data to the clients, with the broadcast
+//! channel compared to cloning the messages into a channel per client like before
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mycap_server::bench::TrackerBroadcast;
+
+const TRACKERS: usize = 32;
+const CLIENT_COUNTS: [usize; 3] = [1, 4, 16];
+
+fn broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast");
+
+    for clients in CLIENT_COUNTS {
+        let mut broadcast = TrackerBroadcast::new(TRACKERS, clients);
+        group.bench_function(BenchmarkId::new("broadcast_channel", clients), |b| {
+            b.iter(|| broadcast.broadcast())
+        });
+
+        group.bench_function(BenchmarkId::new("channel_per_client", clients), |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    // The clients would be reading these on their own tasks
+                    broadcast.drain_clients();
+                    let start = Instant::now();
+                    broadcast.send_per_client();
+                    total += start.elapsed();
+                }
+                total
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
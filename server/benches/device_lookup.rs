@@ -0,0 +1,49 @@
+//! Looking up a device by its address happens for every packet, compares the default SipHash map
+//! with the one the UDP server uses
+
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use mycap_server::bench::FastHashMap;
+
+const DEVICES: u8 = 64;
+
+fn addresses() -> Vec<SocketAddr> {
+    (0..DEVICES)
+        .map(|device| SocketAddr::from((Ipv4Addr::new(192, 168, 1, device), 5828)))
+        .collect()
+}
+
+fn device_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("device_lookup");
+    let addresses = addresses();
+
+    let sip: HashMap<SocketAddr, usize> = addresses.iter().copied().zip(0..).collect();
+    group.bench_function("sip_hash", |b| {
+        b.iter(|| {
+            addresses
+                .iter()
+                .map(|address| sip[black_box(address)])
+                .sum::<usize>()
+        })
+    });
+
+    let fast: FastHashMap<SocketAddr, usize> = addresses.iter().copied().zip(0..).collect();
+    group.bench_function("fast_hash", |b| {
+        b.iter(|| {
+            addresses
+                .iter()
+                .map(|address| fast[black_box(address)])
+                .sum::<usize>()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, device_lookup);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+//! How long the main loop waits for the lock and ticks while clients keep copying the trackers'
+//! history, with the copy under the main lock like before compared to under the tracker's lock
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mycap_server::bench::{HistoryRead, LockContention};
+
+const TRACKERS: u8 = 32;
+const CLIENT_COUNTS: [usize; 3] = [1, 4, 16];
+const HISTORY_SECS: u32 = 10;
+
+fn lock_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lock_contention");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    for clients in CLIENT_COUNTS {
+        for (name, read) in [
+            ("under_main_lock", HistoryRead::UnderMainLock),
+            ("tracker_lock", HistoryRead::TrackerLock),
+        ] {
+            let mut contention = runtime
+                .block_on(LockContention::new(TRACKERS, clients, HISTORY_SECS, read))
+                .unwrap();
+            group.bench_function(BenchmarkId::new(name, clients), |b| {
+                b.iter(|| runtime.block_on(contention.tick()).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, lock_contention);
+criterion_main!(benches);
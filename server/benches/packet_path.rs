@@ -0,0 +1,48 @@
+//! Compares parsing a tracker data packet with receiving it and handling it under the main lock,
+//! parsing has to be a large part of the cost for moving it to other threads to be worth it
+
+use std::net::UdpSocket;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mycap_server::bench::{tracker_data_packet, PacketParser, PacketPath};
+
+const TRACKER_COUNTS: [u8; 3] = [1, 8, 32];
+
+fn packet_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_path");
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    for trackers in TRACKER_COUNTS {
+        let bytes = tracker_data_packet(1, trackers);
+        group.throughput(Throughput::Elements(trackers as u64));
+
+        let mut parser = PacketParser::default();
+        group.bench_with_input(BenchmarkId::new("parse", trackers), &bytes, |b, bytes| {
+            b.iter(|| parser.parse(bytes))
+        });
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        let mut buffer = [0; 1024];
+        group.bench_with_input(BenchmarkId::new("recv", trackers), &bytes, |b, bytes| {
+            b.iter(|| {
+                sender.send(bytes).unwrap();
+                receiver.recv_from(&mut buffer).unwrap()
+            })
+        });
+
+        let mut path = runtime.block_on(PacketPath::new(trackers)).unwrap();
+        group.bench_function(BenchmarkId::new("lock_and_handle", trackers), |b| {
+            b.iter(|| runtime.block_on(path.handle_tracker_data()).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, packet_path);
+criterion_main!(benches);
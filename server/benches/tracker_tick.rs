@@ -0,0 +1,22 @@
+//! Ticking 32 trackers with their filters run in a loop compared to on the tick threads, the
+//! threads only pay off once filtering the trackers takes longer than handing them out
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mycap_server::bench::TrackerTick;
+
+const TRACKERS: usize = 32;
+
+fn tracker_tick(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tracker_tick");
+    group.throughput(Throughput::Elements(TRACKERS as u64));
+
+    for (name, threshold) in [("loop", 0), ("threads", 1)] {
+        let mut tick = TrackerTick::new(TRACKERS, threshold);
+        group.bench_function(BenchmarkId::new(name, TRACKERS), |b| b.iter(|| tick.tick()));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, tracker_tick);
+criterion_main!(benches);
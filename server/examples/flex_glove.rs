@@ -0,0 +1,50 @@
+//! Glove with flex sensors that sends its own packet alongside the normal tracker data
+//!
+//! Packet 0x81: hand orientation as 4 f32 (x, y, z, w) then one f32 bend per finger, all little
+//! endian
+
+use mycap_server::{
+    AccelMps2, MycapServer, SensorQuat, ServerOptions, TrackerConfig, TrackerStatus,
+};
+
+const PACKET_FLEX_GLOVE: u8 = 0x81;
+
+fn f32_values(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    mycap_server::setup_tracing(&mycap_server::TracingConfig::default());
+
+    let mut server = MycapServer::new(ServerOptions::from_args()?);
+    server.register_packet_handler(PACKET_FLEX_GLOVE, |payload, peer_addr, context| {
+        let values = f32_values(payload);
+        if values.len() < 4 {
+            tracing::warn!("Flex glove packet from {peer_addr} is too short");
+            return;
+        }
+
+        let (orientation, fingers) = values.split_at(4);
+        let config = TrackerConfig {
+            name: "Glove".to_string(),
+            ..Default::default()
+        };
+        let index = context.register_tracker(format!("glove/{peer_addr}"), config);
+        context.set_tracker_status(index, TrackerStatus::Ok);
+        context.update_tracker_data(
+            index,
+            AccelMps2::ZERO,
+            SensorQuat(glam::Quat::from_slice(orientation).normalize()),
+        );
+        context.send_custom(
+            "flex_glove",
+            serde_json::json!({ "tracker_index": index, "fingers": fingers }),
+        );
+    })?;
+
+    server.start().await
+}
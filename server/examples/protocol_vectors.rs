@@ -0,0 +1,15 @@
+//! Rewrites the protocol fixtures from the server's packet definitions, run it after an intended
+//! protocol change and copy the files into the firmware
+//!
+//! cargo run --example protocol_vectors [dir]
+
+use mycap_server::protocol_vectors::{write_fixtures, FIXTURE_DIR};
+
+fn main() -> anyhow::Result<()> {
+    let dir = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| FIXTURE_DIR.to_string());
+    write_fixtures(dir.as_ref())?;
+    println!("Wrote the protocol fixtures to {dir}");
+    Ok(())
+}
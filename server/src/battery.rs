@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::config::ConfigError;
+
+/// Most readings kept for each device, past this the oldest go even if they're inside the window
+const MAX_BATTERY_READINGS: usize = 1024;
+/// Readings needed before the slope is trusted over the saved rate
+const MIN_FIT_READINGS: usize = 5;
+/// Readings have to span this long before the slope is trusted since the level barely changes
+/// over a minute
+const MIN_FIT_SPAN_US: u64 = 2 * 60 * 1_000_000;
+/// The level has to rise this far above its lowest to count as charging, and fall this far below
+/// its highest to stop, which is more than the readings jump around by
+const CHARGING_RISE_PERCENT: f32 = 2.;
+/// Discharging slower than this in percent per minute is as good as not discharging
+const MIN_DISCHARGE_RATE: f32 = 0.01;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct BatteryConfig {
+    /// Minutes of readings to fit the discharge rate over
+    pub window_mins: u32,
+    /// Warn when a device has less than this many minutes of battery left
+    /// 0 means never
+    pub low_warning_mins: u32,
+    /// Percent per minute each device was discharging at when last seen by its mac, so the first
+    /// estimate after starting isn't a guess
+    pub discharge_rates: BTreeMap<String, f32>,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self {
+            window_mins: 15,
+            low_warning_mins: 20,
+            discharge_rates: BTreeMap::new(),
+        }
+    }
+}
+
+impl BatteryConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.window_mins == 0 {
+            return Err(ConfigError::new("window_mins", "must be at least 1"));
+        }
+
+        for (mac, rate) in &self.discharge_rates {
+            if !(rate.is_finite() && *rate > 0.) {
+                return Err(ConfigError::new(
+                    format!("discharge_rates.{mac}"),
+                    "must be greater than 0",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn window_us(&self) -> u64 {
+        self.window_mins as u64 * 60 * 1_000_000
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+pub struct BatteryStatus {
+    pub percent: f32,
+    pub charging: bool,
+    /// None while charging or until the discharge rate is known
+    pub estimated_minutes_remaining: Option<u32>,
+}
+
+/// Estimates how long a battery has left from the slope of its recent readings
+pub struct BatteryEstimator {
+    /// Timestamp in microseconds and percent of the readings since it last started discharging,
+    /// oldest first
+    readings: VecDeque<(u64, f32)>,
+    charging: bool,
+    /// Lowest level while discharging or highest while charging, to tell when that changes
+    extreme: Option<f32>,
+    /// From the last session to use until there are enough readings
+    saved_rate: Option<f32>,
+}
+
+impl BatteryEstimator {
+    pub fn new(saved_rate: Option<f32>) -> Self {
+        Self {
+            readings: VecDeque::new(),
+            charging: false,
+            extreme: None,
+            saved_rate,
+        }
+    }
+
+    pub fn add_reading(
+        &mut self,
+        timestamp_us: u64,
+        percent: f32,
+        window_us: u64,
+    ) -> BatteryStatus {
+        let extreme = *self.extreme.get_or_insert(percent);
+        let switched = if self.charging {
+            percent < extreme - CHARGING_RISE_PERCENT
+        } else {
+            percent > extreme + CHARGING_RISE_PERCENT
+        };
+
+        if switched {
+            self.charging = !self.charging;
+            self.readings.clear();
+            self.extreme = Some(percent);
+        } else if self.charging {
+            self.extreme = Some(extreme.max(percent));
+        } else {
+            self.extreme = Some(extreme.min(percent));
+        }
+
+        if !self.charging {
+            self.readings.push_back((timestamp_us, percent));
+            while self.readings.len() > MAX_BATTERY_READINGS
+                || self.readings.front().is_some_and(|(oldest_us, _)| {
+                    timestamp_us.saturating_sub(*oldest_us) > window_us
+                })
+            {
+                self.readings.pop_front();
+            }
+        }
+
+        self.status(percent)
+    }
+
+    /// Discharge rate in percent per minute from the readings, None until there are enough of them
+    pub fn measured_rate(&self) -> Option<f32> {
+        Some(-self.fit()?.0 * 60_000_000.)
+    }
+
+    fn status(&self, percent: f32) -> BatteryStatus {
+        let mut status = BatteryStatus {
+            percent,
+            charging: self.charging,
+            estimated_minutes_remaining: None,
+        };
+        if self.charging {
+            return status;
+        }
+
+        // The fitted level is less noisy than the last reading
+        let (rate, level) = match self.fit() {
+            Some((slope, level)) => (-slope * 60_000_000., level),
+            None => (self.saved_rate.unwrap_or_default(), percent),
+        };
+        if rate >= MIN_DISCHARGE_RATE {
+            status.estimated_minutes_remaining = Some((level.max(0.) / rate) as u32);
+        }
+
+        status
+    }
+
+    /// Least squares line through the readings as the slope in percent per microsecond and the
+    /// level at the latest reading
+    fn fit(&self) -> Option<(f32, f32)> {
+        let (first_us, _) = *self.readings.front()?;
+        let (last_us, _) = *self.readings.back()?;
+        if self.readings.len() < MIN_FIT_READINGS || last_us - first_us < MIN_FIT_SPAN_US {
+            return None;
+        }
+
+        // Relative to the first reading and in f64 so the timestamps don't lose precision
+        let count = self.readings.len() as f64;
+        let points = || {
+            (self.readings.iter()).map(|(us, percent)| ((us - first_us) as f64, *percent as f64))
+        };
+        let mean_t = points().map(|(t, _)| t).sum::<f64>() / count;
+        let mean_p = points().map(|(_, p)| p).sum::<f64>() / count;
+        let (covariance, variance) = points().fold((0., 0.), |(covariance, variance), (t, p)| {
+            (
+                covariance + (t - mean_t) * (p - mean_p),
+                variance + (t - mean_t) * (t - mean_t),
+            )
+        });
+        if variance == 0. {
+            return None;
+        }
+
+        let slope = covariance / variance;
+        let level = mean_p + slope * ((last_us - first_us) as f64 - mean_t);
+        Some((slope as f32, level as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE_US: u64 = 60 * 1_000_000;
+
+    fn window_us() -> u64 {
+        BatteryConfig::default().window_us()
+    }
+
+    #[test]
+    fn saved_rate_is_used_until_the_readings_span_long_enough() {
+        let mut estimator = BatteryEstimator::new(Some(0.5));
+        let status = estimator.add_reading(0, 80., window_us());
+        assert_eq!(status.estimated_minutes_remaining, Some(160));
+        assert_eq!(estimator.measured_rate(), None);
+
+        // Plenty of readings but only over a minute
+        for i in 1..=10 {
+            estimator.add_reading(i * MINUTE_US / 10, 80., window_us());
+        }
+        assert_eq!(estimator.measured_rate(), None);
+
+        let status = BatteryEstimator::new(None).add_reading(0, 80., window_us());
+        assert_eq!(status.estimated_minutes_remaining, None);
+    }
+
+    #[test]
+    fn slope_of_the_readings_estimates_the_time_left() {
+        let mut estimator = BatteryEstimator::new(Some(5.));
+        let mut status = None;
+        // Losing 1% every 2 minutes with the readings jumping around by 0.5%
+        for minute in 0..=10 {
+            let noise = if minute % 2 == 0 { 0.5 } else { -0.5 };
+            let percent = 90. - minute as f32 / 2. + noise;
+            status = Some(estimator.add_reading(minute * MINUTE_US, percent, window_us()));
+        }
+
+        let rate = estimator.measured_rate().unwrap();
+        assert!((rate - 0.5).abs() < 0.05, "{rate}");
+        let minutes = status.unwrap().estimated_minutes_remaining.unwrap();
+        assert!((165..=175).contains(&minutes), "{minutes}");
+    }
+
+    #[test]
+    fn old_readings_leave_the_window() {
+        let mut estimator = BatteryEstimator::new(None);
+        let window_us = 5 * MINUTE_US;
+        // Fast at first then slower, only the recent slope should count
+        for minute in 0..=10 {
+            estimator.add_reading(minute * MINUTE_US, 100. - minute as f32 * 2., window_us);
+        }
+        for minute in 11..=20 {
+            let percent = 80. - (minute - 10) as f32 * 0.25;
+            estimator.add_reading(minute * MINUTE_US, percent, window_us);
+        }
+
+        let rate = estimator.measured_rate().unwrap();
+        assert!((rate - 0.25).abs() < 1e-3, "{rate}");
+    }
+
+    #[test]
+    fn charging_has_no_estimate() {
+        let mut estimator = BatteryEstimator::new(Some(0.5));
+        for minute in 0..5 {
+            estimator.add_reading(minute * MINUTE_US, 50., window_us());
+        }
+
+        // Small jumps aren't charging
+        let status = estimator.add_reading(5 * MINUTE_US, 51.5, window_us());
+        assert!(!status.charging);
+
+        let status = estimator.add_reading(6 * MINUTE_US, 53., window_us());
+        assert!(status.charging);
+        assert_eq!(status.estimated_minutes_remaining, None);
+        let status = estimator.add_reading(7 * MINUTE_US, 60., window_us());
+        assert!(status.charging);
+
+        // Unplugged, starting again from the saved rate with the old readings gone
+        let status = estimator.add_reading(8 * MINUTE_US, 57., window_us());
+        assert!(!status.charging);
+        assert_eq!(status.estimated_minutes_remaining, Some(114));
+        assert_eq!(estimator.measured_rate(), None);
+    }
+
+    #[test]
+    fn flat_readings_have_no_estimate() {
+        let mut estimator = BatteryEstimator::new(None);
+        let mut status = None;
+        for minute in 0..=10 {
+            status = Some(estimator.add_reading(minute * MINUTE_US, 70., window_us()));
+        }
+        assert_eq!(estimator.measured_rate(), Some(0.));
+        assert_eq!(status.unwrap().estimated_minutes_remaining, None);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let config = BatteryConfig {
+            window_mins: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.validate().unwrap_err().field, "window_mins");
+
+        let mut config = BatteryConfig::default();
+        config.discharge_rates.insert("aa:bb".to_string(), -1.);
+        assert_eq!(
+            config.validate().unwrap_err().field,
+            "discharge_rates.aa:bb"
+        );
+        assert!(BatteryConfig::default().validate().is_ok());
+    }
+}
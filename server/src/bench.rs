@@ -0,0 +1,341 @@
+//! Internals that the benches in benches/ measure, not part of the API
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+#[cfg(feature = "websocket")]
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    config::{DiscoveryConfig, PacketOrderPolicy},
+    fusion::KalmanConfig,
+    main_server::MainServer,
+    tracker::PositionFilter,
+    udp_packet::{UdpPacket, PACKET_HANDSHAKE, PACKET_TRACKER_DATA},
+    udp_server::{UdpDevice, UdpServer},
+    units::{AccelMps2, SensorQuat},
+    warning_aggregator::WarningAggregator,
+};
+#[cfg(feature = "websocket")]
+use crate::{
+    main_server::{ChannelMessage, ServerMessage},
+    tracker::{TrackerConfig, TrackerStatus},
+};
+
+pub use crate::fast_hash::FastHashMap;
+
+/// The main server's tick rate
+const TICK_DELTA: Duration = Duration::from_millis(20);
+
+/// Where the simulated device sends from
+const DEVICE_ADDRESS: &str = "10.0.0.2:5828";
+
+/// Tracker data packet from a device with this many trackers, the largest that fits in the receive
+/// buffer is 35
+pub fn tracker_data_packet(packet_number: u32, trackers: u8) -> Vec<u8> {
+    let mut bytes = vec![PACKET_TRACKER_DATA];
+    bytes.extend(packet_number.to_le_bytes());
+    for tracker_index in 0..trackers {
+        bytes.push(tracker_index);
+        let orientation = glam::Quat::from_rotation_z(tracker_index as f32 * 0.1);
+        for value in orientation.to_array().into_iter().chain([0.1, 0.2, 9.8]) {
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+    bytes.push(0xff);
+    bytes
+}
+
+/// Only the parsing the UDP server does for a packet without anything it does with the result
+pub struct PacketParser {
+    device: UdpDevice,
+    warnings: WarningAggregator,
+}
+
+impl Default for PacketParser {
+    fn default() -> Self {
+        Self {
+            device: UdpDevice::new(DEVICE_ADDRESS.parse().unwrap(), "bench".to_string()),
+            warnings: WarningAggregator::default(),
+        }
+    }
+}
+
+impl PacketParser {
+    /// Decodes every tracker in a tracker data packet, returning how many there were
+    pub fn parse(&mut self, bytes: &[u8]) -> usize {
+        let mut bytes = bytes.iter();
+        let packet = UdpPacket::parse(
+            &mut bytes,
+            Some(&mut self.device),
+            // The same packet gets parsed over and over
+            PacketOrderPolicy::AcceptAll,
+            &mut self.warnings,
+        );
+
+        match packet {
+            Some(UdpPacket::TrackerData((mut packet, _))) => {
+                std::iter::from_fn(|| packet.next()).count()
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Everything after a packet is received, taking the main lock then handling it like the UDP
+/// server does
+pub struct PacketPath {
+    udp: UdpServer,
+    main: Arc<RwLock<MainServer>>,
+    address: SocketAddr,
+    trackers: u8,
+    packet_number: u32,
+}
+
+impl PacketPath {
+    /// Has a device with this many trackers that has already handshaked and sent its first data
+    pub async fn new(trackers: u8) -> anyhow::Result<Self> {
+        let config = DiscoveryConfig {
+            udp_port: 0,
+            ..Default::default()
+        };
+        let mut path = Self {
+            udp: UdpServer::new(&config, 1).await?,
+            main: Arc::new(RwLock::new(MainServer::default())),
+            address: DEVICE_ADDRESS.parse()?,
+            trackers,
+            packet_number: 0,
+        };
+
+        let handshake = [&[PACKET_HANDSHAKE][..], b"MCDEV", &[1, 2, 3, 4, 5, 6]].concat();
+        path.handle(&handshake).await?;
+        path.handle_tracker_data().await?;
+        Ok(path)
+    }
+
+    /// Handles the next tracker data packet from the device
+    pub async fn handle_tracker_data(&mut self) -> anyhow::Result<()> {
+        self.packet_number += 1;
+        let bytes = tracker_data_packet(self.packet_number, self.trackers);
+        self.handle(&bytes).await
+    }
+
+    /// Handles the next tracker data packet and ticks under the same lock like the main loop
+    #[cfg(feature = "websocket")]
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        self.packet_number += 1;
+        let bytes = tracker_data_packet(self.packet_number, self.trackers);
+        let main = &mut *self.main.write().await;
+        self.udp
+            .handle_packet_in_span(&bytes, self.address, main)
+            .await?;
+        main.tick(TICK_DELTA);
+        Ok(())
+    }
+
+    async fn handle(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let main = &mut *self.main.write().await;
+        self.udp
+            .handle_packet_in_span(bytes, self.address, main)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Main server ticking trackers that estimate their position with the Kalman filter and keep a
+/// few seconds of history, with new data for every tracker each tick
+pub struct TrackerTick {
+    pub(crate) main: MainServer,
+    tick: u64,
+}
+
+impl TrackerTick {
+    /// Filters the trackers on the tick threads when there are at least `parallel_threshold`, 0
+    /// never does
+    pub fn new(trackers: usize, parallel_threshold: usize) -> Self {
+        let mut main = MainServer::default();
+        main.config.parallel_tick_trackers = parallel_threshold;
+        main.config.history_secs = 5;
+        for tracker in 0..trackers {
+            let config = crate::tracker::TrackerConfig::builder(tracker.to_string())
+                .estimate_position(true)
+                .position_filter(PositionFilter::Kalman(KalmanConfig::default()))
+                .build()
+                .expect("the default Kalman config is valid");
+            main.register_tracker(tracker.to_string(), config);
+        }
+
+        Self { main, tick: 0 }
+    }
+
+    /// Gives every tracker new data that's different for each one then ticks the main server
+    pub fn tick(&mut self) {
+        self.tick += 1;
+        let indices: Vec<_> = (self.main.trackers.iter())
+            .map(|tracker| tracker.info.index)
+            .collect();
+        self.main.replay_timestamp_us = Some(self.tick * 10_000);
+        for index in indices {
+            let phase = (self.tick as f32 * 0.05) + index as f32;
+            let acceleration = AccelMps2(glam::Vec3A::new(phase.sin(), phase.cos(), 0.1));
+            let orientation = SensorQuat(glam::Quat::from_rotation_z(phase));
+            self.main
+                .update_tracker_data(index, acceleration, orientation, Instant::now());
+        }
+        self.main.tick(TICK_DELTA);
+    }
+
+    /// Data of every tracker in index order, to compare runs
+    pub fn tracker_data(&self) -> Vec<String> {
+        (self.main.trackers.iter())
+            .map(|tracker| serde_json::to_string(&tracker.data).unwrap())
+            .collect()
+    }
+}
+
+/// Main server with trackers that are sending data and clients that are subscribed to it
+#[cfg(feature = "websocket")]
+pub struct TrackerBroadcast {
+    main: MainServer,
+    _receivers: Vec<broadcast::Receiver<ChannelMessage>>,
+    /// How the messages were sent before the broadcast channel, cloned into a channel per client
+    senders: Vec<mpsc::UnboundedSender<ServerMessage>>,
+    per_client_receivers: Vec<mpsc::UnboundedReceiver<ServerMessage>>,
+}
+
+#[cfg(feature = "websocket")]
+impl TrackerBroadcast {
+    pub fn new(trackers: usize, clients: usize) -> Self {
+        let mut main = MainServer::default();
+        for tracker in 0..trackers {
+            let index = main.register_tracker(tracker.to_string(), TrackerConfig::default());
+            if let Some(tracker) = main.trackers.get_mut(index) {
+                tracker.info.status = TrackerStatus::Ok;
+            }
+        }
+
+        let receivers = (0..clients).map(|_| main.new_message_channel()).collect();
+        let (senders, per_client_receivers) =
+            (0..clients).map(|_| mpsc::unbounded_channel()).unzip();
+        Self {
+            main,
+            _receivers: receivers,
+            senders,
+            per_client_receivers,
+        }
+    }
+
+    /// Sends the data of every tracker through the broadcast channel like the tick does
+    pub fn broadcast(&self) {
+        for message in self.tracker_data() {
+            self.main.message_channels.send_to_all(message);
+        }
+    }
+
+    /// Sends the data of every tracker to every client's channel
+    pub fn send_per_client(&self) {
+        for message in self.tracker_data() {
+            for sender in &self.senders {
+                sender.send(message.clone()).ok();
+            }
+        }
+    }
+
+    /// Empties the per client channels so they don't grow between runs
+    pub fn drain_clients(&mut self) {
+        for receiver in &mut self.per_client_receivers {
+            while receiver.try_recv().is_ok() {}
+        }
+    }
+
+    fn tracker_data(&self) -> impl Iterator<Item = ServerMessage> + '_ {
+        self.main
+            .trackers
+            .iter()
+            .map(|tracker| ServerMessage::TrackerData {
+                index: tracker.info.index,
+                data: tracker.data.clone(),
+                raw_data: Some(tracker.raw_data.clone()),
+            })
+    }
+}
+
+/// How the clients in the lock contention bench copy a tracker's history
+#[cfg(feature = "websocket")]
+#[derive(Clone, Copy, Debug)]
+pub enum HistoryRead {
+    /// Holding the main lock for the whole copy like before trackers shared their history
+    UnderMainLock,
+    /// Only holding the main lock to get the tracker's history
+    TrackerLock,
+}
+
+/// The main loop handling packets and ticking while clients keep copying the trackers' history
+/// from other tasks
+#[cfg(feature = "websocket")]
+pub struct LockContention {
+    path: PacketPath,
+    clients: Vec<tokio::task::JoinHandle<()>>,
+}
+
+#[cfg(feature = "websocket")]
+impl LockContention {
+    /// Has a device with this many trackers and a full history of history_secs, the clients start
+    /// reading on the current runtime straight away
+    pub async fn new(
+        trackers: u8,
+        clients: usize,
+        history_secs: u32,
+        read: HistoryRead,
+    ) -> anyhow::Result<Self> {
+        let mut path = PacketPath::new(trackers).await?;
+        path.main.write().await.config.history_secs = history_secs;
+        for _ in 0..crate::main_server::ticks_in(history_secs as f32) {
+            path.tick().await?;
+        }
+
+        let history_length = crate::main_server::ticks_in(history_secs as f32);
+        let clients = (0..clients)
+            .map(|client| {
+                let main = path.main.clone();
+                tokio::spawn(async move {
+                    for index in (0..trackers as usize).cycle().skip(client) {
+                        let samples = match read {
+                            HistoryRead::UnderMainLock => {
+                                let main = main.read().await;
+                                main.tracker_history(index).unwrap().latest(history_length)
+                            }
+                            HistoryRead::TrackerLock => {
+                                let history = main.read().await.tracker_history(index).unwrap();
+                                history.latest(history_length)
+                            }
+                        };
+                        std::hint::black_box(samples);
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+
+        Ok(Self { path, clients })
+    }
+
+    /// Handles the next tracker data packet then ticks, waiting for the clients to let go of the
+    /// main lock
+    pub async fn tick(&mut self) -> anyhow::Result<()> {
+        self.path.tick().await
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Drop for LockContention {
+    fn drop(&mut self) {
+        for client in &self.clients {
+            client.abort();
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::config::ServerConfig;
+
+/// Sources that get their packets dropped before anything is parsed or logged
+#[derive(Default)]
+pub struct Blocklist {
+    macs: HashSet<String>,
+    addresses: HashSet<IpAddr>,
+    /// Addresses that handshaked with a blocked mac so their later packets get dropped straight away
+    mac_sources: HashSet<SocketAddr>,
+    /// Packets dropped from each address since the counts were last taken
+    counts: HashMap<IpAddr, u64>,
+}
+
+impl Blocklist {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            macs: config.blocked_macs.iter().cloned().collect(),
+            addresses: config.blocked_addresses.iter().copied().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Counts the packet if the address is blocked
+    pub fn check_packet(&mut self, address: SocketAddr) -> bool {
+        if !self.addresses.contains(&address.ip()) && !self.mac_sources.contains(&address) {
+            return false;
+        }
+
+        *self.counts.entry(address.ip()).or_default() += 1;
+        true
+    }
+
+    /// Counts the handshake and remembers the address if the mac is blocked
+    pub fn check_handshake(&mut self, mac: &str, address: SocketAddr) -> bool {
+        if !self.macs.contains(mac) {
+            return false;
+        }
+
+        if self.mac_sources.insert(address) {
+            tracing::info!("Ignoring handshakes from blocked device {mac} at {address}");
+        }
+
+        *self.counts.entry(address.ip()).or_default() += 1;
+        true
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn is_blocked(&self, mac: &str, address: SocketAddr) -> bool {
+        self.macs.contains(mac) || self.addresses.contains(&address.ip())
+    }
+
+    /// Takes the counts while keeping the map's memory around for the next packets
+    pub fn drain_counts(&mut self) -> impl Iterator<Item = (IpAddr, u64)> + '_ {
+        self.counts.drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocklist() -> Blocklist {
+        Blocklist::from_config(&ServerConfig {
+            blocked_macs: vec!["AA:BB".to_string()],
+            blocked_addresses: vec!["10.0.0.9".parse().unwrap()],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn blocked_addresses_drop_every_port() {
+        let mut blocklist = blocklist();
+        assert!(blocklist.check_packet("10.0.0.9:5828".parse().unwrap()));
+        assert!(blocklist.check_packet("10.0.0.9:1234".parse().unwrap()));
+        assert!(!blocklist.check_packet("10.0.0.2:5828".parse().unwrap()));
+
+        let counts: Vec<_> = blocklist.drain_counts().collect();
+        assert_eq!(counts, [("10.0.0.9".parse().unwrap(), 2)]);
+        assert_eq!(blocklist.drain_counts().count(), 0);
+    }
+
+    #[test]
+    fn blocked_mac_drops_the_packets_after_its_handshake() {
+        let mut blocklist = blocklist();
+        let (blocked, other) = (
+            "10.0.0.2:5828".parse().unwrap(),
+            "10.0.0.3:5828".parse().unwrap(),
+        );
+        assert!(!blocklist.check_packet(blocked));
+
+        assert!(blocklist.check_handshake("AA:BB", blocked));
+        assert!(!blocklist.check_handshake("CC:DD", other));
+        assert!(blocklist.check_packet(blocked));
+        assert!(!blocklist.check_packet(other));
+
+        let counts: Vec<_> = blocklist.drain_counts().collect();
+        assert_eq!(counts, [(blocked.ip(), 2)]);
+    }
+}
@@ -0,0 +1,414 @@
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "websocket")]
+use crate::{
+    gravity::{REMOVED_THRESHOLD, STANDARD_GRAVITY},
+    messages::CodedMessage,
+};
+use crate::{
+    tracker::{TrackerLocation, TrackerSide},
+    units::AccelMps2,
+};
+
+/// Longest countdown before a calibration starts
+#[cfg(feature = "websocket")]
+pub const MAX_CALIBRATION_DELAY_SECS: u64 = 60;
+/// Longest time to watch for the movement when checking the sides
+#[cfg(feature = "websocket")]
+pub const MAX_SIDE_CHECK_SECS: f32 = 30.;
+/// The tracker that moved needs to have moved this many times more than the other one to be sure
+/// which one it was
+const SIDE_CHECK_MOVEMENT_RATIO: f32 = 2.;
+
+/// Calibrations that can be started after a countdown so there's time to get into pose
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub enum CalibrationKind {
+    /// Make every tracker face forwards
+    ResetYaw,
+    /// Keep the tracker still for some seconds to find out how its IMU reports gravity
+    Gravity { index: usize, seconds: f32 },
+    /// Move the tracker on one side, such as lifting the right foot, to check that the left and
+    /// right trackers at the location aren't swapped
+    SideCheck {
+        location: TrackerLocation,
+        side: TrackerSide,
+        seconds: f32,
+    },
+}
+
+/// Counts down the seconds until the calibration runs
+#[cfg(feature = "websocket")]
+pub struct CalibrationCountdown {
+    pub kind: CalibrationKind,
+    end_time: Instant,
+    /// Seconds left that were last sent to the clients
+    last_remaining: Option<u64>,
+}
+
+#[cfg(feature = "websocket")]
+impl CalibrationCountdown {
+    pub fn new(kind: CalibrationKind, delay: Duration) -> Self {
+        Self {
+            kind,
+            end_time: Instant::now() + delay,
+            last_remaining: None,
+        }
+    }
+
+    /// Returns the whole seconds left when it changes, 0 means the calibration should run now
+    pub fn tick(&mut self) -> Option<u64> {
+        let left = self.end_time.saturating_duration_since(Instant::now());
+        let remaining = left.as_secs() + (left.subsec_nanos() > 0) as u64;
+        if self.last_remaining == Some(remaining) {
+            return None;
+        }
+
+        self.last_remaining = Some(remaining);
+        Some(remaining)
+    }
+}
+
+/// One of the pair of trackers being watched while checking the sides
+struct SideCheckTracker {
+    index: usize,
+    side: TrackerSide,
+    last_timestamp_us: u64,
+    last_acceleration: Option<AccelMps2>,
+    /// Sum of how much the acceleration changed between samples, which works whether or not the
+    /// gravity has been removed
+    movement: f32,
+    samples: usize,
+}
+
+impl SideCheckTracker {
+    fn mean_movement(&self) -> f32 {
+        self.movement / self.samples.max(1) as f32
+    }
+}
+
+/// Watches a left and right tracker while the user moves the one on the side they were told to
+pub struct SideCheck {
+    /// The side that the user was told to move
+    pub side: TrackerSide,
+    end_time: Instant,
+    pair: [SideCheckTracker; 2],
+}
+
+impl SideCheck {
+    pub fn new(side: TrackerSide, duration: Duration, left: usize, right: usize) -> Self {
+        let tracker = |index, side| SideCheckTracker {
+            index,
+            side,
+            last_timestamp_us: 0,
+            last_acceleration: None,
+            movement: 0.,
+            samples: 0,
+        };
+
+        Self {
+            side,
+            end_time: Instant::now() + duration,
+            pair: [
+                tracker(left, TrackerSide::Left),
+                tracker(right, TrackerSide::Right),
+            ],
+        }
+    }
+
+    pub fn indices(&self) -> [usize; 2] {
+        self.pair.each_ref().map(|tracker| tracker.index)
+    }
+
+    pub fn add_sample(&mut self, index: usize, acceleration: AccelMps2, timestamp_us: u64) {
+        let Some(tracker) = self.pair.iter_mut().find(|tracker| tracker.index == index) else {
+            return;
+        };
+
+        // Only count each received packet once
+        if timestamp_us > tracker.last_timestamp_us {
+            tracker.last_timestamp_us = timestamp_us;
+            if let Some(last_acceleration) = tracker.last_acceleration {
+                tracker.movement += (acceleration.0 - last_acceleration.0).length();
+                tracker.samples += 1;
+            }
+            tracker.last_acceleration = Some(acceleration);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        Instant::now() >= self.end_time
+    }
+
+    /// The index of the tracker that moved, None if they moved too similarly to tell
+    pub fn moved(&self) -> Option<(usize, TrackerSide)> {
+        let [left, right] = &self.pair;
+        let (moved, still) = if left.mean_movement() > right.mean_movement() {
+            (left, right)
+        } else {
+            (right, left)
+        };
+
+        (moved.mean_movement() > still.mean_movement() * SIDE_CHECK_MOVEMENT_RATIO)
+            .then_some((moved.index, moved.side))
+    }
+}
+
+/// Faces the tracker rests on in the accelerometer scale calibration, named by which of its axes
+/// points up
+#[cfg(feature = "websocket")]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub enum AccelFace {
+    ZUp,
+    ZDown,
+    XUp,
+    XDown,
+    YUp,
+    YDown,
+}
+
+#[cfg(feature = "websocket")]
+impl AccelFace {
+    /// In the order the user is asked to hold the tracker
+    pub const ALL: [Self; 6] = [
+        Self::ZUp,
+        Self::ZDown,
+        Self::XUp,
+        Self::XDown,
+        Self::YUp,
+        Self::YDown,
+    ];
+
+    fn axis(self) -> usize {
+        match self {
+            Self::XUp | Self::XDown => 0,
+            Self::YUp | Self::YDown => 1,
+            Self::ZUp | Self::ZDown => 2,
+        }
+    }
+}
+
+/// Samples a face needs before moving onto the next one
+#[cfg(feature = "websocket")]
+const MIN_FACE_SAMPLES: usize = 20;
+/// Standard deviation above this in m/s² means the tracker was moving on the face
+#[cfg(feature = "websocket")]
+const FACE_STATIONARY_THRESHOLD: f32 = 0.5;
+/// Gravity along the face's axis needs to be at least this fraction of what the other axes read
+/// for the tracker to be resting on that face
+#[cfg(feature = "websocket")]
+const FACE_AXIS_DOMINANCE: f32 = 2.;
+
+#[cfg(feature = "websocket")]
+pub enum AccelScaleStep {
+    Next(AccelFace),
+    /// Scale and bias for each axis to correct the acceleration with
+    Finished(glam::Vec3A, AccelMps2),
+    /// The faces don't fit together so the calibration has to start again
+    Failed(CodedMessage),
+}
+
+/// Finds the scale and bias of each of the accelerometer's axes by measuring gravity with the
+/// tracker resting on each of its 6 faces, since the faces on opposite sides of an axis should
+/// read the same amount of gravity with opposite signs
+#[cfg(feature = "websocket")]
+pub struct AccelScaleCalibration {
+    pub index: usize,
+    last_timestamp_us: u64,
+    /// For the face the tracker is on now
+    samples: Vec<glam::Vec3A>,
+    /// Mean of each face done so far
+    means: Vec<glam::Vec3A>,
+}
+
+#[cfg(feature = "websocket")]
+impl AccelScaleCalibration {
+    pub fn new(index: usize) -> Self {
+        Self {
+            index,
+            last_timestamp_us: 0,
+            samples: Vec::new(),
+            means: Vec::new(),
+        }
+    }
+
+    pub fn face(&self) -> AccelFace {
+        AccelFace::ALL[self.means.len()]
+    }
+
+    /// How many faces have been done
+    pub fn step(&self) -> usize {
+        self.means.len()
+    }
+
+    /// Takes the uncorrected acceleration from the tracker
+    pub fn add_sample(&mut self, acceleration: AccelMps2, timestamp_us: u64) {
+        // Only count each received packet once
+        if timestamp_us > self.last_timestamp_us {
+            self.last_timestamp_us = timestamp_us;
+            self.samples.push(acceleration.0);
+        }
+    }
+
+    /// Finishes the current face, staying on it if the samples don't look like the tracker was
+    /// resting on it
+    pub fn advance(&mut self) -> Result<AccelScaleStep, CodedMessage> {
+        let face = self.face();
+        if self.samples.len() < MIN_FACE_SAMPLES {
+            return Err(
+                CodedMessage::new("accel_scale_not_enough_data").param("face", format!("{face:?}"))
+            );
+        }
+
+        let samples = std::mem::take(&mut self.samples);
+        let count = samples.len() as f32;
+        let mean = samples.iter().sum::<glam::Vec3A>() / count;
+        let variance = (samples.iter())
+            .map(|sample| sample.distance_squared(mean))
+            .sum::<f32>()
+            / count;
+        if variance.sqrt() > FACE_STATIONARY_THRESHOLD {
+            return Err(CodedMessage::new("accel_scale_moving").param("face", format!("{face:?}")));
+        }
+
+        let along_axis = mean[face.axis()].abs();
+        let across_axis = mean.length_squared() - along_axis * along_axis;
+        if along_axis < across_axis.sqrt() * FACE_AXIS_DOMINANCE || along_axis < REMOVED_THRESHOLD {
+            return Err(
+                CodedMessage::new("accel_scale_wrong_face").param("face", format!("{face:?}"))
+            );
+        }
+
+        self.means.push(mean);
+        if self.means.len() < AccelFace::ALL.len() {
+            return Ok(AccelScaleStep::Next(self.face()));
+        }
+
+        let mut scale = glam::Vec3A::ONE;
+        let mut bias = glam::Vec3A::ZERO;
+        for (faces, means) in AccelFace::ALL.chunks(2).zip(self.means.chunks(2)) {
+            let axis = faces[0].axis();
+            let (up, down) = (means[0][axis], means[1][axis]);
+            // Works whichever sign the IMU reports gravity with
+            if up.signum() == down.signum() {
+                return Ok(AccelScaleStep::Failed(
+                    CodedMessage::new("accel_scale_same_side")
+                        .param("up", format!("{:?}", faces[0]))
+                        .param("down", format!("{:?}", faces[1])),
+                ));
+            }
+
+            scale[axis] = 2. * STANDARD_GRAVITY / (up - down).abs();
+            bias[axis] = (up + down) / 2.;
+        }
+
+        Ok(AccelScaleStep::Finished(scale, AccelMps2(bias)))
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+
+    /// Gravity as read by an accelerometer that's off by the scale and bias
+    fn reading(face: AccelFace, scale: glam::Vec3A, bias: glam::Vec3A) -> glam::Vec3A {
+        let sign = match face {
+            AccelFace::XUp | AccelFace::YUp | AccelFace::ZUp => 1.,
+            _ => -1.,
+        };
+        let mut gravity = glam::Vec3A::ZERO;
+        gravity[face.axis()] = sign * STANDARD_GRAVITY;
+        gravity / scale + bias
+    }
+
+    fn rest_on(calibration: &mut AccelScaleCalibration, acceleration: glam::Vec3A) {
+        for _ in 0..MIN_FACE_SAMPLES {
+            let timestamp_us = calibration.last_timestamp_us + 10_000;
+            calibration.add_sample(AccelMps2(acceleration), timestamp_us);
+        }
+    }
+
+    #[test]
+    fn six_faces_solve_for_scale_and_bias() {
+        let scale = glam::Vec3A::new(1.05, 0.95, 1.1);
+        let bias = glam::Vec3A::new(0.2, -0.3, 0.1);
+        let mut calibration = AccelScaleCalibration::new(3);
+
+        for (step, face) in AccelFace::ALL.into_iter().enumerate() {
+            assert_eq!(calibration.step(), step);
+            assert_eq!(calibration.face(), face);
+            rest_on(&mut calibration, reading(face, scale, bias));
+            match calibration.advance().unwrap() {
+                AccelScaleStep::Next(next) => assert_eq!(next, AccelFace::ALL[step + 1]),
+                AccelScaleStep::Finished(found_scale, found_bias) => {
+                    assert_eq!(face, AccelFace::YDown);
+                    assert!(found_scale.abs_diff_eq(scale, 1e-4));
+                    assert!(found_bias.0.abs_diff_eq(bias, 1e-4));
+                }
+                AccelScaleStep::Failed(error) => panic!("failed with {}", error.code),
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_packets_are_counted_once() {
+        let mut calibration = AccelScaleCalibration::new(0);
+        for _ in 0..MIN_FACE_SAMPLES {
+            calibration.add_sample(AccelMps2(glam::Vec3A::Z * STANDARD_GRAVITY), 10_000);
+        }
+
+        let error = calibration.advance().err().unwrap();
+        assert_eq!(error.code, "accel_scale_not_enough_data");
+        assert_eq!(calibration.step(), 0);
+    }
+
+    #[test]
+    fn bad_faces_have_to_be_redone() {
+        let mut calibration = AccelScaleCalibration::new(0);
+        let gravity = glam::Vec3A::Z * STANDARD_GRAVITY;
+
+        for i in 0..MIN_FACE_SAMPLES {
+            let shake = if i % 2 == 0 { 2. } else { -2. };
+            let timestamp_us = (i as u64 + 1) * 10_000;
+            calibration.add_sample(AccelMps2(gravity + glam::Vec3A::X * shake), timestamp_us);
+        }
+        let error = calibration.advance().err().unwrap();
+        assert_eq!(error.code, "accel_scale_moving");
+
+        // Tilted halfway between two faces
+        rest_on(&mut calibration, (glam::Vec3A::Z + glam::Vec3A::X) * 7.);
+        let error = calibration.advance().err().unwrap();
+        assert_eq!(error.code, "accel_scale_wrong_face");
+        assert_eq!(error.params["face"], "ZUp");
+        assert_eq!(calibration.step(), 0);
+
+        rest_on(&mut calibration, gravity);
+        assert!(matches!(
+            calibration.advance(),
+            Ok(AccelScaleStep::Next(AccelFace::ZDown))
+        ));
+    }
+
+    #[test]
+    fn faces_on_the_same_side_fail() {
+        let mut calibration = AccelScaleCalibration::new(0);
+        for face in AccelFace::ALL {
+            // Never flipped over for the z axis
+            let face = if face == AccelFace::ZDown {
+                AccelFace::ZUp
+            } else {
+                face
+            };
+            rest_on(
+                &mut calibration,
+                reading(face, glam::Vec3A::ONE, glam::Vec3A::ZERO),
+            );
+            if let AccelScaleStep::Failed(error) = calibration.advance().unwrap() {
+                assert_eq!(error.code, "accel_scale_same_side");
+                assert_eq!(error.params["up"], "ZUp");
+                assert_eq!(error.params["down"], "ZDown");
+                return;
+            }
+        }
+        panic!("the calibration should have failed");
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Wall clock changes smaller than this are treated as normal drift
+const WALL_CLOCK_JUMP_THRESHOLD_US: i64 = 1_000_000;
+const WALL_CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of wall clock jumps to remember
+const MAX_CLOCK_ADJUSTMENTS: usize = 16;
+
+pub fn unix_now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Monotonic clock that all timestamps sent to clients are relative to
+#[derive(Clone, Copy)]
+pub struct ServerClock {
+    start: Instant,
+    /// Wall clock time of `start` so clients can map timestamps back to real time
+    start_unix_us: u64,
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            start_unix_us: unix_now_us(),
+        }
+    }
+}
+
+impl ServerClock {
+    pub fn timestamp_us(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.start).as_micros() as u64
+    }
+
+    pub fn now_us(&self) -> u64 {
+        self.timestamp_us(Instant::now())
+    }
+
+    pub fn start_unix_us(&self) -> u64 {
+        self.start_unix_us
+    }
+
+    /// Adding this to a time on a device's clock gives the server timestamp, assuming the device
+    /// read its clock halfway through the round trip from the ping to the pong
+    pub fn device_offset_us(
+        &self,
+        ping_time: Instant,
+        pong_time: Instant,
+        device_time_us: u64,
+    ) -> i64 {
+        let midpoint = ping_time + pong_time.saturating_duration_since(ping_time) / 2;
+        self.timestamp_us(midpoint) as i64 - device_time_us as i64
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+pub struct ClockAdjustment {
+    /// Server clock time when the jump was noticed
+    pub timestamp_us: u64,
+    /// How far the wall clock moved compared to the server clock, positive if it went forwards
+    pub jump_us: i64,
+}
+
+/// Notices the wall clock jumping (e.g. NTP corrections or being changed by hand) compared to the
+/// server clock, the timestamps stay relative to the wall clock time at the start so they don't
+/// jump with it
+pub struct WallClockMonitor {
+    /// Where the wall clock is read from, tests set the time themselves
+    unix_now_us: Box<dyn Fn() -> u64 + Send + Sync>,
+    last_offset_us: Option<i64>,
+    last_check_time: Option<Instant>,
+    adjustments: VecDeque<ClockAdjustment>,
+}
+
+impl Default for WallClockMonitor {
+    fn default() -> Self {
+        Self::with_unix_clock(unix_now_us)
+    }
+}
+
+impl WallClockMonitor {
+    pub fn with_unix_clock(unix_now_us: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+        Self {
+            unix_now_us: Box::new(unix_now_us),
+            last_offset_us: None,
+            last_check_time: None,
+            adjustments: VecDeque::new(),
+        }
+    }
+
+    /// Returns the jump if the wall clock has moved since the last check
+    pub fn check(&mut self, clock: &ServerClock) -> Option<ClockAdjustment> {
+        if self
+            .last_check_time
+            .is_some_and(|time| time.elapsed() < WALL_CLOCK_CHECK_INTERVAL)
+        {
+            return None;
+        }
+        self.last_check_time = Some(Instant::now());
+
+        let timestamp_us = clock.now_us();
+        let offset_us = (self.unix_now_us)() as i64 - (clock.start_unix_us + timestamp_us) as i64;
+        let last_offset_us = self.last_offset_us.replace(offset_us).unwrap_or(0);
+        let jump_us = offset_us - last_offset_us;
+        if jump_us.abs() < WALL_CLOCK_JUMP_THRESHOLD_US {
+            return None;
+        }
+
+        let adjustment = ClockAdjustment {
+            timestamp_us,
+            jump_us,
+        };
+        if self.adjustments.len() == MAX_CLOCK_ADJUSTMENTS {
+            self.adjustments.pop_front();
+        }
+        self.adjustments.push_back(adjustment);
+        Some(adjustment)
+    }
+
+    pub fn adjustments(&self) -> Vec<ClockAdjustment> {
+        self.adjustments.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn timestamps_are_relative_to_the_start_and_only_go_forward() {
+        let clock = ServerClock::default();
+        assert_eq!(clock.timestamp_us(clock.start), 0);
+        assert_eq!(
+            clock.timestamp_us(clock.start + Duration::from_millis(1500)),
+            1_500_000
+        );
+        // Instants from before the server started can't go negative
+        if let Some(before) = clock.start.checked_sub(Duration::from_secs(1)) {
+            assert_eq!(clock.timestamp_us(before), 0);
+        }
+
+        let mut last_us = 0;
+        for _ in 0..1000 {
+            let now_us = clock.now_us();
+            assert!(now_us >= last_us);
+            last_us = now_us;
+        }
+    }
+
+    #[test]
+    fn device_times_are_converted_with_the_offset() {
+        let clock = ServerClock::default();
+        let ping_time = clock.start + Duration::from_secs(10);
+        let pong_time = ping_time + Duration::from_millis(20);
+        // The device's clock started 4 seconds after the server's, it read 6.01s at the midpoint
+        let offset_us = clock.device_offset_us(ping_time, pong_time, 6_010_000);
+        assert_eq!(offset_us, 4_000_000);
+        assert_eq!(6_010_000_u64.saturating_add_signed(offset_us), 10_010_000);
+
+        // And ahead of the server's
+        let offset_us = clock.device_offset_us(ping_time, pong_time, 15_010_000);
+        assert_eq!(offset_us, -5_000_000);
+        assert_eq!(15_010_000_u64.saturating_add_signed(offset_us), 10_010_000);
+    }
+
+    /// Monitor reading a wall clock that starts when the server clock did and only moves when the
+    /// test moves it
+    fn fake_wall_clock(clock: &ServerClock) -> (WallClockMonitor, Arc<AtomicU64>) {
+        let unix_us = Arc::new(AtomicU64::new(clock.start_unix_us()));
+        let monitor = WallClockMonitor::with_unix_clock({
+            let unix_us = unix_us.clone();
+            move || unix_us.load(Ordering::Relaxed)
+        });
+        (monitor, unix_us)
+    }
+
+    /// Jump in whole seconds since the server clock moves a little between the checks too
+    fn jump_secs(adjustment: &ClockAdjustment) -> i64 {
+        (adjustment.jump_us as f64 / 1e6).round() as i64
+    }
+
+    /// Checks without waiting for the interval
+    fn check_now(monitor: &mut WallClockMonitor, clock: &ServerClock) -> Option<i64> {
+        monitor.last_check_time = None;
+        monitor.check(clock).as_ref().map(jump_secs)
+    }
+
+    #[test]
+    fn wall_clock_jumps_are_noticed_both_ways() {
+        let clock = ServerClock::default();
+        let (mut monitor, unix_us) = fake_wall_clock(&clock);
+        assert_eq!(check_now(&mut monitor, &clock), None);
+
+        unix_us.fetch_add(5_000_000, Ordering::Relaxed);
+        assert_eq!(check_now(&mut monitor, &clock), Some(5));
+        // Only the change since the last check counts
+        assert_eq!(check_now(&mut monitor, &clock), None);
+
+        unix_us.fetch_sub(3_000_000, Ordering::Relaxed);
+        assert_eq!(check_now(&mut monitor, &clock), Some(-3));
+
+        let jumps = monitor
+            .adjustments()
+            .iter()
+            .map(jump_secs)
+            .collect::<Vec<_>>();
+        assert_eq!(jumps, [5, -3]);
+
+        // Jumps aren't looked for again until the interval has passed
+        unix_us.fetch_add(10_000_000, Ordering::Relaxed);
+        assert!(monitor.check(&clock).is_none());
+        assert_eq!(check_now(&mut monitor, &clock), Some(10));
+    }
+
+    #[test]
+    fn wall_clock_drift_is_not_a_jump() {
+        let clock = ServerClock::default();
+        let (mut monitor, unix_us) = fake_wall_clock(&clock);
+        check_now(&mut monitor, &clock);
+
+        // Drifting just under the threshold every check never adds up to a jump
+        for _ in 0..5 {
+            unix_us.fetch_add(900_000, Ordering::Relaxed);
+            assert_eq!(check_now(&mut monitor, &clock), None);
+        }
+        unix_us.fetch_sub(900_000, Ordering::Relaxed);
+        assert_eq!(check_now(&mut monitor, &clock), None);
+        assert!(monitor.adjustments().is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_wall_clock_jumps_are_kept() {
+        let clock = ServerClock::default();
+        let (mut monitor, unix_us) = fake_wall_clock(&clock);
+        check_now(&mut monitor, &clock);
+
+        let jumps = 2..MAX_CLOCK_ADJUSTMENTS as i64 + 6;
+        for seconds in jumps.clone() {
+            unix_us.fetch_add(seconds as u64 * 1_000_000, Ordering::Relaxed);
+            assert_eq!(check_now(&mut monitor, &clock), Some(seconds));
+        }
+
+        let kept = monitor
+            .adjustments()
+            .iter()
+            .map(jump_secs)
+            .collect::<Vec<_>>();
+        assert_eq!(kept.len(), MAX_CLOCK_ADJUSTMENTS);
+        assert_eq!(kept, jumps.skip(4).collect::<Vec<_>>());
+        // In the order they happened
+        assert!(monitor
+            .adjustments()
+            .windows(2)
+            .all(|pair| pair[0].timestamp_us <= pair[1].timestamp_us));
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+};
+
+use crate::{
+    battery::BatteryConfig,
+    exporter::ExportConfig,
+    fusion::YawCorrectionConfig,
+    gravity::GravityConfig,
+    input::InputConfig,
+    profiles::{validate_profiles, ConfigProfile},
+    serial::SerialProtocol,
+    tracker::{AnomalyConfig, StatusRecoveryConfig, TrackerConfig},
+    udp_server::{MULTICAST_IP, UDP_PORT},
+};
+#[cfg(feature = "osc")]
+use crate::{
+    osc::VrchatOscConfig,
+    routing::{validate_routes, OutputRoute},
+};
+
+pub const CONFIG_PATH: &str = "mycap_config.json";
+
+/// A config value that isn't allowed, naming the field so that a hand edited config can be fixed
+#[derive(Debug)]
+pub struct ConfigError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Prefixes the field with the field that contains it
+    pub fn in_field(self, parent: &str) -> Self {
+        Self {
+            field: format!("{parent}.{}", self.field),
+            message: self.message,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct TrackerConfigEntry {
+    pub id: String,
+    pub index: usize,
+    #[serde(flatten)]
+    pub config: TrackerConfig,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum DiscoveryMode {
+    /// Devices send their handshakes to the multicast group
+    #[default]
+    Multicast,
+    /// The server announces itself to the broadcast address of every interface
+    Broadcast,
+}
+
+/// Largest reorder window that duplicate packets can still be detected in
+pub const MAX_REORDER_WINDOW: u32 = 63;
+
+/// What to do with packets that have a packet number older than the latest one received
+#[derive(Clone, Copy, PartialEq, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum PacketOrderPolicy {
+    /// Drop anything that isn't newer than the latest packet
+    #[default]
+    StrictDrop,
+    /// Ignore the packet numbers completely
+    AcceptAll,
+    /// Accept packets up to this many packet numbers older than the latest packet, at most
+    /// MAX_REORDER_WINDOW
+    ReorderWindow(u32),
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub mode: DiscoveryMode,
+    /// Port the server listens on, devices find out about a different port from the server's packets
+    pub udp_port: u16,
+    pub multicast_ip: Ipv4Addr,
+    pub multicast_ttl: u32,
+    /// Fallback to broadcast discovery if no device has connected after this many seconds
+    /// 0 means never fallback
+    pub broadcast_fallback_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            mode: DiscoveryMode::default(),
+            udp_port: UDP_PORT,
+            multicast_ip: MULTICAST_IP,
+            multicast_ttl: 1,
+            broadcast_fallback_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WebsocketConfig {
+    /// Origins of the webpages that can connect, anything else gets a 403 so that any page open in
+    /// a browser can't read the trackers or send commands
+    /// An origin without a port allows every port
+    pub allowed_origins: Vec<String>,
+    /// Allow clients without an Origin header, which is anything that isn't a browser
+    pub allow_missing_origin: bool,
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: [
+                "http://localhost",
+                "https://localhost",
+                "http://127.0.0.1",
+                "http://[::1]",
+                // The app's webview
+                "tauri://localhost",
+                "http://tauri.localhost",
+                "https://tauri.localhost",
+            ]
+            .map(String::from)
+            .to_vec(),
+            allow_missing_origin: true,
+        }
+    }
+}
+
+impl WebsocketConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (i, origin) in self.allowed_origins.iter().enumerate() {
+            if !origin.contains("://") {
+                return Err(ConfigError::new(
+                    format!("allowed_origins[{i}]"),
+                    "must start with the scheme such as http://",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn is_origin_allowed(&self, origin: Option<&str>) -> bool {
+        let Some(origin) = origin else {
+            return self.allow_missing_origin;
+        };
+
+        self.allowed_origins.iter().any(|allowed| {
+            let allowed = allowed.trim_end_matches('/');
+            match origin.get(..allowed.len()) {
+                Some(start) if start.eq_ignore_ascii_case(allowed) => {
+                    let rest = &origin[allowed.len()..];
+                    rest.is_empty()
+                        || rest.strip_prefix(':').is_some_and(|port| {
+                            !port.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit())
+                        })
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+/// Stops a typo in the config from keeping hours of data for every tracker
+pub const MAX_HISTORY_SECS: u32 = 300;
+
+/// Everything that gets saved to the config file
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Tracker configs along with the index they were assigned so that it stays the same
+    pub trackers: Vec<TrackerConfigEntry>,
+    /// Only accept devices with a mac address inside the allowlist
+    pub allowlist_enabled: bool,
+    pub allowlist: Vec<String>,
+    /// Devices and addresses that have all their packets dropped
+    pub blocked_macs: Vec<String>,
+    pub blocked_addresses: Vec<IpAddr>,
+    /// New devices get told the server is full past this many devices
+    /// 0 means no limit
+    pub max_devices: usize,
+    /// Space for this many devices is reserved when starting, more can still connect
+    pub expected_devices: usize,
+    pub discovery: DiscoveryConfig,
+    /// Newer firmware can use the JSON protocol to support extra wifi options
+    pub serial_protocol: SerialProtocol,
+    pub export: ExportConfig,
+    #[cfg(feature = "osc")]
+    pub vrchat_osc: VrchatOscConfig,
+    pub input: InputConfig,
+    /// Send some of the trackers to other apps, evaluated every tick
+    #[cfg(feature = "osc")]
+    pub routes: Vec<OutputRoute>,
+    /// Sets of settings that can be switched between at runtime
+    pub profiles: Vec<ConfigProfile>,
+    /// Profile that was applied last, the settings stay applied after restarting
+    pub active_profile: Option<String>,
+    pub gravity: GravityConfig,
+    /// Treat a gap between ticks longer than this as the computer having been asleep
+    /// 0 means never
+    pub clock_jump_secs: u64,
+    /// Mark a tracker's data as stale when nothing has been received for this long, separate from
+    /// the device timing out
+    /// 0 means never
+    pub stale_data_ms: u64,
+    /// Treat no data for this many of the tracker's usual intervals between packets as a gap
+    /// 0 means never
+    pub data_gap_intervals: u32,
+    /// Keep this many seconds of each tracker's data for clients to look back at, at most
+    /// MAX_HISTORY_SECS
+    /// 0 means off which saves the memory
+    pub history_secs: u32,
+    /// Height of the floor in meters that gets subtracted from the position of foot trackers
+    pub floor_offset: f32,
+    /// Filter the trackers on a pool of threads each tick when there are at least this many
+    /// 0 means never, handing the trackers to the threads costs more than filtering them unless
+    /// there are lots of them, needs the parallel feature
+    pub parallel_tick_trackers: usize,
+    pub yaw_correction: YawCorrectionConfig,
+    pub status_recovery: StatusRecoveryConfig,
+    pub anomaly: AnomalyConfig,
+    pub packet_order: PacketOrderPolicy,
+    /// Check that the firewall isn't blocking devices when starting
+    pub firewall_probe: bool,
+    pub battery: BatteryConfig,
+    /// Changes take effect when the websocket server restarts
+    pub websocket: WebsocketConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            trackers: Vec::new(),
+            allowlist_enabled: false,
+            allowlist: Vec::new(),
+            blocked_macs: Vec::new(),
+            blocked_addresses: Vec::new(),
+            max_devices: 0,
+            expected_devices: 16,
+            discovery: DiscoveryConfig::default(),
+            serial_protocol: SerialProtocol::default(),
+            export: ExportConfig::default(),
+            #[cfg(feature = "osc")]
+            vrchat_osc: VrchatOscConfig::default(),
+            input: InputConfig::default(),
+            #[cfg(feature = "osc")]
+            routes: Vec::new(),
+            profiles: Vec::new(),
+            active_profile: None,
+            gravity: GravityConfig::default(),
+            clock_jump_secs: 5,
+            stale_data_ms: 1000,
+            data_gap_intervals: 10,
+            history_secs: 0,
+            floor_offset: 0.,
+            parallel_tick_trackers: 0,
+            yaw_correction: YawCorrectionConfig::default(),
+            status_recovery: StatusRecoveryConfig::default(),
+            anomaly: AnomalyConfig::default(),
+            packet_order: PacketOrderPolicy::default(),
+            firewall_probe: true,
+            battery: BatteryConfig::default(),
+            websocket: WebsocketConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(string) => {
+                let config: Self = serde_json::from_str(&string)?;
+                // An invalid tracker config only resets that tracker when it gets registered
+                config.validate_settings()?;
+                Ok(config)
+            }
+            // No config yet so just use the default
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for entry in &self.trackers {
+            entry
+                .config
+                .validate()
+                .map_err(|error| error.in_field(&format!("trackers[{}]", entry.id)))?;
+        }
+
+        self.validate_settings()
+    }
+
+    /// Everything other than the trackers' configs
+    fn validate_settings(&self) -> Result<(), ConfigError> {
+        #[cfg(feature = "osc")]
+        self.vrchat_osc
+            .validate()
+            .map_err(|error| error.in_field("vrchat_osc"))?;
+        self.input
+            .validate()
+            .map_err(|error| error.in_field("input"))?;
+        self.anomaly
+            .validate()
+            .map_err(|error| error.in_field("anomaly"))?;
+        self.battery
+            .validate()
+            .map_err(|error| error.in_field("battery"))?;
+        self.websocket
+            .validate()
+            .map_err(|error| error.in_field("websocket"))?;
+        #[cfg(feature = "osc")]
+        validate_routes(&self.routes)?;
+        validate_profiles(&self.profiles)?;
+
+        if !self.floor_offset.is_finite() {
+            return Err(ConfigError::new("floor_offset", "must be finite"));
+        }
+
+        if let PacketOrderPolicy::ReorderWindow(window) = self.packet_order {
+            if window == 0 || window > MAX_REORDER_WINDOW {
+                return Err(ConfigError::new(
+                    "packet_order",
+                    format!("reorder window must be between 1 and {MAX_REORDER_WINDOW}"),
+                ));
+            }
+        }
+
+        if self.history_secs > MAX_HISTORY_SECS {
+            return Err(ConfigError::new(
+                "history_secs",
+                format!("can't be more than {MAX_HISTORY_SECS}"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn is_device_allowed(&self, mac: &str) -> bool {
+        !self.allowlist_enabled || self.allowlist.iter().any(|allowed| allowed == mac)
+    }
+
+    pub fn tracker_entry(&self, id: &str) -> Option<&TrackerConfigEntry> {
+        self.trackers.iter().find(|entry| entry.id == id)
+    }
+
+    /// Replaces the entry with the same id or adds it if it doesn't exist
+    pub fn set_tracker_entry(&mut self, entry: TrackerConfigEntry) {
+        match self.trackers.iter_mut().find(|saved| saved.id == entry.id) {
+            Some(saved) => *saved = entry,
+            None => self.trackers.push(entry),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_match_with_any_port() {
+        let config = WebsocketConfig {
+            allowed_origins: vec![
+                "http://localhost/".to_string(),
+                "https://app.example:8443".to_string(),
+            ],
+            allow_missing_origin: false,
+        };
+
+        for origin in [
+            "http://localhost",
+            "http://LOCALHOST:5173",
+            "https://app.example:8443",
+        ] {
+            assert!(config.is_origin_allowed(Some(origin)), "{origin}");
+        }
+        for origin in [
+            "http://localhost.evil.com",
+            "http://localhost:",
+            "http://localhost:80abc",
+            "https://localhost",
+            "https://app.example:9000",
+            "http://local",
+        ] {
+            assert!(!config.is_origin_allowed(Some(origin)), "{origin}");
+        }
+        assert!(!config.is_origin_allowed(None));
+    }
+
+    #[test]
+    fn missing_origin_follows_the_config() {
+        let config = WebsocketConfig::default();
+        assert!(config.is_origin_allowed(None));
+        assert!(config.is_origin_allowed(Some("tauri://localhost")));
+        assert!(!config.is_origin_allowed(Some("https://example.com")));
+    }
+
+    #[test]
+    fn origins_need_a_scheme() {
+        let mut config = WebsocketConfig::default();
+        assert!(config.validate().is_ok());
+        config.allowed_origins.push("localhost:3000".to_string());
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.field,
+            format!("allowed_origins[{}]", config.allowed_origins.len() - 1)
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Maximum number of disconnects to remember per device
+const MAX_EPISODES: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+pub enum DisconnectCause {
+    /// Stopped sending packets for too long
+    Timeout,
+    /// Handshaked again from a different address
+    AddressChange,
+    /// Handshaked again from the same address which resets the packet number
+    Reboot,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisconnectEpisode {
+    pub start: Instant,
+    pub end: Instant,
+    pub cause: DisconnectCause,
+}
+
+impl DisconnectEpisode {
+    pub fn duration(&self) -> Duration {
+        self.end.saturating_duration_since(self.start)
+    }
+}
+
+/// Keeps track of when a device disconnects and reconnects to see how often it happens
+#[derive(Default)]
+pub struct ConnectionHistory {
+    /// When the current disconnect started if currently disconnected
+    disconnected: Option<(Instant, DisconnectCause)>,
+    episodes: VecDeque<DisconnectEpisode>,
+    pub total_downtime: Duration,
+    pub episode_count: usize,
+}
+
+impl ConnectionHistory {
+    /// Marks the start of a disconnect, ignored if already disconnected
+    pub fn disconnected(&mut self, since: Instant, cause: DisconnectCause) {
+        if self.disconnected.is_none() {
+            self.disconnected = Some((since, cause));
+        }
+    }
+
+    /// Finishes the current disconnect, returning it if there was one
+    pub fn reconnected(&mut self, now: Instant) -> Option<DisconnectEpisode> {
+        let (start, cause) = self.disconnected.take()?;
+        let episode = DisconnectEpisode {
+            start,
+            end: now,
+            cause,
+        };
+
+        if self.episodes.len() == MAX_EPISODES {
+            self.episodes.pop_front();
+        }
+
+        self.episodes.push_back(episode);
+        self.total_downtime += episode.duration();
+        self.episode_count += 1;
+        Some(episode)
+    }
+
+    /// Number of remembered disconnects that ended within the duration before now
+    pub fn episodes_within(&self, duration: Duration, now: Instant) -> usize {
+        self.episodes
+            .iter()
+            .rev()
+            .take_while(|episode| now.saturating_duration_since(episode.end) <= duration)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Disconnects and reconnects every second after start, each down for half a second
+    fn flap(history: &mut ConnectionHistory, start: Instant, count: u32) {
+        for i in 0..count {
+            let since = start + SECOND * i;
+            history.disconnected(since, DisconnectCause::Timeout);
+            history.reconnected(since + SECOND / 2).unwrap();
+        }
+    }
+
+    #[test]
+    fn disconnecting_again_keeps_the_first_start_and_cause() {
+        let mut history = ConnectionHistory::default();
+        let start = Instant::now();
+        history.disconnected(start, DisconnectCause::Timeout);
+        history.disconnected(start + SECOND, DisconnectCause::Reboot);
+
+        let episode = history.reconnected(start + SECOND * 3).unwrap();
+        assert_eq!(episode.start, start);
+        assert_eq!(episode.cause, DisconnectCause::Timeout);
+        assert_eq!(episode.duration(), SECOND * 3);
+        assert_eq!(history.total_downtime, SECOND * 3);
+        assert_eq!(history.episode_count, 1);
+    }
+
+    #[test]
+    fn reconnecting_without_a_disconnect_is_ignored() {
+        let mut history = ConnectionHistory::default();
+        let now = Instant::now();
+        assert!(history.reconnected(now).is_none());
+
+        history.disconnected(now, DisconnectCause::AddressChange);
+        assert!(history.reconnected(now + SECOND).is_some());
+        assert!(history.reconnected(now + SECOND * 2).is_none());
+        assert_eq!(history.episode_count, 1);
+        assert_eq!(history.total_downtime, SECOND);
+    }
+
+    #[test]
+    fn only_the_latest_episodes_are_kept_but_all_are_counted() {
+        let mut history = ConnectionHistory::default();
+        let start = Instant::now();
+        flap(&mut history, start, MAX_EPISODES as u32 + 10);
+
+        assert_eq!(history.episodes.len(), MAX_EPISODES);
+        assert_eq!(history.episodes[0].start, start + SECOND * 10);
+        assert_eq!(history.episode_count, MAX_EPISODES + 10);
+        assert_eq!(
+            history.total_downtime,
+            SECOND / 2 * (MAX_EPISODES as u32 + 10)
+        );
+    }
+
+    #[test]
+    fn episodes_are_counted_by_when_they_ended() {
+        let mut history = ConnectionHistory::default();
+        let start = Instant::now();
+        flap(&mut history, start, 5);
+        // Ended at 0.5, 1.5, 2.5, 3.5 and 4.5 seconds
+        let now = start + SECOND * 5;
+
+        assert_eq!(history.episodes_within(Duration::ZERO, now), 0);
+        assert_eq!(history.episodes_within(SECOND / 2, now), 1);
+        assert_eq!(history.episodes_within(SECOND * 2, now), 2);
+        assert_eq!(history.episodes_within(SECOND * 3 / 2, now + SECOND), 1);
+        assert_eq!(history.episodes_within(SECOND * 60, now), 5);
+        // Still disconnected ones haven't ended
+        history.disconnected(now, DisconnectCause::Reboot);
+        assert_eq!(history.episodes_within(SECOND * 60, now), 5);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Maximum number of errors to remember per device
+const MAX_ERRORS: usize = 32;
+/// Only send one error per device to the clients this often so a crash looping device can't flood
+/// them, the rest are counted and sent with the next one
+const BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+pub enum DeviceErrorCode {
+    ImuInitFail,
+    Brownout,
+    WifiWeak,
+    FlashFull,
+    /// From newer firmware that this server doesn't know about yet
+    Unknown(u8),
+}
+
+impl From<u8> for DeviceErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => Self::ImuInitFail,
+            0x02 => Self::Brownout,
+            0x03 => Self::WifiWeak,
+            0x04 => Self::FlashFull,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl DeviceErrorCode {
+    /// What the user can do about it, kept here so the UI only has to show it
+    pub fn advice(self) -> Option<&'static str> {
+        Some(match self {
+            Self::ImuInitFail => {
+                "A sensor failed to start, check its wiring and that its address is correct"
+            }
+            Self::Brownout => {
+                "The device lost power for a moment, check the battery and the power wiring"
+            }
+            Self::WifiWeak => {
+                "The wifi signal is weak, try moving the device closer to the router or using a less congested channel"
+            }
+            Self::FlashFull => "The device's storage is full, try resetting its settings",
+            Self::Unknown(_) => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceError {
+    pub code: DeviceErrorCode,
+    pub detail: Option<String>,
+    pub time: Instant,
+}
+
+/// Errors a device has reported along with how many haven't been sent to the clients
+#[derive(Default)]
+pub struct DeviceErrorLog {
+    errors: VecDeque<DeviceError>,
+    last_broadcast_time: Option<Instant>,
+    suppressed: u32,
+}
+
+impl DeviceErrorLog {
+    /// Remembers the error, returning how many errors were suppressed before it if it should be
+    /// sent to the clients
+    pub fn push(&mut self, error: DeviceError) -> Option<u32> {
+        let now = error.time;
+        if self.errors.len() == MAX_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(error);
+
+        if self
+            .last_broadcast_time
+            .is_some_and(|last| now.saturating_duration_since(last) < BROADCAST_INTERVAL)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.last_broadcast_time = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+
+    /// Number of remembered errors within the duration before now
+    pub fn errors_within(&self, duration: Duration, now: Instant) -> usize {
+        self.errors
+            .iter()
+            .rev()
+            .take_while(|error| now.saturating_duration_since(error.time) <= duration)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(time: Instant) -> DeviceError {
+        DeviceError {
+            code: DeviceErrorCode::Brownout,
+            detail: None,
+            time,
+        }
+    }
+
+    #[test]
+    fn crash_loops_are_rate_limited() {
+        let mut log = DeviceErrorLog::default();
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        assert_eq!(log.push(error(start)), Some(0));
+        for i in 1..5 {
+            assert_eq!(log.push(error(start + second * i)), None);
+        }
+
+        // The next one sent says how many were held back
+        assert_eq!(log.push(error(start + BROADCAST_INTERVAL)), Some(4));
+        assert_eq!(log.push(error(start + BROADCAST_INTERVAL * 3)), Some(0));
+    }
+
+    #[test]
+    fn only_the_latest_errors_are_remembered() {
+        let mut log = DeviceErrorLog::default();
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        for i in 0..MAX_ERRORS as u32 + 8 {
+            log.push(error(start + minute * i));
+        }
+
+        let now = start + minute * (MAX_ERRORS as u32 + 7);
+        assert_eq!(log.errors.len(), MAX_ERRORS);
+        assert_eq!(log.errors_within(minute * 10, now), 11);
+        assert_eq!(log.errors_within(minute * 1000, now), MAX_ERRORS);
+    }
+
+    #[test]
+    fn unknown_codes_are_kept() {
+        assert_eq!(DeviceErrorCode::from(0x02), DeviceErrorCode::Brownout);
+        assert_eq!(DeviceErrorCode::from(0x7f), DeviceErrorCode::Unknown(0x7f));
+        assert!(DeviceErrorCode::Unknown(0x7f).advice().is_none());
+        assert!(DeviceErrorCode::WifiWeak.advice().is_some());
+    }
+}
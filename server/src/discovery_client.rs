@@ -0,0 +1,210 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use futures_util::Stream;
+// Follows tokio's clock so tests can pause it, it's the same as the std one otherwise
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::{
+    udp_packet::{UdpPacketServerInfo, UdpPacketServerProbe},
+    udp_server::{broadcast_addresses, interface_addresses, MULTICAST_IP},
+};
+
+/// How often the client asks for servers again
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Servers that haven't replied for this long are treated as gone
+pub const SERVER_EXPIRY: Duration = Duration::from_secs(5);
+
+/// A mycap server that replied to a probe
+#[derive(Clone, PartialEq, Debug, serde::Serialize)]
+pub struct DiscoveredServer {
+    /// Random for each run of the server
+    pub instance_id: u64,
+    pub address: IpAddr,
+    /// 0 if the server was built without the websocket server
+    pub websocket_port: u16,
+    pub udp_port: u16,
+    pub version: String,
+    pub tls: bool,
+}
+
+impl DiscoveredServer {
+    fn new(info: UdpPacketServerInfo, address: IpAddr) -> Self {
+        Self {
+            instance_id: info.instance_id,
+            address,
+            websocket_port: info.websocket_port,
+            udp_port: info.udp_port,
+            version: info.version,
+            tls: info.tls,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum DiscoveryEvent {
+    Found(DiscoveredServer),
+    /// Hasn't replied for SERVER_EXPIRY
+    Lost(DiscoveredServer),
+}
+
+/// Finds mycap servers on the LAN for UIs, separate from the server so it works without one running
+pub struct DiscoveryClient {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    /// Servers that have replied and when they last did
+    servers: Vec<(DiscoveredServer, Instant)>,
+    next_probe_time: Instant,
+}
+
+impl DiscoveryClient {
+    /// Looks for servers listening on the UDP port, which is UDP_PORT unless changed in their config
+    pub async fn new(udp_port: u16) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            targets: probe_targets(udp_port),
+            servers: Vec::new(),
+            next_probe_time: Instant::now(),
+        })
+    }
+
+    /// Servers that have replied recently
+    pub fn servers(&self) -> impl Iterator<Item = &DiscoveredServer> {
+        self.servers.iter().map(|(server, _)| server)
+    }
+
+    /// Probes for servers every PROBE_INTERVAL until one is found or lost
+    pub async fn next_event(&mut self) -> anyhow::Result<DiscoveryEvent> {
+        let mut buffer = [0; 64];
+        loop {
+            if let Some(i) = (self.servers.iter())
+                .position(|(_, last_seen)| last_seen.elapsed() >= SERVER_EXPIRY)
+            {
+                let (server, _) = self.servers.remove(i);
+                return Ok(DiscoveryEvent::Lost(server));
+            }
+
+            if Instant::now() >= self.next_probe_time {
+                for target in &self.targets {
+                    // Some interfaces might not allow broadcasting so just ignore them
+                    if let Err(error) = self
+                        .socket
+                        .send_to(&UdpPacketServerProbe::to_bytes(), target)
+                        .await
+                    {
+                        tracing::trace!("Failed to send server probe to {target}: {error}");
+                    }
+                }
+                self.next_probe_time = Instant::now() + PROBE_INTERVAL;
+            }
+
+            let wake_time = (self.servers.iter())
+                .map(|(_, last_seen)| *last_seen + SERVER_EXPIRY)
+                .fold(self.next_probe_time, Instant::min);
+
+            tokio::select! {
+                result = self.socket.recv_from(&mut buffer) => {
+                    let (length, address) = result?;
+                    let Some(info) = UdpPacketServerInfo::from_bytes(&buffer[..length]) else {
+                        continue;
+                    };
+
+                    if let Some(server) = self.server_replied(info, address.ip()) {
+                        return Ok(DiscoveryEvent::Found(server));
+                    }
+                }
+                _ = tokio::time::sleep_until(wake_time) => (),
+            }
+        }
+    }
+
+    /// Returns the server if it's new
+    fn server_replied(
+        &mut self,
+        info: UdpPacketServerInfo,
+        address: IpAddr,
+    ) -> Option<DiscoveredServer> {
+        // A server can reply from more than one address but only the first is kept
+        let known =
+            (self.servers.iter_mut()).find(|(server, _)| server.instance_id == info.instance_id);
+        if let Some((_, last_seen)) = known {
+            *last_seen = Instant::now();
+            return None;
+        }
+
+        let server = DiscoveredServer::new(info, address);
+        self.servers.push((server.clone(), Instant::now()));
+        Some(server)
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = anyhow::Result<DiscoveryEvent>> {
+        futures_util::stream::unfold(self, |mut client| async move {
+            let event = client.next_event().await;
+            Some((event, client))
+        })
+    }
+}
+
+/// Probes for servers listening on the UDP port, which is UDP_PORT unless changed in their config,
+/// and waits for the timeout to collect the replies
+pub fn discover_servers(udp_port: u16, timeout: Duration) -> anyhow::Result<Vec<DiscoveredServer>> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    for target in probe_targets(udp_port) {
+        if let Err(error) = socket.send_to(&UdpPacketServerProbe::to_bytes(), target) {
+            tracing::trace!("Failed to send server probe to {target}: {error}");
+        }
+    }
+
+    let end_time = std::time::Instant::now() + timeout;
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut buffer = [0; 64];
+    while let Some(remaining) = end_time.checked_duration_since(std::time::Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+
+        socket.set_read_timeout(Some(remaining))?;
+        let (length, address) = match socket.recv_from(&mut buffer) {
+            Ok(result) => result,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        if let Some(info) = UdpPacketServerInfo::from_bytes(&buffer[..length]) {
+            if !servers
+                .iter()
+                .any(|server| server.instance_id == info.instance_id)
+            {
+                servers.push(DiscoveredServer::new(info, address.ip()));
+            }
+        }
+    }
+
+    Ok(servers)
+}
+
+/// This computer, the multicast group the servers join and the broadcast address of every interface
+fn probe_targets(udp_port: u16) -> Vec<SocketAddr> {
+    let mut targets = vec![
+        SocketAddr::from((Ipv4Addr::LOCALHOST, udp_port)),
+        SocketAddr::from((MULTICAST_IP, udp_port)),
+    ];
+    targets.extend(
+        broadcast_addresses(&interface_addresses())
+            .into_iter()
+            .map(|address| SocketAddr::V4(SocketAddrV4::new(address, udp_port))),
+    );
+    targets
+}
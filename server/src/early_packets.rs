@@ -0,0 +1,118 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::fast_hash::FastHashMap;
+
+/// Packets older than this when the handshake arrives are from before it was sent so they're dropped
+pub const EARLY_PACKET_TTL: Duration = Duration::from_secs(1);
+/// The first packets after the handshake are the ones that matter, later ones are dropped
+pub const MAX_EARLY_PACKETS: usize = 8;
+/// Stops packets from lots of addresses that never handshake from using up memory
+pub const MAX_EARLY_ADDRESSES: usize = 64;
+
+/// Packets from addresses that haven't handshaked, kept for a moment since UDP can deliver a
+/// device's first packets before its handshake
+#[derive(Default)]
+pub struct EarlyPacketBuffer {
+    packets: FastHashMap<SocketAddr, VecDeque<(Instant, Vec<u8>)>>,
+}
+
+impl EarlyPacketBuffer {
+    pub fn push(&mut self, address: SocketAddr, bytes: &[u8], now: Instant) {
+        if self.packets.len() >= MAX_EARLY_ADDRESSES && !self.packets.contains_key(&address) {
+            self.evict(now);
+            if self.packets.len() >= MAX_EARLY_ADDRESSES {
+                return;
+            }
+        }
+
+        let packets = self.packets.entry(address).or_default();
+        if packets.len() < MAX_EARLY_PACKETS {
+            packets.push_back((now, bytes.to_vec()));
+        }
+    }
+
+    /// The address's packets that are still fresh in the order they arrived
+    pub fn take(&mut self, address: SocketAddr, now: Instant) -> Vec<Vec<u8>> {
+        let Some(packets) = self.packets.remove(&address) else {
+            return Vec::new();
+        };
+
+        packets
+            .into_iter()
+            .filter(|(received, _)| now.duration_since(*received) < EARLY_PACKET_TTL)
+            .map(|(_, bytes)| bytes)
+            .collect()
+    }
+
+    pub fn evict(&mut self, now: Instant) {
+        self.packets.retain(|_, packets| {
+            packets.retain(|(received, _)| now.duration_since(*received) < EARLY_PACKET_TTL);
+            !packets.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 2], port))
+    }
+
+    #[test]
+    fn keeps_the_first_packets_in_order() {
+        let mut buffer = EarlyPacketBuffer::default();
+        let now = Instant::now();
+        for i in 0..MAX_EARLY_PACKETS as u8 + 2 {
+            buffer.push(address(1), &[i], now);
+        }
+
+        let expected = (0..MAX_EARLY_PACKETS as u8)
+            .map(|i| vec![i])
+            .collect::<Vec<_>>();
+        assert_eq!(buffer.take(address(1), now), expected);
+        assert!(buffer.take(address(1), now).is_empty());
+    }
+
+    #[test]
+    fn drops_packets_past_the_ttl() {
+        let mut buffer = EarlyPacketBuffer::default();
+        let start = Instant::now();
+        buffer.push(address(1), &[0], start);
+        buffer.push(address(1), &[1], start + EARLY_PACKET_TTL / 2);
+
+        assert_eq!(buffer.take(address(1), start + EARLY_PACKET_TTL), [[1]]);
+
+        buffer.push(address(2), &[2], start);
+        buffer.evict(start + EARLY_PACKET_TTL);
+        assert!(buffer.packets.is_empty());
+    }
+
+    #[test]
+    fn limits_the_addresses() {
+        let mut buffer = EarlyPacketBuffer::default();
+        let start = Instant::now();
+        for port in 0..MAX_EARLY_ADDRESSES as u16 {
+            buffer.push(address(port), &[0], start);
+        }
+
+        // Full of fresh packets so the new address is dropped but known ones still get added to
+        let extra = address(MAX_EARLY_ADDRESSES as u16);
+        buffer.push(extra, &[0], start);
+        assert!(buffer.take(extra, start).is_empty());
+        buffer.push(address(0), &[1], start);
+        assert_eq!(buffer.take(address(0), start), [[0], [1]]);
+        buffer.push(address(0), &[0], start);
+
+        // Expired addresses make room
+        let later = start + EARLY_PACKET_TTL;
+        buffer.push(extra, &[0], later);
+        assert_eq!(buffer.packets.len(), 1);
+        assert_eq!(buffer.take(extra, later), [[0]]);
+    }
+}
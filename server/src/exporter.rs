@@ -0,0 +1,353 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::mpsc,
+};
+
+use crate::{
+    messages::CodedMessage,
+    tracker::{Tracker, TrackerList, TrackerStatus},
+};
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ExportColumn {
+    Orientation,
+    Acceleration,
+    Velocity,
+    Position,
+}
+
+impl ExportColumn {
+    fn headers(&self) -> &'static str {
+        match self {
+            Self::Orientation => "qw,qx,qy,qz",
+            Self::Acceleration => "ax,ay,az",
+            Self::Velocity => "vx,vy,vz",
+            Self::Position => "px,py,pz",
+        }
+    }
+}
+
+/// What to export for a tracker during a gap in its data
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum GapPolicy {
+    /// Keep exporting the last data
+    #[default]
+    Hold,
+    /// Skip the tracker's rows until the data comes back
+    Drop,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// CSV file to append rows to
+    pub csv_path: Option<PathBuf>,
+    /// Address to send each row to as a UDP text datagram
+    pub udp_target: Option<SocketAddr>,
+    pub columns: Vec<ExportColumn>,
+    /// Only export every nth tick
+    pub decimation: u32,
+    pub gap_policy: GapPolicy,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            csv_path: None,
+            udp_target: None,
+            columns: vec![ExportColumn::Orientation, ExportColumn::Acceleration],
+            decimation: 1,
+            gap_policy: GapPolicy::default(),
+        }
+    }
+}
+
+/// Exports the processed tracker data every tick for tools that read CSV or lines over UDP
+pub struct Exporter {
+    config: ExportConfig,
+    /// Rows get written to the file on a seperate thread so the tick isn't blocked by disk IO
+    csv_tx: Option<mpsc::Sender<String>>,
+    udp_socket: Option<UdpSocket>,
+    tick_count: u64,
+}
+
+impl Exporter {
+    pub fn start(config: ExportConfig) -> anyhow::Result<Self> {
+        if config.csv_path.is_none() && config.udp_target.is_none() {
+            return Err(CodedMessage::new("export_needs_target").into());
+        }
+
+        let csv_tx = match &config.csv_path {
+            Some(path) => Some(spawn_csv_writer(path, csv_header(&config.columns))?),
+            None => None,
+        };
+
+        let udp_socket = match config.udp_target {
+            Some(_) => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.set_nonblocking(true)?;
+                Some(socket)
+            }
+            None => None,
+        };
+
+        tracing::info!("Started exporting tracker data");
+        Ok(Self {
+            config,
+            csv_tx,
+            udp_socket,
+            tick_count: 0,
+        })
+    }
+
+    /// Starts again with the same config, the CSV file gets appended to
+    pub fn restart(&mut self) -> anyhow::Result<()> {
+        *self = Self::start(self.config.clone())?;
+        Ok(())
+    }
+
+    /// Fails if the CSV writer has stopped
+    pub fn export(&mut self, timestamp_unix_us: u64, trackers: &TrackerList) -> anyhow::Result<()> {
+        self.tick_count += 1;
+        if !self
+            .tick_count
+            .is_multiple_of(self.config.decimation.max(1) as u64)
+        {
+            return Ok(());
+        }
+
+        for tracker in trackers.iter() {
+            // Trackers that have never been seen would only export zeros
+            if tracker.info.status == TrackerStatus::Unknown {
+                continue;
+            }
+
+            if self.config.gap_policy == GapPolicy::Drop && tracker.gap_start_us.is_some() {
+                continue;
+            }
+
+            let row = csv_row(timestamp_unix_us, tracker, &self.config.columns);
+
+            if let Some(csv_tx) = &self.csv_tx {
+                csv_tx
+                    .send(row.clone())
+                    .map_err(|_| anyhow::anyhow!("The export file writer stopped"))?;
+            }
+
+            if let (Some(socket), Some(target)) = (&self.udp_socket, self.config.udp_target) {
+                // Dropping a line is better than blocking the tick
+                socket.send_to(row.as_bytes(), target).ok();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn spawn_csv_writer(path: &PathBuf, header: String) -> anyhow::Result<mpsc::Sender<String>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let write_header = file.metadata()?.len() == 0;
+    let mut writer = std::io::BufWriter::new(file);
+    let (tx, rx) = mpsc::channel::<String>();
+
+    std::thread::spawn(move || {
+        if write_header {
+            writeln!(writer, "{header}").ok();
+        }
+
+        while let Ok(row) = rx.recv() {
+            if let Err(error) = writeln!(writer, "{row}") {
+                tracing::error!("Failed to write to export file: {error}");
+                return;
+            }
+
+            // Write everything that's queued before flushing
+            while let Ok(row) = rx.try_recv() {
+                writeln!(writer, "{row}").ok();
+            }
+
+            writer.flush().ok();
+        }
+    });
+
+    Ok(tx)
+}
+
+fn csv_header(columns: &[ExportColumn]) -> String {
+    let mut header = String::from("timestamp,tracker_id");
+    for column in columns {
+        header.push(',');
+        header.push_str(column.headers());
+    }
+
+    header
+}
+
+fn csv_row(timestamp_unix_us: u64, tracker: &Tracker, columns: &[ExportColumn]) -> String {
+    let data = &tracker.data;
+    let mut row = format!("{timestamp_unix_us},{}", tracker.info.id);
+    for column in columns {
+        let values = match column {
+            ExportColumn::Orientation => {
+                let q = data.orientation.0;
+                vec![q.w, q.x, q.y, q.z]
+            }
+            ExportColumn::Acceleration => data.acceleration.0.to_array().to_vec(),
+            ExportColumn::Velocity => data.velocity.to_array().to_vec(),
+            ExportColumn::Position => data.position.to_array().to_vec(),
+        };
+
+        for value in values {
+            write!(row, ",{value:.6}").ok();
+        }
+    }
+
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn trackers() -> TrackerList {
+        let mut trackers = TrackerList::default();
+        for (index, (id, status)) in [
+            ("hip", TrackerStatus::Ok),
+            ("foot", TrackerStatus::Ok),
+            ("unseen", TrackerStatus::Unknown),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut tracker = Tracker::new(id.to_string(), index, Default::default());
+            tracker.info.status = status;
+            trackers.insert(tracker);
+        }
+        trackers
+    }
+
+    /// Exports to a UDP socket that gets returned to read the rows from
+    fn udp_exporter(config: ExportConfig) -> (Exporter, UdpSocket) {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let config = ExportConfig {
+            udp_target: Some(receiver.local_addr().unwrap()),
+            ..config
+        };
+        (Exporter::start(config).unwrap(), receiver)
+    }
+
+    fn received_rows(receiver: &UdpSocket) -> Vec<String> {
+        let mut buffer = [0; 1024];
+        std::iter::from_fn(|| {
+            let len = receiver.recv(&mut buffer).ok()?;
+            Some(String::from_utf8_lossy(&buffer[..len]).into_owned())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn exporting_needs_somewhere_to_go() {
+        let error = Exporter::start(ExportConfig::default()).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<CodedMessage>().unwrap().code,
+            "export_needs_target"
+        );
+    }
+
+    #[test]
+    fn only_every_nth_tick_is_exported() {
+        let (mut exporter, receiver) = udp_exporter(ExportConfig {
+            decimation: 3,
+            ..Default::default()
+        });
+        let trackers = trackers();
+        for tick in 1..=7 {
+            exporter.export(tick, &trackers).unwrap();
+        }
+
+        let timestamps: Vec<_> = received_rows(&receiver)
+            .iter()
+            .map(|row| row.split(',').take(2).collect::<Vec<_>>().join(","))
+            .collect();
+        // Trackers that have never sent data are left out
+        assert_eq!(timestamps, ["3,hip", "3,foot", "6,hip", "6,foot"]);
+    }
+
+    #[test]
+    fn trackers_in_a_gap_can_be_dropped() {
+        let mut trackers = trackers();
+        trackers.get_mut(0).unwrap().gap_start_us = Some(0);
+
+        for (gap_policy, expected) in [(GapPolicy::Hold, 2), (GapPolicy::Drop, 1)] {
+            let (mut exporter, receiver) = udp_exporter(ExportConfig {
+                gap_policy,
+                ..Default::default()
+            });
+            exporter.export(1, &trackers).unwrap();
+            assert_eq!(received_rows(&receiver).len(), expected);
+        }
+    }
+
+    #[test]
+    fn rows_follow_the_columns() {
+        let columns = [ExportColumn::Orientation, ExportColumn::Position];
+        assert_eq!(
+            csv_header(&columns),
+            "timestamp,tracker_id,qw,qx,qy,qz,px,py,pz"
+        );
+
+        let mut tracker = Tracker::new("hip".to_string(), 0, Default::default());
+        tracker.data.position = glam::Vec3A::new(1., -2., 0.5);
+        assert_eq!(
+            csv_row(42, &tracker, &columns),
+            "42,hip,1.000000,0.000000,0.000000,0.000000,1.000000,-2.000000,0.500000"
+        );
+    }
+
+    /// Waits for the writer thread to get the file to this many lines
+    fn wait_for_lines(path: &PathBuf, count: usize) -> String {
+        let mut contents = String::new();
+        for _ in 0..50 {
+            contents = std::fs::read_to_string(path).unwrap();
+            if contents.lines().count() >= count {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        contents
+    }
+
+    #[test]
+    fn csv_header_is_only_written_to_a_new_file() {
+        let path = std::env::temp_dir().join(format!("mycap-export-{}.csv", std::process::id()));
+        let config = ExportConfig {
+            csv_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let trackers = trackers();
+
+        let mut exporter = Exporter::start(config).unwrap();
+        exporter.export(1, &trackers).unwrap();
+        wait_for_lines(&path, 3);
+        exporter.restart().unwrap();
+        exporter.export(2, &trackers).unwrap();
+        let contents = wait_for_lines(&path, 5);
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 5, "{contents}");
+        assert_eq!(lines[0], csv_header(&ExportConfig::default().columns));
+        assert!(lines[1..].iter().all(|line| !line.starts_with("timestamp")));
+    }
+}
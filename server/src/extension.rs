@@ -0,0 +1,99 @@
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use crate::{
+    main_server::{MainServer, ServerMessage},
+    tracker::{TrackerConfig, TrackerStatus},
+    units::{AccelMps2, SensorQuat},
+};
+
+/// Packet types from this up are reserved for extensions and never used by mycap itself
+pub const EXTENSION_PACKET_START: u8 = 0x80;
+
+pub fn is_extension_packet(packet_type: u8) -> bool {
+    packet_type >= EXTENSION_PACKET_START
+}
+
+/// Gets called with the payload after the packet type byte and the address it came from
+pub type PacketHandler = Box<dyn FnMut(&[u8], SocketAddr, &mut PacketContext) + Send>;
+
+/// What a packet handler is allowed to do with the server
+pub struct PacketContext<'a> {
+    main: &'a mut MainServer,
+    received_time: Instant,
+}
+
+impl PacketContext<'_> {
+    /// Gets the index of the tracker with the id, registering it if it's new
+    pub fn register_tracker(&mut self, id: impl Into<String>, config: TrackerConfig) -> usize {
+        self.main.register_tracker(id.into(), config)
+    }
+
+    pub fn set_tracker_status(&mut self, index: usize, status: TrackerStatus) {
+        self.main.update_tracker_status(index, status).ok();
+    }
+
+    pub fn update_tracker_data(
+        &mut self,
+        index: usize,
+        acceleration: AccelMps2,
+        orientation: SensorQuat,
+    ) {
+        if self.main.trackers.get(index).is_some() {
+            self.main
+                .update_tracker_data(index, acceleration, orientation, self.received_time);
+        }
+    }
+
+    /// Sends a message with extension specific data to all the websocket clients
+    pub fn send_custom(&self, kind: impl Into<String>, payload: serde_json::Value) {
+        self.main.send_to_clients(ServerMessage::Custom {
+            kind: kind.into(),
+            payload,
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct PacketHandlers {
+    handlers: HashMap<u8, PacketHandler>,
+    /// Extension packets received without a handler since last taken
+    unhandled_count: u64,
+}
+
+impl PacketHandlers {
+    pub fn register(&mut self, packet_type: u8, handler: PacketHandler) -> anyhow::Result<()> {
+        if !is_extension_packet(packet_type) {
+            anyhow::bail!(
+                "Packet type {packet_type:#04x} is below {EXTENSION_PACKET_START:#04x} so is reserved for mycap"
+            );
+        }
+
+        if self.handlers.contains_key(&packet_type) {
+            anyhow::bail!("Packet type {packet_type:#04x} already has a handler");
+        }
+
+        self.handlers.insert(packet_type, handler);
+        Ok(())
+    }
+
+    pub fn handle(&mut self, bytes: &[u8], peer_addr: SocketAddr, main: &mut MainServer) {
+        let Some((packet_type, payload)) = bytes.split_first() else {
+            return;
+        };
+
+        let Some(handler) = self.handlers.get_mut(packet_type) else {
+            self.unhandled_count += 1;
+            return;
+        };
+
+        let mut context = PacketContext {
+            main,
+            received_time: Instant::now(),
+        };
+        handler(payload, peer_addr, &mut context);
+    }
+
+    pub fn take_unhandled_count(&mut self) -> u64 {
+        std::mem::take(&mut self.unhandled_count)
+    }
+}
@@ -0,0 +1,7 @@
+use std::collections::HashMap;
+
+/// Map for the lookups done for every packet, swap the hasher here to change it everywhere
+///
+/// Uses the hasher from rustc, a lot quicker than the default SipHash for small keys like addresses
+/// but not resistant to collisions being forced which is fine for devices on the LAN
+pub type FastHashMap<K, V> = HashMap<K, V, rustc_hash::FxBuildHasher>;
@@ -0,0 +1,90 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, SystemTime},
+};
+
+// Follows tokio's clock so tests can pause it, it's the same as the std one otherwise
+use tokio::{net::UdpSocket, time::Instant};
+
+/// Doesn't start with a packet type that devices send so it can't be mistaken for a device packet
+const PROBE_MAGIC: &[u8] = b"MCPROBE";
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks that the firewall lets packets through to the server's port by sending a packet from
+/// another socket to the server's own LAN address
+pub struct FirewallProbe {
+    token: u32,
+    sent_time: Instant,
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+impl FirewallProbe {
+    pub async fn send(address: Ipv4Addr, port: u16) -> std::io::Result<Self> {
+        // Only needs to be different between runs so the time is random enough
+        let token = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket
+            .send_to(&probe_bytes(token), SocketAddrV4::new(address, port))
+            .await?;
+
+        Ok(Self {
+            token,
+            sent_time: Instant::now(),
+            address,
+            port,
+        })
+    }
+
+    pub fn is_probe(&self, bytes: &[u8]) -> bool {
+        bytes == probe_bytes(self.token)
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.sent_time.elapsed() > PROBE_TIMEOUT
+    }
+}
+
+fn probe_bytes(token: u32) -> Vec<u8> {
+    [PROBE_MAGIC, &token.to_le_bytes()].concat()
+}
+
+/// How to allow the server through the firewall on the current platform
+pub fn remediation_hint(port: u16) -> String {
+    if cfg!(windows) {
+        format!("Allow mycap through Windows Defender Firewall on private networks or add an inbound rule for UDP port {port}")
+    } else if cfg!(target_os = "macos") {
+        "Allow incoming connections for mycap in System Settings > Network > Firewall".to_string()
+    } else {
+        format!("Allow inbound UDP port {port}, for example with `sudo ufw allow {port}/udp`")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn only_its_own_probe_matches_until_it_times_out() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        let probe = FirewallProbe::send(Ipv4Addr::LOCALHOST, port)
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 64];
+        let (amount, _) = receiver.recv_from(&mut buffer).await.unwrap();
+        assert!(probe.is_probe(&buffer[..amount]));
+        assert!(!probe.is_probe(&probe_bytes(probe.token.wrapping_add(1))));
+        assert!(!probe.is_probe(PROBE_MAGIC));
+
+        tokio::time::advance(PROBE_TIMEOUT).await;
+        assert!(!probe.timed_out());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(probe.timed_out());
+    }
+}
@@ -0,0 +1,243 @@
+use std::f32::consts::{PI, TAU};
+
+use glam::{Mat3, Quat, Vec3, Vec3A};
+
+use crate::{config::ConfigError, units::AccelMps2};
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct KalmanConfig {
+    /// How much the acceleration is expected to change by (jerk) in m/s³
+    pub process_noise: f32,
+    /// Noise of the acceleration measurements in m/s²
+    pub accel_noise: f32,
+    /// Noise of the zero velocity measurement when stationary in m/s
+    pub zupt_noise: f32,
+    /// Acceleration magnitude below which the tracker is assumed to be stationary
+    pub zupt_threshold: f32,
+}
+
+impl KalmanConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let noises = [
+            ("process_noise", self.process_noise),
+            ("accel_noise", self.accel_noise),
+            ("zupt_noise", self.zupt_noise),
+        ];
+
+        for (field, noise) in noises {
+            if !(noise.is_finite() && noise > 0.) {
+                return Err(ConfigError::new(field, "must be greater than 0"));
+            }
+        }
+
+        if !(self.zupt_threshold.is_finite() && self.zupt_threshold >= 0.) {
+            return Err(ConfigError::new("zupt_threshold", "must be at least 0"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for KalmanConfig {
+    fn default() -> Self {
+        Self {
+            process_noise: 50.,
+            accel_noise: 0.5,
+            zupt_noise: 0.01,
+            zupt_threshold: 0.15,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct YawCorrectionConfig {
+    /// Maximum amount to correct the yaw by in degrees per second, kept slow so it doesn't fight
+    /// real motion
+    pub rate_deg_per_sec: f32,
+    /// Acceleration magnitude below which every tracker has to be to correct the yaw
+    pub stationary_threshold: f32,
+}
+
+impl Default for YawCorrectionConfig {
+    fn default() -> Self {
+        Self {
+            rate_deg_per_sec: 1.,
+            stationary_threshold: 0.15,
+        }
+    }
+}
+
+/// Yaw around the z (up) axis in radians
+pub fn yaw(orientation: Quat) -> f32 {
+    orientation.to_euler(glam::EulerRot::ZYX).0
+}
+
+/// Wraps the angle in radians to be between -PI and PI
+pub fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+/// Constant acceleration kalman filter for a single axis with the state [position, velocity, acceleration]
+#[derive(Clone)]
+struct AxisKalman {
+    state: Vec3,
+    covariance: Mat3,
+}
+
+impl Default for AxisKalman {
+    fn default() -> Self {
+        Self {
+            state: Vec3::ZERO,
+            covariance: Mat3::IDENTITY,
+        }
+    }
+}
+
+impl AxisKalman {
+    fn predict(&mut self, dt: f32, process_noise: f32) {
+        let dt2 = dt * dt;
+        let dt3 = dt2 * dt;
+
+        #[rustfmt::skip]
+        let transition = Mat3::from_cols(
+            Vec3::new(1., 0., 0.),
+            Vec3::new(dt, 1., 0.),
+            Vec3::new(dt2 / 2., dt, 1.),
+        );
+
+        // Discrete white noise jerk model
+        #[rustfmt::skip]
+        let noise = Mat3::from_cols(
+            Vec3::new(dt3 * dt2 / 20., dt3 * dt / 8., dt3 / 6.),
+            Vec3::new(dt3 * dt / 8., dt3 / 3., dt2 / 2.),
+            Vec3::new(dt3 / 6., dt2 / 2., dt),
+        ) * process_noise;
+
+        self.state = transition * self.state;
+        self.covariance = transition * self.covariance * transition.transpose() + noise;
+    }
+
+    /// Updates with a measurement of a single state variable selected by `observation`
+    fn update(&mut self, observation: Vec3, measurement: f32, noise: f32) {
+        let innovation = measurement - observation.dot(self.state);
+        let innovation_covariance = observation.dot(self.covariance * observation) + noise;
+        let gain = self.covariance * observation / innovation_covariance;
+
+        self.state += gain * innovation;
+        let gain_observation = Mat3::from_cols(
+            gain * observation.x,
+            gain * observation.y,
+            gain * observation.z,
+        );
+        self.covariance = (Mat3::IDENTITY - gain_observation) * self.covariance;
+    }
+}
+
+/// Estimates position from acceleration using a kalman filter on each axis, using zero velocity
+/// updates (ZUPT) when the tracker is stationary to keep the velocity from drifting
+#[derive(Clone, Default)]
+pub struct PositionKalman {
+    axes: [AxisKalman; 3],
+}
+
+impl PositionKalman {
+    pub fn update(&mut self, acceleration: AccelMps2, dt: f32, config: &KalmanConfig) {
+        let stationary = acceleration.length() < config.zupt_threshold;
+
+        for (axis, measured_accel) in self.axes.iter_mut().zip(acceleration.0.to_array()) {
+            axis.predict(dt, config.process_noise);
+            axis.update(
+                Vec3::Z,
+                measured_accel,
+                config.accel_noise * config.accel_noise,
+            );
+            if stationary {
+                axis.update(Vec3::Y, 0., config.zupt_noise * config.zupt_noise);
+            }
+        }
+    }
+
+    /// Stops the estimate from moving while keeping the position
+    pub fn reset_motion(&mut self) {
+        for axis in &mut self.axes {
+            axis.state = Vec3::new(axis.state.x, 0., 0.);
+            axis.covariance = Mat3::IDENTITY;
+        }
+    }
+
+    pub fn position(&self) -> Vec3A {
+        Vec3A::from_array(self.axes.each_ref().map(|axis| axis.state.x))
+    }
+
+    pub fn velocity(&self) -> Vec3A {
+        Vec3A::from_array(self.axes.each_ref().map(|axis| axis.state.y))
+    }
+
+    /// Variance of the position estimate on each axis, lower means more confident
+    pub fn position_variance(&self) -> Vec3A {
+        Vec3A::from_array(self.axes.each_ref().map(|axis| axis.covariance.x_axis.x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.01;
+
+    /// Deterministic noise between -amplitude and amplitude so the tests don't need a random crate
+    fn noise(seed: &mut u32, amplitude: f32) -> f32 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*seed >> 8) as f32 / (1 << 24) as f32 * 2. * amplitude - amplitude
+    }
+
+    #[test]
+    fn kalman_follows_constant_velocity() {
+        // Moving at a constant velocity would look stationary to the zero velocity updates
+        let config = KalmanConfig {
+            zupt_threshold: 0.,
+            ..Default::default()
+        };
+        let mut kalman = PositionKalman::default();
+
+        // 1 m/s² along x for a second then coasting at 1 m/s for another
+        for step in 0..200 {
+            let acceleration = if step < 100 { Vec3A::X } else { Vec3A::ZERO };
+            kalman.update(AccelMps2(acceleration), DT, &config);
+        }
+
+        let (position, velocity) = (kalman.position(), kalman.velocity());
+        assert!((velocity.x - 1.).abs() < 0.05, "velocity {velocity}");
+        assert!((position.x - 1.5).abs() < 0.05, "position {position}");
+        assert!(
+            position.y.abs() + position.z.abs() < 1e-4,
+            "position {position}"
+        );
+    }
+
+    #[test]
+    fn kalman_stays_put_with_stationary_noise() {
+        let mut seed = 1;
+        let mut run = |config: &KalmanConfig| {
+            let mut kalman = PositionKalman::default();
+            for _ in 0..1000 {
+                let acceleration = Vec3A::from_array([(); 3].map(|_| noise(&mut seed, 0.05)));
+                kalman.update(AccelMps2(acceleration), DT, config);
+            }
+            kalman
+        };
+
+        let kalman = run(&KalmanConfig::default());
+        assert!(kalman.position().length() < 0.01, "{}", kalman.position());
+        assert!(kalman.velocity().length() < 0.01, "{}", kalman.velocity());
+
+        // The zero velocity updates are what keep the noise from being integrated
+        let without_zupt = run(&KalmanConfig {
+            zupt_threshold: 0.,
+            ..Default::default()
+        });
+        assert!(without_zupt.position().length() > kalman.position().length() * 10.);
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use crate::units::{AccelG, AccelMps2};
+
+pub const STANDARD_GRAVITY: f32 = 9.8;
+
+/// Mean acceleration magnitude below this means the firmware has already removed gravity
+pub const REMOVED_THRESHOLD: f32 = STANDARD_GRAVITY * 0.2;
+/// Standard deviation above this means the tracker was moving during calibration
+const STATIONARY_THRESHOLD: f32 = 0.5;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GravityConfig {
+    /// Subtract the gravity from the acceleration on the server, only needed for firmware that
+    /// doesn't remove gravity itself
+    pub compensate: bool,
+    /// What a stationary tracker reports which depends on the sign convention of the IMU
+    pub gravity: AccelMps2,
+}
+
+impl Default for GravityConfig {
+    fn default() -> Self {
+        Self {
+            compensate: false,
+            gravity: AccelG(glam::Vec3A::new(0., 0., -1.)).to_mps2(),
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct GravityCalibrationResult {
+    pub index: usize,
+    pub samples: usize,
+    /// Mean acceleration measured while the tracker was stationary
+    pub measured: AccelMps2,
+    /// None when the calibration failed
+    pub config: Option<GravityConfig>,
+    pub message: String,
+}
+
+/// Measures a stationary tracker to find out how its IMU reports gravity
+pub struct GravityCalibration {
+    pub index: usize,
+    start_time: Instant,
+    duration: Duration,
+    last_timestamp_us: u64,
+    samples: Vec<glam::Vec3A>,
+}
+
+impl GravityCalibration {
+    pub fn new(index: usize, duration: Duration) -> Self {
+        Self {
+            index,
+            start_time: Instant::now(),
+            duration,
+            last_timestamp_us: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, acceleration: AccelMps2, timestamp_us: u64) {
+        // Only count each received packet once
+        if timestamp_us > self.last_timestamp_us {
+            self.last_timestamp_us = timestamp_us;
+            self.samples.push(acceleration.0);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.start_time.elapsed() >= self.duration
+    }
+
+    pub fn finish(self) -> GravityCalibrationResult {
+        let mut result = GravityCalibrationResult {
+            index: self.index,
+            samples: self.samples.len(),
+            measured: AccelMps2::ZERO,
+            config: None,
+            message: String::new(),
+        };
+
+        if self.samples.is_empty() {
+            result.message = "No data was received from the tracker".to_string();
+            return result;
+        }
+
+        let count = self.samples.len() as f32;
+        let mean = self.samples.iter().sum::<glam::Vec3A>() / count;
+        let variance = self
+            .samples
+            .iter()
+            .map(|sample| sample.distance_squared(mean))
+            .sum::<f32>()
+            / count;
+        result.measured = AccelMps2(mean);
+
+        if variance.sqrt() > STATIONARY_THRESHOLD {
+            result.message = "The tracker was moving, keep it still and try again".to_string();
+            return result;
+        }
+
+        let config = if mean.length() < REMOVED_THRESHOLD {
+            result.message = "Gravity is already removed by the firmware".to_string();
+            GravityConfig {
+                compensate: false,
+                ..Default::default()
+            }
+        } else {
+            // Z is up so the sign of the z component is all that's needed
+            let sign = mean.z.signum();
+            result.message = format!(
+                "The IMU reports gravity as {}1g up",
+                if sign > 0. { "+" } else { "-" }
+            );
+            GravityConfig {
+                compensate: true,
+                gravity: AccelG(glam::Vec3A::new(0., 0., sign)).to_mps2(),
+            }
+        };
+
+        result.config = Some(config);
+        result
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::{config::ConfigError, udp_packet::parse_mac};
+
+/// Buttons are pressed at or above this value
+const BUTTON_PRESSED: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum InputKind {
+    /// 1 when pressed and 0 when released
+    Button,
+    /// Anything from -1 to 1
+    Axis,
+}
+
+impl InputKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        Some(match kind {
+            0 => Self::Button,
+            1 => Self::Axis,
+            _ => return None,
+        })
+    }
+}
+
+/// What the server does when a bound button gets pressed
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum InputAction {
+    /// Make every tracker face forwards
+    ResetYaw,
+    /// Stop or carry on updating the tracker data
+    TogglePauseTracking,
+    /// Start or stop exporting the tracker data with the saved export config
+    ToggleExport,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct InputBinding {
+    pub mac: String,
+    pub input: u8,
+    pub action: InputAction,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct InputConfig {
+    /// Send the input events over OSC as well as to the websocket clients
+    pub osc_enabled: bool,
+    pub osc_target: SocketAddr,
+    /// {mac} and {input} get replaced with the device's mac and the input id
+    pub osc_address: String,
+    /// Send each axis at most this many times per second
+    pub axis_rate_hz: f32,
+    pub bindings: Vec<InputBinding>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            osc_enabled: false,
+            osc_target: SocketAddr::from((Ipv4Addr::LOCALHOST, 9000)),
+            osc_address: "/mycap/{mac}/input/{input}".to_string(),
+            axis_rate_hz: 30.,
+            bindings: Vec::new(),
+        }
+    }
+}
+
+impl InputConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(0.1..=1000.).contains(&self.axis_rate_hz) {
+            return Err(ConfigError::new(
+                "axis_rate_hz",
+                "must be between 0.1 and 1000",
+            ));
+        }
+
+        if !self.osc_address.starts_with('/') {
+            return Err(ConfigError::new("osc_address", "must start with /"));
+        }
+
+        for (i, binding) in self.bindings.iter().enumerate() {
+            if parse_mac(&binding.mac).is_none() {
+                return Err(ConfigError::new(
+                    format!("bindings[{i}].mac"),
+                    "must be a MAC address",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn axis_interval(&self) -> Duration {
+        Duration::from_secs_f32(1. / self.axis_rate_hz)
+    }
+
+    #[cfg(feature = "osc")]
+    pub fn osc_address(&self, mac: &str, input: u8) -> String {
+        self.osc_address
+            .replace("{mac}", mac)
+            .replace("{input}", &input.to_string())
+    }
+
+    /// Actions bound to the button that should run now that it's pressed
+    pub fn actions<'a>(
+        &'a self,
+        mac: &'a str,
+        input: u8,
+        value: f32,
+    ) -> impl Iterator<Item = InputAction> + 'a {
+        self.bindings
+            .iter()
+            .filter(move |binding| {
+                value >= BUTTON_PRESSED
+                    && binding.input == input
+                    && binding.mac.eq_ignore_ascii_case(mac)
+            })
+            .map(|binding| binding.action)
+    }
+}
+
+struct AxisState {
+    last_sent_time: Instant,
+    /// Latest value held back by the rate limit
+    pending: Option<f32>,
+}
+
+/// Drops repeated button states and limits how often each axis gets sent for a device
+#[derive(Default)]
+pub struct InputFilter {
+    buttons: HashMap<u8, bool>,
+    axes: HashMap<u8, AxisState>,
+}
+
+impl InputFilter {
+    /// Returns the value if it should be sent now
+    pub fn filter(
+        &mut self,
+        input: u8,
+        kind: InputKind,
+        value: f32,
+        axis_interval: Duration,
+    ) -> Option<f32> {
+        match kind {
+            InputKind::Button => {
+                let pressed = value >= BUTTON_PRESSED;
+                let previous = self.buttons.insert(input, pressed);
+                (previous != Some(pressed)).then_some(pressed as u8 as f32)
+            }
+            InputKind::Axis => {
+                let now = Instant::now();
+                match self.axes.get_mut(&input) {
+                    Some(axis) if now.duration_since(axis.last_sent_time) < axis_interval => {
+                        axis.pending = Some(value);
+                        None
+                    }
+                    _ => {
+                        self.axes.insert(
+                            input,
+                            AxisState {
+                                last_sent_time: now,
+                                pending: None,
+                            },
+                        );
+                        Some(value)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Axis values that were held back by the rate limit and can be sent now
+    pub fn take_due_axes(&mut self, axis_interval: Duration) -> Vec<(u8, f32)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (input, axis) in &mut self.axes {
+            if axis.pending.is_some() && now.duration_since(axis.last_sent_time) >= axis_interval {
+                due.extend(axis.pending.take().map(|value| (*input, value)));
+                axis.last_sent_time = now;
+            }
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn repeated_button_states_are_dropped() {
+        let mut filter = InputFilter::default();
+        let mut press = |value| filter.filter(0, InputKind::Button, value, INTERVAL);
+        assert_eq!(press(1.), Some(1.));
+        assert_eq!(press(0.9), None);
+        assert_eq!(press(0.), Some(0.));
+        assert_eq!(press(0.2), None);
+        assert_eq!(press(0.5), Some(1.));
+
+        // Each button has its own state
+        assert_eq!(filter.filter(1, InputKind::Button, 1., INTERVAL), Some(1.));
+    }
+
+    #[test]
+    fn axes_are_rate_limited_to_the_latest_value() {
+        let mut filter = InputFilter::default();
+        assert_eq!(filter.filter(0, InputKind::Axis, 0.1, INTERVAL), Some(0.1));
+        assert_eq!(filter.filter(0, InputKind::Axis, 0.2, INTERVAL), None);
+        assert_eq!(filter.filter(0, InputKind::Axis, 0.3, INTERVAL), None);
+        assert_eq!(filter.filter(1, InputKind::Axis, -1., INTERVAL), Some(-1.));
+        assert!(filter.take_due_axes(INTERVAL).is_empty());
+
+        // Only the value held back is sent once the interval has passed
+        for axis in filter.axes.values_mut() {
+            axis.last_sent_time -= INTERVAL;
+        }
+        assert_eq!(filter.take_due_axes(INTERVAL), [(0, 0.3)]);
+        assert!(filter.take_due_axes(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn bound_actions_run_on_press() {
+        let config = InputConfig {
+            bindings: vec![
+                InputBinding {
+                    mac: "AA:BB:CC:DD:EE:FF".to_string(),
+                    input: 2,
+                    action: InputAction::ResetYaw,
+                },
+                InputBinding {
+                    mac: "AA:BB:CC:DD:EE:FF".to_string(),
+                    input: 3,
+                    action: InputAction::ToggleExport,
+                },
+            ],
+            ..Default::default()
+        };
+        let actions = |mac, input, value| config.actions(mac, input, value).collect::<Vec<_>>();
+
+        assert_eq!(actions("aa:bb:cc:dd:ee:ff", 2, 1.), [InputAction::ResetYaw]);
+        assert!(actions("AA:BB:CC:DD:EE:FF", 2, 0.).is_empty());
+        assert!(actions("11:22:33:44:55:66", 2, 1.).is_empty());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn config_is_validated() {
+        let invalid = [
+            InputConfig {
+                axis_rate_hz: 0.,
+                ..Default::default()
+            },
+            InputConfig {
+                osc_address: "mycap/input".to_string(),
+                ..Default::default()
+            },
+            InputConfig {
+                bindings: vec![InputBinding {
+                    mac: "not a mac".to_string(),
+                    input: 0,
+                    action: InputAction::ResetYaw,
+                }],
+                ..Default::default()
+            },
+        ];
+        let fields: Vec<_> = invalid
+            .iter()
+            .map(|config| config.validate().unwrap_err().field)
+            .collect();
+        assert_eq!(fields, ["axis_rate_hz", "osc_address", "bindings[0].mac"]);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Copy)]
+pub enum LatencyStage {
+    /// From the packet being received to the data being broadcasted in the tick
+    ReceiveToBroadcast,
+    /// From the packet being received to the data being written to the websocket
+    #[cfg(feature = "websocket")]
+    ReceiveToWebsocket,
+}
+
+#[derive(Clone, Default, serde::Serialize)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl LatencyStats {
+    pub fn from_samples(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+
+        samples.sort_unstable();
+        let percentile = |p: f32| samples[((samples.len() - 1) as f32 * p).round() as usize];
+
+        Self {
+            samples: samples.len(),
+            p50_us: percentile(0.5),
+            p90_us: percentile(0.9),
+            p99_us: percentile(0.99),
+            max_us: samples[samples.len() - 1],
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct LatencyTestResult {
+    pub duration_secs: f32,
+    pub receive_to_broadcast: LatencyStats,
+    pub receive_to_websocket: LatencyStats,
+}
+
+#[derive(Default)]
+struct LatencySamples {
+    receive_to_broadcast: Vec<u64>,
+    receive_to_websocket: Vec<u64>,
+}
+
+#[derive(Default)]
+struct LatencyRecorderInner {
+    active: AtomicBool,
+    samples: Mutex<LatencySamples>,
+}
+
+/// Collects latency samples from different parts of the server while a latency test is running
+/// Recording is just an atomic load when no test is running
+#[derive(Clone, Default)]
+pub struct LatencyRecorder(Arc<LatencyRecorderInner>);
+
+impl LatencyRecorder {
+    pub fn is_active(&self) -> bool {
+        self.0.active.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, stage: LatencyStage, latency_us: u64) {
+        if !self.is_active() {
+            return;
+        }
+
+        let mut samples = self.0.samples.lock().unwrap();
+        match stage {
+            LatencyStage::ReceiveToBroadcast => samples.receive_to_broadcast.push(latency_us),
+            #[cfg(feature = "websocket")]
+            LatencyStage::ReceiveToWebsocket => samples.receive_to_websocket.push(latency_us),
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn start(&self) {
+        *self.0.samples.lock().unwrap() = LatencySamples::default();
+        self.0.active.store(true, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self, duration: Duration) -> LatencyTestResult {
+        self.0.active.store(false, Ordering::Relaxed);
+        let samples = std::mem::take(&mut *self.0.samples.lock().unwrap());
+
+        LatencyTestResult {
+            duration_secs: duration.as_secs_f32(),
+            receive_to_broadcast: LatencyStats::from_samples(samples.receive_to_broadcast),
+            receive_to_websocket: LatencyStats::from_samples(samples.receive_to_websocket),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(stats: &LatencyStats) -> [u64; 5] {
+        [
+            stats.samples as u64,
+            stats.p50_us,
+            stats.p90_us,
+            stats.p99_us,
+            stats.max_us,
+        ]
+    }
+
+    #[test]
+    fn percentiles_come_from_the_sorted_samples() {
+        let stats = LatencyStats::from_samples((1..=100).rev().collect());
+        assert_eq!(summary(&stats), [100, 51, 90, 99, 100]);
+
+        let stats = LatencyStats::from_samples(vec![7]);
+        assert_eq!(summary(&stats), [1, 7, 7, 7, 7]);
+        assert_eq!(summary(&LatencyStats::from_samples(Vec::new())), [0; 5]);
+    }
+
+    #[test]
+    fn nothing_is_recorded_without_a_test_running() {
+        let recorder = LatencyRecorder::default();
+        assert!(!recorder.is_active());
+        recorder.record(LatencyStage::ReceiveToBroadcast, 100);
+
+        let result = recorder.finish(Duration::from_secs(1));
+        assert_eq!(result.receive_to_broadcast.samples, 0);
+        assert_eq!(result.receive_to_websocket.samples, 0);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn each_stage_gets_its_own_percentiles() {
+        let recorder = LatencyRecorder::default();
+        recorder.start();
+        assert!(recorder.is_active());
+        // Clones share the samples since each part of the server has its own
+        let clone = recorder.clone();
+        for latency_us in 1..=100 {
+            clone.record(LatencyStage::ReceiveToBroadcast, latency_us);
+        }
+        for latency_us in [500, 100, 300, 200, 400] {
+            recorder.record(LatencyStage::ReceiveToWebsocket, latency_us);
+        }
+
+        let result = recorder.finish(Duration::from_millis(2500));
+        assert!(!recorder.is_active());
+        assert_eq!(result.duration_secs, 2.5);
+        assert_eq!(
+            summary(&result.receive_to_broadcast),
+            [100, 51, 90, 99, 100]
+        );
+        assert_eq!(
+            summary(&result.receive_to_websocket),
+            [5, 300, 500, 500, 500]
+        );
+
+        // Samples after the test finished are dropped and the next test starts from nothing
+        recorder.record(LatencyStage::ReceiveToBroadcast, 1);
+        recorder.start();
+        recorder.record(LatencyStage::ReceiveToWebsocket, 42);
+        let result = recorder.finish(Duration::from_secs(1));
+        assert_eq!(summary(&result.receive_to_broadcast), [0; 5]);
+        assert_eq!(summary(&result.receive_to_websocket), [1, 42, 42, 42, 42]);
+    }
+}
@@ -1,35 +1,267 @@
+mod battery;
+#[doc(hidden)]
+pub mod bench;
+mod blocklist;
+mod calibration;
+mod clock;
+mod config;
+mod connection_history;
+mod device_error;
+mod discovery_client;
+mod early_packets;
+mod exporter;
+mod extension;
+mod fast_hash;
+mod firewall;
+mod fusion;
+mod gravity;
+mod input;
+mod latency_test;
+mod log_forward;
 mod main_server;
+mod messages;
+mod network_test;
+#[cfg(feature = "osc")]
+mod osc;
+#[cfg(feature = "recording")]
+mod packet_log;
+#[cfg(feature = "recording")]
+mod playback;
+mod prediction;
+mod profiles;
+#[doc(hidden)]
+pub mod protocol_vectors;
+#[cfg(all(feature = "serial", feature = "websocket"))]
+mod provisioning;
+mod raw_sensor_recorder;
+#[cfg(feature = "osc")]
+mod routing;
+mod send_queue;
 mod serial;
+#[cfg(feature = "websocket")]
+mod snapshot;
+#[cfg(test)]
+mod span_recorder;
+mod supervisor;
+mod tick_budget;
 mod tracker;
 mod udp_packet;
 mod udp_server;
+mod units;
+mod warning_aggregator;
+#[cfg(feature = "websocket")]
 mod websocket;
 
+pub use config::ConfigError;
+pub use discovery_client::{
+    discover_servers, DiscoveredServer, DiscoveryClient, DiscoveryEvent, SERVER_EXPIRY,
+};
+pub use extension::{PacketContext, EXTENSION_PACKET_START};
+pub use main_server::{AccelUnit, Axis, Conventions, Handedness};
+pub use tracker::{
+    PositionFilter, TrackerConfig, TrackerConfigBuilder, TrackerLocation, TrackerSide,
+    TrackerStatus,
+};
 pub use udp_server::UDP_PORT;
+pub use units::{AccelG, AccelMps2, SensorQuat, WorldQuat};
+#[cfg(feature = "websocket")]
 pub use websocket::WEBSOCKET_PORT;
 
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::sync::{watch, RwLock};
+use tracing_subscriber::EnvFilter;
 
-use crate::main_server::MainServer;
+use crate::{extension::PacketHandlers, main_server::MainServer};
 
-pub fn setup_log() {
-    env_logger::builder()
-        .format_timestamp(None)
-        .filter_level(log::LevelFilter::Warn)
-        .filter_module("mycap", log::LevelFilter::Trace)
-        .init();
+/// Target of the tracing spans around the tick phases and packets so they can be filtered
+/// separately from the logs
+pub const SPAN_TARGET: &str = "mycap_server::spans";
+/// Used when neither the config nor RUST_LOG have a filter
+const DEFAULT_TRACING_FILTER: &str = "warn,mycap=trace";
+
+/// How the logs and spans are output
+#[derive(Default)]
+pub struct TracingConfig {
+    /// Filter directives in the same format as RUST_LOG, which is used if this isn't set
+    pub filter: Option<String>,
+}
+
+/// Prints the logs to stderr and forwards them to the clients that subscribed, records from the
+/// log crate go through the same filter
+pub fn setup_tracing(config: &TracingConfig) {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+    let directives = config
+        .filter
+        .clone()
+        .or_else(|| std::env::var(EnvFilter::DEFAULT_ENV).ok())
+        .unwrap_or_else(|| DEFAULT_TRACING_FILTER.to_string());
+    let filter = EnvFilter::builder().parse_lossy(directives);
+    let output = tracing_subscriber::fmt::layer()
+        .event_format(log_forward::LogFormat)
+        .with_writer(std::io::stderr)
+        .and_then(log_forward::ForwardingLayer)
+        .with_filter(filter);
+
+    let registry = tracing_subscriber::registry().with(output);
+    // Has its own filter for tokio's instrumentation
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+
+    if let Err(error) = registry.try_init() {
+        eprintln!("Failed to set up tracing: {error}");
+    }
+}
+
+/// Options for debugging that are set from the command line
+#[derive(Default)]
+pub struct ServerOptions {
+    /// Append every received udp packet to this file
+    pub record_raw: Option<PathBuf>,
+    /// Handle the udp packets from this file instead of the network
+    pub replay_raw: Option<PathBuf>,
+    /// Replay the packets this many times faster than they were recorded
+    pub replay_speed: Option<f32>,
+    /// Start the replay again after the last packet
+    pub replay_loop: bool,
+    /// Open the pairing window for this many seconds when starting
+    pub pairing_window_secs: Option<u64>,
+    /// Accept websocket commands that are only meant for testing such as SetStatus
+    pub debug_commands: bool,
+}
+
+impl ServerOptions {
+    pub fn from_args() -> anyhow::Result<Self> {
+        let mut options = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut path = || {
+                args.next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow::anyhow!("{arg} needs a path"))
+            };
+
+            match arg.as_str() {
+                "--record-raw" => options.record_raw = Some(path()?),
+                "--replay-raw" => options.replay_raw = Some(path()?),
+                "--speed" => {
+                    let speed = args
+                        .next()
+                        .and_then(|speed| speed.parse().ok())
+                        .ok_or_else(|| anyhow::anyhow!("{arg} needs a speed multiplier"))?;
+                    options.replay_speed = Some(speed);
+                }
+                "--loop" => options.replay_loop = true,
+                "--pairing-window" => {
+                    let seconds = args
+                        .next()
+                        .and_then(|seconds| seconds.parse().ok())
+                        .ok_or_else(|| anyhow::anyhow!("{arg} needs a number of seconds"))?;
+                    options.pairing_window_secs = Some(seconds);
+                }
+                "--debug-commands" => options.debug_commands = true,
+                _ => anyhow::bail!("Unknown argument {arg}"),
+            }
+        }
+
+        Ok(options)
+    }
 }
 
 pub async fn start_server() -> anyhow::Result<()> {
-    let main = Arc::new(RwLock::new(MainServer::default()));
+    start_server_with_options(ServerOptions::default()).await
+}
+
+pub async fn start_server_with_options(options: ServerOptions) -> anyhow::Result<()> {
+    MycapServer::new(options).start().await
+}
+
+/// Used to set up the server before starting it when embedding mycap
+pub struct MycapServer {
+    options: ServerOptions,
+    packet_handlers: PacketHandlers,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+}
+
+/// Stops the server cleanly, telling the devices first so they wait to reconnect
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    /// The server's start returns once the devices have been told
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl MycapServer {
+    pub fn new(options: ServerOptions) -> Self {
+        Self {
+            options,
+            packet_handlers: PacketHandlers::default(),
+            shutdown_tx: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// For stopping the server from a Ctrl-C handler or when the embedding app closes
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown_tx.clone())
+    }
+
+    /// Handle udp packets with a packet type from EXTENSION_PACKET_START up for custom devices
+    pub fn register_packet_handler(
+        &mut self,
+        packet_type: u8,
+        handler: impl FnMut(&[u8], SocketAddr, &mut PacketContext) + Send + 'static,
+    ) -> anyhow::Result<()> {
+        self.packet_handlers
+            .register(packet_type, Box::new(handler))
+    }
+
+    pub async fn start(self) -> anyhow::Result<()> {
+        let mut main = MainServer::default();
+        main.config_path = Some(PathBuf::from(config::CONFIG_PATH));
+        main.load_config();
+        #[cfg(feature = "websocket")]
+        main.publish_snapshot();
+        let main = Arc::new(RwLock::new(main));
+
+        #[cfg(feature = "websocket")]
+        let websocket = {
+            let (websocket_main, snapshots) =
+                (main.clone(), main.read().await.snapshot_subscriber());
+            tokio::spawn(supervisor::supervise(
+                "websocket",
+                main.clone(),
+                move || websocket::start_server(websocket_main.clone(), snapshots.clone()),
+            ))
+        };
 
-    tokio::try_join!(
-        flatten(tokio::spawn(websocket::start_server(main.clone()))),
-        flatten(tokio::spawn(main_server::start_server(main)))
-    )?;
+        let main_server = tokio::spawn(main_server::start_server(
+            main,
+            self.options,
+            self.packet_handlers,
+            self.shutdown_tx.subscribe(),
+        ));
 
-    Ok(())
+        #[cfg(feature = "websocket")]
+        {
+            let websocket_abort = websocket.abort_handle();
+            // The websocket finishing on its own means it gave up restarting, the rest keeps going
+            let websocket = async {
+                flatten(websocket).await?;
+                std::future::pending().await
+            };
+            let result = tokio::select! {
+                result = websocket => result,
+                result = flatten(main_server) => result,
+            };
+            websocket_abort.abort();
+            result
+        }
+        #[cfg(not(feature = "websocket"))]
+        flatten(main_server).await
+    }
 }
 
 async fn flatten(handle: tokio::task::JoinHandle<anyhow::Result<()>>) -> anyhow::Result<()> {
@@ -0,0 +1,115 @@
+use std::sync::OnceLock;
+
+use tokio::sync::broadcast;
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    layer::Context,
+    registry::LookupSpan,
+};
+
+/// Records waiting to be sent before the slowest subscriber starts missing them
+#[cfg(feature = "websocket")]
+const LOG_CHANNEL_CAPACITY: usize = 256;
+/// Records from here aren't forwarded since sending them to the clients could log again and loop
+const WEBSOCKET_TARGET: &str = "mycap_server::websocket";
+
+static LOG_SENDER: OnceLock<broadcast::Sender<LogRecord>> = OnceLock::new();
+
+#[derive(Clone, serde::Serialize)]
+pub struct LogRecord {
+    #[serde(serialize_with = "serialize_level")]
+    pub level: tracing::Level,
+    pub target: String,
+    pub message: String,
+}
+
+fn serialize_level<S: serde::Serializer>(
+    level: &tracing::Level,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(level.as_str())
+}
+
+/// Prints the events as [LEVEL target] message like the server did when it used env_logger
+pub struct LogFormat;
+
+impl<S, N> FormatEvent<S, N> for LogFormat
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        write!(writer, "[{:<5} {}] ", metadata.level(), metadata.target())?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Forwards the events that get through the filter to anything subscribed
+pub struct ForwardingLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for ForwardingLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let Some(sender) = LOG_SENDER.get() else {
+            return;
+        };
+
+        // Records from the log crate have their real target in the fields
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        if sender.receiver_count() == 0 || metadata.target().starts_with(WEBSOCKET_TARGET) {
+            return;
+        }
+
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+
+        // Only errors when there are no receivers which is fine
+        sender
+            .send(LogRecord {
+                level: *metadata.level(),
+                target: metadata.target().to_string(),
+                message: message.0,
+            })
+            .ok();
+    }
+}
+
+/// Formats the message then the other fields after it like the log output
+struct MessageVisitor(String);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write;
+
+        if field.name().starts_with("log.") {
+            return;
+        }
+
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+
+        if field.name() == "message" {
+            write!(self.0, "{value:?}").ok();
+        } else {
+            write!(self.0, "{}={value:?}", field.name()).ok();
+        }
+    }
+}
+
+/// Receives every record that gets logged from now on
+#[cfg(feature = "websocket")]
+pub fn subscribe() -> broadcast::Receiver<LogRecord> {
+    LOG_SENDER
+        .get_or_init(|| broadcast::channel(LOG_CHANNEL_CAPACITY).0)
+        .subscribe()
+}
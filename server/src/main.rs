@@ -1,7 +1,23 @@
 #[tokio::main]
 async fn main() {
-    mycap_server::setup_log();
-    if let Err(error) = mycap_server::start_server().await {
-        log::error!("Server error: {error:?}");
+    mycap_server::setup_tracing(&mycap_server::TracingConfig::default());
+    let options = match mycap_server::ServerOptions::from_args() {
+        Ok(options) => options,
+        Err(error) => {
+            tracing::error!("{error}");
+            return;
+        }
+    };
+
+    let server = mycap_server::MycapServer::new(options);
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown();
+        }
+    });
+
+    if let Err(error) = server.start().await {
+        tracing::error!("Server error: {error:?}");
     }
 }
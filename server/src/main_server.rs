@@ -1,168 +1,3014 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    RwLock,
-};
+use futures_util::FutureExt;
+use tokio::sync::{watch, RwLock};
+use tracing::Instrument;
+
+#[cfg(feature = "websocket")]
+use std::net::IpAddr;
+#[cfg(feature = "websocket")]
+use std::sync::Mutex;
+#[cfg(feature = "websocket")]
+use tokio::sync::broadcast;
 
-use crate::{tracker::*, udp_server::UdpServer};
+#[cfg(feature = "recording")]
+use crate::playback::PlaybackState;
+#[cfg(all(feature = "websocket", feature = "osc"))]
+use crate::routing::{validate_routes, OutputRoute, RouteStats};
+#[cfg(feature = "websocket")]
+use crate::{
+    calibration::{AccelFace, AccelScaleCalibration, AccelScaleStep, CalibrationCountdown},
+    log_forward::LogRecord,
+    profiles::ConfigProfile,
+    snapshot::{Snapshot, SnapshotPublisher, SnapshotSubscriber, TrackerSnapshot},
+    udp_server::DeviceCommand,
+};
+use crate::{
+    calibration::{CalibrationKind, SideCheck},
+    clock::{self, ClockAdjustment, ServerClock, WallClockMonitor},
+    config::{DiscoveryMode, ServerConfig, TrackerConfigEntry},
+    connection_history::DisconnectCause,
+    device_error::DeviceErrorCode,
+    exporter::{ExportConfig, Exporter},
+    extension::PacketHandlers,
+    gravity::{GravityCalibration, GravityCalibrationResult},
+    input::{InputAction, InputKind},
+    latency_test::{LatencyRecorder, LatencyStage, LatencyTestResult},
+    messages::CodedMessage,
+    network_test::NetworkTestResult,
+    raw_sensor_recorder::RawSensorRecorder,
+    supervisor::{catch_panic, panic_reason, RestartBackoff},
+    tick_budget::{SkippedStages, TickBudget, TickStage},
+    tracker::*,
+    udp_packet::RawSensorSample,
+    udp_server::{DeviceInfo, UdpServer},
+    units::{AccelMps2, SensorQuat},
+    ServerOptions, SPAN_TARGET,
+};
+#[cfg(feature = "osc")]
+use crate::{
+    osc::{OscSender, VrchatOscSender, VRCHAT_TRACKER_SLOTS},
+    routing::Router,
+};
+#[cfg(all(feature = "serial", feature = "websocket"))]
+use crate::{
+    provisioning::{Provisioning, ProvisioningProgress, ProvisioningQueue},
+    serial::WifiCredentials,
+};
 
 #[derive(Clone, serde::Serialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
-    TrackerInfo { info: TrackerInfo },
-    TrackerData { index: usize, data: TrackerData },
-    Error { error: String },
+    TrackerInfo {
+        info: TrackerInfo,
+    },
+    #[cfg(feature = "websocket")]
+    TrackerRemoved {
+        index: usize,
+    },
+    /// The tracker that was at mapping[i] is now at index i, sent before the TrackerInfo of each
+    #[cfg(feature = "websocket")]
+    TrackerIndicesRemapped {
+        mapping: Vec<usize>,
+    },
+    TrackerData {
+        index: usize,
+        data: TrackerData,
+        /// The data before being processed, only sent to clients subscribed to both streams
+        #[serde(skip_serializing_if = "Option::is_none")]
+        raw_data: Option<RawTrackerData>,
+    },
+    /// Only the data before being processed, for clients subscribed to the raw stream
+    #[cfg(feature = "websocket")]
+    #[serde(rename = "TrackerData")]
+    RawTrackerData {
+        index: usize,
+        data: RawTrackerData,
+    },
+    ServerStatus {
+        status: ServerStatus,
+    },
+    /// Seconds until the calibration started with a delay runs, sent every second
+    #[cfg(feature = "websocket")]
+    CalibrationCountdown {
+        remaining: u64,
+    },
+    /// The tracker's data came back after a gap
+    TrackerDataGap {
+        index: usize,
+        duration_ms: u64,
+    },
+    Conventions(Conventions),
+    Error {
+        error: String,
+        /// Code and params of the error for clients to match on or show in their own language
+        #[serde(flatten, skip_serializing_if = "Option::is_none")]
+        coded: Option<CodedMessage>,
+    },
+    Warning {
+        warning: String,
+    },
+    /// The device's battery will run out in less than the battery's low_warning_mins
+    LowBattery {
+        mac: String,
+        trackers: Vec<usize>,
+        estimated_minutes_remaining: u32,
+    },
+    DeviceReconnected {
+        mac: String,
+        cause: DisconnectCause,
+        downtime_ms: u64,
+        episodes_today: usize,
+        total_downtime_ms: u64,
+    },
+    #[cfg(feature = "recording")]
+    PlaybackState {
+        state: PlaybackState,
+    },
+    InputEvent {
+        mac: String,
+        input: u8,
+        kind: InputKind,
+        value: f32,
+    },
+    /// Only sent to clients that subscribed to the logs
+    #[cfg(feature = "websocket")]
+    LogRecord(LogRecord),
+    /// Added to the allowlist while the pairing window was open
+    DevicePaired {
+        mac: String,
+    },
+    DeviceError {
+        mac: String,
+        code: DeviceErrorCode,
+        detail: Option<String>,
+        advice: Option<&'static str>,
+        /// Errors that weren't sent since the last one to not flood the clients
+        suppressed: u32,
+        errors_last_hour: usize,
+    },
+    LatencyTestResult {
+        result: LatencyTestResult,
+    },
+    GravityCalibrationResult {
+        result: GravityCalibrationResult,
+    },
+    /// Rest the tracker on the face then send NextAccelScaleFace, step counts from 0
+    #[cfg(feature = "websocket")]
+    AccelScaleProgress {
+        index: usize,
+        face: AccelFace,
+        step: usize,
+        steps: usize,
+    },
+    /// The accelerometer scale calibration finished and was saved in the tracker's config
+    #[cfg(feature = "websocket")]
+    AccelScaleCalibrated {
+        index: usize,
+        scale: glam::Vec3A,
+        bias: AccelMps2,
+    },
+    /// The side check finished, moved is None when it couldn't tell which tracker moved
+    SideCheckFinished {
+        moved: Option<usize>,
+    },
+    /// The tracker that moved in the side check was on the other side, swap them with SwapSides
+    SwapSuggestion {
+        a: usize,
+        b: usize,
+    },
+    NetworkTestResult {
+        result: NetworkTestResult,
+    },
+    DeviceConfig {
+        mac: String,
+        entries: BTreeMap<String, String>,
+    },
+    /// Sent every second even when nothing else is happening so clients know the server is alive
+    Heartbeat {
+        /// Wall clock time in microseconds since the unix epoch when it was sent
+        timestamp_unix_us: u64,
+    },
+    /// The server loop didn't run for a while like when the computer was asleep
+    ClockAdjusted {
+        adjustment: ClockAdjustment,
+    },
+    ServerResumed {
+        gap_ms: u64,
+    },
+    /// The whole config as JSON, only sent to the client that asked for it
+    #[cfg(feature = "websocket")]
+    ConfigBlob {
+        json: String,
+    },
+    /// The state was written to the path, only sent to the client that asked for it
+    #[cfg(feature = "websocket")]
+    StateDumped {
+        path: String,
+    },
+    /// Recent data of the tracker, oldest first, only sent to the client that asked for it
+    #[cfg(feature = "websocket")]
+    TrackerHistory {
+        index: usize,
+        samples: Vec<TrackerData>,
+    },
+    /// A device plugged in while provisioning changed state
+    #[cfg(all(feature = "serial", feature = "websocket"))]
+    ProvisioningProgress(ProvisioningProgress),
+    /// The saved profiles, only sent to the client that asked for it
+    #[cfg(feature = "websocket")]
+    Profiles {
+        profiles: Vec<ConfigProfile>,
+        active_profile: Option<String>,
+    },
+    /// The output routes and how much they're sending, only sent to the client that asked for it
+    #[cfg(all(feature = "websocket", feature = "osc"))]
+    Routes {
+        routes: Vec<RouteStats>,
+    },
+    /// Part of the server panicked or failed, restarting is false when it failed too many times in
+    /// a row and won't be restarted again
+    SubsystemFailed {
+        name: &'static str,
+        reason: String,
+        restarting: bool,
+    },
+    /// The UDP socket failed and was rebound
+    UdpSocketRestarted {
+        attempts: u32,
+    },
+    /// Part of the initial state sent to a client when it connects
+    #[cfg(feature = "websocket")]
+    SyncChunk {
+        seq: usize,
+        total: usize,
+        payload: Vec<ServerMessage>,
+    },
+    /// Sent after the last sync chunk, live updates come after this
+    #[cfg(feature = "websocket")]
+    SyncComplete,
+    #[cfg(feature = "websocket")]
+    Blocklist {
+        macs: Vec<String>,
+        addresses: Vec<IpAddr>,
+    },
+    /// From a packet handler registered by an extension
+    Custom {
+        kind: String,
+        payload: serde_json::Value,
+    },
+}
+
+impl ServerMessage {
+    /// Gets the index of the tracker the message is about for logging
+    #[cfg(feature = "websocket")]
+    pub fn tracker_index(&self) -> Option<usize> {
+        match self {
+            Self::TrackerInfo { info } => Some(info.index),
+            Self::TrackerData { index, .. }
+            | Self::RawTrackerData { index, .. }
+            | Self::TrackerRemoved { index }
+            | Self::TrackerDataGap { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct ServerStatus {
+    /// Wall clock time in microseconds since the unix epoch that data timestamps are relative to
+    pub epoch_unix_us: u64,
+    pub discovery_mode: DiscoveryMode,
+    /// Packets dropped from blocked devices and addresses since the server started
+    pub blocked_packets: u64,
+    /// Packets to devices dropped because too many were waiting to be sent since the server started
+    pub dropped_outgoing_packets: u64,
+    /// Optional work that was skipped to keep the tick on time
+    pub skipped_stages: SkippedStages,
+    /// When the pairing window closes relative to the server clock if it's open
+    pub pairing_window_end_us: Option<u64>,
+    /// Times the wall clock jumped, the timestamps ignore these and stay relative to the epoch
+    pub clock_adjustments: Vec<ClockAdjustment>,
+    /// Tracker data is being ignored from an input action
+    pub tracking_paused: bool,
+    pub active_profile: Option<String>,
+    /// Set when the config file couldn't be loaded and the defaults are being used
+    pub config_load_error: Option<CodedMessage>,
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+pub enum AccelUnit {
+    MetersPerSecondSquared,
+    Gravities,
+}
+
+/// Describes the coordinate system and units of the data sent to clients
+#[derive(Clone, serde::Serialize)]
+pub struct Conventions {
+    pub up_axis: Axis,
+    pub handedness: Handedness,
+    /// The gravity vector that has been removed from the acceleration
+    pub gravity: AccelMps2,
+    pub accel_unit: AccelUnit,
+    /// Order of euler angles if sent as euler angles, None since orientations are quaternions
+    pub euler_order: Option<String>,
 }
 
 /// Stuff like the websocket server needs to run on a completely seperate task but need to receive
-/// tracker data when it is ready. So we use mspc channels
-#[derive(Default)]
+/// tracker data when it is ready. So we use a broadcast channel which only stores the message once
+/// no matter how many clients there are, keeping the time spent holding the main lock low
+/// Without the websocket server there's nothing to send the messages to so they get dropped
+#[derive(Clone)]
+#[cfg_attr(not(feature = "websocket"), derive(Default))]
 pub struct MessageChannelManager {
-    channels: Vec<UnboundedSender<ServerMessage>>,
+    #[cfg(feature = "websocket")]
+    sender: broadcast::Sender<ChannelMessage>,
+    /// Locked while sending so that subscribing gets the exact id of the first message it receives
+    #[cfg(feature = "websocket")]
+    next_id: Arc<Mutex<u64>>,
 }
 
-impl MessageChannelManager {
-    fn send_to_all(&mut self, message: ServerMessage) {
-        let mut to_remove = None;
+/// Messages are given increasing ids so that clients can skip the ones already in a snapshot
+#[cfg(feature = "websocket")]
+#[derive(Clone)]
+pub struct ChannelMessage {
+    pub id: u64,
+    pub message: ServerMessage,
+}
 
-        for (i, channel) in self.channels.iter().enumerate() {
-            // The channel got closed so remove it
-            if channel.send(message.clone()).is_err() {
-                to_remove = Some(i);
-            }
+#[cfg(feature = "websocket")]
+impl Default for MessageChannelManager {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(MESSAGE_CHANNEL_CAPACITY);
+        Self {
+            sender,
+            next_id: Arc::new(Mutex::new(0)),
         }
+    }
+}
 
-        if let Some(to_remove) = to_remove {
-            self.channels.swap_remove(to_remove);
-        }
+impl MessageChannelManager {
+    #[cfg(feature = "websocket")]
+    pub(crate) fn send_to_all(&self, message: ServerMessage) {
+        let mut next_id = self.next_id.lock().unwrap();
+        // Only errors when there are no receivers which is fine
+        self.sender
+            .send(ChannelMessage {
+                id: *next_id,
+                message,
+            })
+            .ok();
+        *next_id += 1;
+    }
+
+    #[cfg(not(feature = "websocket"))]
+    fn send_to_all(&self, _message: ServerMessage) {}
+
+    /// Also gives the id of the first message the receiver will get
+    #[cfg(feature = "websocket")]
+    pub(crate) fn subscribe(&self) -> (broadcast::Receiver<ChannelMessage>, u64) {
+        let next_id = self.next_id.lock().unwrap();
+        (self.sender.subscribe(), *next_id)
     }
 }
 
 #[derive(Default)]
 pub struct MainServer {
-    pub trackers: Vec<Tracker>,
+    pub trackers: TrackerList,
+    pub config: ServerConfig,
+    /// Where the config is loaded from and saved to, only kept in memory when None
+    pub config_path: Option<PathBuf>,
+    /// Why the config couldn't be loaded, kept in the status so clients that connect later see it
+    config_load_error: Option<CodedMessage>,
+    /// The config file couldn't be loaded or backed up so it isn't saved over until a config is
+    /// imported
+    config_save_blocked: bool,
+    pub clock: ServerClock,
+    wall_clock: WallClockMonitor,
+    pub discovery_mode: DiscoveryMode,
+    pub blocked_packets: u64,
+    pub dropped_outgoing_packets: u64,
     tracker_id_to_index: HashMap<String, usize>,
-    message_channels: MessageChannelManager,
+    pub(crate) message_channels: MessageChannelManager,
+    #[cfg(feature = "websocket")]
+    snapshots: SnapshotPublisher,
+    latency_recorder: LatencyRecorder,
+    /// When the current latency test started and how long to run it for
+    latency_test: Option<(Instant, Duration)>,
+    last_tick_us: u64,
+    exporter: Option<Exporter>,
+    raw_sensor_recorder: Option<RawSensorRecorder>,
+    /// Holds the wifi credentials for provisioning, never saved to the config
+    #[cfg(all(feature = "serial", feature = "websocket"))]
+    provisioning: Option<Provisioning>,
+    #[cfg(feature = "osc")]
+    vrchat_osc: Option<VrchatOscSender>,
+    #[cfg(feature = "osc")]
+    input_osc: Option<OscSender>,
+    #[cfg(feature = "osc")]
+    router: Router,
+    tracking_paused: bool,
+    /// Websocket commands that are only meant for testing are accepted
+    pub debug_commands: bool,
+    tick_budget: TickBudget,
+    /// Threads that filter the trackers when there are enough of them, started the first time
+    /// they're needed and kept so they aren't started every tick, None if they couldn't be started
+    #[cfg(feature = "parallel")]
+    tick_pool: std::sync::OnceLock<Option<rayon::ThreadPool>>,
+    gravity_calibration: Option<GravityCalibration>,
+    #[cfg(feature = "websocket")]
+    calibration_countdown: Option<CalibrationCountdown>,
+    side_check: Option<SideCheck>,
+    #[cfg(feature = "websocket")]
+    accel_scale_calibration: Option<AccelScaleCalibration>,
+    last_heartbeat_time: Option<Instant>,
+    /// Outputs that run in the tick by name, they get restarted after failing
+    output_restarts: HashMap<&'static str, RestartBackoff>,
+    #[cfg(feature = "websocket")]
+    device_commands: Vec<DeviceCommand>,
+    /// New devices get added to the allowlist until this time, always closed at startup
+    pairing_window_end_us: Option<u64>,
+    /// Set while replaying a recording
+    #[cfg(feature = "recording")]
+    pub playback: Option<PlaybackState>,
+    /// Summaries of the devices from the udp server, updated every upkeep
+    pub devices: Vec<DeviceInfo>,
+    /// Recorded time of the packet being replayed so the data keeps its original timestamp
+    pub replay_timestamp_us: Option<u64>,
 }
 
 impl MainServer {
-    pub fn new_message_channel(&mut self) -> UnboundedReceiver<ServerMessage> {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        self.message_channels.channels.push(tx);
-        rx
+    #[cfg(feature = "websocket")]
+    pub fn new_message_channel(&self) -> broadcast::Receiver<ChannelMessage> {
+        self.message_channels.sender.subscribe()
+    }
+
+    /// Published at the end of every tick for the websocket server to sync clients from
+    #[cfg(feature = "websocket")]
+    pub fn publish_snapshot(&self) {
+        // Taking the id and copying happen with the main lock held so no message gets in between
+        let next_message_id = *self.message_channels.next_id.lock().unwrap();
+        self.snapshots.publish(self.snapshot(next_message_id));
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn snapshot_subscriber(&self) -> SnapshotSubscriber {
+        SnapshotSubscriber {
+            messages: self.message_channels.clone(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    pub(crate) fn snapshot(&self, next_message_id: u64) -> Snapshot {
+        Snapshot {
+            trackers: self
+                .trackers
+                .iter()
+                .map(|tracker| TrackerSnapshot {
+                    info: tracker.info.clone(),
+                    data: tracker.data.clone(),
+                })
+                .collect(),
+            status: self.server_status(),
+            conventions: self.conventions(),
+            next_message_id,
+        }
     }
 
     pub fn load_config(&mut self) {
-        let tracker_configs = HashMap::<String, TrackerConfig>::new();
-        for (id, config) in tracker_configs {
-            self.register_tracker(id, config);
+        if let Some(path) = self.config_path.clone() {
+            match ServerConfig::load(&path) {
+                Ok(config) => self.config = config,
+                Err(error) => {
+                    tracing::error!("Failed to load config: {error:?}");
+                    self.config = ServerConfig::default();
+                    self.config_load_failed(&path, error);
+                }
+            }
+        }
+
+        // Register all the known trackers before any new ones so they get their saved index
+        for entry in self.config.trackers.clone() {
+            self.register_tracker(entry.id, entry.config);
+        }
+    }
+
+    /// Keeps a copy of the config that failed to load before the defaults get saved over it
+    fn config_load_failed(&mut self, path: &Path, error: anyhow::Error) {
+        let backup_path = config_backup_path(path);
+        let coded = match std::fs::copy(path, &backup_path) {
+            Ok(_) => {
+                tracing::warn!("Backed up the config to {}", backup_path.display());
+                CodedMessage::new("config_load_failed").param("backup", backup_path.display())
+            }
+            Err(copy_error) => {
+                tracing::error!("Failed to back up the config, it won't be saved: {copy_error}");
+                self.config_save_blocked = true;
+                CodedMessage::new("config_load_failed_not_saving")
+            }
+        };
+
+        let coded = coded.param("details", format!("{error:#}"));
+        self.notify_coded_error(coded.clone());
+        self.config_load_error = Some(coded);
+    }
+
+    pub fn save_config(&self) {
+        let Some(path) = &self.config_path else {
+            return;
+        };
+
+        if self.config_save_blocked {
+            tracing::warn!("Not saving the config since the one on disk couldn't be loaded");
+            return;
+        }
+
+        if let Err(error) = self.config.save(path) {
+            tracing::error!("Failed to save config: {error:?}");
+        }
+    }
+
+    /// Replaces the whole config, either applying all of it or none of it if it's invalid
+    /// Discovery settings only take effect after restarting
+    #[cfg(feature = "websocket")]
+    pub fn import_config(&mut self, mut config: ServerConfig) -> anyhow::Result<()> {
+        config.validate()?;
+
+        let mut ids = std::collections::HashSet::new();
+        for entry in &config.trackers {
+            if !ids.insert(&entry.id) {
+                return Err(CodedMessage::new("duplicate_tracker_in_config")
+                    .param("id", &entry.id)
+                    .into());
+            }
+        }
+
+        // Trackers that are running keep their index
+        for entry in &mut config.trackers {
+            if let Some(index) = self.tracker_id_to_index.get(&entry.id) {
+                entry.index = *index;
+            }
+        }
+
+        self.config = config;
+        // Importing replaces whatever couldn't be loaded
+        self.config_save_blocked = false;
+        self.config_load_error = None;
+        self.save_config();
+        tracing::info!("Imported config");
+
+        for entry in self.config.trackers.clone() {
+            if let Some(tracker) = self.trackers.get_mut(entry.index) {
+                if tracker.info.id == entry.id {
+                    tracker.info.config = entry.config;
+                    self.tracker_info_updated(entry.index);
+                }
+            }
         }
+
+        #[cfg(feature = "osc")]
+        self.router.invalidate();
+        self.queue_device_command(DeviceCommand::UpdateBlocklist);
+        self.send_to_clients(self.blocklist_message());
+        self.server_status_updated();
+        self.send_to_clients(ServerMessage::Conventions(self.conventions()));
+        Ok(())
     }
 
     pub fn tick(&mut self, delta: Duration) {
-        for tracker in &mut self.trackers {
+        let _span = tracing::debug_span!(target: SPAN_TARGET, "tick").entered();
+        self.tick_budget.start_tick();
+        let now_us = self.clock.now_us();
+        // Replayed data keeps its recorded timestamps so compare them with the replay's position
+        #[cfg(feature = "recording")]
+        let data_now_us = self.playback.map_or(now_us, |state| state.position_us);
+        #[cfg(not(feature = "recording"))]
+        let data_now_us = now_us;
+        let recording_latency = self.latency_recorder.is_active();
+        let stale_data_us = self.config.stale_data_ms * 1000;
+        let history_length = ticks_in(self.config.history_secs as f32);
+        let gap_intervals = self.config.data_gap_intervals;
+        let floor_offset = self.config.floor_offset;
+        self.correct_yaw(delta);
+
+        let tracker_count = self.trackers.iter().count();
+        let span =
+            tracing::debug_span!(target: SPAN_TARGET, "process_trackers", tracker_count).entered();
+        // Only touches the tracker so they can be filtered on any thread in any order
+        let process = |tracker: &mut Tracker| {
             tracker.tick(delta);
+            if tracker.info.config.location == TrackerLocation::Foot {
+                tracker.data.position.z -= floor_offset;
+            }
+
+            tracker.check_data_gap(data_now_us, gap_intervals);
+            tracker.record_history(history_length);
+            tracker.data.stale = stale_data_us != 0
+                && data_now_us.saturating_sub(tracker.data.timestamp_us) > stale_data_us;
+        };
+
+        #[cfg(feature = "parallel")]
+        {
+            let threshold = self.config.parallel_tick_trackers;
+            let pool = (threshold != 0 && tracker_count >= threshold)
+                .then(|| self.tick_pool.get_or_init(build_tick_pool).as_ref())
+                .flatten();
+            match pool {
+                Some(pool) => self.trackers.par_for_each_mut(pool, process),
+                None => self.trackers.iter_mut().for_each(process),
+            }
+        }
+        #[cfg(not(feature = "parallel"))]
+        self.trackers.iter_mut().for_each(process);
+        span.exit();
+
+        let span = tracing::debug_span!(target: SPAN_TARGET, "broadcast").entered();
+        for tracker in self.trackers.iter() {
+            // There's no data to send until the tracker has been seen
+            if tracker.info.status == TrackerStatus::Unknown {
+                continue;
+            }
+
+            // Only data received since the last tick is new
+            if recording_latency && tracker.data.timestamp_us > self.last_tick_us {
+                let latency_us = data_now_us.saturating_sub(tracker.data.timestamp_us);
+                self.latency_recorder
+                    .record(LatencyStage::ReceiveToBroadcast, latency_us);
+            }
+
             self.message_channels
                 .send_to_all(ServerMessage::TrackerData {
                     index: tracker.info.index,
                     data: tracker.data.clone(),
+                    raw_data: Some(tracker.raw_data.clone()),
                 });
             // Reset acceleration to prevent drift in case tracker stop sending data
             // tracker.data.acceleration = glam::Vec3A::ZERO;
         }
-    }
 
-    // Register a tracker to get its index and use that to access it later since using strings with
-    // hashmaps is a bit slow
-    pub fn register_tracker(&mut self, id: String, config: TrackerConfig) -> usize {
-        if let Some(index) = self.tracker_id_to_index.get(&id) {
-            return *index;
+        span.exit();
+        self.last_tick_us = data_now_us;
+
+        if self.exporter.is_some() && self.tick_budget.should_run(TickStage::Export) {
+            let stage_start = Instant::now();
+            let timestamp_unix_us = self.clock.start_unix_us() + now_us;
+            self.run_output("export", |main, restarting| {
+                let Some(exporter) = &mut main.exporter else {
+                    return Ok(());
+                };
+
+                if restarting {
+                    exporter.restart()?;
+                }
+                exporter.export(timestamp_unix_us, &main.trackers)
+            });
+            self.tick_budget.record(TickStage::Export, stage_start);
         }
 
-        let index = self.trackers.len();
-        let tracker = Tracker::new(id.clone(), index, config);
-        self.tracker_id_to_index.insert(id, index);
-        self.message_channels
-            .send_to_all(ServerMessage::TrackerInfo {
-                info: tracker.info.clone(),
+        #[cfg(feature = "osc")]
+        self.run_osc_outputs();
+
+        if self
+            .pairing_window_end_us
+            .is_some_and(|end_us| now_us >= end_us)
+        {
+            self.close_pairing_window();
+        }
+
+        if let Some(adjustment) = self.wall_clock.check(&self.clock) {
+            tracing::warn!(
+                "System clock jumped by {}ms, timestamps will stay relative to the time the server started",
+                adjustment.jump_us / 1000
+            );
+            self.send_to_clients(ServerMessage::ClockAdjusted { adjustment });
+            self.server_status_updated();
+        }
+
+        if self.tick_budget.should_report() {
+            self.server_status_updated();
+        }
+
+        if (self.last_heartbeat_time).is_none_or(|time| time.elapsed() >= HEARTBEAT_INTERVAL) {
+            self.last_heartbeat_time = Some(Instant::now());
+            self.send_to_clients(ServerMessage::Heartbeat {
+                timestamp_unix_us: clock::unix_now_us(),
             });
-        self.trackers.push(tracker);
-        index
+
+            // Glitches can happen on every packet so only send the new counts along with this
+            let glitched = self
+                .trackers
+                .iter_mut()
+                .filter_map(|tracker| tracker.take_new_glitches().then_some(tracker.info.index))
+                .collect::<Vec<_>>();
+            for index in glitched {
+                self.tracker_info_updated(index);
+            }
+        }
+
+        if let Some((start_time, duration)) = self.latency_test {
+            if start_time.elapsed() >= duration {
+                self.latency_test = None;
+                let result = self.latency_recorder.finish(duration);
+                tracing::info!(
+                    "Latency test finished, receive to broadcast p50: {}us, receive to websocket p50: {}us",
+                    result.receive_to_broadcast.p50_us,
+                    result.receive_to_websocket.p50_us
+                );
+                self.send_to_clients(ServerMessage::LatencyTestResult { result });
+            }
+        }
+
+        #[cfg(all(feature = "serial", feature = "websocket"))]
+        {
+            let provisioning_progress = (self.provisioning.as_mut())
+                .map(|provisioning| provisioning.take_progress())
+                .unwrap_or_default();
+            for progress in provisioning_progress {
+                self.send_to_clients(ServerMessage::ProvisioningProgress(progress));
+            }
+        }
+
+        #[cfg(feature = "websocket")]
+        if let Some(countdown) = &mut self.calibration_countdown {
+            match countdown.tick() {
+                Some(0) => {
+                    let kind = countdown.kind;
+                    self.calibration_countdown = None;
+                    self.send_to_clients(ServerMessage::CalibrationCountdown { remaining: 0 });
+                    self.run_calibration(kind);
+                }
+                Some(remaining) => {
+                    self.send_to_clients(ServerMessage::CalibrationCountdown { remaining });
+                }
+                None => (),
+            }
+        }
+
+        if let Some(calibration) = &mut self.gravity_calibration {
+            if let Some(tracker) = self.trackers.get(calibration.index) {
+                let raw_data = &tracker.raw_data;
+                calibration.add_sample(raw_data.acceleration, raw_data.timestamp_us);
+            }
+
+            if calibration.is_finished() {
+                self.finish_gravity_calibration();
+            }
+        }
+
+        #[cfg(feature = "websocket")]
+        if let Some(calibration) = &mut self.accel_scale_calibration {
+            if let Some(tracker) = self.trackers.get(calibration.index) {
+                let raw_data = &tracker.raw_data;
+                calibration.add_sample(raw_data.acceleration, raw_data.timestamp_us);
+            }
+        }
+
+        if let Some(side_check) = &mut self.side_check {
+            for index in side_check.indices() {
+                if let Some(tracker) = self.trackers.get(index) {
+                    let raw_data = &tracker.raw_data;
+                    side_check.add_sample(index, raw_data.acceleration, raw_data.timestamp_us);
+                }
+            }
+
+            if side_check.is_finished() {
+                self.finish_side_check();
+            }
+        }
     }
 
-    pub fn tracker_info_updated(&mut self, index: usize) {
-        self.message_channels
-            .send_to_all(ServerMessage::TrackerInfo {
-                info: self.trackers[index].clone().info,
+    /// Runs an output that's part of the tick, a panic or error only stops that output which gets
+    /// started again after a backoff, the closure is told when it's being restarted
+    fn run_output(
+        &mut self,
+        name: &'static str,
+        run: impl FnOnce(&mut Self, bool) -> anyhow::Result<()>,
+    ) {
+        let Some(restarting) = self.output_restarts.entry(name).or_default().poll() else {
+            return;
+        };
+
+        if restarting {
+            tracing::info!("Restarting {name}");
+        }
+
+        let _span = tracing::debug_span!(target: SPAN_TARGET, "output", name).entered();
+
+        if let Err(error) = catch_panic(|| run(self, restarting)) {
+            let restart_in = self.output_restarts.entry(name).or_default().failed();
+            self.subsystem_failed(name, format!("{error:#}"), restart_in);
+        }
+    }
+
+    /// Tells the clients that part of the server failed and whether it's going to be restarted
+    pub fn subsystem_failed(
+        &self,
+        name: &'static str,
+        reason: String,
+        restart_in: Option<Duration>,
+    ) {
+        match restart_in {
+            Some(restart_in) => {
+                tracing::error!("{name} failed, restarting in {restart_in:?}: {reason}")
+            }
+            None => {
+                tracing::error!("{name} failed too many times in a row, giving up on it: {reason}")
+            }
+        }
+
+        self.send_to_clients(ServerMessage::SubsystemFailed {
+            name,
+            reason,
+            restarting: restart_in.is_some(),
+        });
+    }
+
+    /// Sends to VRChat and along the output routes, lowest priority in the tick
+    #[cfg(feature = "osc")]
+    fn run_osc_outputs(&mut self) {
+        if !self.config.vrchat_osc.enabled {
+            self.vrchat_osc = None;
+            self.output_restarts.remove("vrchat_osc");
+        } else if self.tick_budget.should_run(TickStage::VrchatOsc) {
+            let stage_start = Instant::now();
+            self.run_output("vrchat_osc", |main, restarting| {
+                if restarting {
+                    main.vrchat_osc = None;
+                }
+                main.send_vrchat_osc();
+                Ok(())
+            });
+            self.tick_budget.record(TickStage::VrchatOsc, stage_start);
+        }
+
+        if !self.config.routes.is_empty() && self.tick_budget.should_run(TickStage::Routes) {
+            let stage_start = Instant::now();
+            self.run_output("routes", |main, restarting| {
+                if restarting {
+                    main.router = Router::default();
+                }
+
+                if let Err(error) = main.router.send(&main.config.routes, &main.trackers) {
+                    let error = format!("Failed to start sending the output routes: {error}");
+                    tracing::error!("{error}");
+                    main.notify_error(&error);
+                    main.config
+                        .routes
+                        .iter_mut()
+                        .for_each(|route| route.enabled = false);
+                }
+                Ok(())
             });
+            self.tick_budget.record(TickStage::Routes, stage_start);
+        }
     }
 
-    pub fn update_tracker_data(
+    #[cfg(feature = "osc")]
+    fn send_vrchat_osc(&mut self) {
+        let config = &self.config.vrchat_osc;
+        let timestamp_unix_us = self.clock.start_unix_us() + self.clock.now_us();
+        let sender = match &mut self.vrchat_osc {
+            Some(sender) => sender,
+            None => match VrchatOscSender::new() {
+                Ok(sender) => self.vrchat_osc.insert(sender),
+                Err(error) => {
+                    let error = format!("Failed to start sending to VRChat over OSC: {error}");
+                    tracing::error!("{error}");
+                    self.notify_error(&error);
+                    self.config.vrchat_osc.enabled = false;
+                    return;
+                }
+            },
+        };
+
+        let slots = if config.slots.is_empty() {
+            (1..=VRCHAT_TRACKER_SLOTS)
+                .zip(self.trackers.iter())
+                .collect::<Vec<_>>()
+        } else {
+            (config.slots.iter())
+                .filter_map(|assignment| {
+                    let index = self.tracker_id_to_index.get(&assignment.tracker_id)?;
+                    Some((assignment.slot, self.trackers.get(*index)?))
+                })
+                .collect()
+        };
+
+        sender.send(slots, config, timestamp_unix_us);
+    }
+
+    /// Corrects the yaw drift of the trackers towards the yaw reference tracker while everything is
+    /// still since any yaw change then has to be drift
+    fn correct_yaw(&mut self, delta: Duration) {
+        let config = &self.config.yaw_correction;
+        let reference = self.trackers.iter().find(|tracker| {
+            tracker.info.config.is_yaw_reference && tracker.info.status == TrackerStatus::Ok
+        });
+        let Some(reference_yaw) = reference.map(|tracker| tracker.data.orientation.yaw()) else {
+            return;
+        };
+
+        let stationary = self
+            .trackers
+            .iter()
+            .filter(|tracker| tracker.info.status == TrackerStatus::Ok)
+            .all(|tracker| tracker.data.acceleration.length() < config.stationary_threshold);
+        if !stationary {
+            return;
+        }
+
+        let max_step = config.rate_deg_per_sec.to_radians() * delta.as_secs_f32();
+        for tracker in self.trackers.iter_mut() {
+            if tracker.info.status == TrackerStatus::Ok && !tracker.info.config.is_yaw_reference {
+                tracker.correct_yaw(reference_yaw, max_step);
+            }
+        }
+    }
+
+    /// Starts exporting with the config, saving it to be used next time
+    pub fn start_export(&mut self, config: Option<ExportConfig>) -> anyhow::Result<()> {
+        if let Some(config) = config {
+            self.config.export = config;
+            self.save_config();
+        }
+
+        self.exporter = Some(Exporter::start(self.config.export.clone())?);
+        self.output_restarts.remove("export");
+        Ok(())
+    }
+
+    pub fn stop_export(&mut self) {
+        self.output_restarts.remove("export");
+        if self.exporter.take().is_some() {
+            tracing::info!("Stopped exporting tracker data");
+        }
+    }
+
+    /// Sends the credentials to every mycap device plugged in over USB until stopped
+    #[cfg(all(feature = "serial", feature = "websocket"))]
+    pub fn start_provisioning(&mut self, credentials: WifiCredentials) -> anyhow::Result<()> {
+        let queue = ProvisioningQueue::new(credentials, self.config.serial_protocol)?;
+        self.provisioning = Some(Provisioning::start(queue)?);
+        tracing::info!("Started provisioning devices plugged in over USB");
+        Ok(())
+    }
+
+    #[cfg(all(feature = "serial", feature = "websocket"))]
+    pub fn stop_provisioning(&mut self) {
+        if self.provisioning.take().is_some() {
+            tracing::info!("Stopped provisioning devices");
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn start_raw_sensor_recording(&mut self, path: PathBuf) {
+        self.raw_sensor_recorder = Some(RawSensorRecorder::new(path));
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn stop_raw_sensor_recording(&mut self) {
+        if self.raw_sensor_recorder.take().is_some() {
+            tracing::info!("Stopped recording raw sensor data");
+        }
+    }
+
+    pub fn record_raw_sensors(
         &mut self,
         index: usize,
-        acceleration: glam::Vec3A,
-        orientation: glam::Quat,
+        received_time: Instant,
+        sample: &RawSensorSample,
     ) {
-        let data = &mut self.trackers[index].data;
-        data.orientation = orientation;
-        data.acceleration = acceleration;
+        let (Some(recorder), Some(tracker)) =
+            (&mut self.raw_sensor_recorder, self.trackers.get(index))
+        else {
+            return;
+        };
+
+        let timestamp_us = self.clock.timestamp_us(received_time);
+        if let Err(error) = recorder.record(timestamp_us, &tracker.info.id, sample) {
+            let error = format!("Failed to record raw sensor data: {error}");
+            tracing::error!("{error}");
+            self.notify_error(&error);
+            self.raw_sensor_recorder = None;
+        }
     }
 
-    pub fn notify_error(&mut self, error: &str) {
-        self.message_channels.send_to_all(ServerMessage::Error {
-            error: error.to_string(),
+    #[cfg(feature = "websocket")]
+    pub fn latency_recorder(&self) -> LatencyRecorder {
+        self.latency_recorder.clone()
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn start_latency_test(&mut self, duration: Duration) {
+        tracing::info!("Running latency test for {duration:?}");
+        self.latency_recorder.start();
+        self.latency_test = Some((Instant::now(), duration));
+    }
+
+    pub fn open_pairing_window(&mut self, duration: Duration) {
+        tracing::info!("Opened the pairing window for {duration:?}");
+        self.pairing_window_end_us = Some(self.clock.now_us() + duration.as_micros() as u64);
+        self.server_status_updated();
+    }
+
+    pub fn close_pairing_window(&mut self) {
+        if self.pairing_window_end_us.take().is_some() {
+            tracing::info!("Closed the pairing window");
+            self.server_status_updated();
+        }
+    }
+
+    pub fn is_pairing_window_open(&self) -> bool {
+        (self.pairing_window_end_us).is_some_and(|end_us| self.clock.now_us() < end_us)
+    }
+
+    /// Adds the device to the allowlist if the pairing window is open, returns whether it was added
+    pub fn pair_device(&mut self, mac: &str) -> bool {
+        if !self.is_pairing_window_open() {
+            return false;
+        }
+
+        tracing::info!("Added {mac} to the allowlist from the pairing window");
+        self.config.allowlist.push(mac.to_string());
+        self.save_config();
+        self.send_to_clients(ServerMessage::DevicePaired {
+            mac: mac.to_string(),
         });
+        true
     }
-}
 
-const TARGET_LOOP_DELTA: Duration = Duration::from_millis(1000 / 50);
+    /// Sends the input to the clients and over OSC then runs the actions bound to it
+    pub fn handle_input(&mut self, mac: &str, input: u8, kind: InputKind, value: f32) {
+        self.send_to_clients(ServerMessage::InputEvent {
+            mac: mac.to_string(),
+            input,
+            kind,
+            value,
+        });
 
-pub async fn start_server(main: Arc<RwLock<MainServer>>) -> anyhow::Result<()> {
-    let mut last_loop_time = Instant::now();
-    let mut sub_servers = SubServers::new().await?;
+        #[cfg(feature = "osc")]
+        self.send_input_osc(mac, input, value);
 
-    loop {
-        let delta = last_loop_time.elapsed();
-        last_loop_time = Instant::now();
+        if kind == InputKind::Button {
+            let actions: Vec<InputAction> = self.config.input.actions(mac, input, value).collect();
+            for action in actions {
+                self.run_input_action(action);
+            }
+        }
+    }
 
-        // Tick all the servers
-        {
-            let mut main = main.write().await;
-            main.tick(delta);
-            sub_servers.tick(&mut main).await?;
+    #[cfg(feature = "osc")]
+    fn send_input_osc(&mut self, mac: &str, input: u8, value: f32) {
+        if self.config.input.osc_enabled && self.input_osc.is_none() {
+            match OscSender::new() {
+                Ok(sender) => self.input_osc = Some(sender),
+                Err(error) => {
+                    let error = format!("Failed to start sending inputs over OSC: {error}");
+                    tracing::error!("{error}");
+                    self.notify_error(&error);
+                    self.config.input.osc_enabled = false;
+                }
+            }
         }
 
-        let post_delta = last_loop_time.elapsed();
-        if let Some(sleep_duration) = TARGET_LOOP_DELTA.checked_sub(post_delta) {
-            tokio::time::sleep(sleep_duration).await;
-        } else {
-            log::warn!(
-                "Main server loop took {post_delta:?} which is longer than target {TARGET_LOOP_DELTA:?}"
-            );
+        let config = &self.config.input;
+        if let Some(sender) = self.input_osc.as_ref().filter(|_| config.osc_enabled) {
+            sender.send(&config.osc_address(mac, input), &[value], config.osc_target);
         }
     }
-}
 
-pub struct SubServers {
-    udp: UdpServer,
-}
+    fn run_input_action(&mut self, action: InputAction) {
+        tracing::info!("Running {action:?} from an input");
+        match action {
+            InputAction::ResetYaw => self.run_calibration(CalibrationKind::ResetYaw),
+            InputAction::TogglePauseTracking => {
+                self.tracking_paused = !self.tracking_paused;
+                self.server_status_updated();
+            }
+            InputAction::ToggleExport => {
+                if self.exporter.is_some() {
+                    self.stop_export();
+                } else if let Err(error) = self.start_export(None) {
+                    tracing::error!("{error}");
+                    self.notify_error(&error.to_string());
+                }
+            }
+        }
+    }
 
-impl SubServers {
-    async fn new() -> anyhow::Result<Self> {
-        let udp = UdpServer::new()
-            .await
-            .context("Failed to start UDP server")?;
-        Ok(Self { udp })
+    /// Resets anything that would be thrown off by the server not running for the gap
+    pub fn resumed(&mut self, gap: Duration) {
+        tracing::info!("Server resumed after not running for {gap:?}");
+        for tracker in self.trackers.iter_mut() {
+            tracker.reset_motion();
+        }
+
+        self.send_to_clients(ServerMessage::ServerResumed {
+            gap_ms: gap.as_millis() as u64,
+        });
     }
 
-    async fn tick(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
-        self.udp.tick(main).await?;
-        Ok(())
+    #[cfg(feature = "websocket")]
+    pub fn queue_device_command(&mut self, command: DeviceCommand) {
+        self.device_commands.push(command);
+    }
+
+    /// Saves the changed blocklist and gets the udp server to apply it
+    #[cfg(feature = "websocket")]
+    pub fn blocklist_updated(&mut self) {
+        self.save_config();
+        self.queue_device_command(DeviceCommand::UpdateBlocklist);
+        self.send_to_clients(self.blocklist_message());
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn blocklist_message(&self) -> ServerMessage {
+        ServerMessage::Blocklist {
+            macs: self.config.blocked_macs.clone(),
+            addresses: self.config.blocked_addresses.clone(),
+        }
+    }
+
+    /// Everything useful for a bug report as pretty printed JSON
+    #[cfg(feature = "websocket")]
+    pub fn dump_state(&self) -> anyhow::Result<String> {
+        #[derive(serde::Serialize)]
+        struct TrackerDump<'a> {
+            info: &'a TrackerInfo,
+            data: &'a TrackerData,
+            raw_data: &'a RawTrackerData,
+        }
+
+        #[derive(serde::Serialize)]
+        struct StateDump<'a> {
+            status: ServerStatus,
+            trackers: Vec<TrackerDump<'a>>,
+            devices: &'a [DeviceInfo],
+            config: &'a ServerConfig,
+        }
+
+        let dump = StateDump {
+            status: self.server_status(),
+            trackers: self
+                .trackers
+                .iter()
+                .map(|tracker| TrackerDump {
+                    info: &tracker.info,
+                    data: &tracker.data,
+                    raw_data: &tracker.raw_data,
+                })
+                .collect(),
+            devices: &self.devices,
+            config: &self.config,
+        };
+
+        Ok(serde_json::to_string_pretty(&dump)?)
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn take_device_commands(&mut self) -> Vec<DeviceCommand> {
+        std::mem::take(&mut self.device_commands)
+    }
+
+    /// Measures the tracker while it's stationary to figure out how its IMU reports gravity
+    /// Runs the calibration after the delay, replacing any countdown that's already going
+    #[cfg(feature = "websocket")]
+    pub fn start_calibration(&mut self, kind: CalibrationKind, delay: Duration) {
+        tracing::info!("Running {kind:?} calibration in {delay:?}");
+        self.calibration_countdown = Some(CalibrationCountdown::new(kind, delay));
+    }
+
+    fn run_calibration(&mut self, kind: CalibrationKind) {
+        match kind {
+            CalibrationKind::ResetYaw => {
+                tracing::info!("Resetting the yaw of every tracker");
+                for tracker in self.trackers.iter_mut() {
+                    tracker.reset_yaw();
+                }
+            }
+            CalibrationKind::Gravity { index, seconds } => {
+                if self.trackers.get(index).is_none() {
+                    self.notify_error(&format!(
+                        "Tracker {index} was removed before its calibration started"
+                    ));
+                    return;
+                }
+
+                self.start_gravity_calibration(index, Duration::from_secs_f32(seconds));
+            }
+            CalibrationKind::SideCheck {
+                location,
+                side,
+                seconds,
+            } => {
+                if let Err(error) =
+                    self.start_side_check(location, side, Duration::from_secs_f32(seconds))
+                {
+                    self.notify_coded_error(error);
+                }
+            }
+        }
+    }
+
+    /// The left and right tracker at the location
+    pub fn side_pair(&self, location: TrackerLocation) -> Result<(usize, usize), CodedMessage> {
+        let find = |side| {
+            let mut found = (self.trackers.iter())
+                .filter(|tracker| {
+                    tracker.info.config.location == location && tracker.info.config.side == side
+                })
+                .map(|tracker| tracker.info.index);
+            match (found.next(), found.next()) {
+                (Some(index), None) => Some(index),
+                _ => None,
+            }
+        };
+
+        find(TrackerSide::Left)
+            .zip(find(TrackerSide::Right))
+            .ok_or_else(|| {
+                CodedMessage::new("side_check_needs_pair")
+                    .param("location", format!("{location:?}"))
+            })
+    }
+
+    /// Watches the left and right trackers at the location while the user moves the one on the
+    /// side, suggesting a swap if the other one moved
+    pub fn start_side_check(
+        &mut self,
+        location: TrackerLocation,
+        side: TrackerSide,
+        duration: Duration,
+    ) -> Result<(), CodedMessage> {
+        let (left, right) = self.side_pair(location)?;
+        tracing::info!("Checking the {side:?} {location:?} tracker for {duration:?}");
+        self.side_check = Some(SideCheck::new(side, duration, left, right));
+        Ok(())
+    }
+
+    fn finish_side_check(&mut self) {
+        let Some(side_check) = self.side_check.take() else {
+            return;
+        };
+
+        let moved = side_check.moved();
+        match moved {
+            Some((index, side)) if side != side_check.side => {
+                let [left, right] = side_check.indices();
+                let (a, b) = if index == left {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                tracing::warn!("Tracker {a} moved instead of tracker {b}, they might be swapped");
+                self.send_to_clients(ServerMessage::SwapSuggestion { a, b });
+            }
+            Some((index, _)) => tracing::info!("Tracker {index} is on the right side"),
+            None => tracing::warn!("Couldn't tell which tracker moved in the side check"),
+        }
+
+        self.send_to_clients(ServerMessage::SideCheckFinished {
+            moved: moved.map(|(index, _)| index),
+        });
+    }
+
+    /// Swaps the sides of the left and right tracker, saved in their configs
+    #[cfg(feature = "websocket")]
+    pub fn swap_sides(&mut self, a: usize, b: usize) -> anyhow::Result<()> {
+        let config = |index: usize| {
+            self.trackers
+                .get(index)
+                .map(|tracker| &tracker.info.config)
+                .ok_or_else(|| CodedMessage::new("tracker_not_found").param("index", index))
+        };
+        let (config_a, config_b) = (config(a)?, config(b)?);
+        if a == b
+            || config_a.location != config_b.location
+            || config_a.side == TrackerSide::None
+            || config_a.side.opposite() != config_b.side
+        {
+            return Err(CodedMessage::new("trackers_not_a_pair")
+                .param("a", a)
+                .param("b", b)
+                .into());
+        }
+
+        for index in [a, b] {
+            let Some(tracker) = self.trackers.get_mut(index) else {
+                continue;
+            };
+            let info = &mut tracker.info;
+            info.config.side = info.config.side.opposite();
+            let entry = TrackerConfigEntry {
+                id: info.id.clone(),
+                index,
+                config: info.config.clone(),
+            };
+            self.config.set_tracker_entry(entry);
+            self.tracker_info_updated(index);
+        }
+
+        self.save_config();
+        tracing::info!("Swapped the sides of tracker {a} and {b}");
+        Ok(())
+    }
+
+    pub fn start_gravity_calibration(&mut self, index: usize, duration: Duration) {
+        tracing::info!("Calibrating gravity with tracker {index} for {duration:?}");
+        self.gravity_calibration = Some(GravityCalibration::new(index, duration));
+    }
+
+    fn finish_gravity_calibration(&mut self) {
+        let Some(calibration) = self.gravity_calibration.take() else {
+            return;
+        };
+
+        let result = calibration.finish();
+        tracing::info!(
+            "Gravity calibration finished, measured {}: {}",
+            result.measured.0,
+            result.message
+        );
+
+        if let Some(config) = &result.config {
+            self.config.gravity = config.clone();
+            self.save_config();
+            self.send_to_clients(ServerMessage::Conventions(self.conventions()));
+        }
+
+        self.send_to_clients(ServerMessage::GravityCalibrationResult { result });
+    }
+
+    /// Starts measuring the tracker on each of its faces in turn to correct its accelerometer's
+    /// scale and bias, replacing any that's already going
+    #[cfg(feature = "websocket")]
+    pub fn start_accel_scale_calibration(&mut self, index: usize) -> anyhow::Result<()> {
+        if self.trackers.get(index).is_none() {
+            return Err(CodedMessage::new("tracker_not_found")
+                .param("index", index)
+                .into());
+        }
+
+        tracing::info!("Calibrating the accelerometer scale of tracker {index}");
+        let calibration = AccelScaleCalibration::new(index);
+        self.send_to_clients(accel_scale_progress(&calibration));
+        self.accel_scale_calibration = Some(calibration);
+        Ok(())
+    }
+
+    /// Finishes the face the tracker is resting on and moves onto the next one
+    #[cfg(feature = "websocket")]
+    pub fn next_accel_scale_face(&mut self) -> anyhow::Result<()> {
+        let Some(calibration) = &mut self.accel_scale_calibration else {
+            return Err(CodedMessage::new("accel_scale_not_running").into());
+        };
+
+        let index = calibration.index;
+        match calibration.advance()? {
+            AccelScaleStep::Next(face) => {
+                tracing::info!(
+                    "Tracker {index} accelerometer scale calibration moving onto {face:?}"
+                );
+                let message = accel_scale_progress(calibration);
+                self.send_to_clients(message);
+            }
+            AccelScaleStep::Finished(scale, bias) => {
+                self.accel_scale_calibration = None;
+                let tracker = self
+                    .trackers
+                    .get_mut(index)
+                    .ok_or_else(|| CodedMessage::new("tracker_not_found").param("index", index))?;
+                tracker.info.config.accel_scale = scale;
+                tracker.info.config.accel_bias = bias;
+                self.config.set_tracker_entry(TrackerConfigEntry {
+                    id: tracker.info.id.clone(),
+                    index,
+                    config: tracker.info.config.clone(),
+                });
+                self.save_config();
+                self.tracker_info_updated(index);
+
+                tracing::info!(
+                    "Calibrated the accelerometer of tracker {index}, scale {scale} bias {}",
+                    bias.0
+                );
+                self.send_to_clients(ServerMessage::AccelScaleCalibrated { index, scale, bias });
+            }
+            AccelScaleStep::Failed(error) => {
+                self.accel_scale_calibration = None;
+                return Err(error.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Register a tracker to get its index and use that to access it later since using strings with
+    // hashmaps is a bit slow
+    pub fn register_tracker(&mut self, id: String, config: TrackerConfig) -> usize {
+        if let Some(index) = self.tracker_id_to_index.get(&id) {
+            return *index;
+        }
+
+        let saved = self
+            .config
+            .tracker_entry(&id)
+            .map(|entry| (entry.index, entry.config.clone()));
+
+        let (index, config) = match saved {
+            Some((index, config)) if self.trackers.get(index).is_none() => (index, config),
+            saved => {
+                // New trackers (or ones with a clashing index) go after the highest index and get
+                // saved to the config so they keep their index next session
+                let index = self.trackers.next_index();
+                let config = saved.map_or(config, |(_, config)| config);
+                self.config.set_tracker_entry(TrackerConfigEntry {
+                    id: id.clone(),
+                    index,
+                    config: config.clone(),
+                });
+                self.save_config();
+                (index, config)
+            }
+        };
+
+        let mut config = config;
+        // The saved config is left as it is so it can be fixed by hand
+        if let Err(error) = config.validate() {
+            tracing::error!("Invalid config for tracker {id}, using the default: {error}");
+            self.notify_coded_error(
+                CodedMessage::new("invalid_tracker_config")
+                    .param("id", &id)
+                    .param("field", &error.field)
+                    .param("message", &error.message),
+            );
+            config = TrackerConfig {
+                name: config.name,
+                ..Default::default()
+            };
+        }
+
+        let tracker = Tracker::new(id.clone(), index, config);
+        self.tracker_id_to_index.insert(id, index);
+        #[cfg(feature = "osc")]
+        self.router.invalidate();
+        self.message_channels
+            .send_to_all(ServerMessage::TrackerInfo {
+                info: tracker.info.clone(),
+            });
+        self.trackers.insert(tracker);
+        index
+    }
+
+    /// Removes the tracker while keeping its config entry so it gets the same index if it comes back
+    #[cfg(feature = "websocket")]
+    pub fn remove_tracker(&mut self, index: usize) {
+        let Some(tracker) = self.trackers.remove(index) else {
+            return;
+        };
+
+        self.tracker_id_to_index.remove(&tracker.info.id);
+        #[cfg(feature = "osc")]
+        self.router.invalidate();
+        self.message_channels
+            .send_to_all(ServerMessage::TrackerRemoved { index });
+    }
+
+    /// Moves the tracker at mapping[i] to index i, the mapping has to have every current index once
+    /// Indices in the routes follow their trackers
+    #[cfg(feature = "websocket")]
+    pub fn remap_tracker_indices(&mut self, mapping: Vec<usize>) -> anyhow::Result<()> {
+        // Already sorted since the list is stored by index
+        let old_indices: Vec<usize> = self
+            .trackers
+            .iter()
+            .map(|tracker| tracker.info.index)
+            .collect();
+        let Some(new_indices) = invert_tracker_mapping(&old_indices, &mapping) else {
+            return Err(CodedMessage::new("remap_not_permutation").into());
+        };
+
+        // They hold onto the index of the tracker they're calibrating
+        if self.gravity_calibration.is_some()
+            || self.side_check.is_some()
+            || self.accel_scale_calibration.is_some()
+            || self.calibration_countdown.is_some()
+        {
+            return Err(CodedMessage::new("remap_during_calibration").into());
+        }
+
+        // The trackers come out in the order of their old indices like the new indices do
+        let old_trackers = std::mem::take(&mut self.trackers);
+        for (mut tracker, &new_index) in old_trackers.into_iter().zip(&new_indices) {
+            tracker.info.index = new_index;
+            self.tracker_id_to_index
+                .insert(tracker.info.id.clone(), new_index);
+            if let Some(entry) =
+                (self.config.trackers.iter_mut()).find(|entry| entry.id == tracker.info.id)
+            {
+                entry.index = new_index;
+            }
+            self.trackers.insert(tracker);
+        }
+
+        let mut new_indices: HashMap<usize, usize> =
+            old_indices.iter().copied().zip(new_indices).collect();
+        // Saved entries of trackers that aren't running get registered in config order when
+        // loading, so one that clashes would take the index before the remapped tracker does
+        for (old_index, new_index) in self.move_clashing_config_entries(mapping.len()) {
+            new_indices.entry(old_index).or_insert(new_index);
+        }
+        #[cfg(feature = "osc")]
+        {
+            let profile_routes = (self.config.profiles.iter_mut())
+                .filter_map(|profile| profile.routes.as_mut())
+                .flatten();
+            for route in self.config.routes.iter_mut().chain(profile_routes) {
+                route.selector.remap_indices(&new_indices);
+            }
+
+            self.router.invalidate();
+            if let Some(osc) = &mut self.vrchat_osc {
+                osc.clear_predictions();
+            }
+        }
+
+        self.save_config();
+        self.queue_device_command(DeviceCommand::RemapTrackerIndices { new_indices });
+        tracing::info!("Remapped the tracker indices to {mapping:?}");
+
+        let count = mapping.len();
+        self.send_to_clients(ServerMessage::TrackerIndicesRemapped { mapping });
+        // Removed trackers left holes so the highest indices can be free now
+        for index in old_indices.into_iter().filter(|index| *index >= count) {
+            self.send_to_clients(ServerMessage::TrackerRemoved { index });
+        }
+        for index in 0..count {
+            self.tracker_info_updated(index);
+        }
+        Ok(())
+    }
+
+    /// Gives the config entries of trackers that aren't running and share an index with a running
+    /// one or each other an index from the first free one at or after `start`
+    /// Returns the old and new index of the moved entries
+    #[cfg(feature = "websocket")]
+    fn move_clashing_config_entries(&mut self, start: usize) -> Vec<(usize, usize)> {
+        let is_running = |id: &String| self.tracker_id_to_index.contains_key(id);
+        let mut used: std::collections::HashSet<usize> =
+            self.tracker_id_to_index.values().copied().collect();
+        let mut clashing = Vec::new();
+        for (position, entry) in self.config.trackers.iter().enumerate() {
+            if !is_running(&entry.id) && !used.insert(entry.index) {
+                clashing.push(position);
+            }
+        }
+
+        let mut next_index = start;
+        let mut moved = Vec::new();
+        for position in clashing {
+            while used.contains(&next_index) {
+                next_index += 1;
+            }
+            used.insert(next_index);
+            let entry = &mut self.config.trackers[position];
+            moved.push((entry.index, next_index));
+            entry.index = next_index;
+        }
+        moved
+    }
+
+    /// Moves the tracker's position by the offset from now on and saves it in its config
+    #[cfg(feature = "websocket")]
+    pub fn set_position_offset(&mut self, index: usize, offset: glam::Vec3A) -> anyhow::Result<()> {
+        if !offset.is_finite() {
+            return Err(CodedMessage::new("position_offset_not_finite").into());
+        }
+
+        let tracker = self
+            .trackers
+            .get_mut(index)
+            .ok_or_else(|| CodedMessage::new("tracker_not_found").param("index", index))?;
+        tracker.info.config.position_offset = offset;
+        self.config.set_tracker_entry(TrackerConfigEntry {
+            id: tracker.info.id.clone(),
+            index,
+            config: tracker.info.config.clone(),
+        });
+        self.save_config();
+        self.tracker_info_updated(index);
+        Ok(())
+    }
+
+    /// Uses the current height of the foot tracker as the floor for all the foot trackers
+    #[cfg(feature = "websocket")]
+    pub fn set_floor(&mut self, index: usize) -> anyhow::Result<()> {
+        let tracker = self
+            .trackers
+            .get(index)
+            .ok_or_else(|| CodedMessage::new("tracker_not_found").param("index", index))?;
+        if tracker.info.config.location != TrackerLocation::Foot {
+            return Err(CodedMessage::new("tracker_not_foot")
+                .param("index", index)
+                .into());
+        }
+
+        // The position already has the old floor taken away
+        let floor_offset = self.config.floor_offset + tracker.data.position.z;
+        if !floor_offset.is_finite() {
+            return Err(CodedMessage::new("position_not_finite").into());
+        }
+
+        self.config.floor_offset = floor_offset;
+        self.save_config();
+        tracing::info!("Set the floor to {floor_offset}m from tracker {index}");
+        Ok(())
+    }
+
+    /// Adds the route or replaces the one with the same name
+    #[cfg(all(feature = "websocket", feature = "osc"))]
+    pub fn set_route(&mut self, route: OutputRoute) -> anyhow::Result<()> {
+        let mut routes = self.config.routes.clone();
+        match routes.iter_mut().find(|other| other.name == route.name) {
+            Some(other) => *other = route,
+            None => routes.push(route),
+        }
+
+        validate_routes(&routes)?;
+        self.config.routes = routes;
+        self.save_config();
+        self.router.invalidate();
+        Ok(())
+    }
+
+    #[cfg(all(feature = "websocket", feature = "osc"))]
+    pub fn remove_route(&mut self, name: &str) -> anyhow::Result<()> {
+        let count = self.config.routes.len();
+        self.config.routes.retain(|route| route.name != name);
+        if self.config.routes.len() == count {
+            return Err(CodedMessage::new("route_not_found")
+                .param("name", name)
+                .into());
+        }
+
+        self.save_config();
+        self.router.invalidate();
+        Ok(())
+    }
+
+    #[cfg(all(feature = "websocket", feature = "osc"))]
+    pub fn route_stats(&mut self) -> Vec<RouteStats> {
+        self.router.stats(&self.config.routes, &self.trackers)
+    }
+
+    /// History of the tracker, up to history_secs long, to be read after letting go of the lock
+    #[cfg(feature = "websocket")]
+    pub fn tracker_history(&self, index: usize) -> anyhow::Result<TrackerHistory> {
+        if self.config.history_secs == 0 {
+            return Err(CodedMessage::new("history_disabled").into());
+        }
+
+        let tracker = self
+            .trackers
+            .get(index)
+            .ok_or_else(|| CodedMessage::new("tracker_not_found").param("index", index))?;
+        Ok(tracker.history())
+    }
+
+    /// Switches to the profile, either applying all of it or none of it if the result is invalid
+    /// Only the trackers and settings that changed get sent to the clients
+    #[cfg(feature = "websocket")]
+    pub fn apply_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let profile = (self.config.profiles.iter())
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| CodedMessage::new("profile_not_found").param("name", name))?;
+        let config = profile.apply_to(&self.config);
+        config.validate()?;
+
+        let old_config = std::mem::replace(&mut self.config, config);
+        self.save_config();
+        tracing::info!("Applied profile {name}");
+
+        for entry in self.config.trackers.clone() {
+            let Some(tracker) = self.trackers.get_mut(entry.index) else {
+                continue;
+            };
+
+            if tracker.info.id == entry.id && tracker.info.config != entry.config {
+                tracker.info.config = entry.config;
+                self.tracker_info_updated(entry.index);
+            }
+        }
+
+        #[cfg(feature = "osc")]
+        if self.config.routes != old_config.routes {
+            self.router.invalidate();
+        }
+
+        if self.config.active_profile != old_config.active_profile {
+            self.server_status_updated();
+        }
+
+        Ok(())
+    }
+
+    /// Saves the current settings as the profile, replacing the one with the same name
+    #[cfg(feature = "websocket")]
+    pub fn save_current_as_profile(&mut self, name: String) -> anyhow::Result<()> {
+        if name.is_empty() {
+            return Err(CodedMessage::new("profile_name_empty").into());
+        }
+
+        let profile = ConfigProfile::capture(name.clone(), &self.config);
+        let profiles = &mut self.config.profiles;
+        match profiles.iter_mut().find(|other| other.name == name) {
+            Some(other) => *other = profile,
+            None => profiles.push(profile),
+        }
+
+        let changed = self.config.active_profile.as_ref() != Some(&name);
+        self.config.active_profile = Some(name);
+        self.save_config();
+        if changed {
+            self.server_status_updated();
+        }
+
+        Ok(())
+    }
+
+    /// Sets the status and clears the data if it changed
+    pub fn update_tracker_status(
+        &mut self,
+        index: usize,
+        status: TrackerStatus,
+    ) -> anyhow::Result<()> {
+        let tracker = self
+            .trackers
+            .get_mut(index)
+            .ok_or_else(|| CodedMessage::new("tracker_not_found").param("index", index))?;
+
+        if tracker.info.status != status {
+            tracker.info.status = status;
+            tracker.data = TrackerData::default();
+            self.tracker_info_updated(index);
+        }
+
+        Ok(())
+    }
+
+    pub fn tracker_info_updated(&mut self, index: usize) {
+        let Some(tracker) = self.trackers.get(index) else {
+            return;
+        };
+        let info = tracker.info.clone();
+
+        // The tracker's location or group could've changed
+        #[cfg(feature = "osc")]
+        self.router.invalidate();
+        self.message_channels
+            .send_to_all(ServerMessage::TrackerInfo { info });
+    }
+
+    pub fn update_tracker_data(
+        &mut self,
+        index: usize,
+        acceleration: AccelMps2,
+        orientation: SensorQuat,
+        received_time: Instant,
+    ) {
+        if self.tracking_paused {
+            return;
+        }
+
+        let timestamp_us = self
+            .replay_timestamp_us
+            .unwrap_or_else(|| self.clock.timestamp_us(received_time));
+        // The tracker could've been removed while its device is still sending
+        let Some(tracker) = self.trackers.get_mut(index) else {
+            return;
+        };
+
+        // The clients get told after the tracker isn't borrowed anymore
+        let mut info_updated = false;
+        let mut warning = None;
+        let valid = is_plausible_data(acceleration, orientation);
+        if valid && tracker.info.status == TrackerStatus::Unknown {
+            tracing::info!("Got the first data from tracker {index}");
+            tracker.info.status = TrackerStatus::Ok;
+            info_updated = true;
+        }
+
+        let recovery = &self.config.status_recovery;
+        if recovery.enabled && tracker.update_status_from_data(valid, recovery) {
+            let status = tracker.info.status;
+            tracing::info!("Changed tracker {index} to {status:?} based on the data it's sending");
+            info_updated = true;
+        }
+
+        // NaN gets serialized as null which clients won't be expecting
+        let finite = acceleration.0.is_finite() && orientation.0.is_finite();
+        if !finite {
+            tracing::warn!("Discarding non-finite data for tracker {index}");
+        } else if self.config.anomaly.enabled
+            && tracker.check_anomalies(orientation, &self.config.anomaly)
+        {
+            match tracker.info.suspect {
+                Some(reason) => {
+                    warning = Some(format!(
+                        "Tracker {index} might have a broken IMU, its data is {reason:?}"
+                    ));
+                }
+                None => tracing::info!("Tracker {index} is sending normal data again"),
+            }
+            info_updated = true;
+        }
+
+        let gap_us = finite
+            .then(|| {
+                apply_tracker_data(
+                    tracker,
+                    acceleration,
+                    orientation,
+                    timestamp_us,
+                    &self.config,
+                )
+            })
+            .flatten();
+
+        if let Some(warning) = warning {
+            tracing::warn!("{warning}");
+            self.notify_warning(&warning);
+        }
+
+        if info_updated {
+            self.tracker_info_updated(index);
+        }
+
+        if let Some(gap_us) = gap_us {
+            let duration_ms = gap_us / 1000;
+            tracing::info!("Tracker {index} had no data for {duration_ms}ms");
+            self.send_to_clients(ServerMessage::TrackerDataGap { index, duration_ms });
+        }
+    }
+
+    pub fn server_status(&self) -> ServerStatus {
+        ServerStatus {
+            epoch_unix_us: self.clock.start_unix_us(),
+            discovery_mode: self.discovery_mode,
+            blocked_packets: self.blocked_packets,
+            dropped_outgoing_packets: self.dropped_outgoing_packets,
+            skipped_stages: self.tick_budget.skipped,
+            pairing_window_end_us: self.pairing_window_end_us,
+            clock_adjustments: self.wall_clock.adjustments(),
+            tracking_paused: self.tracking_paused,
+            active_profile: self.config.active_profile.clone(),
+            config_load_error: self.config_load_error.clone(),
+        }
+    }
+
+    pub fn conventions(&self) -> Conventions {
+        Conventions {
+            up_axis: Axis::Z,
+            handedness: Handedness::Right,
+            gravity: self.config.gravity.gravity,
+            accel_unit: AccelUnit::MetersPerSecondSquared,
+            euler_order: None,
+        }
+    }
+
+    pub fn server_status_updated(&mut self) {
+        let status = self.server_status();
+        self.message_channels
+            .send_to_all(ServerMessage::ServerStatus { status });
+    }
+
+    pub fn send_to_clients(&self, message: ServerMessage) {
+        self.message_channels.send_to_all(message);
+    }
+
+    pub fn notify_warning(&self, warning: &str) {
+        self.message_channels.send_to_all(ServerMessage::Warning {
+            warning: warning.to_string(),
+        });
+    }
+
+    pub fn notify_error(&self, error: &str) {
+        self.message_channels.send_to_all(ServerMessage::Error {
+            error: error.to_string(),
+            coded: None,
+        });
+    }
+
+    /// Sends the error in English, each websocket client then gets it in its own locale
+    pub fn notify_coded_error(&self, error: CodedMessage) {
+        self.message_channels.send_to_all(ServerMessage::Error {
+            error: error.to_string(),
+            coded: Some(error),
+        });
+    }
+}
+
+/// Maximum number of messages a client can fall behind by before it starts missing messages
+#[cfg(feature = "websocket")]
+pub(crate) const MESSAGE_CHANNEL_CAPACITY: usize = 1024;
+
+/// The new index of each tracker in the order of their old indices, None if the mapping doesn't
+/// have every old index exactly once
+#[cfg(feature = "websocket")]
+fn invert_tracker_mapping(old_indices: &[usize], mapping: &[usize]) -> Option<Vec<usize>> {
+    let mut new_indices = vec![None; old_indices.len()];
+    for (new_index, old_index) in mapping.iter().enumerate() {
+        let position = old_indices.binary_search(old_index).ok()?;
+        if new_indices[position].replace(new_index).is_some() {
+            return None;
+        }
+    }
+
+    new_indices.into_iter().collect()
+}
+
+#[cfg(feature = "parallel")]
+fn build_tick_pool() -> Option<rayon::ThreadPool> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .thread_name(|i| format!("mycap-tick-{i}"))
+        .build();
+    match pool {
+        Ok(pool) => Some(pool),
+        Err(error) => {
+            tracing::error!("Failed to start the tracker threads, filtering on one: {error}");
+            None
+        }
+    }
+}
+
+const TARGET_LOOP_DELTA: Duration = Duration::from_millis(1000 / 50);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of ticks in the seconds at the target loop rate
+pub(crate) fn ticks_in(seconds: f32) -> usize {
+    (seconds / TARGET_LOOP_DELTA.as_secs_f32()).ceil() as usize
+}
+
+/// Stop the server if the UDP socket still can't be rebound after this many attempts
+const MAX_UDP_RESTART_ATTEMPTS: u32 = 8;
+const UDP_RESTART_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_UDP_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+pub async fn start_server(
+    main: Arc<RwLock<MainServer>>,
+    options: ServerOptions,
+    packet_handlers: PacketHandlers,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut last_loop_time = Instant::now();
+    let config = main.read().await.config.clone();
+    let mut sub_servers = SubServers::new(&config, &options, packet_handlers).await?;
+    main.write().await.debug_commands = options.debug_commands;
+    if let Some(seconds) = options.pairing_window_secs {
+        main.write()
+            .await
+            .open_pairing_window(Duration::from_secs(seconds));
+    }
+
+    loop {
+        let delta = last_loop_time.elapsed();
+        last_loop_time = Instant::now();
+        let resumed = tick_servers(&mut *main.write().await, &mut sub_servers, delta).await?;
+
+        if *shutdown_rx.borrow() {
+            break;
+        }
+
+        let post_delta = last_loop_time.elapsed();
+        if let Some(sleep_duration) = TARGET_LOOP_DELTA.checked_sub(post_delta) {
+            tokio::select! {
+                _ = tokio::time::sleep(sleep_duration) => (),
+                // Dropping the handle without shutting down leaves the server running
+                Ok(()) = shutdown_rx.changed() => (),
+            }
+        } else if !resumed {
+            tracing::warn!(
+                "Main server loop took {post_delta:?} which is longer than target {TARGET_LOOP_DELTA:?}"
+            );
+        }
+    }
+
+    tracing::info!("Shutting down");
+    sub_servers.udp.shutdown().await;
+    Ok(())
+}
+
+/// Ticks the main server and sub servers, returning true if the delta was long enough to be the
+/// computer waking up from sleep
+async fn tick_servers(
+    main: &mut MainServer,
+    sub_servers: &mut SubServers,
+    mut delta: Duration,
+) -> anyhow::Result<bool> {
+    // A huge delta would make the integration explode so pretend it was a normal tick
+    let clock_jump_secs = main.config.clock_jump_secs;
+    let resumed = clock_jump_secs != 0 && delta > Duration::from_secs(clock_jump_secs);
+    if resumed {
+        main.resumed(delta);
+        sub_servers.resumed();
+        delta = TARGET_LOOP_DELTA;
+    }
+
+    main.tick(delta);
+    sub_servers.tick(main).await?;
+    #[cfg(feature = "websocket")]
+    main.publish_snapshot();
+    Ok(resumed)
+}
+
+pub struct SubServers {
+    udp: UdpServer,
+    udp_restart: Option<UdpRestart>,
+}
+
+/// Rebinding the UDP socket after it failed
+struct UdpRestart {
+    attempts: u32,
+    next_attempt_time: Instant,
+}
+
+impl SubServers {
+    async fn new(
+        config: &ServerConfig,
+        options: &ServerOptions,
+        packet_handlers: PacketHandlers,
+    ) -> anyhow::Result<Self> {
+        let mut udp = UdpServer::new(&config.discovery, config.expected_devices)
+            .await
+            .context("Failed to start UDP server")?;
+        udp.set_packet_handlers(packet_handlers);
+        udp.load_blocklist(config);
+
+        #[cfg(feature = "recording")]
+        {
+            if let Some(path) = &options.record_raw {
+                udp.record_raw(path)?;
+            }
+
+            if let Some(path) = &options.replay_raw {
+                udp.replay_raw(path, options.replay_speed, options.replay_loop)?;
+            } else if options.replay_speed.is_some() || options.replay_loop {
+                anyhow::bail!("--speed and --loop are only for replaying with --replay-raw");
+            }
+        }
+
+        #[cfg(not(feature = "recording"))]
+        if options.record_raw.is_some() || options.replay_raw.is_some() {
+            anyhow::bail!("This build of mycap doesn't support recording or replaying raw packets");
+        }
+
+        if config.firewall_probe {
+            udp.probe_firewall().await;
+        }
+
+        Ok(Self {
+            udp,
+            udp_restart: None,
+        })
+    }
+
+    fn resumed(&mut self) {
+        self.udp.resumed();
+    }
+
+    async fn tick(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
+        if let Some(restart) = &mut self.udp_restart {
+            if Instant::now() < restart.next_attempt_time {
+                return Ok(());
+            }
+
+            restart.attempts += 1;
+            match self.udp.rebind(&main.config.discovery).await {
+                Ok(()) => {
+                    let attempts = restart.attempts;
+                    self.udp_restart = None;
+                    main.send_to_clients(ServerMessage::UdpSocketRestarted { attempts });
+                }
+                Err(error) if restart.attempts >= MAX_UDP_RESTART_ATTEMPTS => {
+                    return Err(error.context("Failed to restart UDP server"));
+                }
+                Err(error) => {
+                    let backoff = UDP_RESTART_BACKOFF * 2_u32.pow(restart.attempts);
+                    let backoff = backoff.min(MAX_UDP_RESTART_BACKOFF);
+                    tracing::warn!(
+                        "Failed to restart UDP server, trying again in {backoff:?}: {error}"
+                    );
+                    restart.next_attempt_time = Instant::now() + backoff;
+                }
+            }
+
+            return Ok(());
+        }
+
+        // The tracker state is kept in the main server so restarting the socket loses nothing
+        let udp_tick = self
+            .udp
+            .tick(main)
+            .instrument(tracing::debug_span!(target: SPAN_TARGET, "udp_ingest"));
+        let result = (AssertUnwindSafe(udp_tick).catch_unwind().await).unwrap_or_else(|payload| {
+            Err(anyhow::anyhow!("Panicked: {}", panic_reason(&*payload)))
+        });
+        if let Err(error) = result {
+            main.subsystem_failed("udp_server", format!("{error:#}"), Some(Duration::ZERO));
+            self.udp_restart = Some(UdpRestart {
+                attempts: 0,
+                next_attempt_time: Instant::now(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "websocket")]
+fn accel_scale_progress(calibration: &AccelScaleCalibration) -> ServerMessage {
+    ServerMessage::AccelScaleProgress {
+        index: calibration.index,
+        face: calibration.face(),
+        step: calibration.step(),
+        steps: AccelFace::ALL.len(),
+    }
+}
+
+/// Next to the config with .bak added, mycap_config.json becomes mycap_config.json.bak
+fn config_backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Puts new data from the tracker's device into the tracker, returning how long there was no data
+/// for if it was long enough to be a gap
+fn apply_tracker_data(
+    tracker: &mut Tracker,
+    acceleration: AccelMps2,
+    orientation: SensorQuat,
+    timestamp_us: u64,
+    config: &ServerConfig,
+) -> Option<u64> {
+    let index = tracker.info.index;
+    let gap_us = tracker.record_data_interval(timestamp_us, config.data_gap_intervals);
+    tracker.raw_data.orientation = orientation;
+    tracker.raw_data.acceleration = acceleration;
+    tracker.raw_data.timestamp_us = timestamp_us;
+
+    // Acceleration gets smoothed into the data on the next tick
+    let acceleration = tracker.info.config.correct_acceleration(acceleration);
+    let acceleration = if config.gravity.compensate {
+        AccelMps2(acceleration.0 - config.gravity.gravity.0)
+    } else {
+        acceleration
+    };
+    tracker.acceleration_input = if acceleration.length() < tracker.info.config.accel_deadzone {
+        AccelMps2::ZERO
+    } else {
+        acceleration
+    };
+
+    let new_orientation = orientation.to_world(tracker.yaw_offset);
+    if !tracker.update_orientation(new_orientation, timestamp_us, &config.anomaly) {
+        tracing::debug!("Dropped an orientation from tracker {index} that turned too fast");
+    }
+
+    gap_us
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span_recorder::SpanRecorder;
+
+    #[test]
+    fn tick_is_traced_in_stages() {
+        let recorder = SpanRecorder::default();
+        let _guard = recorder.install();
+        let mut main = MainServer::default();
+        main.register_tracker("test".to_string(), TrackerConfig::default());
+
+        main.tick(TARGET_LOOP_DELTA);
+
+        let ticks = recorder.named("tick");
+        assert_eq!(ticks.len(), 1);
+        assert_eq!(ticks[0].parent, None);
+
+        let process = recorder.named("process_trackers");
+        assert_eq!(process.len(), 1);
+        assert_eq!(process[0].parent, Some("tick"));
+        assert_eq!(process[0].fields["tracker_count"], "1");
+
+        let broadcast = recorder.named("broadcast");
+        assert_eq!(broadcast.len(), 1);
+        assert_eq!(broadcast[0].parent, Some("tick"));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn history_taken_before_ticking_keeps_up_with_the_tracker() {
+        let mut main = MainServer::default();
+        main.config.history_secs = 1;
+        let index = main.register_tracker("test".to_string(), TrackerConfig::default());
+        let history = main.tracker_history(index).unwrap();
+        assert!(history.latest(usize::MAX).is_empty());
+
+        for _ in 0..ticks_in(2.) {
+            main.tick(TARGET_LOOP_DELTA);
+        }
+        assert_eq!(history.latest(usize::MAX).len(), ticks_in(1.));
+        assert_eq!(history.latest(3).len(), 3);
+
+        main.config.history_secs = 0;
+        main.tick(TARGET_LOOP_DELTA);
+        assert!(history.latest(usize::MAX).is_empty());
+        assert_eq!(
+            main.tracker_history(index)
+                .err()
+                .unwrap()
+                .downcast_ref::<CodedMessage>()
+                .unwrap()
+                .code,
+            "history_disabled"
+        );
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn ticking_after_a_clock_jump_resets_motion_and_times_out_devices() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let config = crate::config::DiscoveryConfig {
+            udp_port: 0,
+            ..Default::default()
+        };
+        let mut sub_servers = SubServers {
+            udp: UdpServer::new(&config, 1).await.unwrap(),
+            udp_restart: None,
+        };
+        sub_servers.udp.add_test_device(&mut main).await;
+
+        let tracker = main.trackers.get_mut(0).unwrap();
+        assert_eq!(tracker.info.status, TrackerStatus::Ok);
+        tracker.info.config.estimate_position = true;
+        tracker.data.velocity = glam::Vec3A::new(1., 0., 0.);
+        tracker.acceleration_input = AccelMps2(glam::Vec3A::new(0., 2., 0.));
+        let position = tracker.data.position;
+
+        // Asleep for a few minutes so the device stopped sending too
+        let gap = Duration::from_secs(180);
+        sub_servers.udp.age_devices(gap);
+        assert!(tick_servers(&mut main, &mut sub_servers, gap)
+            .await
+            .unwrap());
+
+        let tracker = main.trackers.get(0).unwrap();
+        assert_eq!(tracker.data.velocity, glam::Vec3A::ZERO);
+        assert_eq!(tracker.data.acceleration, AccelMps2::ZERO);
+        // Moved by at most a normal tick's worth instead of integrating the whole gap
+        assert!(tracker.data.position.distance(position) < 0.01);
+        // Timed out on the same tick instead of waiting for the next upkeep
+        assert_eq!(tracker.info.status, TrackerStatus::TimedOut);
+
+        let resumed_gaps = |messages: &mut broadcast::Receiver<ChannelMessage>| {
+            std::iter::from_fn(|| messages.try_recv().ok())
+                .filter_map(|message| match message.message {
+                    ServerMessage::ServerResumed { gap_ms } => Some(gap_ms),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(resumed_gaps(&mut messages), [gap.as_millis() as u64]);
+
+        // Only the tick after the jump counts as resuming
+        assert!(
+            !tick_servers(&mut main, &mut sub_servers, TARGET_LOOP_DELTA)
+                .await
+                .unwrap()
+        );
+        let just_under = Duration::from_secs(main.config.clock_jump_secs);
+        assert!(!tick_servers(&mut main, &mut sub_servers, just_under)
+            .await
+            .unwrap());
+        main.config.clock_jump_secs = 0;
+        assert!(!tick_servers(&mut main, &mut sub_servers, gap)
+            .await
+            .unwrap());
+        assert!(resumed_gaps(&mut messages).is_empty());
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn unknown_trackers_become_ok_on_their_first_valid_data() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let index = main.register_tracker("test".to_string(), TrackerConfig::default());
+        assert_eq!(
+            main.trackers.get(index).unwrap().info.status,
+            TrackerStatus::Unknown
+        );
+
+        let orientation = SensorQuat(glam::Quat::IDENTITY);
+        let invalid = AccelMps2(glam::Vec3A::splat(f32::NAN));
+        main.update_tracker_data(index, invalid, orientation, Instant::now());
+        assert_eq!(
+            main.trackers.get(index).unwrap().info.status,
+            TrackerStatus::Unknown
+        );
+
+        main.update_tracker_data(index, AccelMps2::ZERO, orientation, Instant::now());
+        assert_eq!(
+            main.trackers.get(index).unwrap().info.status,
+            TrackerStatus::Ok
+        );
+        let ok_infos = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter(|message| {
+                matches!(&message.message, ServerMessage::TrackerInfo { info } if info.status == TrackerStatus::Ok)
+            })
+            .count();
+        assert_eq!(ok_infos, 1);
+    }
+
+    #[cfg(all(feature = "websocket", feature = "osc"))]
+    #[test]
+    fn unknown_trackers_are_left_out_of_the_outputs() {
+        use crate::routing::{OutputRoute, RouteDestination, RouteSelector};
+
+        let receiver = || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            (socket.set_read_timeout(Some(Duration::from_millis(100)))).unwrap();
+            socket
+        };
+        let received = |socket: &std::net::UdpSocket| {
+            let mut buffer = [0; 1024];
+            let mut text = String::new();
+            while let Ok(amount) = socket.recv(&mut buffer) {
+                text += &String::from_utf8_lossy(&buffer[..amount]);
+            }
+            text
+        };
+        let (vrchat, route) = (receiver(), receiver());
+
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        main.config.vrchat_osc.enabled = true;
+        main.config.vrchat_osc.target = vrchat.local_addr().unwrap();
+        main.config.routes = vec![OutputRoute {
+            name: "all".to_string(),
+            enabled: true,
+            destination: RouteDestination::Osc {
+                target: route.local_addr().unwrap(),
+                address: "/tracker/{index}".to_string(),
+            },
+            selector: RouteSelector::Indices(vec![0, 1]),
+        }];
+        let unseen = main.register_tracker("unseen".to_string(), TrackerConfig::default());
+        let seen = main.register_tracker("seen".to_string(), TrackerConfig::default());
+        let orientation = SensorQuat(glam::Quat::IDENTITY);
+        main.update_tracker_data(seen, AccelMps2::ZERO, orientation, Instant::now());
+
+        main.tick(TARGET_LOOP_DELTA);
+
+        // A position and a rotation for the one tracker in its slot
+        assert_eq!(received(&vrchat).matches("/tracking/trackers/").count(), 2);
+        let routed = received(&route);
+        assert!(routed.contains(&format!("/tracker/{seen}")));
+        assert!(!routed.contains(&format!("/tracker/{unseen}")));
+
+        let data_indices: Vec<_> = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter_map(|message| match message.message {
+                ServerMessage::TrackerData { index, .. } => Some(index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(data_indices, [seen]);
+    }
+
+    #[cfg(feature = "osc")]
+    #[test]
+    fn outputs_are_traced_by_name() {
+        let recorder = SpanRecorder::default();
+        let _guard = recorder.install();
+        let mut main = MainServer::default();
+        main.config.vrchat_osc.enabled = true;
+
+        main.tick(TARGET_LOOP_DELTA);
+
+        let outputs = recorder.named("output");
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].parent, Some("tick"));
+        assert_eq!(outputs[0].fields["name"], "vrchat_osc");
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn tracker_mapping_must_be_a_permutation() {
+        // Index 1 was removed so it's a hole
+        let old_indices = [0, 2, 3];
+        assert_eq!(
+            invert_tracker_mapping(&old_indices, &[3, 0, 2]),
+            Some(vec![1, 2, 0])
+        );
+        assert_eq!(
+            invert_tracker_mapping(&old_indices, &[0, 2, 3]),
+            Some(vec![0, 1, 2])
+        );
+
+        for mapping in [
+            &[0, 2][..],
+            &[0, 2, 2],
+            &[0, 1, 3],
+            &[0, 2, 3, 4],
+            &[0, 2, 3, 0],
+        ] {
+            assert_eq!(
+                invert_tracker_mapping(&old_indices, mapping),
+                None,
+                "{mapping:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn remapping_moves_everything_that_refers_to_the_indices() {
+        let mut main = MainServer::default();
+        for id in ["a", "b", "c"] {
+            main.register_tracker(id.to_string(), TrackerConfig::default());
+        }
+        main.remove_tracker(1);
+        #[cfg(feature = "osc")]
+        main.config.routes.push(crate::routing::OutputRoute {
+            name: "test".to_string(),
+            enabled: false,
+            destination: crate::routing::RouteDestination::Osc {
+                target: "127.0.0.1:9000".parse().unwrap(),
+                address: "/tracker".to_string(),
+            },
+            selector: crate::routing::RouteSelector::Indices(vec![0, 2]),
+        });
+
+        // Leaves everything alone when the mapping names the hole
+        assert!(main.remap_tracker_indices(vec![1, 0]).is_err());
+        assert_eq!(main.tracker_id_to_index["c"], 2);
+
+        main.remap_tracker_indices(vec![2, 0]).unwrap();
+
+        let ids: Vec<_> = main.trackers.iter().map(|t| t.info.id.as_str()).collect();
+        assert_eq!(ids, ["c", "a"]);
+        assert_eq!(main.trackers.get(0).unwrap().info.index, 0);
+        assert_eq!(main.trackers.get(1).unwrap().info.index, 1);
+        assert!(main.trackers.get(2).is_none());
+
+        assert_eq!(main.tracker_id_to_index["c"], 0);
+        assert_eq!(main.tracker_id_to_index["a"], 1);
+        assert!(!main.tracker_id_to_index.contains_key("b"));
+
+        let config_index = |id: &str| {
+            let entries = &main.config.trackers;
+            entries.iter().find(|entry| entry.id == id).unwrap().index
+        };
+        assert_eq!(config_index("c"), 0);
+        assert_eq!(config_index("a"), 1);
+        // Left for when it comes back, moved past the running ones since its old index is taken
+        assert_eq!(config_index("b"), 2);
+
+        #[cfg(feature = "osc")]
+        assert!(
+            main.config.routes[0].selector == crate::routing::RouteSelector::Indices(vec![1, 0])
+        );
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn remapped_indices_are_kept_after_loading_the_config() {
+        let path = std::env::temp_dir().join(format!("mycap-remap-{}.json", std::process::id()));
+        let mut main = MainServer {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        };
+        for id in ["a", "b", "c", "d"] {
+            main.register_tracker(id.to_string(), TrackerConfig::default());
+        }
+        main.remove_tracker(0);
+        main.remove_tracker(2);
+        main.remap_tracker_indices(vec![3, 1]).unwrap();
+
+        let mut loaded = MainServer {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        };
+        loaded.load_config();
+        std::fs::remove_file(&path).unwrap();
+
+        for (id, index) in [("d", 0), ("b", 1), ("c", 2), ("a", 3)] {
+            assert_eq!(loaded.tracker_id_to_index[id], index, "{id}");
+            assert_eq!(loaded.trackers.get(index).unwrap().info.id, id);
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn invalid_saved_tracker_config_is_reported() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let invalid = TrackerConfig {
+            name: "left foot".to_string(),
+            accel_deadzone: -1.,
+            ..Default::default()
+        };
+        main.config.set_tracker_entry(TrackerConfigEntry {
+            id: "foot".to_string(),
+            index: 0,
+            config: invalid.clone(),
+        });
+
+        let index = main.register_tracker("foot".to_string(), TrackerConfig::default());
+        let config = &main.trackers.get(index).unwrap().info.config;
+        assert_eq!(config.name, "left foot");
+        assert_eq!(config.accel_deadzone, 0.);
+        assert!(main.config.tracker_entry("foot").unwrap().config == invalid);
+
+        let coded = std::iter::from_fn(|| messages.try_recv().ok())
+            .find_map(|message| match message.message {
+                ServerMessage::Error { coded, .. } => coded,
+                _ => None,
+            })
+            .expect("clients should be told");
+        assert_eq!(coded.code, "invalid_tracker_config");
+        assert_eq!(coded.params["id"], "foot");
+        assert_eq!(coded.params["field"], "accel_deadzone");
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn accel_scale_calibration_corrects_the_tracker() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let index = main.register_tracker("hip".to_string(), TrackerConfig::default());
+        let error = main.next_accel_scale_face().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CodedMessage>().unwrap().code,
+            "accel_scale_not_running"
+        );
+
+        main.start_accel_scale_calibration(index).unwrap();
+        let orientation = SensorQuat(glam::Quat::IDENTITY);
+        let mut timestamp_us = 0;
+        for face in AccelFace::ALL {
+            // An accelerometer that reads 10% too high on every axis
+            let up = match face {
+                AccelFace::XUp => glam::Vec3A::X,
+                AccelFace::XDown => -glam::Vec3A::X,
+                AccelFace::YUp => glam::Vec3A::Y,
+                AccelFace::YDown => -glam::Vec3A::Y,
+                AccelFace::ZUp => glam::Vec3A::Z,
+                AccelFace::ZDown => -glam::Vec3A::Z,
+            };
+            let acceleration = AccelMps2(up * crate::gravity::STANDARD_GRAVITY * 1.1);
+            for _ in 0..30 {
+                timestamp_us += 10_000;
+                main.replay_timestamp_us = Some(timestamp_us);
+                main.update_tracker_data(index, acceleration, orientation, Instant::now());
+                main.tick(TARGET_LOOP_DELTA);
+            }
+            main.next_accel_scale_face().unwrap();
+        }
+
+        let config = &main.trackers.get(index).unwrap().info.config;
+        assert!(config
+            .accel_scale
+            .abs_diff_eq(glam::Vec3A::splat(1. / 1.1), 1e-4));
+        assert!(config.accel_bias.0.abs_diff_eq(glam::Vec3A::ZERO, 1e-4));
+        assert!(main.accel_scale_calibration.is_none());
+
+        let messages: Vec<_> = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter_map(|message| match message.message {
+                ServerMessage::AccelScaleProgress { step, .. } => Some(format!("step {step}")),
+                ServerMessage::AccelScaleCalibrated { index, .. } => {
+                    Some(format!("calibrated {index}"))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "step 0",
+                "step 1",
+                "step 2",
+                "step 3",
+                "step 4",
+                "step 5",
+                "calibrated 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn unloadable_config_is_backed_up_before_saving() {
+        let path = std::env::temp_dir().join(format!("mycap-broken-{}.json", std::process::id()));
+        let backup_path = config_backup_path(&path);
+        std::fs::write(&path, "{ not json").unwrap();
+        let mut main = MainServer {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        main.load_config();
+        assert_eq!(std::fs::read_to_string(&backup_path).unwrap(), "{ not json");
+        let error = main.server_status().config_load_error.unwrap();
+        assert_eq!(error.code, "config_load_failed");
+        assert_eq!(error.params["backup"], backup_path.display().to_string());
+
+        // Saving the defaults is fine now that there's a copy
+        main.register_tracker("hip".to_string(), TrackerConfig::default());
+        let saved = ServerConfig::load(&path).unwrap();
+        assert_eq!(saved.trackers.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup_path).unwrap();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn config_is_not_saved_over_without_a_backup() {
+        let path = std::env::temp_dir().join(format!("mycap-unsaved-{}.json", std::process::id()));
+        // Nothing can be copied onto a directory
+        let backup_path = config_backup_path(&path);
+        std::fs::create_dir_all(&backup_path).unwrap();
+        std::fs::write(&path, "{ not json").unwrap();
+        let mut main = MainServer {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        main.load_config();
+        let error = main.server_status().config_load_error.unwrap();
+        assert_eq!(error.code, "config_load_failed_not_saving");
+        main.register_tracker("hip".to_string(), TrackerConfig::default());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ not json");
+
+        // Importing a config is the user choosing to replace it
+        main.import_config(ServerConfig::default()).unwrap();
+        assert!(ServerConfig::load(&path).is_ok());
+        assert!(main.server_status().config_load_error.is_none());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir(&backup_path).unwrap();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn invalid_tracker_in_the_config_file_only_resets_that_tracker() {
+        let path = std::env::temp_dir().join(format!("mycap-tracker-{}.json", std::process::id()));
+        let mut config = ServerConfig {
+            max_devices: 7,
+            ..Default::default()
+        };
+        for (index, (id, accel_deadzone)) in [("hip", 0.5), ("foot", -1.)].into_iter().enumerate() {
+            config.set_tracker_entry(TrackerConfigEntry {
+                id: id.to_string(),
+                index,
+                config: TrackerConfig {
+                    accel_deadzone,
+                    ..Default::default()
+                },
+            });
+        }
+        config.save(&path).unwrap();
+
+        let mut main = MainServer {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut messages = main.new_message_channel();
+        main.load_config();
+
+        assert!(main.config_load_error.is_none());
+        assert_eq!(main.config.max_devices, 7);
+        let deadzone = |index| main.trackers.get(index).unwrap().info.config.accel_deadzone;
+        assert_eq!((deadzone(0), deadzone(1)), (0.5, 0.));
+        let coded = std::iter::from_fn(|| messages.try_recv().ok())
+            .find_map(|message| match message.message {
+                ServerMessage::Error { coded, .. } => coded,
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(coded.code, "invalid_tracker_config");
+        assert_eq!(coded.params["id"], "foot");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// What a client knows about each tracker, its id, status and latest data timestamp
+    #[cfg(feature = "websocket")]
+    type ClientTrackers = BTreeMap<usize, (String, TrackerStatus, u64)>;
+
+    #[cfg(feature = "websocket")]
+    fn apply_to_client(trackers: &mut ClientTrackers, message: ServerMessage) {
+        match message {
+            ServerMessage::TrackerInfo { info } => {
+                let tracker = trackers.entry(info.index).or_default();
+                (tracker.0, tracker.1) = (info.id, info.status);
+            }
+            ServerMessage::TrackerData { index, data, .. } => {
+                trackers.entry(index).or_default().2 = data.timestamp_us;
+            }
+            ServerMessage::TrackerRemoved { index } => {
+                trackers.remove(&index);
+            }
+            _ => {}
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    fn server_trackers(main: &MainServer) -> ClientTrackers {
+        (main.trackers.iter())
+            .map(|tracker| {
+                let info = &tracker.info;
+                let state = (info.id.clone(), info.status, tracker.data.timestamp_us);
+                (info.index, state)
+            })
+            .collect()
+    }
+
+    /// Registers a tracker now and then and sends data for all of them each tick
+    #[cfg(feature = "websocket")]
+    fn stream_tick(main: &mut MainServer, tick: u64) {
+        if tick.is_multiple_of(10) {
+            let index = main.register_tracker(format!("tracker {tick}"), TrackerConfig::default());
+            main.update_tracker_status(index, TrackerStatus::Ok)
+                .unwrap();
+        }
+        if tick == 25 {
+            main.remove_tracker(1);
+        }
+
+        main.replay_timestamp_us = Some(tick * 10_000);
+        let indices: Vec<_> = main
+            .trackers
+            .iter()
+            .map(|tracker| tracker.info.index)
+            .collect();
+        for index in indices {
+            let orientation = SensorQuat(glam::Quat::IDENTITY);
+            main.update_tracker_data(index, AccelMps2::ZERO, orientation, Instant::now());
+        }
+        main.tick(TARGET_LOOP_DELTA);
+    }
+
+    /// Subscribes to the messages along with a snapshot of the state right before the first one, so
+    /// applying the snapshot and then the messages doesn't miss or repeat anything
+    #[cfg(feature = "websocket")]
+    fn subscribe_with_snapshot(
+        main: &MainServer,
+    ) -> (broadcast::Receiver<ChannelMessage>, Snapshot) {
+        let (receiver, next_message_id) = main.message_channels.subscribe();
+        (receiver, main.snapshot(next_message_id))
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn snapshot_and_later_messages_rebuild_the_server_state() {
+        let mut main = MainServer::default();
+        let mut clients = Vec::new();
+        for tick in 1..=50_u64 {
+            if tick.is_multiple_of(7) {
+                clients.push(subscribe_with_snapshot(&main));
+            }
+            stream_tick(&mut main, tick);
+        }
+
+        assert_eq!(clients.len(), 7);
+        for (mut messages, snapshot) in clients {
+            let mut trackers = ClientTrackers::new();
+            for message in snapshot.to_messages() {
+                apply_to_client(&mut trackers, message);
+            }
+            let mut next_id = snapshot.next_message_id;
+            while let Ok(message) = messages.try_recv() {
+                // Nothing from before the snapshot and nothing skipped after it
+                assert_eq!(message.id, next_id);
+                next_id += 1;
+                apply_to_client(&mut trackers, message.message);
+            }
+            assert_eq!(trackers, server_trackers(&main));
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn clients_syncing_while_data_streams_miss_nothing() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        let streaming = tokio::spawn({
+            let main = main.clone();
+            async move {
+                for tick in 1..=50 {
+                    stream_tick(&mut *main.write().await, tick);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let mut clients = Vec::new();
+        while !streaming.is_finished() {
+            clients.push(subscribe_with_snapshot(&*main.read().await));
+            tokio::task::yield_now().await;
+        }
+        streaming.await.unwrap();
+
+        let main = main.read().await;
+        for (mut messages, snapshot) in clients {
+            let mut trackers = ClientTrackers::new();
+            for message in snapshot.to_messages() {
+                apply_to_client(&mut trackers, message);
+            }
+            while let Ok(message) = messages.try_recv() {
+                assert!(message.id >= snapshot.next_message_id);
+                apply_to_client(&mut trackers, message.message);
+            }
+            assert_eq!(trackers, server_trackers(&main));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn filtering_on_the_tick_threads_gives_the_same_data() {
+        use crate::bench::TrackerTick;
+
+        let mut in_loop = TrackerTick::new(32, 0);
+        let mut on_threads = TrackerTick::new(32, 16);
+        for _ in 0..100 {
+            in_loop.tick();
+            on_threads.tick();
+            assert_eq!(in_loop.tracker_data(), on_threads.tracker_data());
+        }
+        assert!(in_loop.main.tick_pool.get().is_none());
+        assert!(on_threads.main.tick_pool.get().unwrap().is_some());
+
+        // Too few trackers to be worth starting the threads
+        let mut below_threshold = TrackerTick::new(8, 16);
+        below_threshold.tick();
+        assert!(below_threshold.main.tick_pool.get().is_none());
+    }
+
+    #[test]
+    fn tracker_indices_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("mycap-restart-{}.json", std::process::id()));
+        let session = |ids: &[&str]| {
+            let mut main = MainServer {
+                config_path: Some(path.clone()),
+                ..Default::default()
+            };
+            main.load_config();
+            ids.iter()
+                .map(|id| main.register_tracker(id.to_string(), TrackerConfig::default()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(session(&["hip", "left foot", "right foot"]), [0, 1, 2]);
+        // The devices connect in a different order the next time
+        assert_eq!(session(&["right foot", "hip", "left foot"]), [2, 0, 1]);
+        assert_eq!(session(&["chest", "left foot"]), [3, 1]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn removed_tracker_indices_are_ignored() {
+        let mut main = MainServer::default();
+        for id in ["hip", "foot"] {
+            main.register_tracker(id.to_string(), TrackerConfig::default());
+        }
+        main.remove_tracker(0);
+
+        // Its device could still be sending
+        let orientation = SensorQuat(glam::Quat::IDENTITY);
+        main.update_tracker_data(0, AccelMps2::ZERO, orientation, Instant::now());
+        main.tracker_info_updated(0);
+        assert!(main.swap_sides(0, 1).is_err());
+        assert!(main.set_floor(0).is_err());
+        assert!(main.trackers.get(0).is_none());
+        assert!(main.trackers.get(1).is_some());
+    }
+
+    #[test]
+    fn pairing_window_only_pairs_until_it_closes() {
+        let mut main = MainServer::default();
+        main.config.allowlist_enabled = true;
+        assert!(!main.pair_device("AA:BB"));
+
+        main.open_pairing_window(Duration::from_secs(60));
+        assert!(main.pair_device("AA:BB"));
+        assert!(main.config.is_device_allowed("AA:BB"));
+
+        // Still open but past when it should've closed before the tick notices
+        main.pairing_window_end_us = Some(main.clock.now_us());
+        assert!(!main.pair_device("CC:DD"));
+        main.tick(TARGET_LOOP_DELTA);
+        assert_eq!(main.pairing_window_end_us, None);
+        assert!(!main.config.is_device_allowed("CC:DD"));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn clients_are_told_about_data_gaps() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let index = main.register_tracker("hip".to_string(), TrackerConfig::default());
+        let orientation = SensorQuat(glam::Quat::IDENTITY);
+        let send = |main: &mut MainServer, timestamp_us| {
+            main.replay_timestamp_us = Some(timestamp_us);
+            main.update_tracker_data(index, AccelMps2::ZERO, orientation, Instant::now());
+        };
+
+        for i in 1..=50 {
+            send(&mut main, i * 5_000);
+        }
+        send(&mut main, 50 * 5_000 + 300_000);
+
+        let gaps: Vec<_> = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter_map(|message| match message.message {
+                ServerMessage::TrackerDataGap { index, duration_ms } => Some((index, duration_ms)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(gaps, [(index, 300)]);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn invalid_profile_is_rolled_back() {
+        use crate::profiles::{ConfigProfile, TrackerOverride};
+
+        let mut main = MainServer::default();
+        for id in ["hip", "foot"] {
+            main.register_tracker(id.to_string(), TrackerConfig::default());
+        }
+        let profile = |name: &str, accel_deadzone| ConfigProfile {
+            name: name.to_string(),
+            trackers: ["hip", "foot"]
+                .map(|id| TrackerOverride {
+                    id: id.to_string(),
+                    accel_deadzone: Some(accel_deadzone),
+                    ..Default::default()
+                })
+                .to_vec(),
+            #[cfg(feature = "osc")]
+            vrchat_osc: None,
+            #[cfg(feature = "osc")]
+            routes: None,
+        };
+        main.config.profiles = vec![profile("valid", 0.2), profile("invalid", -1.)];
+        let mut messages = main.new_message_channel();
+
+        let error = main.apply_profile("invalid").unwrap_err();
+        assert!(error.to_string().contains("accel_deadzone"), "{error}");
+        assert_eq!(main.config.active_profile, None);
+        for tracker in main.trackers.iter() {
+            assert_eq!(tracker.info.config.accel_deadzone, 0.);
+        }
+        assert!(messages.try_recv().is_err());
+
+        main.apply_profile("valid").unwrap();
+        assert_eq!(main.config.active_profile.as_deref(), Some("valid"));
+        for tracker in main.trackers.iter() {
+            assert_eq!(tracker.info.config.accel_deadzone, 0.2);
+        }
+        let updated = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter(|message| matches!(message.message, ServerMessage::TrackerInfo { .. }))
+            .count();
+        assert_eq!(updated, 2);
+
+        // Nothing changes the second time so the clients aren't told again
+        main.apply_profile("valid").unwrap();
+        assert!(messages.try_recv().is_err());
+
+        let error = main.apply_profile("missing").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CodedMessage>().unwrap().code,
+            "profile_not_found"
+        );
     }
 }
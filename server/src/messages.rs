@@ -0,0 +1,475 @@
+use std::collections::BTreeMap;
+
+/// Used when a client hasn't set a locale or its locale doesn't have the message
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Error with a stable code that clients can match on, the text comes from the catalog for the
+/// client's locale with the params filled in
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CodedMessage {
+    pub code: &'static str,
+    pub params: BTreeMap<&'static str, String>,
+}
+
+impl CodedMessage {
+    pub fn new(code: &'static str) -> Self {
+        Self {
+            code,
+            params: BTreeMap::new(),
+        }
+    }
+
+    pub fn param(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.params.insert(name, value.to_string());
+        self
+    }
+
+    /// Text for the locale, falling back to English and then the code if it's not in the catalog
+    pub fn format(&self, locale: &str) -> String {
+        let Some(template) =
+            template(locale, self.code).or_else(|| template(DEFAULT_LOCALE, self.code))
+        else {
+            return self.code.to_string();
+        };
+
+        self.params
+            .iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{name}}}"), value)
+            })
+    }
+}
+
+impl std::fmt::Display for CodedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.format(DEFAULT_LOCALE))
+    }
+}
+
+impl std::error::Error for CodedMessage {}
+
+fn template(locale: &str, code: &str) -> Option<&'static str> {
+    // Only the language matters so en-GB uses the en catalog
+    let language = locale.split(['-', '_']).next().unwrap_or(locale);
+    let catalog = match language {
+        "en" => EN,
+        "es" => ES,
+        _ => return None,
+    };
+
+    catalog
+        .iter()
+        .find(|(entry_code, _)| *entry_code == code)
+        .map(|(_, template)| *template)
+}
+
+const EN: &[(&str, &str)] = &[
+    ("invalid_message", "Invalid message: {details}"),
+    ("invalid_mac", "{mac} is not a valid MAC address"),
+    ("invalid_config", "Invalid config: {details}"),
+    ("invalid_config_value", "{field}: {message}"),
+    (
+        "invalid_tracker_config",
+        "Saved config of tracker {id} is invalid so it's using the default, {field}: {message}",
+    ),
+    (
+        "config_load_failed",
+        "Failed to load the config so the defaults are used, the old one was copied to {backup}: {details}",
+    ),
+    (
+        "config_load_failed_not_saving",
+        "Failed to load the config so the defaults are used and won't be saved until a config is imported: {details}",
+    ),
+    ("invalid_log_level", "Invalid log level {level}"),
+    ("tracker_not_found", "Tracker {index} does not exist"),
+    (
+        "remap_not_permutation",
+        "The new order has to have every current tracker index exactly once",
+    ),
+    (
+        "remap_during_calibration",
+        "Can't change the tracker indices while a calibration is running",
+    ),
+    ("route_not_found", "No route named {name}"),
+    ("profile_not_found", "No profile named {name}"),
+    ("tracker_not_foot", "Tracker {index} is not a foot tracker"),
+    (
+        "side_check_needs_pair",
+        "Checking the sides needs exactly one left and one right {location} tracker",
+    ),
+    (
+        "side_check_needs_side",
+        "Checking the sides needs the side to move",
+    ),
+    (
+        "side_check_out_of_range",
+        "Checking the sides must take between 0 and {max} seconds",
+    ),
+    (
+        "trackers_not_a_pair",
+        "Trackers {a} and {b} are not the left and right tracker of the same location",
+    ),
+    (
+        "position_not_finite",
+        "The tracker's position is not a finite number",
+    ),
+    (
+        "debug_commands_disabled",
+        "Start the server with --debug-commands to use this",
+    ),
+    ("profile_name_empty", "Profile name must not be empty"),
+    (
+        "history_disabled",
+        "Tracker history is turned off, set history_secs in the config",
+    ),
+    (
+        "history_out_of_range",
+        "History must be between 0 and {max} seconds",
+    ),
+    (
+        "duplicate_tracker_in_config",
+        "Tracker {id} is in the config more than once",
+    ),
+    (
+        "position_offset_not_finite",
+        "Position offset must be finite",
+    ),
+    (
+        "pairing_window_out_of_range",
+        "Pairing window must be between 1 and {max} seconds",
+    ),
+    (
+        "latency_test_out_of_range",
+        "Latency test must be between 0 and {max} seconds",
+    ),
+    (
+        "prediction_out_of_range",
+        "Prediction must be at most {max} ms",
+    ),
+    (
+        "gravity_calibration_out_of_range",
+        "Gravity calibration must be between 0 and {max} seconds",
+    ),
+    (
+        "accel_scale_not_running",
+        "No accelerometer scale calibration is running",
+    ),
+    (
+        "accel_scale_not_enough_data",
+        "Not enough data from the tracker on {face} yet, wait a moment and try again",
+    ),
+    (
+        "accel_scale_moving",
+        "The tracker was moving on {face}, keep it still and try again",
+    ),
+    (
+        "accel_scale_wrong_face",
+        "The tracker isn't resting on {face}, or its firmware already removes gravity",
+    ),
+    (
+        "accel_scale_same_side",
+        "{up} and {down} measured gravity the same way, start the calibration again",
+    ),
+    (
+        "calibration_delay_out_of_range",
+        "Calibration delay must be at most {max} seconds",
+    ),
+    ("state_dump_failed", "Failed to write the state to {path}"),
+    ("serial_not_connected", "USB device not found"),
+    (
+        "serial_unsupported",
+        "This build of mycap doesn't support writing to USB serial devices",
+    ),
+    (
+        "wifi_ssid_length",
+        "ssid must be between 1 and 32 bytes long",
+    ),
+    ("wifi_ssid_null", "ssid can't contain null characters"),
+    (
+        "wifi_ssid_newline_legacy",
+        "ssid can't contain newlines with the legacy serial protocol",
+    ),
+    (
+        "wifi_password_length",
+        "password must be empty or between 8 and 63 characters long",
+    ),
+    (
+        "wifi_password_not_ascii",
+        "password can only contain printable ASCII characters",
+    ),
+    ("wifi_bssid_invalid", "bssid is not a valid MAC address"),
+    (
+        "wifi_options_need_json",
+        "hidden, bssid and static_ip require the JSON serial protocol in the config",
+    ),
+    (
+        "static_ip_subnet_invalid",
+        "static_ip.subnet is not a valid subnet mask",
+    ),
+    (
+        "static_ip_gateway_outside_subnet",
+        "static_ip.gateway is not in the same subnet as static_ip.ip",
+    ),
+    (
+        "device_config_key_invalid",
+        "Config key must be printable ascii without spaces",
+    ),
+    (
+        "device_config_key_too_long",
+        "Config key can't be longer than {max} bytes",
+    ),
+    (
+        "device_config_value_too_long",
+        "Config value can't be longer than {max} bytes",
+    ),
+    (
+        "export_needs_target",
+        "Exporting needs a CSV path or a UDP target",
+    ),
+];
+
+const ES: &[(&str, &str)] = &[
+    ("invalid_message", "Mensaje no válido: {details}"),
+    ("invalid_mac", "{mac} no es una dirección MAC válida"),
+    ("invalid_config", "Configuración no válida: {details}"),
+    ("invalid_config_value", "{field}: {message}"),
+    (
+        "invalid_tracker_config",
+        "La configuración guardada del tracker {id} no es válida así que usa la predeterminada, {field}: {message}",
+    ),
+    (
+        "config_load_failed",
+        "No se pudo cargar la configuración así que se usa la predeterminada, la anterior se copió a {backup}: {details}",
+    ),
+    (
+        "config_load_failed_not_saving",
+        "No se pudo cargar la configuración así que se usa la predeterminada y no se guardará hasta importar una configuración: {details}",
+    ),
+    ("invalid_log_level", "Nivel de registro no válido: {level}"),
+    ("tracker_not_found", "El tracker {index} no existe"),
+    (
+        "remap_not_permutation",
+        "El nuevo orden tiene que tener cada índice de tracker actual exactamente una vez",
+    ),
+    (
+        "remap_during_calibration",
+        "No se pueden cambiar los índices de los trackers mientras hay una calibración en curso",
+    ),
+    ("route_not_found", "No hay ninguna ruta llamada {name}"),
+    ("profile_not_found", "No hay ningún perfil llamado {name}"),
+    (
+        "tracker_not_foot",
+        "El tracker {index} no es un tracker de pie",
+    ),
+    (
+        "side_check_needs_pair",
+        "Comprobar los lados necesita exactamente un tracker {location} izquierdo y uno derecho",
+    ),
+    (
+        "side_check_needs_side",
+        "Comprobar los lados necesita el lado que se va a mover",
+    ),
+    (
+        "side_check_out_of_range",
+        "Comprobar los lados debe durar entre 0 y {max} segundos",
+    ),
+    (
+        "trackers_not_a_pair",
+        "Los trackers {a} y {b} no son el tracker izquierdo y derecho de la misma ubicación",
+    ),
+    (
+        "position_not_finite",
+        "La posición del tracker no es un número finito",
+    ),
+    (
+        "debug_commands_disabled",
+        "Inicia el servidor con --debug-commands para usar esto",
+    ),
+    (
+        "profile_name_empty",
+        "El nombre del perfil no puede estar vacío",
+    ),
+    (
+        "history_disabled",
+        "El historial de los trackers está desactivado, configura history_secs",
+    ),
+    (
+        "history_out_of_range",
+        "El historial debe durar entre 0 y {max} segundos",
+    ),
+    (
+        "duplicate_tracker_in_config",
+        "El tracker {id} aparece más de una vez en la configuración",
+    ),
+    (
+        "position_offset_not_finite",
+        "El desplazamiento de la posición debe ser finito",
+    ),
+    (
+        "pairing_window_out_of_range",
+        "La ventana de emparejamiento debe durar entre 1 y {max} segundos",
+    ),
+    (
+        "latency_test_out_of_range",
+        "La prueba de latencia debe durar entre 0 y {max} segundos",
+    ),
+    (
+        "prediction_out_of_range",
+        "La predicción debe ser de como máximo {max} ms",
+    ),
+    (
+        "gravity_calibration_out_of_range",
+        "La calibración de la gravedad debe durar entre 0 y {max} segundos",
+    ),
+    (
+        "accel_scale_not_running",
+        "No hay ninguna calibración de escala del acelerómetro en curso",
+    ),
+    (
+        "accel_scale_not_enough_data",
+        "Todavía no hay suficientes datos del tracker en {face}, espera un momento y vuelve a intentarlo",
+    ),
+    (
+        "accel_scale_moving",
+        "El tracker se movía en {face}, mantenlo quieto y vuelve a intentarlo",
+    ),
+    (
+        "accel_scale_wrong_face",
+        "El tracker no está apoyado en {face}, o su firmware ya elimina la gravedad",
+    ),
+    (
+        "accel_scale_same_side",
+        "{up} y {down} midieron la gravedad en el mismo sentido, vuelve a empezar la calibración",
+    ),
+    (
+        "calibration_delay_out_of_range",
+        "La cuenta atrás de la calibración no puede superar los {max} segundos",
+    ),
+    (
+        "state_dump_failed",
+        "No se pudo escribir el estado en {path}",
+    ),
+    (
+        "serial_not_connected",
+        "No se encontró ningún dispositivo USB",
+    ),
+    (
+        "serial_unsupported",
+        "Esta versión de mycap no puede escribir en dispositivos serie USB",
+    ),
+    ("wifi_ssid_length", "El SSID debe tener entre 1 y 32 bytes"),
+    (
+        "wifi_ssid_null",
+        "El SSID no puede contener caracteres nulos",
+    ),
+    (
+        "wifi_ssid_newline_legacy",
+        "El SSID no puede contener saltos de línea con el protocolo serie antiguo",
+    ),
+    (
+        "wifi_password_length",
+        "La contraseña debe estar vacía o tener entre 8 y 63 caracteres",
+    ),
+    (
+        "wifi_password_not_ascii",
+        "La contraseña solo puede contener caracteres ASCII imprimibles",
+    ),
+    (
+        "wifi_bssid_invalid",
+        "El BSSID no es una dirección MAC válida",
+    ),
+    (
+        "wifi_options_need_json",
+        "hidden, bssid y static_ip necesitan el protocolo serie JSON en la configuración",
+    ),
+    (
+        "static_ip_subnet_invalid",
+        "static_ip.subnet no es una máscara de subred válida",
+    ),
+    (
+        "static_ip_gateway_outside_subnet",
+        "static_ip.gateway no está en la misma subred que static_ip.ip",
+    ),
+    (
+        "device_config_key_invalid",
+        "La clave de configuración debe ser ASCII imprimible sin espacios",
+    ),
+    (
+        "device_config_key_too_long",
+        "La clave de configuración no puede superar los {max} bytes",
+    ),
+    (
+        "device_config_value_too_long",
+        "El valor de configuración no puede superar los {max} bytes",
+    ),
+    (
+        "export_needs_target",
+        "Exportar necesita una ruta CSV o un destino UDP",
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every code passed to CodedMessage::new in the source, other than the made up ones in here
+    fn codes_in_source() -> Vec<String> {
+        let source_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut codes = Vec::new();
+        for entry in std::fs::read_dir(source_dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.ends_with("messages.rs") {
+                continue;
+            }
+
+            let source = std::fs::read_to_string(path).unwrap();
+            let pattern = "CodedMessage::new(\"";
+            for (i, _) in source.match_indices(pattern) {
+                let rest = &source[i + pattern.len()..];
+                let code = &rest[..rest.find('"').unwrap()];
+                if !codes.iter().any(|known| known == code) {
+                    codes.push(code.to_string());
+                }
+            }
+        }
+
+        codes
+    }
+
+    #[test]
+    fn every_code_is_in_the_catalogs() {
+        let codes = codes_in_source();
+        assert!(codes.iter().any(|code| code == "tracker_not_found"));
+        for code in &codes {
+            assert!(template("en", code).is_some(), "{code} is not in EN");
+            assert!(template("es", code).is_some(), "{code} is not in ES");
+        }
+
+        for (code, _) in ES {
+            assert!(template("en", code).is_some(), "{code} is only in ES");
+        }
+    }
+
+    #[test]
+    fn params_are_filled_in_for_the_locale() {
+        let message = CodedMessage::new("tracker_not_found").param("index", 3);
+        assert_eq!(message.format("es"), "El tracker 3 no existe");
+        assert_eq!(message.format("en-GB"), "Tracker 3 does not exist");
+        assert_eq!(message.format("es_MX"), "El tracker 3 no existe");
+        // Falls back to English for locales without a catalog
+        assert_eq!(message.format("fr"), "Tracker 3 does not exist");
+
+        let message = CodedMessage::new("trackers_not_a_pair")
+            .param("a", 1)
+            .param("b", 2);
+        assert_eq!(
+            message.format("es"),
+            "Los trackers 1 y 2 no son el tracker izquierdo y derecho de la misma ubicación"
+        );
+    }
+
+    #[test]
+    fn unknown_codes_format_as_the_code() {
+        assert_eq!(CodedMessage::new("not_a_code").format("es"), "not_a_code");
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+/// Ping ids from this up are used by network tests so they don't clash with the regular pings
+pub const NETWORK_TEST_PING_ID_START: u8 = 128;
+const PROBE_COUNT: usize = 50;
+const PROBE_INTERVAL: Duration = Duration::from_millis(20);
+/// How long to wait for the last probes to come back before counting them as lost
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, serde::Serialize)]
+pub struct NetworkTestResult {
+    pub mac: String,
+    pub sent: usize,
+    pub received: usize,
+    pub loss_percent: f32,
+    pub min_rtt_us: u64,
+    pub avg_rtt_us: u64,
+    pub max_rtt_us: u64,
+    /// Mean difference between the round trip times of consecutive probes
+    pub jitter_us: u64,
+}
+
+/// Sends a burst of pings to a device to measure the packet loss and round trip times
+pub struct NetworkTest {
+    send_times: Vec<Instant>,
+    round_trips: Vec<Option<Duration>>,
+}
+
+impl Default for NetworkTest {
+    fn default() -> Self {
+        Self {
+            send_times: Vec::with_capacity(PROBE_COUNT),
+            round_trips: vec![None; PROBE_COUNT],
+        }
+    }
+}
+
+impl NetworkTest {
+    /// Gets the ping id to send if the next probe is due
+    pub fn next_probe(&mut self) -> Option<u8> {
+        if self.send_times.len() >= PROBE_COUNT {
+            return None;
+        }
+
+        if let Some(last) = self.send_times.last() {
+            if last.elapsed() < PROBE_INTERVAL {
+                return None;
+            }
+        }
+
+        let id = NETWORK_TEST_PING_ID_START + self.send_times.len() as u8;
+        self.send_times.push(Instant::now());
+        Some(id)
+    }
+
+    pub fn handle_pong(&mut self, id: u8) {
+        let probe = id.wrapping_sub(NETWORK_TEST_PING_ID_START) as usize;
+        if let Some(send_time) = self.send_times.get(probe) {
+            self.round_trips[probe].get_or_insert(send_time.elapsed());
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.send_times.len() >= PROBE_COUNT
+            && self
+                .send_times
+                .last()
+                .is_some_and(|last| last.elapsed() > RESPONSE_TIMEOUT)
+    }
+
+    pub fn finish(self, mac: String) -> NetworkTestResult {
+        let round_trips: Vec<u64> = self
+            .round_trips
+            .iter()
+            .flatten()
+            .map(|rtt| rtt.as_micros() as u64)
+            .collect();
+
+        let sent = self.send_times.len();
+        let received = round_trips.len();
+        let mut result = NetworkTestResult {
+            mac,
+            sent,
+            received,
+            loss_percent: (sent - received) as f32 / sent.max(1) as f32 * 100.,
+            min_rtt_us: round_trips.iter().copied().min().unwrap_or(0),
+            avg_rtt_us: round_trips.iter().sum::<u64>() / received.max(1) as u64,
+            max_rtt_us: round_trips.iter().copied().max().unwrap_or(0),
+            jitter_us: 0,
+        };
+
+        if received > 1 {
+            let differences = round_trips
+                .windows(2)
+                .map(|pair| pair[0].abs_diff(pair[1]))
+                .sum::<u64>();
+            result.jitter_us = differences / (received - 1) as u64;
+        }
+
+        result
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::ConfigError,
+    prediction::{OrientationPredictor, MAX_PREDICTION_MS},
+    tracker::{Tracker, TrackerStatus},
+};
+
+/// VRChat only has this many OSC tracker slots, numbered from 1
+pub const VRCHAT_TRACKER_SLOTS: u8 = 8;
+const VRCHAT_OSC_PORT: u16 = 9000;
+/// Seconds between the OSC time tag's epoch of 1900 and the unix epoch
+const OSC_EPOCH_OFFSET_SECS: u64 = 2_208_988_800;
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct VrchatTrackerSlot {
+    pub slot: u8,
+    pub tracker_id: String,
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct VrchatOscConfig {
+    pub enabled: bool,
+    /// Where VRChat is listening for OSC
+    pub target: SocketAddr,
+    /// Which tracker goes in which slot, the trackers fill the slots in index order if empty
+    pub slots: Vec<VrchatTrackerSlot>,
+    /// Send all the trackers in one OSC bundle each time instead of one datagram per message
+    pub bundle: bool,
+    /// How many times a second to send the trackers, separate from the tick rate
+    /// 0 means every tick
+    pub send_rate_hz: u32,
+    /// Rotate the orientations forward by this many milliseconds to make up for VRChat's latency
+    /// 0 means no prediction
+    pub prediction_ms: u32,
+}
+
+impl Default for VrchatOscConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target: SocketAddr::from((Ipv4Addr::LOCALHOST, VRCHAT_OSC_PORT)),
+            slots: Vec::new(),
+            bundle: true,
+            send_rate_hz: 0,
+            prediction_ms: 0,
+        }
+    }
+}
+
+impl VrchatOscConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (i, assignment) in self.slots.iter().enumerate() {
+            if !(1..=VRCHAT_TRACKER_SLOTS).contains(&assignment.slot) {
+                return Err(ConfigError::new(
+                    format!("slots[{i}].slot"),
+                    format!("must be between 1 and {VRCHAT_TRACKER_SLOTS}"),
+                ));
+            }
+
+            if self.slots[..i]
+                .iter()
+                .any(|other| other.slot == assignment.slot)
+            {
+                return Err(ConfigError::new(
+                    format!("slots[{i}].slot"),
+                    format!("slot {} is used more than once", assignment.slot),
+                ));
+            }
+        }
+
+        if self.prediction_ms > MAX_PREDICTION_MS {
+            return Err(ConfigError::new(
+                "prediction_ms",
+                format!("must be at most {MAX_PREDICTION_MS}"),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends OSC messages with float arguments
+pub struct OscSender {
+    socket: UdpSocket,
+}
+
+impl OscSender {
+    pub fn new() -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    pub fn send(&self, address: &str, values: &[f32], target: SocketAddr) {
+        self.send_bytes(&osc_message(address, values), target);
+    }
+
+    /// Sends the messages in one datagram if bundled, otherwise one datagram each
+    pub fn send_bundle(&self, bundle: &OscBundle, bundled: bool, target: SocketAddr) {
+        if bundle.messages.is_empty() {
+            return;
+        }
+
+        if bundled {
+            self.send_bytes(&bundle.to_bytes(), target);
+        } else {
+            for message in &bundle.messages {
+                self.send_bytes(message, target);
+            }
+        }
+    }
+
+    fn send_bytes(&self, bytes: &[u8], target: SocketAddr) {
+        // Dropping a message is better than blocking the tick
+        self.socket.send_to(bytes, target).ok();
+    }
+}
+
+/// OSC messages from the same frame that get sent together
+pub struct OscBundle {
+    timestamp_unix_us: u64,
+    messages: Vec<Vec<u8>>,
+}
+
+impl OscBundle {
+    pub fn new(timestamp_unix_us: u64) -> Self {
+        Self {
+            timestamp_unix_us,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, address: &str, values: &[f32]) {
+        self.messages.push(osc_message(address, values));
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let length = self
+            .messages
+            .iter()
+            .map(|message| message.len() + 4)
+            .sum::<usize>();
+        let mut bytes = Vec::with_capacity(16 + length);
+        write_osc_string(&mut bytes, "#bundle");
+        bytes.extend_from_slice(&osc_time_tag(self.timestamp_unix_us).to_be_bytes());
+        for message in &self.messages {
+            bytes.extend_from_slice(&(message.len() as i32).to_be_bytes());
+            bytes.extend_from_slice(message);
+        }
+
+        bytes
+    }
+}
+
+/// Sends the trackers to VRChat's OSC tracker addresses
+pub struct VrchatOscSender {
+    sender: OscSender,
+    /// Set when the send rate is limited
+    next_send_time: Option<Instant>,
+    predictor: OrientationPredictor,
+}
+
+impl VrchatOscSender {
+    pub fn new() -> anyhow::Result<Self> {
+        let sender = OscSender::new()?;
+        tracing::info!("Started sending trackers to VRChat over OSC");
+        Ok(Self {
+            sender,
+            next_send_time: None,
+            predictor: OrientationPredictor::default(),
+        })
+    }
+
+    /// The last predictions would be for a different tracker after the indices change
+    #[cfg(feature = "websocket")]
+    pub fn clear_predictions(&mut self) {
+        self.predictor = OrientationPredictor::default();
+    }
+
+    /// Sends the trackers in their slots unless it's too soon since the last time
+    pub fn send<'a>(
+        &mut self,
+        slots: impl IntoIterator<Item = (u8, &'a Tracker)>,
+        config: &VrchatOscConfig,
+        timestamp_unix_us: u64,
+    ) {
+        if config.send_rate_hz == 0 {
+            self.next_send_time = None;
+        } else {
+            let now = Instant::now();
+            if self.next_send_time.is_some_and(|time| now < time) {
+                return;
+            }
+
+            // Keep to the rate on average even though the ticks don't line up with it, unless
+            // it's fallen a whole interval behind
+            let interval = Duration::from_secs(1) / config.send_rate_hz;
+            self.next_send_time = Some(match self.next_send_time {
+                Some(time) if now < time + interval => time + interval,
+                _ => now + interval,
+            });
+        }
+
+        let mut bundle = OscBundle::new(timestamp_unix_us);
+        for (slot, tracker) in slots {
+            if tracker.info.status != TrackerStatus::Ok {
+                continue;
+            }
+
+            let orientation = match config.prediction_ms {
+                0 => tracker.data.orientation,
+                ms => self
+                    .predictor
+                    .predict(tracker.info.index, &tracker.data, ms),
+            };
+            let (position, rotation) = to_unity(tracker.data.position, orientation.0);
+            let base = format!("/tracking/trackers/{slot}");
+            bundle.push(&format!("{base}/position"), &position);
+            bundle.push(&format!("{base}/rotation"), &rotation);
+        }
+
+        self.sender
+            .send_bundle(&bundle, config.bundle, config.target);
+    }
+}
+
+/// Converts from mycap's right handed z up space to Unity's left handed y up space with the
+/// rotation as euler angles in degrees applied in Z, X, Y order
+fn to_unity(position: glam::Vec3A, orientation: glam::Quat) -> ([f32; 3], [f32; 3]) {
+    // Swapping y and z changes the handedness which mirrors the rotation
+    let orientation = glam::Quat::from_xyzw(
+        -orientation.x,
+        -orientation.z,
+        -orientation.y,
+        orientation.w,
+    );
+    let (y, x, z) = orientation.to_euler(glam::EulerRot::YXZ);
+    (
+        [position.x, position.z, position.y],
+        [x.to_degrees(), y.to_degrees(), z.to_degrees()],
+    )
+}
+
+/// OSC message with float arguments
+fn osc_message(address: &str, values: &[f32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(address.len() + values.len() * 5 + 8);
+    write_osc_string(&mut bytes, address);
+    write_osc_string(&mut bytes, &format!(",{}", "f".repeat(values.len())));
+    for value in values {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    bytes
+}
+
+/// NTP timestamp with the seconds since 1900 in the top 32 bits and the fraction in the bottom
+fn osc_time_tag(timestamp_unix_us: u64) -> u64 {
+    let seconds = (timestamp_unix_us / 1_000_000 + OSC_EPOCH_OFFSET_SECS) as u32 as u64;
+    let fraction = ((timestamp_unix_us % 1_000_000) << 32) / 1_000_000;
+    (seconds << 32) | fraction
+}
+
+/// OSC strings are null terminated and padded to a multiple of 4 bytes
+fn write_osc_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(string.as_bytes());
+    let padding = 4 - string.len() % 4;
+    bytes.extend(std::iter::repeat_n(0, padding));
+}
@@ -0,0 +1,427 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    path::Path,
+};
+
+use anyhow::Context;
+// Follows tokio's clock so tests can pause it, it's the same as the std one otherwise
+use tokio::time::Instant;
+
+#[cfg(feature = "websocket")]
+use crate::playback::PlaybackAction;
+use crate::playback::{PlaybackState, MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED};
+
+/// Start of every packet log file to make sure the right file is being read
+const PACKET_LOG_MAGIC: &[u8; 8] = b"MCPKTLOG";
+
+/// A udp packet as it was received
+pub struct LoggedPacket {
+    /// Time received in microseconds relative to the server clock
+    pub timestamp_us: u64,
+    pub address: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+/// Appends received packets to a file where each packet is stored as
+/// timestamp (u64) + address length (u8) + address + bytes length (u16) + bytes
+pub struct PacketLogWriter {
+    writer: BufWriter<File>,
+}
+
+impl PacketLogWriter {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create packet log {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(PACKET_LOG_MAGIC)?;
+        tracing::info!("Recording raw packets to {}", path.display());
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, packet: &LoggedPacket) -> std::io::Result<()> {
+        let address = packet.address.to_string();
+        self.writer.write_all(&packet.timestamp_us.to_le_bytes())?;
+        self.writer.write_all(&[address.len() as u8])?;
+        self.writer.write_all(address.as_bytes())?;
+        self.writer
+            .write_all(&(packet.bytes.len() as u16).to_le_bytes())?;
+        self.writer.write_all(&packet.bytes)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct PacketLogReader {
+    reader: BufReader<File>,
+}
+
+impl PacketLogReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open packet log {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0; PACKET_LOG_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != PACKET_LOG_MAGIC {
+            anyhow::bail!("{} is not a packet log", path.display());
+        }
+
+        Ok(Self { reader })
+    }
+
+    /// Where the next packet starts in the file
+    pub fn offset(&mut self) -> std::io::Result<u64> {
+        self.reader.stream_position()
+    }
+
+    pub fn seek(&mut self, offset: u64) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    /// Reads the next packet or None at the end of the file
+    pub fn read(&mut self) -> anyhow::Result<Option<LoggedPacket>> {
+        let mut timestamp = [0; 8];
+        match self.reader.read_exact(&mut timestamp) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut address_length = [0; 1];
+        self.reader.read_exact(&mut address_length)?;
+        let mut address = vec![0; address_length[0] as usize];
+        self.reader.read_exact(&mut address)?;
+        let address = String::from_utf8(address)?.parse()?;
+
+        let mut bytes_length = [0; 2];
+        self.reader.read_exact(&mut bytes_length)?;
+        let mut bytes = vec![0; u16::from_le_bytes(bytes_length) as usize];
+        self.reader.read_exact(&mut bytes)?;
+
+        Ok(Some(LoggedPacket {
+            timestamp_us: u64::from_le_bytes(timestamp),
+            address,
+            bytes,
+        }))
+    }
+}
+
+/// Recorded time between the seek points in the replay index
+const SEEK_INDEX_INTERVAL_US: u64 = 1_000_000;
+
+/// Reads packets from a log at the rate they were recorded scaled by the playback speed
+pub struct PacketReplay {
+    reader: PacketLogReader,
+    /// Timestamp and file offset of a packet every so often to seek without reading from the start
+    seek_index: Vec<(u64, u64)>,
+    state: PlaybackState,
+    last_advance_time: Instant,
+    next: Option<LoggedPacket>,
+    /// Set when the replay jumps to a different point so the packet numbers can be reset
+    jumped: bool,
+}
+
+impl PacketReplay {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut reader = PacketLogReader::open(path)?;
+        let mut seek_index: Vec<(u64, u64)> = Vec::new();
+        let mut end_us = 0;
+        loop {
+            let offset = reader.offset()?;
+            let Some(packet) = reader.read()? else {
+                break;
+            };
+
+            if seek_index.last().is_none_or(|(timestamp_us, _)| {
+                packet.timestamp_us >= timestamp_us + SEEK_INDEX_INTERVAL_US
+            }) {
+                seek_index.push((packet.timestamp_us, offset));
+            }
+            end_us = end_us.max(packet.timestamp_us);
+        }
+
+        let start_us = seek_index
+            .first()
+            .map_or(0, |(timestamp_us, _)| *timestamp_us);
+        tracing::info!("Replaying raw packets from {}", path.display());
+
+        let mut replay = Self {
+            reader,
+            seek_index,
+            state: PlaybackState {
+                start_us,
+                end_us,
+                position_us: start_us,
+                speed: 1.,
+                paused: false,
+                loop_range: None,
+            },
+            last_advance_time: Instant::now(),
+            next: None,
+            jumped: false,
+        };
+        replay.seek(start_us)?;
+        replay.jumped = false;
+        Ok(replay)
+    }
+
+    /// Moves the position forward by the time since the last advance
+    pub fn advance(&mut self) {
+        let elapsed = self.last_advance_time.elapsed();
+        self.last_advance_time = Instant::now();
+        if self.state.paused {
+            return;
+        }
+
+        self.state.position_us += (elapsed.as_micros() as f64 * self.state.speed as f64) as u64;
+        if self.next.is_none() && self.state.loop_range.is_none() {
+            tracing::info!("Finished replaying raw packets");
+            self.state.position_us = self.state.end_us;
+            self.state.paused = true;
+        }
+    }
+
+    /// Gets the next packet if the position has passed it, wrapping around the loop if set
+    pub fn next_due(&mut self) -> anyhow::Result<Option<LoggedPacket>> {
+        loop {
+            let position_us = self.state.position_us;
+            let loop_range = self.state.loop_range;
+            let due = self.next.as_ref().is_some_and(|packet| {
+                packet.timestamp_us <= position_us
+                    && loop_range.is_none_or(|(_, end_us)| packet.timestamp_us < end_us)
+            });
+            if due {
+                let next = self.reader.read()?;
+                return Ok(std::mem::replace(&mut self.next, next));
+            }
+
+            match loop_range {
+                Some((start_us, end_us)) if position_us >= end_us => {
+                    // Carry over how far past the end it went so the timing stays the same, the
+                    // packets it went past at the start of the loop are still due
+                    let overshoot_us = (position_us - end_us) % (end_us - start_us);
+                    self.seek(start_us)?;
+                    self.state.position_us += overshoot_us;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns true if the replay jumped since last checked
+    pub fn take_jumped(&mut self) -> bool {
+        std::mem::take(&mut self.jumped)
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn apply(&mut self, action: PlaybackAction) -> anyhow::Result<()> {
+        self.advance();
+        match action {
+            PlaybackAction::Play => {
+                if self.next.is_none() && self.state.loop_range.is_none() {
+                    self.seek(self.state.start_us)?;
+                }
+                self.state.paused = false;
+            }
+            PlaybackAction::Pause => self.state.paused = true,
+            PlaybackAction::Seek { timestamp_us } => self.seek(timestamp_us)?,
+            PlaybackAction::SetSpeed { speed } => self.set_speed(speed)?,
+            PlaybackAction::SetLoop { start_us, end_us } => {
+                if start_us >= end_us
+                    || start_us < self.state.start_us
+                    || end_us > self.state.end_us
+                {
+                    anyhow::bail!("Loop must be a range inside the recording");
+                }
+
+                self.state.loop_range = Some((start_us, end_us));
+                if !(start_us..end_us).contains(&self.state.position_us) {
+                    self.seek(start_us)?;
+                }
+            }
+            PlaybackAction::ClearLoop => self.state.loop_range = None,
+        }
+
+        Ok(())
+    }
+
+    /// Replays the packets this many times faster than they were recorded
+    pub fn set_speed(&mut self, speed: f32) -> anyhow::Result<()> {
+        if !(MIN_PLAYBACK_SPEED..=MAX_PLAYBACK_SPEED).contains(&speed) {
+            anyhow::bail!(
+                "Playback speed must be between {MIN_PLAYBACK_SPEED} and {MAX_PLAYBACK_SPEED}"
+            );
+        }
+
+        self.state.speed = speed;
+        Ok(())
+    }
+
+    /// Keeps replaying the whole recording, starting again right after the last packet
+    pub fn loop_recording(&mut self) {
+        // The end of a loop is left out so it has to be past the last packet
+        self.state.loop_range = Some((self.state.start_us, self.state.end_us + 1));
+    }
+
+    /// Moves to the timestamp so the next packet is the first one recorded at or after it
+    fn seek(&mut self, timestamp_us: u64) -> anyhow::Result<()> {
+        let timestamp_us = timestamp_us.clamp(self.state.start_us, self.state.end_us);
+        let entry = self
+            .seek_index
+            .partition_point(|(entry_us, _)| *entry_us <= timestamp_us)
+            .saturating_sub(1);
+
+        if let Some((_, offset)) = self.seek_index.get(entry) {
+            self.reader.seek(*offset)?;
+            self.next = self.reader.read()?;
+            while self
+                .next
+                .as_ref()
+                .is_some_and(|packet| packet.timestamp_us < timestamp_us)
+            {
+                self.next = self.reader.read()?;
+            }
+        }
+
+        self.state.position_us = timestamp_us;
+        self.jumped = true;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+
+    /// Time between the packets in the test recordings
+    const INTERVAL_US: u64 = 10_000;
+
+    /// Records 3 seconds of packets where each packet's bytes are its number
+    fn replay(name: &str) -> PacketReplay {
+        let path =
+            std::env::temp_dir().join(format!("mycap-replay-{name}-{}.log", std::process::id()));
+        let mut writer = PacketLogWriter::create(&path).unwrap();
+        for number in 0..300_u16 {
+            let packet = LoggedPacket {
+                timestamp_us: 5_000_000 + number as u64 * INTERVAL_US,
+                address: "127.0.0.1:5828".parse().unwrap(),
+                bytes: number.to_le_bytes().to_vec(),
+            };
+            writer.write(&packet).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let replay = PacketReplay::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        replay
+    }
+
+    fn number(packet: &LoggedPacket) -> u16 {
+        u16::from_le_bytes([packet.bytes[0], packet.bytes[1]])
+    }
+
+    /// Moves the position forward without waiting on the clock, returning the packets that became
+    /// due
+    fn step(replay: &mut PacketReplay, amount_us: u64) -> Vec<u16> {
+        replay.state.position_us += amount_us;
+        let mut numbers = Vec::new();
+        while let Some(packet) = replay.next_due().unwrap() {
+            numbers.push(number(&packet));
+        }
+        numbers
+    }
+
+    #[test]
+    fn seek_lands_on_the_first_packet_at_or_after_the_timestamp() {
+        let mut replay = replay("seek");
+        assert_eq!(replay.state().start_us, 5_000_000);
+        assert_eq!(replay.state().end_us, 5_000_000 + 299 * INTERVAL_US);
+
+        // Past the first seek point and between two packets
+        replay
+            .apply(PlaybackAction::Seek {
+                timestamp_us: 6_504_000,
+            })
+            .unwrap();
+        assert!(replay.take_jumped());
+        assert_eq!(replay.state().position_us, 6_504_000);
+        assert_eq!(step(&mut replay, 6_000), [151]);
+
+        // Backwards onto a packet exactly
+        replay
+            .apply(PlaybackAction::Seek {
+                timestamp_us: 5_200_000,
+            })
+            .unwrap();
+        assert_eq!(step(&mut replay, 0), [20]);
+
+        // Outside the recording is clamped to it
+        replay
+            .apply(PlaybackAction::Seek { timestamp_us: 0 })
+            .unwrap();
+        assert_eq!(replay.state().position_us, 5_000_000);
+        assert_eq!(step(&mut replay, 0), [0]);
+    }
+
+    #[test]
+    fn loop_wraps_back_to_its_start() {
+        let mut replay = replay("loop");
+        replay
+            .apply(PlaybackAction::SetLoop {
+                start_us: 5_100_000,
+                end_us: 5_150_000,
+            })
+            .unwrap();
+        assert_eq!(replay.state().position_us, 5_100_000);
+        assert_eq!(step(&mut replay, 0), [10]);
+        replay.take_jumped();
+
+        assert_eq!(step(&mut replay, 40_000), [11, 12, 13, 14]);
+        assert!(!replay.take_jumped());
+
+        // Going past the end carries the overshoot over to the start of the loop
+        assert_eq!(step(&mut replay, 20_000), [10, 11]);
+        assert!(replay.take_jumped());
+        assert_eq!(replay.state().position_us, 5_110_000);
+
+        replay.apply(PlaybackAction::ClearLoop).unwrap();
+        assert_eq!(step(&mut replay, 50_000), [12, 13, 14, 15, 16]);
+    }
+
+    #[test]
+    fn changing_speed_doesnt_drop_packets() {
+        let mut replay = replay("speed");
+        let mut numbers = step(&mut replay, 0);
+        for speed in [0.25, 4., 16., 1.] {
+            replay.apply(PlaybackAction::SetSpeed { speed }).unwrap();
+            assert_eq!(replay.state().speed, speed);
+            for _ in 0..10 {
+                numbers.extend(step(&mut replay, (7_000. * speed) as u64));
+            }
+        }
+
+        let expected = (0..numbers.len() as u16).collect::<Vec<_>>();
+        assert_eq!(numbers, expected);
+        assert!(!replay.take_jumped());
+    }
+
+    #[test]
+    fn rejects_speeds_out_of_range() {
+        let mut replay = replay("speed-range");
+        for speed in [0., MAX_PLAYBACK_SPEED * 2.] {
+            assert!(replay.apply(PlaybackAction::SetSpeed { speed }).is_err());
+        }
+        assert_eq!(replay.state().speed, 1.);
+    }
+}
@@ -0,0 +1,34 @@
+/// Slowest and fastest a recording can be replayed at
+pub const MIN_PLAYBACK_SPEED: f32 = 0.05;
+pub const MAX_PLAYBACK_SPEED: f32 = 16.;
+
+/// Controls for replaying a recording, the timestamps are the recorded ones
+#[cfg(feature = "websocket")]
+#[derive(Clone, Debug, serde::Deserialize)]
+pub enum PlaybackAction {
+    Play,
+    Pause,
+    Seek {
+        timestamp_us: u64,
+    },
+    SetSpeed {
+        speed: f32,
+    },
+    /// Keep replaying the packets between start and end
+    SetLoop {
+        start_us: u64,
+        end_us: u64,
+    },
+    ClearLoop,
+}
+
+/// Where the replay is in the recording so clients can show a scrub bar
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+pub struct PlaybackState {
+    pub start_us: u64,
+    pub end_us: u64,
+    pub position_us: u64,
+    pub speed: f32,
+    pub paused: bool,
+    pub loop_range: Option<(u64, u64)>,
+}
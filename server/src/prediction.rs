@@ -0,0 +1,153 @@
+#[cfg(any(feature = "websocket", feature = "osc"))]
+use crate::tracker::TrackerData;
+use crate::units::WorldQuat;
+
+/// Longest prediction allowed since the error grows quickly past a few frames
+#[cfg(any(feature = "websocket", feature = "osc"))]
+pub const MAX_PREDICTION_MS: u32 = 100;
+/// Fraction of the way to move to the new prediction each update after overshooting
+#[cfg(any(feature = "websocket", feature = "osc"))]
+const OVERSHOOT_BLEND: f32 = 0.3;
+/// Corrections bigger than this in radians are snapped to straight away since blending them would
+/// lag behind for too long
+#[cfg(any(feature = "websocket", feature = "osc"))]
+const MAX_BLEND_ANGLE: f32 = 0.5;
+
+/// Shortest rotation from one orientation to the other as an axis scaled by the angle in radians
+pub fn rotation_between(from: WorldQuat, to: WorldQuat) -> glam::Vec3A {
+    let rotation = to.0 * from.0.inverse();
+    // Both q and -q are the same orientation, the one with a positive w is the shorter way round
+    let rotation = if rotation.w < 0. { -rotation } else { rotation };
+    rotation.to_scaled_axis().into()
+}
+
+/// The orientation rotated forward by the angular velocity for the time
+#[cfg(any(feature = "websocket", feature = "osc"))]
+pub fn predict_orientation(data: &TrackerData, prediction_ms: u32) -> WorldQuat {
+    let seconds = prediction_ms.min(MAX_PREDICTION_MS) as f32 / 1000.;
+    let rotation = glam::Quat::from_scaled_axis((data.angular_velocity * seconds).into());
+    WorldQuat((rotation * data.orientation.0).normalize())
+}
+
+#[cfg(any(feature = "websocket", feature = "osc"))]
+#[derive(Clone, Copy)]
+struct Prediction {
+    orientation: WorldQuat,
+    angular_velocity: glam::Vec3A,
+}
+
+/// Predicts the orientations for one consumer, blending back when the real data shows the last
+/// prediction went too far instead of snapping back
+#[cfg(any(feature = "websocket", feature = "osc"))]
+#[derive(Default)]
+pub struct OrientationPredictor {
+    /// Last output for each tracker index
+    last: Vec<Option<Prediction>>,
+}
+
+#[cfg(any(feature = "websocket", feature = "osc"))]
+impl OrientationPredictor {
+    pub fn predict(&mut self, index: usize, data: &TrackerData, prediction_ms: u32) -> WorldQuat {
+        let target = predict_orientation(data, prediction_ms);
+        if index >= self.last.len() {
+            self.last.resize(index + 1, None);
+        }
+
+        let orientation = match self.last[index] {
+            Some(last) => {
+                let correction = rotation_between(last.orientation, target);
+                // Rotating back against the way it was predicted to go means it overshot
+                let overshot = correction.dot(last.angular_velocity) < 0.;
+                if overshot && correction.length() < MAX_BLEND_ANGLE {
+                    WorldQuat(
+                        last.orientation
+                            .0
+                            .slerp(target.0, OVERSHOOT_BLEND)
+                            .normalize(),
+                    )
+                } else {
+                    target
+                }
+            }
+            None => target,
+        };
+
+        self.last[index] = Some(Prediction {
+            orientation,
+            angular_velocity: data.angular_velocity,
+        });
+        orientation
+    }
+}
+
+#[cfg(all(test, any(feature = "websocket", feature = "osc")))]
+mod tests {
+    use super::*;
+
+    /// Turning around z at 90 degrees per second
+    fn turning(yaw_degrees: f32) -> TrackerData {
+        TrackerData {
+            orientation: WorldQuat(glam::Quat::from_rotation_z(yaw_degrees.to_radians())),
+            angular_velocity: glam::Vec3A::Z * 90_f32.to_radians(),
+            ..Default::default()
+        }
+    }
+
+    fn yaw_degrees(orientation: WorldQuat) -> f32 {
+        crate::fusion::yaw(orientation.0).to_degrees()
+    }
+
+    #[test]
+    fn orientation_is_extrapolated_up_to_the_cap() {
+        let data = turning(10.);
+        assert!((yaw_degrees(predict_orientation(&data, 0)) - 10.).abs() < 1e-3);
+        assert!((yaw_degrees(predict_orientation(&data, 50)) - 14.5).abs() < 1e-3);
+
+        // Anything past the cap is the same as the cap
+        let capped = predict_orientation(&data, MAX_PREDICTION_MS * 5);
+        assert!((yaw_degrees(capped) - 19.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rotation_between_takes_the_short_way() {
+        let from = WorldQuat(glam::Quat::from_rotation_z(170_f32.to_radians()));
+        let to = WorldQuat(glam::Quat::from_rotation_z(-170_f32.to_radians()));
+        let rotation = rotation_between(from, to);
+        assert!((rotation.z.to_degrees() - 20.).abs() < 1e-3, "{rotation}");
+    }
+
+    #[test]
+    fn overshoot_is_blended_back() {
+        let mut predictor = OrientationPredictor::default();
+        let predicted = predictor.predict(0, &turning(10.), 100);
+        assert!((yaw_degrees(predicted) - 19.).abs() < 1e-3);
+
+        // It stopped so the last prediction went too far
+        let stopped = TrackerData {
+            angular_velocity: glam::Vec3A::ZERO,
+            ..turning(12.)
+        };
+        let blended = yaw_degrees(predictor.predict(0, &stopped, 100));
+        assert!(
+            (blended - (19. - 7. * OVERSHOOT_BLEND)).abs() < 1e-2,
+            "{blended}"
+        );
+
+        // Other trackers have their own predictions
+        let other = yaw_degrees(predictor.predict(3, &stopped, 100));
+        assert!((other - 12.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn big_corrections_snap() {
+        let mut predictor = OrientationPredictor::default();
+        predictor.predict(0, &turning(10.), 100);
+
+        let flipped = TrackerData {
+            angular_velocity: glam::Vec3A::ZERO,
+            ..turning(-90.)
+        };
+        let snapped = yaw_degrees(predictor.predict(0, &flipped, 100));
+        assert!((snapped + 90.).abs() < 1e-3, "{snapped}");
+    }
+}
@@ -0,0 +1,183 @@
+#[cfg(feature = "websocket")]
+use crate::config::ServerConfig;
+use crate::{config::ConfigError, tracker::PositionFilter};
+#[cfg(feature = "osc")]
+use crate::{osc::VrchatOscConfig, routing::OutputRoute};
+
+/// Settings of one tracker that a profile changes, the ones left empty keep their current value
+#[derive(Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TrackerOverride {
+    pub id: String,
+    pub estimate_position: Option<bool>,
+    pub position_filter: Option<PositionFilter>,
+    pub accel_smoothing_secs: Option<f32>,
+    pub accel_deadzone: Option<f32>,
+}
+
+/// Named set of overrides that can be switched to in one go, the parts left empty keep their
+/// current value
+#[derive(Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ConfigProfile {
+    pub name: String,
+    pub trackers: Vec<TrackerOverride>,
+    #[cfg(feature = "osc")]
+    pub vrchat_osc: Option<VrchatOscConfig>,
+    #[cfg(feature = "osc")]
+    pub routes: Option<Vec<OutputRoute>>,
+}
+
+impl ConfigProfile {
+    /// Takes the current filter settings of every tracker, the VRChat OSC config and the routes
+    #[cfg(feature = "websocket")]
+    pub fn capture(name: String, config: &ServerConfig) -> Self {
+        Self {
+            name,
+            trackers: (config.trackers.iter())
+                .map(|entry| TrackerOverride {
+                    id: entry.id.clone(),
+                    estimate_position: Some(entry.config.estimate_position),
+                    position_filter: Some(entry.config.position_filter.clone()),
+                    accel_smoothing_secs: Some(entry.config.accel_smoothing_secs),
+                    accel_deadzone: Some(entry.config.accel_deadzone),
+                })
+                .collect(),
+            #[cfg(feature = "osc")]
+            vrchat_osc: Some(config.vrchat_osc.clone()),
+            #[cfg(feature = "osc")]
+            routes: Some(config.routes.clone()),
+        }
+    }
+
+    /// The config with the overrides applied, which still needs to be validated
+    #[cfg(feature = "websocket")]
+    pub fn apply_to(&self, config: &ServerConfig) -> ServerConfig {
+        let mut config = config.clone();
+        for tracker in &self.trackers {
+            // Trackers that have never connected don't have an entry to override
+            let Some(entry) = (config.trackers.iter_mut()).find(|entry| entry.id == tracker.id)
+            else {
+                continue;
+            };
+
+            if let Some(estimate_position) = tracker.estimate_position {
+                entry.config.estimate_position = estimate_position;
+            }
+            if let Some(position_filter) = &tracker.position_filter {
+                entry.config.position_filter = position_filter.clone();
+            }
+            if let Some(accel_smoothing_secs) = tracker.accel_smoothing_secs {
+                entry.config.accel_smoothing_secs = accel_smoothing_secs;
+            }
+            if let Some(accel_deadzone) = tracker.accel_deadzone {
+                entry.config.accel_deadzone = accel_deadzone;
+            }
+        }
+
+        #[cfg(feature = "osc")]
+        {
+            if let Some(vrchat_osc) = &self.vrchat_osc {
+                config.vrchat_osc = vrchat_osc.clone();
+            }
+            if let Some(routes) = &self.routes {
+                config.routes = routes.clone();
+            }
+        }
+
+        config.active_profile = Some(self.name.clone());
+        config
+    }
+}
+
+pub fn validate_profiles(profiles: &[ConfigProfile]) -> Result<(), ConfigError> {
+    for (i, profile) in profiles.iter().enumerate() {
+        if profile.name.is_empty() {
+            return Err(ConfigError::new(
+                format!("profiles[{i}].name"),
+                "must not be empty",
+            ));
+        }
+
+        if profiles[..i].iter().any(|other| other.name == profile.name) {
+            return Err(ConfigError::new(
+                format!("profiles[{i}].name"),
+                format!("{} is used more than once", profile.name),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_names_are_unique() {
+        let profile = |name: &str| ConfigProfile {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        assert!(validate_profiles(&[profile("walking"), profile("sitting")]).is_ok());
+
+        let error = validate_profiles(&[profile("walking"), profile("walking")]).unwrap_err();
+        assert_eq!(error.field, "profiles[1].name");
+        let error = validate_profiles(&[profile("")]).unwrap_err();
+        assert_eq!(error.field, "profiles[0].name");
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn only_the_overridden_settings_change() {
+        use crate::{config::TrackerConfigEntry, tracker::TrackerConfig};
+
+        let mut config = ServerConfig::default();
+        for id in ["hip", "foot"] {
+            config.set_tracker_entry(TrackerConfigEntry {
+                id: id.to_string(),
+                index: config.trackers.len(),
+                config: TrackerConfig {
+                    accel_deadzone: 0.1,
+                    ..Default::default()
+                },
+            });
+        }
+
+        let profile = ConfigProfile {
+            name: "still".to_string(),
+            trackers: vec![
+                TrackerOverride {
+                    id: "hip".to_string(),
+                    estimate_position: Some(false),
+                    ..Default::default()
+                },
+                // Hasn't connected yet so there's nothing to override
+                TrackerOverride {
+                    id: "hand".to_string(),
+                    accel_deadzone: Some(0.5),
+                    ..Default::default()
+                },
+            ],
+            #[cfg(feature = "osc")]
+            vrchat_osc: None,
+            #[cfg(feature = "osc")]
+            routes: None,
+        };
+
+        let applied = profile.apply_to(&config);
+        assert_eq!(applied.active_profile.as_deref(), Some("still"));
+        assert_eq!(applied.trackers.len(), 2);
+        let hip = &applied.tracker_entry("hip").unwrap().config;
+        assert!(!hip.estimate_position);
+        assert_eq!(hip.accel_deadzone, 0.1);
+        let foot = &applied.tracker_entry("foot").unwrap().config;
+        assert!(*foot == config.tracker_entry("foot").unwrap().config);
+
+        // Capturing it gets back what was applied
+        let captured = ConfigProfile::capture("still".to_string(), &applied);
+        assert_eq!(captured.trackers[0].estimate_position, Some(false));
+        assert_eq!(captured.trackers[0].accel_deadzone, Some(0.1));
+    }
+}
@@ -0,0 +1,467 @@
+//! Canonical encodings of every udp packet so the server and the firmware can check they agree on
+//! the bytes. The fixtures in protocol_vectors/ are written from these with
+//! `cargo run --example protocol_vectors` and the firmware vendors the same files for its tests
+
+use std::path::Path;
+
+use crate::udp_packet::*;
+
+/// Where the fixtures are checked in
+pub const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/protocol_vectors");
+/// Lists each fixture with what it decodes to
+const INDEX_FILE: &str = "index.txt";
+
+/// Every packet from a device after the handshake starts with its packet number
+const PACKET_NUMBER: [u8; 4] = 1u32.to_le_bytes();
+const MAC: [u8; 6] = [0x0a, 0x1b, 0x02, 0xc3, 0xd4, 0xe5];
+
+pub struct ProtocolVector {
+    /// Name of the fixture file without the extension
+    pub name: &'static str,
+    /// What the packet decodes to, written to the index for the firmware's tests
+    pub decoded: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+fn vector(name: &'static str, decoded: &'static str, parts: &[&[u8]]) -> ProtocolVector {
+    ProtocolVector {
+        name,
+        decoded,
+        bytes: parts.concat(),
+    }
+}
+
+fn f32s(values: &[f32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+/// The packets devices send are laid out by hand since the server only parses them, the rest come
+/// from the server's serializers so changing those changes the fixtures
+pub fn vectors() -> Vec<ProtocolVector> {
+    vec![
+        vector(
+            "device_handshake",
+            "mac a:1b:2:c3:d4:e5, no variant",
+            &[&[PACKET_HANDSHAKE], b"MCDEV", &MAC],
+        ),
+        vector(
+            "device_handshake_variant",
+            "mac a:1b:2:c3:d4:e5, variant V2",
+            &[&[PACKET_HANDSHAKE], b"MCDEV-V2\0", &MAC],
+        ),
+        vector(
+            "device_pong",
+            "id 7, device time 0x0102030405060708 us",
+            &[&[PACKET_PING_PONG, 7], &0x0102030405060708u64.to_le_bytes()],
+        ),
+        vector(
+            "device_tracker_status",
+            "packet 1, tracker 1 is error",
+            &[&[PACKET_TRACKER_STATUS], &PACKET_NUMBER, &[1, 1]],
+        ),
+        vector(
+            "device_tracker_data",
+            "packet 1, tracker 0 orientation (0, 0, 0, 1) acceleration (0.5, -2, 0), \
+             tracker 2 orientation (0.5, 0.5, 0.5, 0.5) acceleration (0, 0, 1)",
+            &[
+                &[PACKET_TRACKER_DATA],
+                &PACKET_NUMBER,
+                &[0],
+                &f32s(&[0., 0., 0., 1., 0.5, -2., 0.]),
+                &[2],
+                &f32s(&[0.5, 0.5, 0.5, 0.5, 0., 0., 1.]),
+                &[0xff],
+            ],
+        ),
+        vector(
+            "device_config",
+            "packet 1, rate = 100, wifi_ssid = home",
+            &[
+                &[PACKET_GET_CONFIG],
+                &PACKET_NUMBER,
+                &[2, 4],
+                b"rate",
+                &[3],
+                b"100",
+                &[9],
+                b"wifi_ssid",
+                &[4],
+                b"home",
+            ],
+        ),
+        vector(
+            "device_error",
+            "packet 1, flash full, detail nvs 0",
+            &[&[PACKET_DEVICE_ERROR], &PACKET_NUMBER, &[0x04, 5], b"nvs 0"],
+        ),
+        vector(
+            "device_input_event",
+            "packet 1, input 3 is an axis at -0.5",
+            &[
+                &[PACKET_INPUT_EVENT],
+                &PACKET_NUMBER,
+                &[3, 1],
+                &f32s(&[-0.5]),
+            ],
+        ),
+        vector(
+            "device_raw_sensor_data",
+            "packet 1, tracker 1 accel (1, 0, 0.5) mag (-2, -2, 1), tracker 3 gyro (0.5, 1, 0)",
+            &[
+                &[PACKET_RAW_SENSOR_DATA],
+                &PACKET_NUMBER,
+                &[1, RawSensorSample::ACCEL | RawSensorSample::MAG],
+                &f32s(&[1., 0., 0.5, -2., -2., 1.]),
+                &[3, RawSensorSample::GYRO],
+                &f32s(&[0.5, 1., 0.]),
+                &[0xff],
+            ],
+        ),
+        vector(
+            "device_battery_level",
+            "packet 1, 87.5 percent",
+            &[&[PACKET_BATTERY_LEVEL], &PACKET_NUMBER, &f32s(&[87.5])],
+        ),
+        vector(
+            "server_ping",
+            "id 7, server time 0x0102030405060708 us",
+            &[&UdpPacketPingPong::to_bytes(7, 0x0102030405060708)],
+        ),
+        vector(
+            "server_handshake",
+            "handshake response",
+            &[&UdpPacketHandshake::to_bytes()],
+        ),
+        vector(
+            "server_tracker_status",
+            "tracker 1 is off",
+            &[&UdpPacketTrackerStatus {
+                tracker_index: 1,
+                tracker_status: crate::tracker::TrackerStatus::Off,
+            }
+            .to_bytes()],
+        ),
+        vector(
+            "server_announce",
+            "server announce",
+            &[&UdpPacketServerAnnounce::to_bytes()],
+        ),
+        vector(
+            "server_get_config",
+            "get config",
+            &[&UdpPacketDeviceConfig::request_bytes()],
+        ),
+        vector(
+            "server_set_config_kv",
+            "set wifi_ssid = home",
+            &[&UdpPacketSetConfigKv {
+                key: "wifi_ssid",
+                value: "home",
+            }
+            .to_bytes()],
+        ),
+        vector(
+            "server_handshake_request",
+            "handshake request",
+            &[&UdpPacketHandshakeRequest::to_bytes()],
+        ),
+        vector(
+            "server_full",
+            "server full",
+            &[&UdpPacketServerFull::to_bytes()],
+        ),
+        vector(
+            "server_shutdown",
+            "server shutdown",
+            &[&UdpPacketServerShutdown::to_bytes()],
+        ),
+        vector(
+            "server_request_status",
+            "request status",
+            &[&UdpPacketRequestStatus::to_bytes()],
+        ),
+        vector(
+            "client_server_probe",
+            "server probe",
+            &[&UdpPacketServerProbe::to_bytes()],
+        ),
+        vector(
+            "server_info",
+            "instance 0x0102030405060708, websocket port 8298, udp port 5828, tls, version 1.2.0",
+            &[&UdpPacketServerInfo {
+                instance_id: 0x0102030405060708,
+                websocket_port: 8298,
+                udp_port: 5828,
+                tls: true,
+                version: "1.2.0".to_string(),
+            }
+            .to_bytes()],
+        ),
+    ]
+}
+
+/// The contents of each fixture file by its name
+pub fn fixture_files() -> Vec<(String, Vec<u8>)> {
+    let vectors = vectors();
+    let index = vectors
+        .iter()
+        .map(|vector| format!("{}.bin: {}\n", vector.name, vector.decoded))
+        .collect::<String>();
+
+    vectors
+        .into_iter()
+        .map(|vector| (format!("{}.bin", vector.name), vector.bytes))
+        .chain([(INDEX_FILE.to_string(), index.into_bytes())])
+        .collect()
+}
+
+/// Writes the current encodings over the fixtures in the directory, fixtures of removed packets
+/// have to be deleted by hand
+pub fn write_fixtures(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (name, contents) in fixture_files() {
+        std::fs::write(dir.join(name), contents)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PacketOrderPolicy, device_error::DeviceErrorCode, input::InputKind,
+        tracker::TrackerStatus, udp_server::UdpDevice, warning_aggregator::WarningAggregator,
+    };
+
+    fn bytes(name: &str) -> Vec<u8> {
+        vectors()
+            .into_iter()
+            .find(|vector| vector.name == name)
+            .unwrap_or_else(|| panic!("no vector named {name}"))
+            .bytes
+    }
+
+    /// Parses a vector as if it came from a device that has handshaked
+    fn parse<R>(name: &str, check: impl FnOnce(UdpPacket) -> R) -> R {
+        let bytes = bytes(name);
+        let mut device = UdpDevice::new("10.0.0.2:5828".parse().unwrap(), "test".to_string());
+        let mut bytes = bytes.iter();
+        let packet = UdpPacket::parse(
+            &mut bytes,
+            Some(&mut device),
+            PacketOrderPolicy::StrictDrop,
+            &mut WarningAggregator::default(),
+        )
+        .unwrap_or_else(|| panic!("{name} should parse"));
+        check(packet)
+    }
+
+    #[test]
+    fn checked_in_fixtures_match_the_encodings() {
+        let dir = Path::new(FIXTURE_DIR);
+        let files = fixture_files();
+        for (name, contents) in &files {
+            let fixture = std::fs::read(dir.join(name))
+                .unwrap_or_else(|error| panic!("can't read fixture {name}: {error}"));
+            assert!(
+                fixture == *contents,
+                "{name} no longer matches its fixture, if the protocol change is intended run \
+                 `cargo run --example protocol_vectors` and update the firmware's copy"
+            );
+        }
+
+        let checked_in = std::fs::read_dir(dir).unwrap().count();
+        assert_eq!(checked_in, files.len(), "fixtures without a vector");
+    }
+
+    #[test]
+    fn every_packet_type_has_a_vector() {
+        let types = vectors()
+            .iter()
+            .map(|vector| vector.bytes[0])
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(types, (PACKET_PING_PONG..=PACKET_REQUEST_STATUS).collect());
+    }
+
+    #[test]
+    fn device_vectors_decode() {
+        for name in ["device_handshake", "device_handshake_variant"] {
+            let UdpPacket::Handshake(handshake) = UdpPacket::parse(
+                &mut bytes(name).iter(),
+                None,
+                PacketOrderPolicy::StrictDrop,
+                &mut WarningAggregator::default(),
+            )
+            .unwrap() else {
+                panic!("{name} should parse as a handshake");
+            };
+            assert_eq!(handshake.mac_string, "a:1b:2:c3:d4:e5");
+            let variant = (name == "device_handshake_variant").then_some("V2");
+            assert_eq!(handshake.variant.as_deref(), variant);
+        }
+
+        parse("device_pong", |packet| {
+            let UdpPacket::PingPong((pong, _)) = packet else {
+                panic!("should parse as a ping pong");
+            };
+            assert_eq!(pong.id, 7);
+            assert_eq!(pong.device_time_us, Some(0x0102030405060708));
+        });
+
+        parse("device_tracker_status", |packet| {
+            let UdpPacket::TrackerStatus((status, device)) = packet else {
+                panic!("should parse as a tracker status");
+            };
+            assert_eq!(device.packet_numbers.latest(), 1);
+            assert_eq!(status.tracker_index, 1);
+            assert_eq!(status.tracker_status, TrackerStatus::Error);
+        });
+
+        let data = parse("device_tracker_data", |packet| {
+            let UdpPacket::TrackerData((mut packet, _)) = packet else {
+                panic!("should parse as tracker data");
+            };
+            std::iter::from_fn(|| packet.next()).collect::<Vec<_>>()
+        });
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].tracker_index, 0);
+        assert_eq!(data[0].orientation.0, glam::Quat::IDENTITY);
+        assert_eq!(data[0].acceleration.0, glam::Vec3A::new(0.5, -2., 0.));
+        assert_eq!(data[1].tracker_index, 2);
+        assert_eq!(
+            data[1].orientation.0,
+            glam::Quat::from_xyzw(0.5, 0.5, 0.5, 0.5)
+        );
+        assert_eq!(data[1].acceleration.0, glam::Vec3A::Z);
+
+        parse("device_config", |packet| {
+            let UdpPacket::DeviceConfig((config, _)) = packet else {
+                panic!("should parse as a device config");
+            };
+            let entries = config.entries.into_iter().collect::<Vec<_>>();
+            assert_eq!(
+                entries,
+                [
+                    ("rate".to_string(), "100".to_string()),
+                    ("wifi_ssid".to_string(), "home".to_string())
+                ]
+            );
+        });
+
+        parse("device_error", |packet| {
+            let UdpPacket::DeviceError((error, _)) = packet else {
+                panic!("should parse as a device error");
+            };
+            assert_eq!(error.code, DeviceErrorCode::FlashFull);
+            assert_eq!(error.detail.as_deref(), Some("nvs 0"));
+        });
+
+        parse("device_input_event", |packet| {
+            let UdpPacket::InputEvent((event, _)) = packet else {
+                panic!("should parse as an input event");
+            };
+            assert_eq!(event.input, 3);
+            assert_eq!(event.kind, InputKind::Axis);
+            assert_eq!(event.value, -0.5);
+        });
+
+        parse("device_raw_sensor_data", |packet| {
+            let UdpPacket::RawSensorData((data, _)) = packet else {
+                panic!("should parse as raw sensor data");
+            };
+            let readings = data
+                .samples
+                .iter()
+                .map(|sample| (sample.tracker_index, sample.readings()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                readings,
+                [
+                    (
+                        1,
+                        [
+                            (RawSensorSample::ACCEL, Some(glam::Vec3::new(1., 0., 0.5))),
+                            (RawSensorSample::GYRO, None),
+                            (RawSensorSample::MAG, Some(glam::Vec3::new(-2., -2., 1.))),
+                        ]
+                    ),
+                    (
+                        3,
+                        [
+                            (RawSensorSample::ACCEL, None),
+                            (RawSensorSample::GYRO, Some(glam::Vec3::new(0.5, 1., 0.))),
+                            (RawSensorSample::MAG, None),
+                        ]
+                    ),
+                ]
+            );
+        });
+
+        parse("device_battery_level", |packet| {
+            let UdpPacket::BatteryLevel((battery, _)) = packet else {
+                panic!("should parse as a battery level");
+            };
+            assert_eq!(battery.percent, 87.5);
+        });
+    }
+
+    #[test]
+    fn server_vectors_are_byte_exact() {
+        let mut ping = vec![PACKET_PING_PONG, 7];
+        ping.extend_from_slice(&0x0102030405060708u64.to_le_bytes());
+        assert_eq!(bytes("server_ping"), ping);
+
+        for (name, packet_type) in [
+            ("server_handshake", PACKET_HANDSHAKE),
+            ("server_announce", PACKET_SERVER_ANNOUNCE),
+            ("server_handshake_request", PACKET_HANDSHAKE_REQUEST),
+            ("server_full", PACKET_SERVER_FULL),
+            ("server_shutdown", PACKET_SERVER_SHUTDOWN),
+            ("server_request_status", PACKET_REQUEST_STATUS),
+        ] {
+            assert_eq!(
+                bytes(name),
+                [&[packet_type][..], b"MCSVR"].concat(),
+                "{name}"
+            );
+        }
+
+        assert_eq!(
+            bytes("server_tracker_status"),
+            [PACKET_TRACKER_STATUS, 1, 2]
+        );
+        assert_eq!(bytes("server_get_config"), [PACKET_GET_CONFIG]);
+        assert_eq!(
+            bytes("server_set_config_kv"),
+            [&[PACKET_SET_CONFIG_KV, 9][..], b"wifi_ssid", &[4], b"home"].concat()
+        );
+
+        let probe = bytes("client_server_probe");
+        assert_eq!(probe, [&[PACKET_SERVER_PROBE][..], b"MCCLI"].concat());
+        assert!(UdpPacketServerProbe::is_probe(&probe));
+
+        let info = bytes("server_info");
+        assert_eq!(
+            info,
+            [
+                &[PACKET_SERVER_INFO][..],
+                b"MCSVR",
+                &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+                &[0x6a, 0x20],
+                &[0xc4, 0x16],
+                &[1, 5],
+                b"1.2.0",
+            ]
+            .concat()
+        );
+        let info = UdpPacketServerInfo::from_bytes(&info).unwrap();
+        assert_eq!(info.instance_id, 0x0102030405060708);
+        assert_eq!((info.websocket_port, info.udp_port), (8298, 5828));
+        assert!(info.tls);
+        assert_eq!(info.version, "1.2.0");
+    }
+}
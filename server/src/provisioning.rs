@@ -0,0 +1,448 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    serial::{SerialProtocol, WifiCredentials},
+    udp_packet::{format_mac, parse_mac},
+};
+
+/// The device might have just been reset by opening the port so give it time to boot
+const MAC_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAC_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const WIFI_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const PORT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+pub enum ProvisioningState {
+    /// Sent the credentials and waiting for the device to connect to the wifi
+    Connecting,
+    Provisioned,
+    /// Was provisioned earlier while provisioning was on
+    AlreadyProvisioned,
+    /// Didn't reply with its MAC so it's probably not running mycap
+    NotMycap,
+    /// Didn't connect to the wifi in time
+    TimedOut,
+    /// Couldn't talk to the device over serial
+    Failed,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ProvisioningProgress {
+    pub port: String,
+    pub mac: Option<String>,
+    pub state: ProvisioningState,
+}
+
+/// Serial connection to a device, separate from the serial port so the sequencing doesn't need one
+pub trait SerialLink {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// The next line from the device without the line ending, None if there wasn't a whole line
+    /// before the deadline
+    fn read_line(&mut self, deadline: Instant) -> anyhow::Result<Option<String>>;
+}
+
+/// Sends the same wifi credentials to every device that gets plugged in
+pub struct ProvisioningQueue {
+    credentials: WifiCredentials,
+    protocol: SerialProtocol,
+    /// Devices provisioned since starting so plugging one in again doesn't provision it again
+    provisioned: HashSet<String>,
+    mac_reply_timeout: Duration,
+    wifi_connect_timeout: Duration,
+}
+
+impl ProvisioningQueue {
+    pub fn new(credentials: WifiCredentials, protocol: SerialProtocol) -> anyhow::Result<Self> {
+        // Fail straight away instead of on the first device
+        wipe(credentials.to_command(protocol)?);
+        Ok(Self {
+            credentials,
+            protocol,
+            provisioned: HashSet::new(),
+            mac_reply_timeout: MAC_REPLY_TIMEOUT,
+            wifi_connect_timeout: WIFI_CONNECT_TIMEOUT,
+        })
+    }
+
+    /// Sends the credentials to the device if it's a mycap device that hasn't been provisioned yet,
+    /// calling progress every time its state changes
+    pub fn provision(
+        &mut self,
+        port: &str,
+        link: &mut impl SerialLink,
+        mut progress: impl FnMut(ProvisioningProgress),
+    ) -> anyhow::Result<()> {
+        let mut report = |mac: Option<&String>, state| {
+            progress(ProvisioningProgress {
+                port: port.to_string(),
+                mac: mac.cloned(),
+                state,
+            })
+        };
+
+        let Some(mac) = self.query_mac(link)? else {
+            report(None, ProvisioningState::NotMycap);
+            return Ok(());
+        };
+
+        if self.provisioned.contains(&mac) {
+            report(Some(&mac), ProvisioningState::AlreadyProvisioned);
+            return Ok(());
+        }
+
+        let command = self.credentials.to_command(self.protocol)?;
+        let result = link.write_all(&command);
+        wipe(command);
+        result?;
+        report(Some(&mac), ProvisioningState::Connecting);
+
+        let deadline = Instant::now() + self.wifi_connect_timeout;
+        if wait_for_line(link, deadline, |line| {
+            (line == "WifiConnected").then_some(())
+        })?
+        .is_some()
+        {
+            tracing::info!("Provisioned {mac} on {port}");
+            self.provisioned.insert(mac.clone());
+            report(Some(&mac), ProvisioningState::Provisioned);
+        } else {
+            tracing::warn!("{mac} on {port} didn't connect to the wifi in time");
+            report(Some(&mac), ProvisioningState::TimedOut);
+        }
+
+        Ok(())
+    }
+
+    /// Asks for the MAC until the device replies, None if it never does
+    fn query_mac(&self, link: &mut impl SerialLink) -> anyhow::Result<Option<String>> {
+        let command: &[u8] = match self.protocol {
+            SerialProtocol::Legacy => b"Mac\n",
+            SerialProtocol::Json => b"{\"command\":\"Mac\"}\n",
+        };
+
+        let end_time = Instant::now() + self.mac_reply_timeout;
+        while Instant::now() < end_time {
+            link.write_all(command)?;
+            let deadline = end_time.min(Instant::now() + MAC_QUERY_INTERVAL);
+            let mac = wait_for_line(link, deadline, |line| {
+                line.strip_prefix("Mac ").and_then(parse_mac)
+            })?;
+            if let Some(mac) = mac {
+                return Ok(Some(format_mac(mac)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Drop for ProvisioningQueue {
+    fn drop(&mut self) {
+        wipe(std::mem::take(&mut self.credentials.password).into_bytes());
+    }
+}
+
+/// Skips the device's log lines until one that the parse function accepts
+fn wait_for_line<T>(
+    link: &mut impl SerialLink,
+    deadline: Instant,
+    parse: impl Fn(&str) -> Option<T>,
+) -> anyhow::Result<Option<T>> {
+    while let Some(line) = link.read_line(deadline)? {
+        if let Some(value) = parse(line.trim()) {
+            return Ok(Some(value));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Overwrites memory that held the password before it's freed
+fn wipe(mut bytes: Vec<u8>) {
+    bytes.fill(0);
+    std::hint::black_box(&bytes);
+}
+
+/// Provisioning running on its own thread, dropping it stops the thread which wipes the credentials
+pub struct Provisioning {
+    stop: Arc<AtomicBool>,
+    progress_rx: mpsc::UnboundedReceiver<ProvisioningProgress>,
+}
+
+impl Provisioning {
+    pub fn start(mut queue: ProvisioningQueue) -> anyhow::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        let thread_stop = stop.clone();
+        std::thread::spawn(move || {
+            let mut seen_ports = HashSet::new();
+            while !thread_stop.load(Ordering::Relaxed) {
+                match serialport::available_ports() {
+                    Ok(ports) => {
+                        let ports = ports
+                            .into_iter()
+                            .filter(|port| {
+                                matches!(port.port_type, serialport::SerialPortType::UsbPort(_))
+                            })
+                            .map(|port| port.port_name)
+                            .collect::<HashSet<_>>();
+
+                        // Unplugged ports count as new again when plugged back in
+                        seen_ports.retain(|port| ports.contains(port));
+                        for port in ports {
+                            if seen_ports.insert(port.clone()) {
+                                provision_port(&mut queue, &port, &progress_tx);
+                            }
+                        }
+                    }
+                    Err(error) => tracing::warn!("Failed to list serial ports: {error}"),
+                }
+
+                std::thread::sleep(PORT_POLL_INTERVAL);
+            }
+        });
+
+        Ok(Self { stop, progress_rx })
+    }
+
+    pub fn take_progress(&mut self) -> Vec<ProvisioningProgress> {
+        let mut progress = Vec::new();
+        while let Ok(update) = self.progress_rx.try_recv() {
+            progress.push(update);
+        }
+
+        progress
+    }
+}
+
+impl Drop for Provisioning {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn provision_port(
+    queue: &mut ProvisioningQueue,
+    port: &str,
+    progress_tx: &mpsc::UnboundedSender<ProvisioningProgress>,
+) {
+    let result = serialport::new(port, 9600)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .map_err(anyhow::Error::from)
+        .and_then(|port_handle| {
+            let mut link = PortLink {
+                port: port_handle,
+                buffer: Vec::new(),
+            };
+            queue.provision(port, &mut link, |progress| {
+                progress_tx.send(progress).ok();
+            })
+        });
+
+    if let Err(error) = result {
+        tracing::warn!("Failed to provision the device on {port}: {error}");
+        progress_tx
+            .send(ProvisioningProgress {
+                port: port.to_string(),
+                mac: None,
+                state: ProvisioningState::Failed,
+            })
+            .ok();
+    }
+}
+
+struct PortLink {
+    port: Box<dyn serialport::SerialPort>,
+    /// Bytes read after the last whole line
+    buffer: Vec<u8>,
+}
+
+impl SerialLink for PortLink {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.port.write_all(data)?;
+        Ok(())
+    }
+
+    fn read_line(&mut self, deadline: Instant) -> anyhow::Result<Option<String>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                return Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()));
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            let mut chunk = [0; 256];
+            match self.port.read(&mut chunk) {
+                Ok(length) => self.buffer.extend_from_slice(&chunk[..length]),
+                Err(error) if error.kind() == std::io::ErrorKind::TimedOut => (),
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replies to the MAC query with the lines in mac_reply and to the wifi command with the lines
+    /// in wifi_reply
+    struct MockLink {
+        mac_reply: Vec<&'static str>,
+        wifi_reply: Vec<&'static str>,
+        written: Vec<String>,
+        lines: Vec<&'static str>,
+    }
+
+    impl MockLink {
+        fn new(mac_reply: Vec<&'static str>, wifi_reply: Vec<&'static str>) -> Self {
+            Self {
+                mac_reply,
+                wifi_reply,
+                written: Vec::new(),
+                lines: Vec::new(),
+            }
+        }
+    }
+
+    impl SerialLink for MockLink {
+        fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            let command = String::from_utf8_lossy(data).to_string();
+            if command.starts_with("Mac") {
+                self.lines = std::mem::take(&mut self.mac_reply);
+            } else if command.starts_with("Wifi") {
+                self.lines = std::mem::take(&mut self.wifi_reply);
+            }
+
+            self.written.push(command);
+            Ok(())
+        }
+
+        fn read_line(&mut self, deadline: Instant) -> anyhow::Result<Option<String>> {
+            if self.lines.is_empty() {
+                std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                return Ok(None);
+            }
+
+            Ok(Some(self.lines.remove(0).to_string()))
+        }
+    }
+
+    fn queue() -> ProvisioningQueue {
+        let credentials = WifiCredentials {
+            ssid: "home".to_string(),
+            password: "password123".to_string(),
+            hidden: false,
+            bssid: None,
+            static_ip: None,
+        };
+        let mut queue = ProvisioningQueue::new(credentials, SerialProtocol::Legacy).unwrap();
+        queue.mac_reply_timeout = Duration::from_millis(50);
+        queue.wifi_connect_timeout = Duration::from_millis(50);
+        queue
+    }
+
+    fn provision(queue: &mut ProvisioningQueue, link: &mut MockLink) -> Vec<ProvisioningProgress> {
+        let mut progress = Vec::new();
+        queue
+            .provision("/dev/ttyUSB0", link, |update| progress.push(update))
+            .unwrap();
+        progress
+    }
+
+    fn states(progress: &[ProvisioningProgress]) -> Vec<ProvisioningState> {
+        progress.iter().map(|progress| progress.state).collect()
+    }
+
+    #[test]
+    fn provisions_a_mycap_device() {
+        let mut queue = queue();
+        let mut link = MockLink::new(
+            vec!["Booting", "Mac a:b:c:d:e:f"],
+            vec!["Connecting to home", "WifiConnected"],
+        );
+
+        let progress = provision(&mut queue, &mut link);
+        assert_eq!(
+            states(&progress),
+            [
+                ProvisioningState::Connecting,
+                ProvisioningState::Provisioned
+            ]
+        );
+        assert_eq!(progress[1].mac.as_deref(), Some("a:b:c:d:e:f"));
+        assert_eq!(link.written, ["Mac\n", "Wifi\0home\0password123\n"]);
+    }
+
+    #[test]
+    fn skips_devices_provisioned_before() {
+        let mut queue = queue();
+        let mut link = MockLink::new(vec!["Mac a:b:c:d:e:f"], vec!["WifiConnected"]);
+        provision(&mut queue, &mut link);
+
+        // The same device plugged in again with the MAC written differently
+        let mut link = MockLink::new(vec!["Mac 0A:0B:0C:0D:0E:0F"], vec!["WifiConnected"]);
+        let progress = provision(&mut queue, &mut link);
+        assert_eq!(states(&progress), [ProvisioningState::AlreadyProvisioned]);
+        assert_eq!(link.written, ["Mac\n"]);
+    }
+
+    #[test]
+    fn reports_devices_not_running_mycap() {
+        let mut queue = queue();
+        let mut link = MockLink::new(vec!["some other firmware"], Vec::new());
+
+        let progress = provision(&mut queue, &mut link);
+        assert_eq!(states(&progress), [ProvisioningState::NotMycap]);
+        assert_eq!(progress[0].mac, None);
+        assert!(link.written.iter().all(|command| command == "Mac\n"));
+    }
+
+    #[test]
+    fn times_out_when_the_wifi_never_connects() {
+        let mut queue = queue();
+        let mut link = MockLink::new(vec!["Mac a:b:c:d:e:f"], vec!["WifiFailed"]);
+
+        let progress = provision(&mut queue, &mut link);
+        assert_eq!(
+            states(&progress),
+            [ProvisioningState::Connecting, ProvisioningState::TimedOut]
+        );
+
+        // Wasn't recorded as provisioned so it gets another go
+        let mut link = MockLink::new(vec!["Mac a:b:c:d:e:f"], vec!["WifiConnected"]);
+        let progress = provision(&mut queue, &mut link);
+        assert_eq!(
+            states(&progress),
+            [
+                ProvisioningState::Connecting,
+                ProvisioningState::Provisioned
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_credentials_straight_away() {
+        let credentials = WifiCredentials {
+            ssid: String::new(),
+            password: "password123".to_string(),
+            hidden: false,
+            bssid: None,
+            static_ip: None,
+        };
+        assert!(ProvisioningQueue::new(credentials, SerialProtocol::Legacy).is_err());
+    }
+}
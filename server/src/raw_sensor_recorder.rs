@@ -0,0 +1,151 @@
+use std::{fmt::Write as _, path::PathBuf, sync::mpsc};
+
+use crate::{exporter::spawn_csv_writer, udp_packet::RawSensorSample};
+
+/// Records the sensor readings from before the device's fusion so the firmware can be tuned
+/// offline, separate from the orientation recording
+pub struct RawSensorRecorder {
+    path: PathBuf,
+    /// Started once the first sample shows which sensors the devices have
+    csv_tx: Option<mpsc::Sender<String>>,
+    /// Sensors in the columns of the file
+    sensors: u8,
+    warned_missing_columns: bool,
+}
+
+impl RawSensorRecorder {
+    #[cfg(feature = "websocket")]
+    pub fn new(path: PathBuf) -> Self {
+        tracing::info!("Recording raw sensor data to {}", path.display());
+        Self {
+            path,
+            csv_tx: None,
+            sensors: 0,
+            warned_missing_columns: false,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        timestamp_us: u64,
+        tracker_id: &str,
+        sample: &RawSensorSample,
+    ) -> anyhow::Result<()> {
+        let csv_tx = match &self.csv_tx {
+            Some(csv_tx) => csv_tx,
+            None => {
+                self.sensors = sample.sensors();
+                let csv_tx = spawn_csv_writer(&self.path, csv_header(self.sensors))?;
+                self.csv_tx.insert(csv_tx)
+            }
+        };
+
+        if sample.sensors() & !self.sensors != 0 && !self.warned_missing_columns {
+            tracing::warn!(
+                "Tracker {tracker_id} has sensors that aren't in the raw sensor recording's columns, start a new recording to include them"
+            );
+            self.warned_missing_columns = true;
+        }
+
+        let mut row = format!("{timestamp_us},{tracker_id}");
+        for (flag, reading) in sample.readings() {
+            if self.sensors & flag == 0 {
+                continue;
+            }
+
+            match reading {
+                Some(reading) => write!(row, ",{},{},{}", reading.x, reading.y, reading.z)?,
+                // Leave the columns empty for sensors this tracker doesn't have
+                None => row.push_str(",,,"),
+            }
+        }
+
+        csv_tx.send(row).ok();
+        Ok(())
+    }
+}
+
+fn csv_header(sensors: u8) -> String {
+    let mut header = String::from("timestamp_us,tracker");
+    for (flag, columns) in RawSensorSample::COLUMNS {
+        if sensors & flag != 0 {
+            header.push(',');
+            header.push_str(columns);
+        }
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn sample(accel: bool, gyro: bool, mag: bool) -> RawSensorSample {
+        let reading = |present: bool, value| present.then_some(glam::Vec3::splat(value));
+        RawSensorSample {
+            tracker_index: 0,
+            accel: reading(accel, 1.),
+            gyro: reading(gyro, 2.),
+            mag: reading(mag, 3.),
+        }
+    }
+
+    #[test]
+    fn columns_are_from_the_first_sample() {
+        let path = std::env::temp_dir().join(format!("mycap-raw-{}.csv", std::process::id()));
+        let mut recorder = RawSensorRecorder {
+            path: path.clone(),
+            csv_tx: None,
+            sensors: 0,
+            warned_missing_columns: false,
+        };
+
+        recorder
+            .record(10, "hip", &sample(true, true, false))
+            .unwrap();
+        recorder
+            .record(20, "foot", &sample(false, true, false))
+            .unwrap();
+        assert!(!recorder.warned_missing_columns);
+        // The magnetometer doesn't have any columns to go in
+        recorder
+            .record(30, "hand", &sample(true, true, true))
+            .unwrap();
+        assert!(recorder.warned_missing_columns);
+        drop(recorder);
+
+        let mut contents = String::new();
+        for _ in 0..50 {
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 4 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "timestamp_us,tracker,ax,ay,az,gx,gy,gz",
+                "10,hip,1,1,1,2,2,2",
+                "20,foot,,,,2,2,2",
+                "30,hand,1,1,1,2,2,2",
+            ]
+        );
+    }
+
+    #[test]
+    fn header_only_has_the_sensors_present() {
+        let sensors = RawSensorSample::ACCEL | RawSensorSample::MAG;
+        assert_eq!(
+            csv_header(sensors),
+            "timestamp_us,tracker,ax,ay,az,mx,my,mz"
+        );
+        assert_eq!(csv_header(0), "timestamp_us,tracker");
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "websocket")]
+use std::collections::HashMap;
+
+use crate::{
+    config::ConfigError,
+    osc::OscSender,
+    tracker::{Tracker, TrackerList, TrackerLocation, TrackerStatus},
+};
+
+/// Each tracker's routes are stored as bits so there can't be more than this many routes
+pub const MAX_ROUTES: usize = 64;
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Which trackers get sent along a route
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RouteSelector {
+    Indices(Vec<usize>),
+    Locations(Vec<TrackerLocation>),
+    /// Trackers with this group in their config
+    Group(String),
+}
+
+impl RouteSelector {
+    /// Indices of trackers that aren't running are left as they are
+    #[cfg(feature = "websocket")]
+    pub fn remap_indices(&mut self, new_indices: &HashMap<usize, usize>) {
+        if let Self::Indices(indices) = self {
+            for index in indices {
+                if let Some(new_index) = new_indices.get(index) {
+                    *index = *new_index;
+                }
+            }
+        }
+    }
+
+    fn matches(&self, tracker: &Tracker) -> bool {
+        let info = &tracker.info;
+        match self {
+            Self::Indices(indices) => indices.contains(&info.index),
+            Self::Locations(locations) => locations.contains(&info.config.location),
+            Self::Group(group) => !group.is_empty() && info.config.group == *group,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RouteDestination {
+    /// Sends {address}/position and {address}/orientation in mycap's conventions
+    Osc {
+        target: SocketAddr,
+        /// {id} and {index} get replaced with the tracker's id and index
+        address: String,
+    },
+}
+
+/// Sends some of the trackers somewhere, a tracker can be in any number of routes
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OutputRoute {
+    pub name: String,
+    pub enabled: bool,
+    pub destination: RouteDestination,
+    pub selector: RouteSelector,
+}
+
+impl OutputRoute {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.name.is_empty() {
+            return Err(ConfigError::new("name", "must not be empty"));
+        }
+
+        match &self.destination {
+            RouteDestination::Osc { address, .. } => {
+                if !address.starts_with('/') {
+                    return Err(ConfigError::new("destination.address", "must start with /"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn validate_routes(routes: &[OutputRoute]) -> Result<(), ConfigError> {
+    if routes.len() > MAX_ROUTES {
+        return Err(ConfigError::new(
+            "routes",
+            format!("can't have more than {MAX_ROUTES} routes"),
+        ));
+    }
+
+    for (i, route) in routes.iter().enumerate() {
+        route
+            .validate()
+            .map_err(|error| error.in_field(&format!("routes[{i}]")))?;
+
+        if routes[..i].iter().any(|other| other.name == route.name) {
+            return Err(ConfigError::new(
+                format!("routes[{i}].name"),
+                format!("{} is used more than once", route.name),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// How much a route is sending for clients to show
+#[cfg(feature = "websocket")]
+#[derive(Clone, serde::Serialize)]
+pub struct RouteStats {
+    pub name: String,
+    pub enabled: bool,
+    /// Trackers currently selected by the route
+    pub trackers: Vec<usize>,
+    /// Tracker updates sent over the last second
+    pub updates_per_sec: u32,
+}
+
+/// Sends the trackers along the routes they're selected by
+#[derive(Default)]
+pub struct Router {
+    /// Bit i is set if the tracker at that index is in route i
+    masks: Vec<u64>,
+    /// Tracker or route configs have changed so the masks need to be worked out again
+    dirty: bool,
+    sender: Option<OscSender>,
+    /// Updates sent by each route since the throughput window started
+    sent: Vec<u32>,
+    updates_per_sec: Vec<u32>,
+    window_start: Option<Instant>,
+}
+
+impl Router {
+    /// Works out the masks again on the next send
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+
+    fn update_masks(&mut self, routes: &[OutputRoute], trackers: &TrackerList) {
+        self.masks.clear();
+        for tracker in trackers.iter() {
+            let index = tracker.info.index;
+            if index >= self.masks.len() {
+                self.masks.resize(index + 1, 0);
+            }
+
+            for (i, route) in routes.iter().enumerate() {
+                if route.selector.matches(tracker) {
+                    self.masks[index] |= 1 << i;
+                }
+            }
+        }
+
+        self.sent.resize(routes.len(), 0);
+        self.updates_per_sec.resize(routes.len(), 0);
+        self.dirty = false;
+    }
+
+    pub fn send(&mut self, routes: &[OutputRoute], trackers: &TrackerList) -> anyhow::Result<()> {
+        if self.dirty {
+            self.update_masks(routes, trackers);
+        }
+
+        let window_start = *self.window_start.get_or_insert_with(Instant::now);
+        if window_start.elapsed() >= THROUGHPUT_WINDOW {
+            self.updates_per_sec.copy_from_slice(&self.sent);
+            self.sent.fill(0);
+            self.window_start = Some(Instant::now());
+        }
+
+        if !routes.iter().any(|route| route.enabled) {
+            return Ok(());
+        }
+
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => self.sender.insert(OscSender::new()?),
+        };
+
+        for tracker in trackers.iter() {
+            let mask = self.masks.get(tracker.info.index).copied().unwrap_or(0);
+            if mask == 0 || tracker.info.status != TrackerStatus::Ok {
+                continue;
+            }
+
+            for (i, route) in routes.iter().enumerate() {
+                if mask & (1 << i) == 0 || !route.enabled {
+                    continue;
+                }
+
+                match &route.destination {
+                    RouteDestination::Osc { target, address } => {
+                        send_osc(sender, tracker, address, *target);
+                    }
+                }
+
+                self.sent[i] += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn stats(&mut self, routes: &[OutputRoute], trackers: &TrackerList) -> Vec<RouteStats> {
+        if self.dirty {
+            self.update_masks(routes, trackers);
+        }
+
+        routes
+            .iter()
+            .enumerate()
+            .map(|(i, route)| RouteStats {
+                name: route.name.clone(),
+                enabled: route.enabled,
+                trackers: (self.masks.iter().enumerate())
+                    .filter(|(_, mask)| *mask & (1 << i) != 0)
+                    .map(|(index, _)| index)
+                    .collect(),
+                updates_per_sec: self.updates_per_sec.get(i).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+fn send_osc(sender: &OscSender, tracker: &Tracker, address: &str, target: SocketAddr) {
+    let address = address
+        .replace("{id}", &tracker.info.id)
+        .replace("{index}", &tracker.info.index.to_string());
+    let data = &tracker.data;
+    sender.send(
+        &format!("{address}/position"),
+        &data.position.to_array(),
+        target,
+    );
+    sender.send(
+        &format!("{address}/orientation"),
+        &data.orientation.0.to_array(),
+        target,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+    use crate::tracker::TrackerConfig;
+
+    fn trackers() -> TrackerList {
+        let mut trackers = TrackerList::default();
+        let configs = [
+            (TrackerLocation::Head, ""),
+            (TrackerLocation::Hand, "hands"),
+            (TrackerLocation::Hand, "hands"),
+            (TrackerLocation::Free, "props"),
+        ];
+        for (index, (location, group)) in configs.into_iter().enumerate() {
+            let config = TrackerConfig {
+                location,
+                group: group.to_string(),
+                ..Default::default()
+            };
+            let mut tracker = Tracker::new(index.to_string(), index, config);
+            tracker.info.status = TrackerStatus::Ok;
+            trackers.insert(tracker);
+        }
+        trackers
+    }
+
+    fn route(name: &str, target: SocketAddr, selector: RouteSelector) -> OutputRoute {
+        OutputRoute {
+            name: name.to_string(),
+            enabled: true,
+            destination: RouteDestination::Osc {
+                target,
+                address: "/tracker/{index}".to_string(),
+            },
+            selector,
+        }
+    }
+
+    fn target() -> SocketAddr {
+        "127.0.0.1:9000".parse().unwrap()
+    }
+
+    #[test]
+    fn masks_have_a_bit_per_route() {
+        let routes = [
+            route("head", target(), RouteSelector::Indices(vec![0, 9])),
+            route(
+                "hands",
+                target(),
+                RouteSelector::Locations(vec![TrackerLocation::Hand]),
+            ),
+            route("group", target(), RouteSelector::Group("hands".to_string())),
+            // Trackers without a group aren't in an empty group
+            route("none", target(), RouteSelector::Group(String::new())),
+        ];
+        let mut router = Router::default();
+        router.update_masks(&routes, &trackers());
+        assert_eq!(router.masks, [0b0001, 0b0110, 0b0110, 0]);
+    }
+
+    #[test]
+    fn only_running_trackers_in_enabled_routes_are_sent() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let target = receiver.local_addr().unwrap();
+
+        let mut trackers = trackers();
+        trackers.get_mut(2).unwrap().info.status = TrackerStatus::TimedOut;
+        let mut disabled = route("props", target, RouteSelector::Group("props".to_string()));
+        disabled.enabled = false;
+        let routes = [
+            route(
+                "hands",
+                target,
+                RouteSelector::Locations(vec![TrackerLocation::Hand]),
+            ),
+            route("all", target, RouteSelector::Indices(vec![0, 1, 2, 3])),
+            disabled,
+        ];
+
+        let mut router = Router::default();
+        router.invalidate();
+        router.send(&routes, &trackers).unwrap();
+        assert_eq!(router.sent, [1, 3, 0]);
+
+        // A position and an orientation message for each tracker sent
+        let mut buffer = [0; 256];
+        let received = std::iter::from_fn(|| receiver.recv(&mut buffer).ok()).count();
+        assert_eq!(received, 8);
+    }
+
+    #[test]
+    fn routes_are_validated() {
+        let routes = vec![route("a", target(), RouteSelector::Indices(vec![])); MAX_ROUTES + 1];
+        let error = validate_routes(&routes).unwrap_err();
+        assert_eq!(error.field, "routes");
+
+        let mut routes = vec![
+            route("a", target(), RouteSelector::Indices(vec![])),
+            route("a", target(), RouteSelector::Indices(vec![])),
+        ];
+        assert_eq!(
+            validate_routes(&routes).unwrap_err().field,
+            "routes[1].name"
+        );
+
+        routes[1].name = "b".to_string();
+        routes[1].destination = RouteDestination::Osc {
+            target: target(),
+            address: "tracker".to_string(),
+        };
+        let error = validate_routes(&routes).unwrap_err();
+        assert_eq!(error.field, "routes[1].destination.address");
+
+        routes[1].name.clear();
+        assert_eq!(
+            validate_routes(&routes).unwrap_err().field,
+            "routes[1].name"
+        );
+    }
+}
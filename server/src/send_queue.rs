@@ -0,0 +1,182 @@
+use std::{collections::VecDeque, net::SocketAddr};
+
+/// Packets waiting to be sent past this get dropped, lowest priority first
+pub const MAX_QUEUED_PACKETS: usize = 1024;
+
+/// Lower priority packets are sent last and dropped first when the queue is full
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SendPriority {
+    /// Announcements, probe replies and network test probes that nothing gets stuck waiting on
+    Telemetry,
+    /// Pings that keep the connection alive and measure the latency
+    Heartbeat,
+    /// Config values and requests which get sent again if they're lost
+    Command,
+    /// Handshake replies and acks that the device waits on before carrying on
+    Handshake,
+}
+
+const PRIORITY_COUNT: usize = SendPriority::Handshake as usize + 1;
+
+pub struct OutgoingPacket {
+    pub bytes: Vec<u8>,
+    pub address: SocketAddr,
+}
+
+/// Holds the packets to send so handling a packet never waits on the socket
+#[derive(Default)]
+pub struct SendQueue {
+    /// Indexed by the priority
+    queues: [VecDeque<OutgoingPacket>; PRIORITY_COUNT],
+    len: usize,
+    /// Packets dropped since this was last taken because the queue was full
+    dropped: u64,
+}
+
+impl SendQueue {
+    pub fn push(&mut self, packet: OutgoingPacket, priority: SendPriority) {
+        if self.len >= MAX_QUEUED_PACKETS {
+            self.dropped += 1;
+
+            // The oldest packet of the lowest priority goes to make room unless the new one is lower
+            let Some(lowest) = self.queues[..=priority as usize]
+                .iter_mut()
+                .find(|queue| !queue.is_empty())
+            else {
+                return;
+            };
+
+            lowest.pop_front();
+            self.len -= 1;
+        }
+
+        self.queues[priority as usize].push_back(packet);
+        self.len += 1;
+    }
+
+    /// The oldest packet of the highest priority
+    pub fn pop(&mut self) -> Option<(OutgoingPacket, SendPriority)> {
+        let index = self.queues.iter().rposition(|queue| !queue.is_empty())?;
+        self.len -= 1;
+        let packet = self.queues[index].pop_front()?;
+        Some((packet, PRIORITIES[index]))
+    }
+
+    /// Puts a packet that couldn't be sent back to be sent first next time
+    pub fn unpop(&mut self, packet: OutgoingPacket, priority: SendPriority) {
+        self.queues[priority as usize].push_front(packet);
+        self.len += 1;
+    }
+
+    /// Removes everything going to the address, returning how many were removed
+    pub fn purge(&mut self, address: SocketAddr) -> usize {
+        let old_len = self.len;
+        for queue in &mut self.queues {
+            queue.retain(|packet| packet.address != address);
+        }
+
+        self.len = self.queues.iter().map(VecDeque::len).sum();
+        old_len - self.len
+    }
+
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+const PRIORITIES: [SendPriority; PRIORITY_COUNT] = [
+    SendPriority::Telemetry,
+    SendPriority::Heartbeat,
+    SendPriority::Command,
+    SendPriority::Handshake,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(id: u8, port: u16) -> OutgoingPacket {
+        OutgoingPacket {
+            bytes: vec![id],
+            address: SocketAddr::from(([10, 0, 0, 2], port)),
+        }
+    }
+
+    fn pop_ids(queue: &mut SendQueue) -> Vec<(u8, SendPriority)> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|(packet, priority)| (packet.bytes[0], priority))
+            .collect()
+    }
+
+    #[test]
+    fn highest_priority_goes_first() {
+        use SendPriority::*;
+
+        let mut queue = SendQueue::default();
+        queue.push(packet(0, 1), Telemetry);
+        queue.push(packet(1, 1), Handshake);
+        queue.push(packet(2, 1), Heartbeat);
+        queue.push(packet(3, 1), Handshake);
+        queue.push(packet(4, 1), Command);
+
+        assert_eq!(
+            pop_ids(&mut queue),
+            [
+                (1, Handshake),
+                (3, Handshake),
+                (4, Command),
+                (2, Heartbeat),
+                (0, Telemetry)
+            ]
+        );
+        assert_eq!(queue.len, 0);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_lowest_priority() {
+        let mut queue = SendQueue::default();
+        queue.push(packet(0, 1), SendPriority::Heartbeat);
+        queue.push(packet(1, 1), SendPriority::Heartbeat);
+        for _ in 2..MAX_QUEUED_PACKETS {
+            queue.push(packet(2, 1), SendPriority::Command);
+        }
+
+        queue.push(packet(3, 1), SendPriority::Handshake);
+        // Nothing is lower than telemetry so it's the new packet that gets dropped
+        queue.push(packet(4, 1), SendPriority::Telemetry);
+        assert_eq!(queue.len, MAX_QUEUED_PACKETS);
+        assert_eq!(queue.take_dropped(), 2);
+        assert_eq!(queue.take_dropped(), 0);
+
+        let ids = pop_ids(&mut queue);
+        assert_eq!(ids.first(), Some(&(3, SendPriority::Handshake)));
+        assert_eq!(ids.last(), Some(&(1, SendPriority::Heartbeat)));
+        assert!(!ids.iter().any(|(id, _)| [0, 4].contains(id)));
+    }
+
+    #[test]
+    fn unsent_packet_goes_back_to_the_front() {
+        let mut queue = SendQueue::default();
+        queue.push(packet(0, 1), SendPriority::Command);
+        queue.push(packet(1, 1), SendPriority::Command);
+
+        let (packet, priority) = queue.pop().unwrap();
+        queue.unpop(packet, priority);
+        assert_eq!(
+            pop_ids(&mut queue),
+            [(0, SendPriority::Command), (1, SendPriority::Command)]
+        );
+    }
+
+    #[test]
+    fn purge_removes_only_the_address() {
+        let mut queue = SendQueue::default();
+        queue.push(packet(0, 1), SendPriority::Command);
+        queue.push(packet(1, 2), SendPriority::Handshake);
+        queue.push(packet(2, 1), SendPriority::Telemetry);
+
+        assert_eq!(queue.purge(packet(0, 1).address), 2);
+        assert_eq!(queue.len, 1);
+        assert_eq!(pop_ids(&mut queue), [(1, SendPriority::Handshake)]);
+    }
+}
@@ -1,14 +1,250 @@
+#[cfg(feature = "websocket")]
+use std::net::Ipv4Addr;
+
+#[cfg(feature = "websocket")]
+use crate::{messages::CodedMessage, udp_packet::parse_mac};
+
+#[cfg(all(feature = "serial", feature = "websocket"))]
 pub fn write_serial(data: &[u8]) -> anyhow::Result<()> {
     let ports = serialport::available_ports()?;
     let port_info = ports
         .iter()
         .find(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(_)))
-        .ok_or_else(|| anyhow::anyhow!("USB device not found"))?;
+        .ok_or_else(|| CodedMessage::new("serial_not_connected"))?;
 
-    log::info!("Writing to USB serial port: {}", port_info.port_name);
+    tracing::info!("Writing to USB serial port: {}", port_info.port_name);
     let mut port = serialport::new(&port_info.port_name, 9600)
         .timeout(std::time::Duration::from_millis(10))
         .open()?;
     port.write_all(data)?;
     Ok(())
 }
+
+#[cfg(all(not(feature = "serial"), feature = "websocket"))]
+pub fn write_serial(_data: &[u8]) -> anyhow::Result<()> {
+    Err(CodedMessage::new("serial_unsupported").into())
+}
+
+/// Format of the commands sent over serial that the firmware understands
+#[derive(Clone, Copy, Default, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum SerialProtocol {
+    /// Arguments seperated by null bytes, can't contain null bytes or newlines
+    #[default]
+    Legacy,
+    /// A single line of JSON which can contain any characters and extra fields
+    Json,
+}
+
+#[cfg(feature = "websocket")]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct StaticIpConfig {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub subnet: Ipv4Addr,
+}
+
+#[cfg(feature = "websocket")]
+#[derive(Clone, serde::Serialize)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: String,
+    /// The network doesn't broadcast its SSID
+    pub hidden: bool,
+    /// Only connect to the access point with this mac address
+    pub bssid: Option<String>,
+    pub static_ip: Option<StaticIpConfig>,
+}
+
+#[cfg(feature = "websocket")]
+impl WifiCredentials {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            return Err(CodedMessage::new("wifi_ssid_length").into());
+        }
+
+        if self.ssid.contains('\0') {
+            return Err(CodedMessage::new("wifi_ssid_null").into());
+        }
+
+        // A 64 character password is the raw hex key instead of a passphrase
+        let is_hex_key =
+            self.password.len() == 64 && self.password.chars().all(|c| c.is_ascii_hexdigit());
+        if !self.password.is_empty() && !(8..=63).contains(&self.password.len()) && !is_hex_key {
+            return Err(CodedMessage::new("wifi_password_length").into());
+        }
+
+        if !self.password.chars().all(|c| matches!(c, ' '..='~')) {
+            return Err(CodedMessage::new("wifi_password_not_ascii").into());
+        }
+
+        if let Some(bssid) = &self.bssid {
+            if parse_mac(bssid).is_none() {
+                return Err(CodedMessage::new("wifi_bssid_invalid").into());
+            }
+        }
+
+        if let Some(static_ip) = &self.static_ip {
+            let subnet = u32::from(static_ip.subnet);
+            if subnet.leading_ones() + subnet.trailing_zeros() != 32 {
+                return Err(CodedMessage::new("static_ip_subnet_invalid").into());
+            }
+
+            if u32::from(static_ip.ip) & subnet != u32::from(static_ip.gateway) & subnet {
+                return Err(CodedMessage::new("static_ip_gateway_outside_subnet").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates and encodes the credentials into a serial command
+    pub fn to_command(&self, protocol: SerialProtocol) -> anyhow::Result<Vec<u8>> {
+        self.validate()?;
+
+        match protocol {
+            SerialProtocol::Legacy => {
+                if self.ssid.contains('\n') {
+                    return Err(CodedMessage::new("wifi_ssid_newline_legacy").into());
+                }
+
+                if self.hidden || self.bssid.is_some() || self.static_ip.is_some() {
+                    return Err(CodedMessage::new("wifi_options_need_json").into());
+                }
+
+                Ok(format!("Wifi\0{}\0{}\n", self.ssid, self.password).into_bytes())
+            }
+            SerialProtocol::Json => {
+                #[derive(serde::Serialize)]
+                struct WifiCommand<'a> {
+                    command: &'static str,
+                    #[serde(flatten)]
+                    credentials: &'a WifiCredentials,
+                }
+
+                // serde_json escapes newlines so the command stays on a single line
+                let mut command = serde_json::to_string(&WifiCommand {
+                    command: "Wifi",
+                    credentials: self,
+                })?;
+                command.push('\n');
+                Ok(command.into_bytes())
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+
+    fn credentials(ssid: &str, password: &str) -> WifiCredentials {
+        WifiCredentials {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            hidden: false,
+            bssid: None,
+            static_ip: None,
+        }
+    }
+
+    fn error_code(result: anyhow::Result<Vec<u8>>) -> &'static str {
+        let error = result.expect_err("should be rejected");
+        error.downcast_ref::<CodedMessage>().unwrap().code
+    }
+
+    #[test]
+    fn legacy_command_is_null_separated() {
+        let command = credentials("home", "password123").to_command(SerialProtocol::Legacy);
+        assert_eq!(command.unwrap(), b"Wifi\0home\0password123\n");
+
+        // Open networks don't have a password
+        let command = credentials("cafe", "").to_command(SerialProtocol::Legacy);
+        assert_eq!(command.unwrap(), b"Wifi\0cafe\0\n");
+    }
+
+    #[test]
+    fn json_command_is_a_single_line() {
+        let mut wifi = credentials("line\nbreak", "password123");
+        wifi.hidden = true;
+        wifi.bssid = Some("01:23:45:67:89:ab".to_string());
+
+        let command = wifi.to_command(SerialProtocol::Json).unwrap();
+        let (line, end) = command.split_at(command.len() - 1);
+        assert_eq!(end, b"\n");
+        assert!(!line.contains(&b'\n'));
+
+        let json: serde_json::Value = serde_json::from_slice(line).unwrap();
+        assert_eq!(json["command"], "Wifi");
+        assert_eq!(json["ssid"], "line\nbreak");
+        assert_eq!(json["hidden"], true);
+        assert_eq!(json["bssid"], "01:23:45:67:89:ab");
+    }
+
+    #[test]
+    fn invalid_credentials_are_rejected() {
+        let hex_key = "0123456789abcdef".repeat(4);
+        assert!(credentials("home", &hex_key).validate().is_ok());
+
+        let cases = [
+            (credentials("", "password123"), "wifi_ssid_length"),
+            (
+                credentials(&"a".repeat(33), "password123"),
+                "wifi_ssid_length",
+            ),
+            (credentials("ho\0me", "password123"), "wifi_ssid_null"),
+            (credentials("home", "short"), "wifi_password_length"),
+            (credentials("home", &"z".repeat(64)), "wifi_password_length"),
+            (
+                credentials("home", "pässword123"),
+                "wifi_password_not_ascii",
+            ),
+            (
+                credentials("home", "pass\0word123"),
+                "wifi_password_not_ascii",
+            ),
+        ];
+        for (wifi, code) in cases {
+            for protocol in [SerialProtocol::Legacy, SerialProtocol::Json] {
+                assert_eq!(error_code(wifi.to_command(protocol)), code, "{}", wifi.ssid);
+            }
+        }
+
+        let mut wifi = credentials("home", "password123");
+        wifi.bssid = Some("not a mac".to_string());
+        assert_eq!(
+            error_code(wifi.to_command(SerialProtocol::Json)),
+            "wifi_bssid_invalid"
+        );
+    }
+
+    #[test]
+    fn legacy_command_only_takes_what_it_can_encode() {
+        let wifi = credentials("line\nbreak", "password123");
+        let code = error_code(wifi.to_command(SerialProtocol::Legacy));
+        assert_eq!(code, "wifi_ssid_newline_legacy");
+
+        let mut wifi = credentials("home", "password123");
+        wifi.hidden = true;
+        let code = error_code(wifi.to_command(SerialProtocol::Legacy));
+        assert_eq!(code, "wifi_options_need_json");
+    }
+
+    #[test]
+    fn static_ip_has_to_fit_the_subnet() {
+        let static_ip = |ip: [u8; 4], gateway: [u8; 4], subnet: [u8; 4]| {
+            let mut wifi = credentials("home", "password123");
+            wifi.static_ip = Some(StaticIpConfig {
+                ip: ip.into(),
+                gateway: gateway.into(),
+                subnet: subnet.into(),
+            });
+            wifi.to_command(SerialProtocol::Json)
+        };
+
+        assert!(static_ip([192, 168, 1, 50], [192, 168, 1, 1], [255, 255, 255, 0]).is_ok());
+        let result = static_ip([192, 168, 1, 50], [192, 168, 1, 1], [255, 0, 255, 0]);
+        assert_eq!(error_code(result), "static_ip_subnet_invalid");
+        let result = static_ip([192, 168, 2, 50], [192, 168, 1, 1], [255, 255, 255, 0]);
+        assert_eq!(error_code(result), "static_ip_gateway_outside_subnet");
+    }
+}
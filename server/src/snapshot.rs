@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use tokio::sync::{broadcast, Notify};
+
+use crate::{
+    main_server::{
+        ChannelMessage, Conventions, MessageChannelManager, ServerMessage, ServerStatus,
+    },
+    tracker::{TrackerData, TrackerInfo},
+};
+
+#[derive(Clone)]
+pub struct TrackerSnapshot {
+    pub info: TrackerInfo,
+    pub data: TrackerData,
+}
+
+/// Copy of the server state for syncing a client
+pub struct Snapshot {
+    pub trackers: Vec<TrackerSnapshot>,
+    pub status: ServerStatus,
+    pub conventions: Conventions,
+    /// Messages with an id before this have already been applied to the snapshot
+    pub next_message_id: u64,
+}
+
+impl Snapshot {
+    /// Messages that recreate the state of the snapshot on a client
+    pub fn to_messages(&self) -> Vec<ServerMessage> {
+        let mut messages = vec![
+            ServerMessage::ServerStatus {
+                status: self.status.clone(),
+            },
+            ServerMessage::Conventions(self.conventions.clone()),
+        ];
+
+        for tracker in &self.trackers {
+            messages.push(ServerMessage::TrackerInfo {
+                info: tracker.info.clone(),
+            });
+            messages.push(ServerMessage::TrackerData {
+                index: tracker.info.index,
+                data: tracker.data.clone(),
+                raw_data: None,
+            });
+        }
+
+        messages
+    }
+}
+
+/// Lets readers like the websocket server get the latest snapshot without locking the main server
+#[derive(Clone, Default)]
+pub struct SnapshotPublisher {
+    latest: Arc<ArcSwapOption<Snapshot>>,
+    published: Arc<Notify>,
+}
+
+impl SnapshotPublisher {
+    pub fn publish(&self, snapshot: Snapshot) {
+        self.latest.store(Some(Arc::new(snapshot)));
+        self.published.notify_waiters();
+    }
+
+    /// Gets the latest snapshot or None if nothing has been published yet
+    pub fn latest(&self) -> Option<Arc<Snapshot>> {
+        self.latest.load_full()
+    }
+
+    /// Waits for a snapshot that has every message before `next_message_id` applied
+    async fn wait_for(&self, next_message_id: u64) -> Arc<Snapshot> {
+        loop {
+            // Made before checking so a snapshot published in between still wakes it
+            let published = self.published.notified();
+            if let Some(snapshot) = self.latest() {
+                if snapshot.next_message_id >= next_message_id {
+                    return snapshot;
+                }
+            }
+            published.await;
+        }
+    }
+}
+
+/// Subscribes clients to the messages along with a snapshot without locking the main server
+#[derive(Clone)]
+pub struct SnapshotSubscriber {
+    pub(crate) messages: MessageChannelManager,
+    pub(crate) snapshots: SnapshotPublisher,
+}
+
+impl SnapshotSubscriber {
+    /// Like `MainServer::subscribe_with_snapshot` but the snapshot can be newer than the first
+    /// message received, the client skips the messages already applied to it by their id
+    /// Waits until the next snapshot gets published if the latest one is older than the receiver
+    pub async fn subscribe(&self) -> (broadcast::Receiver<ChannelMessage>, Arc<Snapshot>) {
+        let (receiver, next_message_id) = self.messages.subscribe();
+        let snapshot = self.snapshots.wait_for(next_message_id).await;
+        (receiver, snapshot)
+    }
+}
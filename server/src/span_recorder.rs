@@ -0,0 +1,87 @@
+//! Records the spans that get created so tests can check that the instrumentation is still there
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+#[derive(Clone, Debug)]
+pub struct RecordedSpan {
+    pub name: &'static str,
+    pub parent: Option<&'static str>,
+    pub fields: BTreeMap<&'static str, String>,
+}
+
+#[derive(Clone, Default)]
+pub struct SpanRecorder(Arc<Mutex<Vec<RecordedSpan>>>);
+
+/// Where the span is in the recorded spans
+struct SpanIndex(usize);
+
+impl SpanRecorder {
+    /// Records the spans created on this thread until the guard is dropped
+    pub fn install(&self) -> tracing::subscriber::DefaultGuard {
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(self.clone()))
+    }
+
+    pub fn spans(&self) -> Vec<RecordedSpan> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// The spans with the name, in the order they were created
+    pub fn named(&self, name: &str) -> Vec<RecordedSpan> {
+        self.spans()
+            .into_iter()
+            .filter(|span| span.name == name)
+            .collect()
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanRecorder {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut FieldRecorder(&mut fields));
+        let mut spans = self.0.lock().unwrap();
+        span.extensions_mut().insert(SpanIndex(spans.len()));
+        spans.push(RecordedSpan {
+            name: span.name(),
+            parent: span.parent().map(|parent| parent.name()),
+            fields,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let extensions = span.extensions();
+        if let Some(SpanIndex(index)) = extensions.get::<SpanIndex>() {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut FieldRecorder(&mut spans[*index].fields));
+        }
+    }
+}
+
+struct FieldRecorder<'a>(&'a mut BTreeMap<&'static str, String>);
+
+impl Visit for FieldRecorder<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name(), format!("{value:?}"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    any::Any,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "websocket")]
+use std::{future::Future, sync::Arc};
+
+#[cfg(feature = "websocket")]
+use tokio::sync::RwLock;
+
+#[cfg(feature = "websocket")]
+use crate::main_server::MainServer;
+
+/// Give up on a subsystem after it fails this many times in a row
+pub const MAX_SUBSYSTEM_RESTARTS: u32 = 8;
+const RESTART_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+/// A subsystem that stays up for this long has recovered so its failures count from 0 again
+const STABLE_TIME: Duration = Duration::from_secs(60);
+
+/// Decides when a failed subsystem gets restarted, waiting twice as long after each failure in a
+/// row
+pub struct RestartBackoff {
+    pub failures: u32,
+    /// Set while waiting to restart
+    restart_time: Option<Instant>,
+    gave_up: bool,
+    started_time: Instant,
+}
+
+impl Default for RestartBackoff {
+    fn default() -> Self {
+        Self {
+            failures: 0,
+            restart_time: None,
+            gave_up: false,
+            started_time: Instant::now(),
+        }
+    }
+}
+
+impl RestartBackoff {
+    /// Returns how long to wait before restarting, None if it has failed too many times
+    pub fn failed(&mut self) -> Option<Duration> {
+        if self.started_time.elapsed() >= STABLE_TIME {
+            self.failures = 0;
+        }
+
+        self.failures += 1;
+        if self.failures > MAX_SUBSYSTEM_RESTARTS {
+            self.gave_up = true;
+            self.restart_time = None;
+            return None;
+        }
+
+        let backoff = (RESTART_BACKOFF * 2_u32.pow(self.failures - 1)).min(MAX_RESTART_BACKOFF);
+        self.restart_time = Some(Instant::now() + backoff);
+        Some(backoff)
+    }
+
+    /// None while waiting to restart or after giving up, otherwise whether this run is the restart
+    pub fn poll(&mut self) -> Option<bool> {
+        if self.gave_up {
+            return None;
+        }
+
+        match self.restart_time {
+            None => Some(false),
+            Some(time) if Instant::now() >= time => {
+                self.restart_time = None;
+                self.started_time = Instant::now();
+                Some(true)
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Runs the task, restarting it with a backoff if it panics or returns an error so the rest of the
+/// server keeps running, gives up on it after too many failures in a row
+#[cfg(feature = "websocket")]
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    main: Arc<RwLock<MainServer>>,
+    mut start: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut backoff = RestartBackoff::default();
+    loop {
+        let reason = match tokio::spawn(start()).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(error)) => format!("{error:#}"),
+            Err(error) if error.is_panic() => panic_reason(&*error.into_panic()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let restart_in = backoff.failed();
+        main.read().await.subsystem_failed(name, reason, restart_in);
+        match restart_in {
+            Some(restart_in) => tokio::time::sleep(restart_in).await,
+            None => return Ok(()),
+        }
+
+        backoff.poll();
+        tracing::info!("Restarting {name}");
+    }
+}
+
+/// Turns a panic in the closure into an error so it only stops what the closure runs
+pub fn catch_panic<T>(run: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    std::panic::catch_unwind(AssertUnwindSafe(run))
+        .unwrap_or_else(|payload| Err(anyhow::anyhow!("Panicked: {}", panic_reason(&*payload))))
+}
+
+pub fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_it_gives_up() {
+        let mut backoff = RestartBackoff::default();
+        let waits: Vec<_> = std::iter::from_fn(|| backoff.failed()).collect();
+        let seconds: Vec<_> = waits.iter().map(Duration::as_secs_f32).collect();
+        assert_eq!(seconds, [0.5, 1., 2., 4., 8., 16., 30., 30.]);
+        assert_eq!(waits.len(), MAX_SUBSYSTEM_RESTARTS as usize);
+        assert_eq!(backoff.poll(), None);
+    }
+
+    #[test]
+    fn restarts_once_the_backoff_has_passed() {
+        let mut backoff = RestartBackoff::default();
+        assert_eq!(backoff.poll(), Some(false));
+
+        backoff.failed();
+        assert_eq!(backoff.poll(), None);
+        backoff.restart_time = Some(Instant::now());
+        assert_eq!(backoff.poll(), Some(true));
+        assert_eq!(backoff.poll(), Some(false));
+    }
+
+    #[test]
+    fn staying_up_resets_the_failures() {
+        let mut backoff = RestartBackoff::default();
+        for _ in 0..MAX_SUBSYSTEM_RESTARTS {
+            backoff.failed();
+        }
+
+        backoff.started_time -= STABLE_TIME;
+        assert_eq!(backoff.failed(), Some(RESTART_BACKOFF));
+        assert_eq!(backoff.failures, 1);
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        let error = catch_panic::<()>(|| panic!("sensor {} broke", 2)).unwrap_err();
+        assert_eq!(error.to_string(), "Panicked: sensor 2 broke");
+        assert_eq!(catch_panic(|| Ok(5)).unwrap(), 5);
+
+        assert_eq!(panic_reason(&"static"), "static");
+        assert_eq!(panic_reason(&5), "unknown panic");
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test(start_paused = true)]
+    async fn failed_task_is_restarted() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        use crate::main_server::ServerMessage;
+
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        let mut messages = main.write().await.new_message_channel();
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let task_runs = runs.clone();
+        supervise("test", main.clone(), move || {
+            let runs = task_runs.clone();
+            async move {
+                match runs.fetch_add(1, Ordering::Relaxed) {
+                    0 => anyhow::bail!("lost the socket"),
+                    1 => panic!("index out of bounds"),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+
+        let failures: Vec<_> = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter_map(|message| match message.message {
+                ServerMessage::SubsystemFailed {
+                    reason, restarting, ..
+                } => Some((reason, restarting)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                ("lost the socket".to_string(), true),
+                ("index out of bounds".to_string(), true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+
+/// Optional work in the tick, in priority order so the later stages get skipped first
+#[derive(Clone, Copy, Debug)]
+pub enum TickStage {
+    Export,
+    #[cfg(feature = "osc")]
+    VrchatOsc,
+    #[cfg(feature = "osc")]
+    Routes,
+}
+
+const STAGE_COUNT: usize = 3;
+/// Half of the loop time so there's still time left for receiving the udp packets
+const TICK_BUDGET: Duration = Duration::from_millis(10);
+/// Don't send the skip counts to the clients more often than this
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Times skipped since the server started
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct SkippedStages {
+    pub export: u64,
+    #[cfg(feature = "osc")]
+    pub vrchat_osc: u64,
+    #[cfg(feature = "osc")]
+    pub routes: u64,
+}
+
+/// Skips the optional stages of the tick when running them would go over the budget so the tracker
+/// data and udp packets don't get delayed
+pub struct TickBudget {
+    budget: Duration,
+    start: Instant,
+    /// Moving average of how long each stage takes to guess whether it'll fit
+    costs: [Duration; STAGE_COUNT],
+    pub skipped: SkippedStages,
+    reported: SkippedStages,
+    last_report_time: Instant,
+}
+
+impl Default for TickBudget {
+    fn default() -> Self {
+        Self {
+            budget: TICK_BUDGET,
+            start: Instant::now(),
+            costs: [Duration::ZERO; STAGE_COUNT],
+            skipped: SkippedStages::default(),
+            reported: SkippedStages::default(),
+            last_report_time: Instant::now(),
+        }
+    }
+}
+
+impl TickBudget {
+    pub fn start_tick(&mut self) {
+        self.start = Instant::now();
+    }
+
+    /// Returns true if the stage fits in what's left of the budget, counting it as skipped if not
+    pub fn should_run(&mut self, stage: TickStage) -> bool {
+        let cost = self.costs[stage as usize];
+        if self.start.elapsed() + cost <= self.budget {
+            return true;
+        }
+
+        let skipped = match stage {
+            TickStage::Export => &mut self.skipped.export,
+            #[cfg(feature = "osc")]
+            TickStage::VrchatOsc => &mut self.skipped.vrchat_osc,
+            #[cfg(feature = "osc")]
+            TickStage::Routes => &mut self.skipped.routes,
+        };
+        *skipped += 1;
+
+        // Let the cost decay so the stage gets tried again when the ticks are quicker
+        self.costs[stage as usize] = cost * 7 / 8;
+        false
+    }
+
+    pub fn record(&mut self, stage: TickStage, stage_start: Instant) {
+        let cost = &mut self.costs[stage as usize];
+        *cost = (*cost * 7 + stage_start.elapsed()) / 8;
+    }
+
+    /// Returns true if more stages were skipped since last reported, at most once per interval
+    pub fn should_report(&mut self) -> bool {
+        if self.skipped == self.reported || self.last_report_time.elapsed() < REPORT_INTERVAL {
+            return false;
+        }
+
+        self.reported = self.skipped;
+        self.last_report_time = Instant::now();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn stage_is_skipped_when_it_would_go_over() {
+        let mut budget = TickBudget::default();
+        budget.costs[TickStage::Export as usize] = 4 * MS;
+        assert!(budget.should_run(TickStage::Export));
+
+        // Most of the budget has already gone on the trackers
+        budget.start = Instant::now() - 8 * MS;
+        assert!(!budget.should_run(TickStage::Export));
+        assert_eq!(budget.skipped.export, 1);
+
+        // The guessed cost comes down until the stage gets another go
+        let skips = (0..100)
+            .take_while(|_| {
+                budget.start = Instant::now() - 8 * MS;
+                !budget.should_run(TickStage::Export)
+            })
+            .count();
+        assert!(skips > 0 && skips < 20, "skipped {skips} more times");
+        assert_eq!(budget.skipped.export, 1 + skips as u64);
+    }
+
+    #[test]
+    fn cost_is_a_moving_average() {
+        let mut budget = TickBudget::default();
+        budget.record(TickStage::Export, Instant::now() - 8 * MS);
+        let cost = budget.costs[TickStage::Export as usize];
+        assert!(cost >= MS && cost < 2 * MS, "{cost:?}");
+
+        for _ in 0..50 {
+            budget.record(TickStage::Export, Instant::now() - 8 * MS);
+        }
+        let cost = budget.costs[TickStage::Export as usize];
+        assert!(cost >= 7 * MS && cost < 9 * MS, "{cost:?}");
+    }
+
+    #[test]
+    fn skips_are_reported_at_most_once_per_interval() {
+        let mut budget = TickBudget::default();
+        budget.last_report_time -= REPORT_INTERVAL;
+        assert!(!budget.should_report());
+
+        budget.skipped.export += 1;
+        assert!(budget.should_report());
+        budget.skipped.export += 1;
+        assert!(!budget.should_report());
+
+        budget.last_report_time -= REPORT_INTERVAL;
+        assert!(budget.should_report());
+        assert!(!budget.should_report());
+    }
+}
@@ -1,70 +1,1166 @@
-use std::time::{Duration, Instant};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-#[derive(Default, PartialEq, Debug, Clone, Copy, serde::Serialize)]
+use crate::{
+    battery::BatteryStatus,
+    config::ConfigError,
+    fusion::{wrap_angle, KalmanConfig, PositionKalman},
+    gravity::STANDARD_GRAVITY,
+    prediction::rotation_between,
+    units::{AccelMps2, SensorQuat, WorldQuat},
+};
+
+#[derive(Default, PartialEq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 #[repr(u8)]
 pub enum TrackerStatus {
     Ok = 0,
     Error = 1,
-    #[default]
     Off = 2,
     TimedOut,
+    /// Registered from the config but no data or status has been received from it yet, it's left
+    /// out of the outputs until then
+    #[default]
+    Unknown,
+}
+
+/// Why the tracker's data looks like it's coming from a broken IMU even though the status is fine
+#[derive(PartialEq, Debug, Clone, Copy, serde::Serialize)]
+pub enum SuspectReason {
+    /// The orientation has been exactly the same for a long time
+    Stuck,
+    /// The orientation jumps around randomly between samples
+    Noisy,
 }
 
-#[derive(Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(Default, Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
 pub enum TrackerLocation {
     /// Not attached to any body part, free to move anywhere
     #[default]
     Free,
     Head,
     Hand,
+    /// Kept above the floor set with SetFloor
+    Foot,
     // TODO: add more locations
 }
 
+/// Which side of the body the tracker is on, only meaningful for locations that come in pairs
+#[derive(Default, Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TrackerSide {
+    #[default]
+    None,
+    Left,
+    Right,
+}
+
+impl TrackerSide {
+    pub fn opposite(self) -> Self {
+        match self {
+            Self::None => Self::None,
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+        }
+    }
+}
+
 #[derive(Clone, Default, serde::Serialize)]
 pub struct TrackerInfo {
+    /// Stable across sessions unlike the index, made from the device id and its local tracker index
+    pub id: String,
     pub index: usize,
     pub status: TrackerStatus,
     pub config: TrackerConfig,
     pub latency_ms: Option<u32>,
+    /// Set while the data looks wrong, separate from the status which comes from the firmware
+    pub suspect: Option<SuspectReason>,
+    /// Of the device the tracker is on, None if it hasn't sent its battery level
+    pub battery: Option<BatteryStatus>,
+    /// Orientations that were dropped for turning faster than the anomaly's max angular rate
+    pub orientation_glitches: u32,
 }
 
 #[derive(Clone, Default, serde::Serialize)]
 pub struct TrackerData {
-    pub orientation: glam::Quat,
-    pub acceleration: glam::Vec3A,
+    pub orientation: WorldQuat,
+    pub acceleration: AccelMps2,
     pub velocity: glam::Vec3A,
     pub position: glam::Vec3A,
+    /// Variance of the position estimate when using the kalman filter
+    pub position_variance: glam::Vec3A,
+    /// In radians per second around each world axis, from the last two orientations received
+    pub angular_velocity: glam::Vec3A,
+    /// When the data was received in microseconds relative to the server clock
+    pub timestamp_us: u64,
+    /// No data has been received for longer than the stale timeout so this is old
+    pub stale: bool,
+}
+
+/// Data as it was received from the tracker before any processing
+#[derive(Clone, Default, serde::Serialize)]
+pub struct RawTrackerData {
+    pub orientation: SensorQuat,
+    pub acceleration: AccelMps2,
+    /// When the data was received in microseconds relative to the server clock
+    pub timestamp_us: u64,
+}
+
+/// IMUs used by the firmware can't measure more than this in m/s^2
+const MAX_PLAUSIBLE_ACCELERATION: f32 = 16. * STANDARD_GRAVITY;
+/// How far the orientation's length can be from 1 before the data is invalid
+const QUAT_LENGTH_TOLERANCE: f32 = 0.1;
+/// How much of each new interval between data goes into the tracker's usual interval
+const DATA_INTERVAL_SMOOTHING: f32 = 0.05;
+/// Orientations turning too fast this many times in a row are let through since the tracker must
+/// have really turned, such as after being reset
+const MAX_GLITCH_STREAK: u32 = 3;
+/// Samples further apart than this are too old to get the angular velocity from
+const MAX_ANGULAR_VELOCITY_INTERVAL_US: u64 = 250_000;
+
+/// Changes the tracker's status based on the data it sends for firmware that doesn't send a status
+/// after recovering from an error
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct StatusRecoveryConfig {
+    pub enabled: bool,
+    /// Consecutive valid data packets to change a tracker from Error to Ok
+    pub valid_packets: u32,
+    /// Consecutive invalid data packets to change a tracker from Ok to Error
+    pub invalid_packets: u32,
+}
+
+impl Default for StatusRecoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            valid_packets: 50,
+            invalid_packets: 50,
+        }
+    }
+}
+
+/// Looks for IMUs that have stopped working but still send data
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    pub enabled: bool,
+    /// Samples in a row with exactly the same orientation for the tracker to be stuck
+    /// 0 means never
+    pub stuck_samples: u32,
+    /// Samples in a row that turn more than the noise angle for the tracker to be noisy, and samples
+    /// in a row that don't for it to stop being noisy
+    /// 0 means never
+    pub noisy_samples: u32,
+    /// Turning this much in degrees between two samples is faster than a person can move
+    pub noise_angle_deg: f32,
+    /// Orientations that turn faster than this in degrees per second from the last one are
+    /// dropped as corrupt, well above what a person can turn so real motion isn't clipped
+    /// 0 means never
+    pub max_angular_rate_deg: f32,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stuck_samples: 1000,
+            noisy_samples: 50,
+            noise_angle_deg: 45.,
+            max_angular_rate_deg: 5000.,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.max_angular_rate_deg.is_finite() && self.max_angular_rate_deg >= 0.) {
+            return Err(ConfigError::new(
+                "max_angular_rate_deg",
+                "must be a finite number that's at least 0",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Data that an IMU could actually produce
+pub fn is_plausible_data(acceleration: AccelMps2, orientation: SensorQuat) -> bool {
+    acceleration.0.is_finite()
+        && orientation.0.is_finite()
+        && acceleration.length() <= MAX_PLAUSIBLE_ACCELERATION
+        && (orientation.0.length() - 1.).abs() <= QUAT_LENGTH_TOLERANCE
+}
+
+/// Data from the most recent ticks of a tracker, oldest first
+/// Clones share the history so clients can copy it without the main lock, the tick only waits for
+/// this tracker's lock when adding to it
+#[derive(Clone, Default)]
+pub struct TrackerHistory(Arc<Mutex<VecDeque<TrackerData>>>);
+
+impl TrackerHistory {
+    /// Adds the data, dropping the oldest data past the length
+    fn record(&self, data: &TrackerData, length: usize) {
+        let mut history = self.0.lock().unwrap();
+        if length == 0 {
+            *history = VecDeque::new();
+            return;
+        }
+
+        while history.len() >= length {
+            history.pop_front();
+        }
+
+        history.push_back(data.clone());
+    }
+
+    /// Up to the last count ticks of data, oldest first
+    #[cfg(feature = "websocket")]
+    pub fn latest(&self, count: usize) -> Vec<TrackerData> {
+        let history = self.0.lock().unwrap();
+        let skip = history.len().saturating_sub(count);
+        history.iter().skip(skip).cloned().collect()
+    }
 }
 
-#[derive(Clone)]
 pub struct Tracker {
-    pub id: String,
     pub info: TrackerInfo,
     pub data: TrackerData,
+    /// Last data received before any processing
+    pub raw_data: RawTrackerData,
+    /// Latest acceleration to be smoothed into the data on the next tick
+    pub acceleration_input: AccelMps2,
+    /// Correction around the up axis applied to the orientation in radians
+    pub yaw_offset: f32,
+    /// Yaw relative to the yaw reference tracker that the correction keeps it at
+    reference_yaw_difference: Option<f32>,
+    position_kalman: PositionKalman,
+    /// Position from the filter before the configured offset is added
+    estimated_position: glam::Vec3A,
+    /// Consecutive data packets that were valid if positive or invalid if negative
+    data_validity_streak: i32,
+    /// Moving average of the time between data in microseconds
+    data_interval_us: Option<f32>,
+    /// Timestamp of the last data before the current gap in the data
+    pub gap_start_us: Option<u64>,
+    /// Empty unless the history is turned on
+    history: TrackerHistory,
+    /// Samples in a row with the same orientation as the last one
+    identical_samples: u32,
+    /// Samples in a row that jumped past the noise angle if positive or didn't if negative
+    noise_streak: i32,
+    /// Orientations in a row that were dropped for turning too fast
+    glitch_streak: u32,
+    /// Orientation glitches that clients have been told about
+    reported_glitches: u32,
 }
 
 impl Tracker {
     pub fn new(id: String, index: usize, config: TrackerConfig) -> Self {
         Self {
             info: TrackerInfo {
+                id,
                 index,
                 config,
                 status: TrackerStatus::default(),
                 latency_ms: None,
+                suspect: None,
+                battery: None,
+                orientation_glitches: 0,
             },
-            id,
             data: TrackerData::default(),
+            raw_data: RawTrackerData::default(),
+            acceleration_input: AccelMps2::ZERO,
+            yaw_offset: 0.,
+            reference_yaw_difference: None,
+            position_kalman: PositionKalman::default(),
+            estimated_position: glam::Vec3A::ZERO,
+            data_validity_streak: 0,
+            data_interval_us: None,
+            gap_start_us: None,
+            history: TrackerHistory::default(),
+            identical_samples: 0,
+            noise_streak: 0,
+            glitch_streak: 0,
+            reported_glitches: 0,
         }
     }
 
     pub fn tick(&mut self, delta: Duration) {
-        self.data.position += self.data.velocity * delta.as_secs_f32();
+        // Low pass filter with the time constant so it behaves the same at any tick rate
+        let smoothing_secs = self.info.config.accel_smoothing_secs;
+        let keep = if smoothing_secs > 0. {
+            (-delta.as_secs_f32() / smoothing_secs).exp()
+        } else {
+            0.
+        };
+        self.data.acceleration = AccelMps2(
+            self.acceleration_input
+                .0
+                .lerp(self.data.acceleration.0, keep),
+        );
+
+        if !self.info.config.estimate_position {
+            // Start from the origin again if it gets turned back on
+            self.estimated_position = glam::Vec3A::ZERO;
+            self.data.velocity = glam::Vec3A::ZERO;
+            self.data.position_variance = glam::Vec3A::ZERO;
+            self.position_kalman = PositionKalman::default();
+        } else {
+            match &self.info.config.position_filter {
+                PositionFilter::Integration => {
+                    self.estimated_position += self.data.velocity * delta.as_secs_f32();
+                }
+                PositionFilter::Kalman(config) => {
+                    let kalman = &mut self.position_kalman;
+                    kalman.update(self.data.acceleration, delta.as_secs_f32(), config);
+                    self.estimated_position = kalman.position();
+                    self.data.velocity = kalman.velocity();
+                    self.data.position_variance = kalman.position_variance();
+                }
+            }
+        }
+
+        self.data.position = self.estimated_position + self.info.config.position_offset;
+    }
+
+    /// Moves the status between Error and Ok after enough valid or invalid data in a row, returns
+    /// true if the status changed
+    pub fn update_status_from_data(&mut self, valid: bool, config: &StatusRecoveryConfig) -> bool {
+        self.data_validity_streak = match (valid, self.data_validity_streak) {
+            (true, streak) if streak > 0 => streak.saturating_add(1),
+            (true, _) => 1,
+            (false, streak) if streak < 0 => streak.saturating_sub(1),
+            (false, _) => -1,
+        };
+
+        let new_status = match self.info.status {
+            TrackerStatus::Error if self.data_validity_streak >= config.valid_packets as i32 => {
+                TrackerStatus::Ok
+            }
+            TrackerStatus::Ok if -self.data_validity_streak >= config.invalid_packets as i32 => {
+                TrackerStatus::Error
+            }
+            _ => return false,
+        };
+
+        self.info.status = new_status;
+        self.data_validity_streak = 0;
+        true
+    }
+
+    fn gap_threshold_us(&self, gap_intervals: u32) -> Option<u64> {
+        let interval_us = self.data_interval_us?;
+        (gap_intervals != 0).then_some((interval_us * gap_intervals as f32) as u64)
+    }
+
+    /// Starts a gap if there's been no data for too many of the usual intervals
+    pub fn check_data_gap(&mut self, now_us: u64, gap_intervals: u32) {
+        let Some(threshold_us) = self.gap_threshold_us(gap_intervals) else {
+            return;
+        };
+
+        let last_us = self.raw_data.timestamp_us;
+        if self.gap_start_us.is_none() && now_us.saturating_sub(last_us) > threshold_us {
+            self.gap_start_us = Some(last_us);
+        }
+    }
+
+    /// Updates the usual interval with new data, returns how long the gap was in microseconds if
+    /// the data ended one
+    pub fn record_data_interval(&mut self, timestamp_us: u64, gap_intervals: u32) -> Option<u64> {
+        let last_us = self.raw_data.timestamp_us;
+        if last_us == 0 || timestamp_us <= last_us {
+            return None;
+        }
+
+        let interval_us = timestamp_us - last_us;
+        let is_gap = self.gap_start_us.take().is_some()
+            || self
+                .gap_threshold_us(gap_intervals)
+                .is_some_and(|threshold_us| interval_us > threshold_us);
+        if is_gap {
+            // Don't let the gap make the usual interval longer
+            return Some(interval_us);
+        }
+
+        let average = self.data_interval_us.get_or_insert(interval_us as f32);
+        *average += (interval_us as f32 - *average) * DATA_INTERVAL_SMOOTHING;
+        None
+    }
+
+    /// Checks the new raw orientation against the last one for a stuck or noisy IMU, returns true if
+    /// the suspect reason changed
+    pub fn check_anomalies(&mut self, orientation: SensorQuat, config: &AnomalyConfig) -> bool {
+        let (orientation, last) = (orientation.0, self.raw_data.orientation.0);
+        // Compare the bits since a working IMU never gives exactly the same value for long
+        let identical =
+            orientation.to_array().map(f32::to_bits) == last.to_array().map(f32::to_bits);
+        self.identical_samples = if identical {
+            self.identical_samples.saturating_add(1)
+        } else {
+            0
+        };
+
+        let jumped = last.angle_between(orientation) > config.noise_angle_deg.to_radians();
+        self.noise_streak = match (jumped, self.noise_streak) {
+            (true, streak) if streak > 0 => streak.saturating_add(1),
+            (true, _) => 1,
+            (false, streak) if streak < 0 => streak.saturating_sub(1),
+            (false, _) => -1,
+        };
+
+        let suspect = if config.stuck_samples != 0 && self.identical_samples >= config.stuck_samples
+        {
+            Some(SuspectReason::Stuck)
+        } else if config.noisy_samples != 0 && self.noise_streak >= config.noisy_samples as i32 {
+            Some(SuspectReason::Noisy)
+        } else if self.info.suspect == Some(SuspectReason::Noisy)
+            && -self.noise_streak < config.noisy_samples as i32
+        {
+            // Stay noisy until it's been calm for a while so it doesn't flap
+            Some(SuspectReason::Noisy)
+        } else {
+            None
+        };
+
+        let changed = suspect != self.info.suspect;
+        self.info.suspect = suspect;
+        changed
+    }
+
+    /// Sets the orientation and the angular velocity from the last one, returns false if it was
+    /// dropped for turning too fast which keeps the last orientation and timestamp
+    pub fn update_orientation(
+        &mut self,
+        orientation: WorldQuat,
+        timestamp_us: u64,
+        config: &AnomalyConfig,
+    ) -> bool {
+        let interval_us = timestamp_us.saturating_sub(self.data.timestamp_us);
+        let angular_velocity = if interval_us > 0 && interval_us < MAX_ANGULAR_VELOCITY_INTERVAL_US
+        {
+            rotation_between(self.data.orientation, orientation) / (interval_us as f32 / 1_000_000.)
+        } else {
+            glam::Vec3A::ZERO
+        };
+
+        // A single corrupt packet can flip the tracker around for a frame so keep the last one
+        if self.check_orientation_glitch(angular_velocity, config) {
+            return false;
+        }
+
+        self.data.angular_velocity = angular_velocity;
+        self.data.orientation = orientation;
+        self.data.timestamp_us = timestamp_us;
+        true
+    }
+
+    /// Returns true if there were more orientation glitches since the last call
+    pub fn take_new_glitches(&mut self) -> bool {
+        let changed = self.info.orientation_glitches != self.reported_glitches;
+        self.reported_glitches = self.info.orientation_glitches;
+        changed
+    }
+
+    /// Checks the angular velocity from the last orientation to a new one, returns true if the new
+    /// one should be dropped for turning faster than the max angular rate
+    fn check_orientation_glitch(
+        &mut self,
+        angular_velocity: glam::Vec3A,
+        config: &AnomalyConfig,
+    ) -> bool {
+        let too_fast = config.enabled
+            && config.max_angular_rate_deg > 0.
+            && angular_velocity.length().to_degrees() > config.max_angular_rate_deg;
+        if !too_fast || self.glitch_streak >= MAX_GLITCH_STREAK {
+            self.glitch_streak = 0;
+            return false;
+        }
+
+        self.glitch_streak += 1;
+        self.info.orientation_glitches = self.info.orientation_glitches.saturating_add(1);
+        true
+    }
+
+    /// Nudges the yaw so the difference to the reference yaw stays the same as when first measured
+    pub fn correct_yaw(&mut self, reference_yaw: f32, max_step: f32) {
+        let difference = wrap_angle(self.data.orientation.yaw() - reference_yaw);
+        let target = *self.reference_yaw_difference.get_or_insert(difference);
+        let step = wrap_angle(target - difference).clamp(-max_step, max_step);
+
+        self.yaw_offset = wrap_angle(self.yaw_offset + step);
+        self.data.orientation = self.data.orientation.rotate_yaw(step);
+    }
+
+    /// Turns the tracker so it faces forwards, the yaw correction then keeps it relative to that
+    pub fn reset_yaw(&mut self) {
+        self.yaw_offset = -self.raw_data.orientation.yaw();
+        self.reference_yaw_difference = None;
+        self.data.orientation = self.raw_data.orientation.to_world(self.yaw_offset);
+    }
+
+    /// Adds the current data to the history, dropping the oldest data past the length
+    pub fn record_history(&mut self, length: usize) {
+        self.history.record(&self.data, length);
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn history(&self) -> TrackerHistory {
+        self.history.clone()
+    }
+
+    /// Clears the velocity and acceleration so that stale data doesn't move the tracker
+    pub fn reset_motion(&mut self) {
+        self.acceleration_input = AccelMps2::ZERO;
+        self.data.acceleration = AccelMps2::ZERO;
+        self.data.velocity = glam::Vec3A::ZERO;
+        self.position_kalman.reset_motion();
     }
 }
 
+/// Trackers stored by their index where removed trackers leave a hole so other indices don't change
+#[derive(Default)]
+pub struct TrackerList(Vec<Option<Tracker>>);
+
+impl TrackerList {
+    pub fn get(&self, index: usize) -> Option<&Tracker> {
+        self.0.get(index)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Tracker> {
+        self.0.get_mut(index)?.as_mut()
+    }
+
+    /// Inserts the tracker at its index, replacing any tracker that was there
+    pub fn insert(&mut self, tracker: Tracker) {
+        let index = tracker.info.index;
+        if index >= self.0.len() {
+            self.0.resize_with(index + 1, || None);
+        }
+
+        self.0[index] = Some(tracker);
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn remove(&mut self, index: usize) -> Option<Tracker> {
+        self.0.get_mut(index)?.take()
+    }
+
+    /// Index after the highest index that has been used
+    pub fn next_index(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tracker> {
+        self.0.iter().flatten()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Tracker> {
+        self.0.iter_mut().flatten()
+    }
+
+    /// Runs the function on every tracker on the pool's threads, each tracker is only given to one
+    /// thread so the result is the same as running it in a loop
+    #[cfg(feature = "parallel")]
+    pub fn par_for_each_mut(
+        &mut self,
+        pool: &rayon::ThreadPool,
+        f: impl Fn(&mut Tracker) + Sync + Send,
+    ) {
+        use rayon::prelude::*;
+        pool.install(|| self.0.par_iter_mut().flatten().for_each(f));
+    }
+}
+
+impl IntoIterator for TrackerList {
+    type Item = Tracker;
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<Option<Tracker>>>;
+
+    /// In the order of their indices
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter().flatten()
+    }
+}
+
+#[derive(Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub enum PositionFilter {
+    /// Integrate the velocity to get the position
+    #[default]
+    Integration,
+    Kalman(KalmanConfig),
+}
+
 /// Seperate from TrackerInfo to be used to save to a file
-#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct TrackerConfig {
     pub name: String,
     pub location: TrackerLocation,
+    pub side: TrackerSide,
+    /// Estimate the position from the acceleration, otherwise only the orientation is used which
+    /// saves the filtering for trackers where the position doesn't matter
+    pub estimate_position: bool,
+    pub position_filter: PositionFilter,
+    /// Time constant of the acceleration's low pass filter in seconds, 0 means no smoothing
+    pub accel_smoothing_secs: f32,
+    /// Accelerations smaller than this in m/s^2 after removing gravity are treated as 0 so the
+    /// noise from a resting tracker doesn't make it drift
+    pub accel_deadzone: f32,
+    /// Trust this tracker's heading and correct the yaw drift of the others towards it
+    pub is_yaw_reference: bool,
+    /// Added to the estimated position to line up trackers from different capture volumes
+    pub position_offset: glam::Vec3A,
+    /// Output routes can select trackers by this, empty means no group
+    pub group: String,
+    /// Multiplies each axis of the acceleration after the bias is taken away, from
+    /// CalibrateAccelScale
+    pub accel_scale: glam::Vec3A,
+    /// Taken away from each axis of the acceleration the tracker sends
+    pub accel_bias: AccelMps2,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            location: TrackerLocation::default(),
+            side: TrackerSide::default(),
+            estimate_position: false,
+            position_filter: PositionFilter::default(),
+            accel_smoothing_secs: 0.,
+            accel_deadzone: 0.,
+            is_yaw_reference: false,
+            position_offset: glam::Vec3A::ZERO,
+            group: String::new(),
+            accel_scale: glam::Vec3A::ONE,
+            accel_bias: AccelMps2::ZERO,
+        }
+    }
+}
+
+impl TrackerConfig {
+    pub fn builder(name: impl Into<String>) -> TrackerConfigBuilder {
+        TrackerConfigBuilder {
+            config: Self {
+                name: name.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Checks that the values make sense
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.accel_smoothing_secs.is_finite() && self.accel_smoothing_secs >= 0.) {
+            return Err(ConfigError::new(
+                "accel_smoothing_secs",
+                "must be a finite number that's at least 0",
+            ));
+        }
+
+        if !(self.accel_deadzone.is_finite() && self.accel_deadzone >= 0.) {
+            return Err(ConfigError::new(
+                "accel_deadzone",
+                "must be a finite number that's at least 0",
+            ));
+        }
+
+        if !self.position_offset.is_finite() {
+            return Err(ConfigError::new("position_offset", "must be finite"));
+        }
+
+        if !(self.accel_scale.is_finite() && self.accel_scale.cmpgt(glam::Vec3A::ZERO).all()) {
+            return Err(ConfigError::new("accel_scale", "must be greater than 0"));
+        }
+
+        if !self.accel_bias.0.is_finite() {
+            return Err(ConfigError::new("accel_bias", "must be finite"));
+        }
+
+        if let PositionFilter::Kalman(kalman) = &self.position_filter {
+            kalman
+                .validate()
+                .map_err(|error| error.in_field("position_filter"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Corrects the acceleration with the scale and bias from the calibration
+    pub fn correct_acceleration(&self, acceleration: AccelMps2) -> AccelMps2 {
+        AccelMps2((acceleration.0 - self.accel_bias.0) * self.accel_scale)
+    }
+}
+
+/// Builds a [`TrackerConfig`] with the defaults for anything not set, validating it at the end
+pub struct TrackerConfigBuilder {
+    config: TrackerConfig,
+}
+
+impl TrackerConfigBuilder {
+    pub fn location(mut self, location: TrackerLocation) -> Self {
+        self.config.location = location;
+        self
+    }
+
+    /// Defaults to neither side
+    pub fn side(mut self, side: TrackerSide) -> Self {
+        self.config.side = side;
+        self
+    }
+
+    /// Defaults to off
+    pub fn estimate_position(mut self, estimate_position: bool) -> Self {
+        self.config.estimate_position = estimate_position;
+        self
+    }
+
+    /// Defaults to integrating the velocity
+    pub fn position_filter(mut self, position_filter: PositionFilter) -> Self {
+        self.config.position_filter = position_filter;
+        self
+    }
+
+    /// In seconds, defaults to 0 which is no smoothing
+    pub fn accel_smoothing_secs(mut self, accel_smoothing_secs: f32) -> Self {
+        self.config.accel_smoothing_secs = accel_smoothing_secs;
+        self
+    }
+
+    /// In m/s^2, defaults to 0 which is no deadzone
+    pub fn accel_deadzone(mut self, accel_deadzone: f32) -> Self {
+        self.config.accel_deadzone = accel_deadzone;
+        self
+    }
+
+    pub fn yaw_reference(mut self, is_yaw_reference: bool) -> Self {
+        self.config.is_yaw_reference = is_yaw_reference;
+        self
+    }
+
+    /// Defaults to no offset
+    pub fn position_offset(mut self, position_offset: glam::Vec3A) -> Self {
+        self.config.position_offset = position_offset;
+        self
+    }
+
+    /// Defaults to a scale of 1 and no bias
+    pub fn accel_calibration(mut self, scale: glam::Vec3A, bias: AccelMps2) -> Self {
+        self.config.accel_scale = scale;
+        self.config.accel_bias = bias;
+        self
+    }
+
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.config.group = group.into();
+        self
+    }
+
+    pub fn build(self) -> Result<TrackerConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time between the samples like a tracker sending at 200hz
+    const INTERVAL_US: u64 = 5_000;
+
+    fn tracker() -> Tracker {
+        Tracker::new("test".to_string(), 0, TrackerConfig::default())
+    }
+
+    fn yaw(degrees: f32) -> WorldQuat {
+        WorldQuat(glam::Quat::from_rotation_z(degrees.to_radians()))
+    }
+
+    #[test]
+    fn status_follows_streaks_of_valid_and_invalid_data() {
+        use TrackerStatus::*;
+
+        let config = StatusRecoveryConfig {
+            enabled: true,
+            valid_packets: 3,
+            invalid_packets: 2,
+        };
+        // Starting status, data where v is valid and x is invalid, status after each packet and
+        // the streak at the end
+        let cases = [
+            (Ok, "xx", [Ok, Error].as_slice(), 0),
+            (Ok, "xvx", &[Ok, Ok, Ok], -1),
+            (Ok, "vvvv", &[Ok, Ok, Ok, Ok], 4),
+            (Error, "vvv", &[Error, Error, Ok], 0),
+            (Error, "vvxvv", &[Error, Error, Error, Error, Error], 2),
+            // The streak starts again after changing so it takes the full count to change back
+            (Ok, "xxvvv", &[Ok, Error, Error, Error, Ok], 0),
+            (Ok, "xxxvvvx", &[Ok, Error, Error, Error, Error, Ok, Ok], -1),
+            (Error, "vvvxxx", &[Error, Error, Ok, Ok, Error, Error], -1),
+            // Only data decides between Ok and Error
+            (TimedOut, "xxxvvv", &[TimedOut; 6], 3),
+            (Off, "xxx", &[Off; 3], -3),
+        ];
+
+        for (start, data, expected, streak) in cases {
+            let mut tracker = tracker();
+            tracker.info.status = start;
+            let mut statuses = Vec::new();
+            for packet in data.chars() {
+                let before = tracker.info.status;
+                let changed = tracker.update_status_from_data(packet == 'v', &config);
+                assert_eq!(changed, tracker.info.status != before, "{start:?} {data}");
+                statuses.push(tracker.info.status);
+            }
+
+            assert_eq!(statuses, expected, "{start:?} {data}");
+            assert_eq!(tracker.data_validity_streak, streak, "{start:?} {data}");
+        }
+    }
+
+    #[test]
+    fn statuses_are_serialized_by_name() {
+        assert_eq!(
+            serde_json::to_value(TrackerStatus::Unknown).unwrap(),
+            serde_json::json!("Unknown")
+        );
+        let status: TrackerStatus = serde_json::from_str(r#""Unknown""#).unwrap();
+        assert_eq!(status, TrackerStatus::Unknown);
+
+        let info = serde_json::to_value(&tracker().info).unwrap();
+        assert_eq!(info["status"], "Unknown");
+    }
+
+    #[test]
+    fn fast_real_motion_passes() {
+        let mut tracker = tracker();
+        let config = AnomalyConfig::default();
+        // 2000 deg/s is already faster than a person can swing their arm
+        for i in 1..=20 {
+            let orientation = yaw(i as f32 * 10.);
+            assert!(tracker.update_orientation(orientation, i * INTERVAL_US, &config));
+            assert_eq!(tracker.data.orientation, orientation);
+        }
+
+        let rate = tracker.data.angular_velocity.length().to_degrees();
+        assert!((rate - 2000.).abs() < 1., "{rate}");
+        assert_eq!(tracker.info.orientation_glitches, 0);
+    }
+
+    #[test]
+    fn one_packet_flip_is_held() {
+        let mut tracker = tracker();
+        let config = AnomalyConfig::default();
+        tracker.update_orientation(yaw(0.), INTERVAL_US, &config);
+
+        assert!(!tracker.update_orientation(yaw(170.), 2 * INTERVAL_US, &config));
+        assert_eq!(tracker.data.orientation, yaw(0.));
+        assert_eq!(tracker.data.timestamp_us, INTERVAL_US);
+
+        // The next good sample carries on from the last one that was kept
+        assert!(tracker.update_orientation(yaw(1.), 3 * INTERVAL_US, &config));
+        assert_eq!(tracker.data.timestamp_us, 3 * INTERVAL_US);
+        assert_eq!(tracker.info.orientation_glitches, 1);
+
+        assert!(tracker.take_new_glitches());
+        assert!(!tracker.take_new_glitches());
+    }
+
+    #[test]
+    fn sustained_flip_is_accepted() {
+        let mut tracker = tracker();
+        let config = AnomalyConfig::default();
+        tracker.update_orientation(yaw(0.), INTERVAL_US, &config);
+
+        // The tracker really turned if it stays there
+        for i in 0..MAX_GLITCH_STREAK as u64 {
+            assert!(!tracker.update_orientation(yaw(170.), (2 + i) * INTERVAL_US, &config));
+        }
+        let timestamp_us = (2 + MAX_GLITCH_STREAK as u64) * INTERVAL_US;
+        assert!(tracker.update_orientation(yaw(170.), timestamp_us, &config));
+        assert_eq!(tracker.data.orientation, yaw(170.));
+        assert_eq!(tracker.info.orientation_glitches, MAX_GLITCH_STREAK);
+
+        assert!(tracker.update_orientation(yaw(171.), timestamp_us + INTERVAL_US, &config));
+    }
+
+    #[test]
+    fn gap_resets_the_angular_velocity() {
+        let mut tracker = tracker();
+        let config = AnomalyConfig::default();
+        tracker.update_orientation(yaw(0.), INTERVAL_US, &config);
+        assert!(!tracker.update_orientation(yaw(170.), 2 * INTERVAL_US, &config));
+
+        // Too long since the last sample to tell how fast it turned
+        let timestamp_us = INTERVAL_US + MAX_ANGULAR_VELOCITY_INTERVAL_US;
+        assert!(tracker.update_orientation(yaw(170.), timestamp_us, &config));
+        assert_eq!(tracker.data.angular_velocity, glam::Vec3A::ZERO);
+
+        // The streak started again so a single flip is held again
+        assert!(!tracker.update_orientation(yaw(0.), timestamp_us + INTERVAL_US, &config));
+    }
+
+    /// Acceleration after a step from 0 to 1 m/s^2 lasting a second at the tick rate
+    fn smoothed_step(accel_smoothing_secs: f32, tick_rate: u32) -> f32 {
+        let mut tracker = tracker();
+        tracker.info.config.accel_smoothing_secs = accel_smoothing_secs;
+        tracker.acceleration_input = AccelMps2(glam::Vec3A::X);
+        for _ in 0..tick_rate {
+            tracker.tick(Duration::from_secs(1) / tick_rate);
+        }
+        tracker.data.acceleration.0.x
+    }
+
+    #[test]
+    fn accel_smoothing_is_a_time_constant() {
+        assert_eq!(smoothed_step(0., 50), 1.);
+
+        // A time constant of a second gets 1 - 1/e of the way there in a second
+        let expected = 1. - (-1_f32).exp();
+        for tick_rate in [50, 60, 200] {
+            let smoothed = smoothed_step(1., tick_rate);
+            assert!(
+                (smoothed - expected).abs() < 1e-4,
+                "{tick_rate}hz: {smoothed}"
+            );
+        }
+
+        assert!(smoothed_step(0.1, 50) > 0.99);
+    }
+
+    #[test]
+    fn accel_smoothing_must_be_a_positive_time() {
+        for accel_smoothing_secs in [-0.1, f32::NAN, f32::INFINITY] {
+            let config = TrackerConfig {
+                accel_smoothing_secs,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{accel_smoothing_secs}");
+        }
+
+        let config = TrackerConfig {
+            accel_smoothing_secs: 2.,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn builder_validates_every_field() {
+        let nan = glam::Vec3A::splat(f32::NAN);
+        let kalman = |config: KalmanConfig| PositionFilter::Kalman(config);
+        let cases: Vec<(TrackerConfigBuilder, Option<&str>)> = vec![
+            (TrackerConfig::builder("default"), None),
+            (
+                TrackerConfig::builder("everything")
+                    .location(TrackerLocation::Foot)
+                    .side(TrackerSide::Left)
+                    .estimate_position(true)
+                    .position_filter(kalman(KalmanConfig::default()))
+                    .accel_smoothing_secs(0.2)
+                    .accel_deadzone(0.05)
+                    .yaw_reference(true)
+                    .position_offset(glam::Vec3A::new(1., -2., 0.5))
+                    .accel_calibration(glam::Vec3A::splat(1.02), AccelMps2(glam::Vec3A::X))
+                    .group("legs"),
+                None,
+            ),
+            (
+                TrackerConfig::builder("negative smoothing").accel_smoothing_secs(-1.),
+                Some("accel_smoothing_secs"),
+            ),
+            (
+                TrackerConfig::builder("infinite smoothing").accel_smoothing_secs(f32::INFINITY),
+                Some("accel_smoothing_secs"),
+            ),
+            (
+                TrackerConfig::builder("negative deadzone").accel_deadzone(-0.1),
+                Some("accel_deadzone"),
+            ),
+            (
+                TrackerConfig::builder("nan deadzone").accel_deadzone(f32::NAN),
+                Some("accel_deadzone"),
+            ),
+            (
+                TrackerConfig::builder("nan offset").position_offset(nan),
+                Some("position_offset"),
+            ),
+            (
+                TrackerConfig::builder("zero scale")
+                    .accel_calibration(glam::Vec3A::new(1., 0., 1.), AccelMps2::ZERO),
+                Some("accel_scale"),
+            ),
+            (
+                TrackerConfig::builder("nan scale").accel_calibration(nan, AccelMps2::ZERO),
+                Some("accel_scale"),
+            ),
+            (
+                TrackerConfig::builder("nan bias")
+                    .accel_calibration(glam::Vec3A::ONE, AccelMps2(nan)),
+                Some("accel_bias"),
+            ),
+            (
+                TrackerConfig::builder("zero process noise").position_filter(kalman(
+                    KalmanConfig {
+                        process_noise: 0.,
+                        ..Default::default()
+                    },
+                )),
+                Some("position_filter.process_noise"),
+            ),
+            (
+                TrackerConfig::builder("negative zupt threshold").position_filter(kalman(
+                    KalmanConfig {
+                        zupt_threshold: -1.,
+                        ..Default::default()
+                    },
+                )),
+                Some("position_filter.zupt_threshold"),
+            ),
+        ];
+
+        for (builder, invalid_field) in cases {
+            let name = builder.config.name.clone();
+            match (builder.build(), invalid_field) {
+                (Ok(config), None) => assert_eq!(config.name, name),
+                (Err(error), Some(field)) => assert_eq!(error.field, field),
+                (result, _) => panic!(
+                    "{name} expected {invalid_field:?} got {:?}",
+                    result.err().map(|error| error.field)
+                ),
+            }
+        }
+    }
+
+    /// Sends data at the usual interval until the timestamp, returning the gaps that were found
+    fn send_data_until(tracker: &mut Tracker, end_us: u64, gap_intervals: u32) -> Vec<u64> {
+        let mut gaps = Vec::new();
+        let mut timestamp_us = tracker.raw_data.timestamp_us;
+        while timestamp_us < end_us {
+            timestamp_us += INTERVAL_US;
+            gaps.extend(tracker.record_data_interval(timestamp_us, gap_intervals));
+            tracker.raw_data.timestamp_us = timestamp_us;
+        }
+        gaps
+    }
+
+    #[test]
+    fn data_gaps_are_longer_than_the_usual_interval() {
+        let mut tracker = tracker();
+        tracker.raw_data.timestamp_us = INTERVAL_US;
+        assert!(send_data_until(&mut tracker, 100 * INTERVAL_US, 10).is_empty());
+        assert_eq!(tracker.data_interval_us, Some(INTERVAL_US as f32));
+
+        // Just under the threshold is only jitter
+        let last_us = tracker.raw_data.timestamp_us;
+        assert_eq!(
+            tracker.record_data_interval(last_us + 9 * INTERVAL_US, 10),
+            None
+        );
+        tracker.raw_data.timestamp_us = last_us + 9 * INTERVAL_US;
+
+        let last_us = tracker.raw_data.timestamp_us;
+        let gap_us = 20 * INTERVAL_US;
+        assert_eq!(
+            tracker.record_data_interval(last_us + gap_us, 10),
+            Some(gap_us)
+        );
+        // The gap doesn't get averaged into the usual interval
+        assert!(tracker.data_interval_us.unwrap() < 1.5 * INTERVAL_US as f32);
+
+        // Detecting gaps can be turned off
+        let last_us = last_us + gap_us;
+        assert_eq!(tracker.record_data_interval(last_us + gap_us, 0), None);
+    }
+
+    #[test]
+    fn silence_starts_a_gap_before_the_data_comes_back() {
+        let mut tracker = tracker();
+        tracker.raw_data.timestamp_us = INTERVAL_US;
+        send_data_until(&mut tracker, 100 * INTERVAL_US, 10);
+        let last_us = tracker.raw_data.timestamp_us;
+
+        tracker.check_data_gap(last_us + 5 * INTERVAL_US, 10);
+        assert_eq!(tracker.gap_start_us, None);
+        tracker.check_data_gap(last_us + 11 * INTERVAL_US, 10);
+        assert_eq!(tracker.gap_start_us, Some(last_us));
+
+        // The gap ends with the next data however long it's been
+        let gap_us = 12 * INTERVAL_US;
+        assert_eq!(
+            tracker.record_data_interval(last_us + gap_us, 10),
+            Some(gap_us)
+        );
+        assert_eq!(tracker.gap_start_us, None);
+    }
+
+    /// Feeds the raw orientations in like update_tracker_data does, returning the samples that
+    /// changed the suspect reason along with what it changed to
+    fn check_samples(
+        tracker: &mut Tracker,
+        orientations: impl IntoIterator<Item = glam::Quat>,
+        config: &AnomalyConfig,
+    ) -> Vec<(usize, Option<SuspectReason>)> {
+        let mut changes = Vec::new();
+        for (i, orientation) in orientations.into_iter().enumerate() {
+            if tracker.check_anomalies(SensorQuat(orientation), config) {
+                changes.push((i, tracker.info.suspect));
+            }
+            tracker.raw_data.orientation = SensorQuat(orientation);
+        }
+        changes
+    }
+
+    fn anomaly_config() -> AnomalyConfig {
+        AnomalyConfig {
+            stuck_samples: 20,
+            noisy_samples: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn identical_samples_are_a_stuck_imu() {
+        let mut tracker = tracker();
+        let config = anomaly_config();
+        let stuck = std::iter::repeat_n(glam::Quat::from_rotation_x(0.3), 30);
+        let moving = (1..10).map(|i| glam::Quat::from_rotation_x(0.3 + i as f32 * 0.01));
+
+        let changes = check_samples(&mut tracker, stuck.chain(moving), &config);
+        // The first sample is different from the default orientation
+        assert_eq!(changes, [(20, Some(SuspectReason::Stuck)), (30, None)]);
+    }
+
+    #[test]
+    fn noisy_imu_has_to_calm_down_before_it_clears() {
+        let mut tracker = tracker();
+        let config = anomaly_config();
+        let jumping = (0..15).map(|i| glam::Quat::from_rotation_z(i as f32 * 1.5));
+        let calm = (0..15).map(|i| glam::Quat::from_rotation_z(i as f32 * 0.01));
+
+        // The first jumping sample is the default orientation and the first calm one still jumps
+        let changes = check_samples(&mut tracker, jumping.chain(calm), &config);
+        assert_eq!(changes, [(10, Some(SuspectReason::Noisy)), (25, None)]);
+    }
+
+    #[test]
+    fn real_motion_and_disabled_checks_are_never_suspect() {
+        let mut tracker = tracker();
+        let config = anomaly_config();
+        let turning = (0..200).map(|i| glam::Quat::from_rotation_y(i as f32 * 0.05));
+        assert!(check_samples(&mut tracker, turning, &config).is_empty());
+
+        let disabled = AnomalyConfig {
+            stuck_samples: 0,
+            noisy_samples: 0,
+            ..Default::default()
+        };
+        let stuck = std::iter::repeat_n(glam::Quat::IDENTITY, 100);
+        let jumping = (0..100).map(|i| glam::Quat::from_rotation_z(i as f32 * 1.5));
+        let samples = stuck.chain(jumping);
+        assert!(check_samples(&mut tracker, samples, &disabled).is_empty());
+    }
 }
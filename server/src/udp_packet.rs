@@ -1,24 +1,109 @@
-use std::time::Instant;
+use std::{collections::BTreeMap, time::Instant};
 
+use crate::config::PacketOrderPolicy;
+use crate::device_error::DeviceErrorCode;
+use crate::input::InputKind;
+#[cfg(feature = "websocket")]
+use crate::messages::CodedMessage;
 use crate::tracker::TrackerStatus;
 use crate::udp_server::UdpDevice;
+use crate::units::{AccelMps2, SensorQuat};
+use crate::warning_aggregator::WarningAggregator;
 
 pub const PACKET_PING_PONG: u8 = 0x00;
 pub const PACKET_HANDSHAKE: u8 = 0x01;
 pub const PACKET_TRACKER_STATUS: u8 = 0x02;
 pub const PACKET_TRACKER_DATA: u8 = 0x03;
+pub const PACKET_SERVER_ANNOUNCE: u8 = 0x04;
+pub const PACKET_GET_CONFIG: u8 = 0x05;
+pub const PACKET_SET_CONFIG_KV: u8 = 0x06;
+pub const PACKET_HANDSHAKE_REQUEST: u8 = 0x07;
+pub const PACKET_DEVICE_ERROR: u8 = 0x08;
+pub const PACKET_SERVER_FULL: u8 = 0x09;
+pub const PACKET_INPUT_EVENT: u8 = 0x0a;
+pub const PACKET_SERVER_PROBE: u8 = 0x0b;
+pub const PACKET_SERVER_INFO: u8 = 0x0c;
+pub const PACKET_RAW_SENSOR_DATA: u8 = 0x0d;
+pub const PACKET_BATTERY_LEVEL: u8 = 0x0e;
+pub const PACKET_SERVER_SHUTDOWN: u8 = 0x0f;
+pub const PACKET_REQUEST_STATUS: u8 = 0x10;
+
+/// Longest version string that fits in a server info packet
+pub const MAX_SERVER_VERSION_LENGTH: usize = 32;
+
+/// Limits from the firmware so that a set config packet fits in its receive buffer
+pub const MAX_CONFIG_KEY_LENGTH: usize = 16;
+pub const MAX_CONFIG_VALUE_LENGTH: usize = 32;
+pub const MAX_CONFIG_ENTRIES: usize = 16;
+pub const MAX_DEVICE_ERROR_DETAIL_LENGTH: usize = 48;
+
+/// Latest packet number received from a device and which of the ones just before it arrived too
+#[derive(Default)]
+pub struct PacketNumbers {
+    latest: u32,
+    /// Bit i is set if the packet i before the latest was received
+    seen: u64,
+}
+
+impl PacketNumbers {
+    pub fn latest(&self) -> u32 {
+        self.latest
+    }
+
+    /// The device starts counting again after it handshakes
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether to handle a packet with this number, the numbers wrap around so anything up to half
+    /// the range ahead of the latest is newer
+    pub fn accept(&mut self, number: u32, policy: PacketOrderPolicy) -> bool {
+        // Nothing has been received yet so any number can start off the count
+        let first = self.seen == 0;
+        let ahead = number.wrapping_sub(self.latest);
+        if first || (ahead != 0 && ahead <= u32::MAX / 2) {
+            self.seen = self.seen.checked_shl(ahead).unwrap_or(0) | 1;
+            self.latest = number;
+            return true;
+        }
+
+        let behind = self.latest.wrapping_sub(number);
+        match policy {
+            PacketOrderPolicy::StrictDrop => false,
+            PacketOrderPolicy::AcceptAll => true,
+            PacketOrderPolicy::ReorderWindow(window) => {
+                if behind == 0 || behind > window || behind >= u64::BITS {
+                    return false;
+                }
+
+                // Only the first copy of a packet gets handled
+                let bit = 1 << behind;
+                let duplicate = self.seen & bit != 0;
+                self.seen |= bit;
+                !duplicate
+            }
+        }
+    }
+}
 
 pub enum UdpPacket<'a> {
     Handshake(UdpPacketHandshake),
     TrackerData((UdpPacketTrackerData<'a>, &'a mut UdpDevice)),
     TrackerStatus((UdpPacketTrackerStatus, &'a mut UdpDevice)),
     PingPong((UdpPacketPingPong, &'a mut UdpDevice)),
+    DeviceConfig((UdpPacketDeviceConfig, &'a mut UdpDevice)),
+    DeviceError((UdpPacketDeviceError, &'a mut UdpDevice)),
+    InputEvent((UdpPacketInputEvent, &'a mut UdpDevice)),
+    RawSensorData((UdpPacketRawSensorData, &'a mut UdpDevice)),
+    BatteryLevel((UdpPacketBatteryLevel, &'a mut UdpDevice)),
 }
 
 impl<'a> UdpPacket<'a> {
     pub fn parse(
         bytes: &'a mut std::slice::Iter<'a, u8>,
         mut device: Option<&'a mut UdpDevice>,
+        order_policy: PacketOrderPolicy,
+        warnings: &mut WarningAggregator,
     ) -> Option<Self> {
         let packet_type = *bytes.next()?;
 
@@ -27,17 +112,19 @@ impl<'a> UdpPacket<'a> {
                 // These packets don't send a packet number so they will never be discarded
                 PACKET_HANDSHAKE | PACKET_PING_PONG => (),
                 _ => {
-                    // Discard the packet if not the latest
                     let packet_number = u32_parse(bytes)?;
-                    if packet_number <= device.last_packet_number {
-                        log::warn!("Received out of order packet {packet_number}");
+                    if !device.packet_numbers.accept(packet_number, order_policy) {
+                        warnings.warn("Received out of order packet", &device.mac);
                         return None;
                     }
-
-                    device.last_packet_number = packet_number;
                 }
             };
 
+            // Anything other than a handshake means the device got the handshake response
+            if packet_type != PACKET_HANDSHAKE {
+                device.unanswered_handshakes = 0;
+            }
+
             device.last_packet_received_time = Instant::now();
         }
 
@@ -50,29 +137,73 @@ impl<'a> UdpPacket<'a> {
             PACKET_TRACKER_STATUS => {
                 Self::TrackerStatus((UdpPacketTrackerStatus::from_bytes(bytes)?, device?))
             }
+            PACKET_GET_CONFIG => {
+                Self::DeviceConfig((UdpPacketDeviceConfig::from_bytes(bytes)?, device?))
+            }
+            PACKET_DEVICE_ERROR => {
+                Self::DeviceError((UdpPacketDeviceError::from_bytes(bytes)?, device?))
+            }
+            PACKET_INPUT_EVENT => {
+                Self::InputEvent((UdpPacketInputEvent::from_bytes(bytes)?, device?))
+            }
+            PACKET_RAW_SENSOR_DATA => {
+                Self::RawSensorData((UdpPacketRawSensorData::from_bytes(bytes)?, device?))
+            }
+            PACKET_BATTERY_LEVEL => {
+                Self::BatteryLevel((UdpPacketBatteryLevel::from_bytes(bytes)?, device?))
+            }
             _ => return None,
         })
     }
 }
 
+/// Handshakes need to start with one of these to be from a mycap device
+const HANDSHAKE_MAGICS: [&[u8]; 1] = [b"MCDEV"];
+const MAX_VARIANT_LENGTH: usize = 16;
+
 pub struct UdpPacketHandshake {
     pub mac_string: String,
+    /// Firmware variant from a handshake like MCDEV-V2\0
+    pub variant: Option<String>,
 }
 
 impl UdpPacketHandshake {
     fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
-        if !next_equals(bytes, b"MCDEV") {
-            return None;
-        }
+        let magic = HANDSHAKE_MAGICS
+            .iter()
+            .find(|magic| bytes.as_slice().starts_with(magic))?;
+        bytes.nth(magic.len() - 1);
+
+        // A mac address can't start with '-' since that would make it a multicast address
+        let variant = if bytes.as_slice().first() == Some(&b'-') {
+            bytes.next();
+            let rest = bytes.as_slice();
+            let length = rest
+                .iter()
+                .take(MAX_VARIANT_LENGTH + 1)
+                .position(|byte| *byte == 0)?;
+            let variant = &rest[..length];
+            if !variant.iter().all(|byte| byte.is_ascii_graphic()) {
+                return None;
+            }
+
+            // Skip past the variant and the null byte
+            bytes.nth(length);
+            Some(String::from_utf8_lossy(variant).into_owned())
+        } else {
+            None
+        };
 
         #[rustfmt::skip]
-        let mac_string = format!(
-            "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
-            bytes.next()?, bytes.next()?, bytes.next()?,
-            bytes.next()?, bytes.next()?, bytes.next()?,
-        );
+        let mac_string = format_mac([
+            *bytes.next()?, *bytes.next()?, *bytes.next()?,
+            *bytes.next()?, *bytes.next()?, *bytes.next()?,
+        ]);
 
-        Some(Self { mac_string })
+        Some(Self {
+            mac_string,
+            variant,
+        })
     }
 
     pub const fn to_bytes() -> [u8; 6] {
@@ -81,17 +212,131 @@ impl UdpPacketHandshake {
     }
 }
 
+pub struct UdpPacketServerAnnounce;
+
+impl UdpPacketServerAnnounce {
+    pub const fn to_bytes() -> [u8; 6] {
+        // PACKET_SERVER_ANNOUNCE + MCSVR
+        [PACKET_SERVER_ANNOUNCE, b'M', b'C', b'S', b'V', b'R']
+    }
+}
+
+/// Sent by UIs looking for servers, devices never send this
+pub struct UdpPacketServerProbe;
+
+impl UdpPacketServerProbe {
+    pub const fn to_bytes() -> [u8; 6] {
+        // PACKET_SERVER_PROBE + MCCLI
+        [PACKET_SERVER_PROBE, b'M', b'C', b'C', b'L', b'I']
+    }
+
+    pub fn is_probe(bytes: &[u8]) -> bool {
+        bytes == Self::to_bytes()
+    }
+}
+
+/// Reply to a probe describing the server, PACKET_SERVER_INFO + MCSVR + instance id (u64) +
+/// websocket port (u16, 0 if there's no websocket server) + udp port (u16) + tls (u8) +
+/// version (u8 length + string)
+#[derive(Clone, PartialEq, Debug)]
+pub struct UdpPacketServerInfo {
+    /// Random for each run of the server so the same server found on two addresses can be merged
+    pub instance_id: u64,
+    pub websocket_port: u16,
+    pub udp_port: u16,
+    pub tls: bool,
+    pub version: String,
+}
+
+impl UdpPacketServerInfo {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let version = &self.version.as_bytes()[..self.version.len().min(MAX_SERVER_VERSION_LENGTH)];
+        let mut bytes = Vec::with_capacity(19 + version.len());
+        bytes.extend_from_slice(&[PACKET_SERVER_INFO, b'M', b'C', b'S', b'V', b'R']);
+        bytes.extend_from_slice(&self.instance_id.to_le_bytes());
+        bytes.extend_from_slice(&self.websocket_port.to_le_bytes());
+        bytes.extend_from_slice(&self.udp_port.to_le_bytes());
+        bytes.push(self.tls as u8);
+        bytes.push(version.len() as u8);
+        bytes.extend_from_slice(version);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(&[PACKET_SERVER_INFO, b'M', b'C', b'S', b'V', b'R'])?;
+        let mut bytes = bytes.iter();
+        Some(Self {
+            instance_id: u64_parse(&mut bytes)?,
+            websocket_port: u16_parse(&mut bytes)?,
+            udp_port: u16_parse(&mut bytes)?,
+            tls: *bytes.next()? != 0,
+            version: string_parse(&mut bytes, MAX_SERVER_VERSION_LENGTH)?,
+        })
+    }
+}
+
+/// Asks a device that is sending data without having handshaked to handshake again
+pub struct UdpPacketHandshakeRequest;
+
+impl UdpPacketHandshakeRequest {
+    pub const fn to_bytes() -> [u8; 6] {
+        // PACKET_HANDSHAKE_REQUEST + MCSVR
+        [PACKET_HANDSHAKE_REQUEST, b'M', b'C', b'S', b'V', b'R']
+    }
+}
+
+/// Tells a new device that it can't connect since the server has reached its device limit
+pub struct UdpPacketServerFull;
+
+impl UdpPacketServerFull {
+    pub const fn to_bytes() -> [u8; 6] {
+        // PACKET_SERVER_FULL + MCSVR
+        [PACKET_SERVER_FULL, b'M', b'C', b'S', b'V', b'R']
+    }
+}
+
+/// Asks a device to send the status of all its trackers, even the ones it thinks the server has
+pub struct UdpPacketRequestStatus;
+
+impl UdpPacketRequestStatus {
+    pub const fn to_bytes() -> [u8; 6] {
+        // PACKET_REQUEST_STATUS + MCSVR
+        [PACKET_REQUEST_STATUS, b'M', b'C', b'S', b'V', b'R']
+    }
+}
+
+/// Tells the devices the server is stopping so they wait to reconnect instead of sending into
+/// nothing
+pub struct UdpPacketServerShutdown;
+
+impl UdpPacketServerShutdown {
+    pub const fn to_bytes() -> [u8; 6] {
+        // PACKET_SERVER_SHUTDOWN + MCSVR
+        [PACKET_SERVER_SHUTDOWN, b'M', b'C', b'S', b'V', b'R']
+    }
+}
+
 pub struct UdpPacketPingPong {
     pub id: u8,
+    /// Device's clock in microseconds when it sent the pong, older firmware doesn't send it
+    pub device_time_us: Option<u64>,
 }
 
 impl UdpPacketPingPong {
     pub fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
-        Some(Self { id: *bytes.next()? })
+        Some(Self {
+            id: *bytes.next()?,
+            device_time_us: u64_parse(bytes),
+        })
     }
 
-    pub const fn to_bytes(id: u8) -> [u8; 2] {
-        [PACKET_PING_PONG, id]
+    /// Includes the server's clock so the device can work out the offset to its own
+    pub fn to_bytes(id: u8, server_time_us: u64) -> [u8; 10] {
+        let mut bytes = [0; 10];
+        bytes[0] = PACKET_PING_PONG;
+        bytes[1] = id;
+        bytes[2..].copy_from_slice(&server_time_us.to_le_bytes());
+        bytes
     }
 }
 
@@ -123,11 +368,126 @@ impl UdpPacketTrackerStatus {
     }
 }
 
+/// The settings stored on the device, sent back after a get config or set config packet
+/// Each entry is a length prefixed key followed by a length prefixed value
+#[derive(Debug)]
+pub struct UdpPacketDeviceConfig {
+    pub entries: BTreeMap<String, String>,
+}
+
+impl UdpPacketDeviceConfig {
+    fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
+        let count = *bytes.next()? as usize;
+        if count > MAX_CONFIG_ENTRIES {
+            return None;
+        }
+
+        let mut entries = BTreeMap::new();
+        for _ in 0..count {
+            let key = string_parse(bytes, MAX_CONFIG_KEY_LENGTH)?;
+            let value = string_parse(bytes, MAX_CONFIG_VALUE_LENGTH)?;
+            entries.insert(key, value);
+        }
+
+        Some(Self { entries })
+    }
+
+    /// Asks the device to send its config
+    pub const fn request_bytes() -> [u8; 1] {
+        [PACKET_GET_CONFIG]
+    }
+}
+
+/// Error code then a length prefixed detail string which is empty if there's no detail
+pub struct UdpPacketDeviceError {
+    pub code: DeviceErrorCode,
+    pub detail: Option<String>,
+}
+
+impl UdpPacketDeviceError {
+    fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
+        let code = DeviceErrorCode::from(*bytes.next()?);
+        let detail = string_parse(bytes, MAX_DEVICE_ERROR_DETAIL_LENGTH)?;
+        Some(Self {
+            code,
+            detail: (!detail.is_empty()).then_some(detail),
+        })
+    }
+}
+
+/// A button or axis on the device changed, input id (u8) + kind (u8) + value (f32)
+pub struct UdpPacketInputEvent {
+    pub input: u8,
+    pub kind: InputKind,
+    pub value: f32,
+}
+
+impl UdpPacketInputEvent {
+    fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
+        let input = *bytes.next()?;
+        let kind = InputKind::from_u8(*bytes.next()?)?;
+        let value = f32_parse(bytes)?;
+        value.is_finite().then_some(Self { input, kind, value })
+    }
+}
+
+/// Charge left in the device's battery in percent (f32)
+pub struct UdpPacketBatteryLevel {
+    pub percent: f32,
+}
+
+impl UdpPacketBatteryLevel {
+    fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
+        let percent = f32_parse(bytes)?;
+        (0. ..=100.).contains(&percent).then_some(Self { percent })
+    }
+}
+
+pub struct UdpPacketSetConfigKv<'a> {
+    pub key: &'a str,
+    pub value: &'a str,
+}
+
+impl UdpPacketSetConfigKv<'_> {
+    /// Checks that the device will be able to store the key and value
+    #[cfg(feature = "websocket")]
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.key.is_empty() || !self.key.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(CodedMessage::new("device_config_key_invalid").into());
+        }
+
+        if self.key.len() > MAX_CONFIG_KEY_LENGTH {
+            return Err(CodedMessage::new("device_config_key_too_long")
+                .param("max", MAX_CONFIG_KEY_LENGTH)
+                .into());
+        }
+
+        if self.value.len() > MAX_CONFIG_VALUE_LENGTH {
+            return Err(CodedMessage::new("device_config_value_too_long")
+                .param("max", MAX_CONFIG_VALUE_LENGTH)
+                .into());
+        }
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3 + self.key.len() + self.value.len());
+        bytes.push(PACKET_SET_CONFIG_KV);
+        for string in [self.key, self.value] {
+            bytes.push(string.len() as u8);
+            bytes.extend_from_slice(string.as_bytes());
+        }
+
+        bytes
+    }
+}
+
 #[derive(Debug)]
 pub struct UdpTrackerData {
     pub tracker_index: u8,
-    pub orientation: glam::Quat,
-    pub accleration: glam::Vec3A,
+    pub orientation: SensorQuat,
+    pub acceleration: AccelMps2,
 }
 
 pub struct UdpPacketTrackerData<'a> {
@@ -148,21 +508,136 @@ impl<'a> UdpPacketTrackerData<'a> {
 
         Some(UdpTrackerData {
             tracker_index,
-            orientation: glam::Quat::from_xyzw(
+            orientation: SensorQuat(glam::Quat::from_xyzw(
                 f32_parse(self.bytes)?,
                 f32_parse(self.bytes)?,
                 f32_parse(self.bytes)?,
                 f32_parse(self.bytes)?,
-            ),
-            accleration: glam::Vec3A::new(
+            )),
+            // The firmware sends it in m/s² with gravity already removed
+            acceleration: AccelMps2(glam::Vec3A::new(
                 f32_parse(self.bytes)?,
                 f32_parse(self.bytes)?,
                 f32_parse(self.bytes)?,
-            ),
+            )),
         })
     }
 }
 
+/// Sensor readings of a tracker from before the device's fusion, in the units the firmware reads
+/// them in
+#[derive(Debug)]
+pub struct RawSensorSample {
+    pub tracker_index: u8,
+    pub accel: Option<glam::Vec3>,
+    pub gyro: Option<glam::Vec3>,
+    pub mag: Option<glam::Vec3>,
+}
+
+impl RawSensorSample {
+    pub const ACCEL: u8 = 1 << 0;
+    pub const GYRO: u8 = 1 << 1;
+    pub const MAG: u8 = 1 << 2;
+    pub const COLUMNS: [(u8, &'static str); 3] = [
+        (Self::ACCEL, "ax,ay,az"),
+        (Self::GYRO, "gx,gy,gz"),
+        (Self::MAG, "mx,my,mz"),
+    ];
+
+    /// The flags of the sensors that are present
+    pub fn sensors(&self) -> u8 {
+        self.readings()
+            .into_iter()
+            .filter(|(_, reading)| reading.is_some())
+            .fold(0, |sensors, (flag, _)| sensors | flag)
+    }
+
+    /// Each sensor in the order of the columns with its flag
+    pub fn readings(&self) -> [(u8, Option<glam::Vec3>); 3] {
+        [
+            (Self::ACCEL, self.accel),
+            (Self::GYRO, self.gyro),
+            (Self::MAG, self.mag),
+        ]
+    }
+}
+
+/// Tracker index (u8, 0xff ends the packet) + sensor flags (u8) + x, y, z (f32) of each sensor in
+/// the flags in the order accel, gyro, mag
+pub struct UdpPacketRawSensorData {
+    pub samples: Vec<RawSensorSample>,
+}
+
+impl UdpPacketRawSensorData {
+    fn from_bytes(bytes: &mut std::slice::Iter<u8>) -> Option<Self> {
+        let mut samples = Vec::new();
+        loop {
+            let tracker_index = *bytes.next()?;
+            if tracker_index == 0xff {
+                return Some(Self { samples });
+            }
+
+            let sensors = *bytes.next()?;
+            let mut reading = |flag| -> Option<Option<glam::Vec3>> {
+                if sensors & flag == 0 {
+                    return Some(None);
+                }
+
+                Some(Some(glam::Vec3::new(
+                    f32_parse(bytes)?,
+                    f32_parse(bytes)?,
+                    f32_parse(bytes)?,
+                )))
+            };
+
+            samples.push(RawSensorSample {
+                tracker_index,
+                accel: reading(RawSensorSample::ACCEL)?,
+                gyro: reading(RawSensorSample::GYRO)?,
+                mag: reading(RawSensorSample::MAG)?,
+            });
+        }
+    }
+}
+
+pub fn format_mac(mac: [u8; 6]) -> String {
+    format!(
+        "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+/// Parses a user provided mac address like 0A:1b:02:... so it can be compared with [`format_mac`]
+pub fn parse_mac(string: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut parts = string.trim().split(':');
+    for byte in &mut mac {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(mac)
+}
+
+/// Parses a string prefixed by its length as a byte
+fn string_parse(bytes: &mut std::slice::Iter<u8>, max_length: usize) -> Option<String> {
+    let length = *bytes.next()? as usize;
+    if length > max_length {
+        return None;
+    }
+
+    let string = std::str::from_utf8(bytes.as_slice().get(..length)?).ok()?;
+    let string = string.to_string();
+    if length > 0 {
+        bytes.nth(length - 1);
+    }
+
+    Some(string)
+}
+
 fn f32_parse(bytes: &mut std::slice::Iter<u8>) -> Option<f32> {
     Some(f32::from_le_bytes([
         *bytes.next()?,
@@ -172,6 +647,19 @@ fn f32_parse(bytes: &mut std::slice::Iter<u8>) -> Option<f32> {
     ]))
 }
 
+fn u64_parse(bytes: &mut std::slice::Iter<u8>) -> Option<u64> {
+    let mut value = [0; 8];
+    for byte in &mut value {
+        *byte = *bytes.next()?;
+    }
+
+    Some(u64::from_le_bytes(value))
+}
+
+fn u16_parse(bytes: &mut std::slice::Iter<u8>) -> Option<u16> {
+    Some(u16::from_le_bytes([*bytes.next()?, *bytes.next()?]))
+}
+
 fn u32_parse(bytes: &mut std::slice::Iter<u8>) -> Option<u32> {
     Some(u32::from_le_bytes([
         *bytes.next()?,
@@ -181,12 +669,426 @@ fn u32_parse(bytes: &mut std::slice::Iter<u8>) -> Option<u32> {
     ]))
 }
 
-fn next_equals(bytes: &mut std::slice::Iter<u8>, slice: &[u8]) -> bool {
-    for expected in slice {
-        if bytes.next() != Some(expected) {
-            return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(policy: PacketOrderPolicy, numbers: &[u32]) -> Vec<u32> {
+        let mut packet_numbers = PacketNumbers::default();
+        numbers
+            .iter()
+            .copied()
+            .filter(|number| packet_numbers.accept(*number, policy))
+            .collect()
+    }
+
+    #[test]
+    fn strict_drop_only_accepts_newer_packets() {
+        let policy = PacketOrderPolicy::StrictDrop;
+        assert_eq!(accepted(policy, &[1, 2, 4, 3, 4, 5, 1]), [1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn accept_all_ignores_the_order() {
+        let policy = PacketOrderPolicy::AcceptAll;
+        assert_eq!(accepted(policy, &[1, 3, 2, 3, 1]), [1, 3, 2, 3, 1]);
+
+        // Old packets don't move the latest back
+        let mut packet_numbers = PacketNumbers::default();
+        for number in [5, 2] {
+            packet_numbers.accept(number, policy);
+        }
+        assert_eq!(packet_numbers.latest(), 5);
+    }
+
+    #[test]
+    fn reorder_window_accepts_late_packets_once() {
+        let policy = PacketOrderPolicy::ReorderWindow(4);
+        // 2 and 3 arrive late, 5 and 2 arrive twice, 1 is too late
+        assert_eq!(
+            accepted(policy, &[1, 5, 5, 3, 2, 6, 2, 1, 7]),
+            [1, 5, 3, 2, 6, 7]
+        );
+
+        // A big jump forgets which packets were seen
+        assert_eq!(
+            accepted(policy, &[10, 9, 200, 199, 9, 199]),
+            [10, 9, 200, 199]
+        );
+
+        // The largest window still detects duplicates at its edge
+        let policy = PacketOrderPolicy::ReorderWindow(crate::config::MAX_REORDER_WINDOW);
+        let oldest = 100 - crate::config::MAX_REORDER_WINDOW;
+        assert_eq!(
+            accepted(policy, &[100, oldest, oldest, oldest - 1]),
+            [100, oldest]
+        );
+    }
+
+    #[test]
+    fn packet_numbers_wrap_around() {
+        let numbers = [u32::MAX - 1, u32::MAX, 0, 1];
+        for policy in [
+            PacketOrderPolicy::StrictDrop,
+            PacketOrderPolicy::AcceptAll,
+            PacketOrderPolicy::ReorderWindow(4),
+        ] {
+            assert_eq!(accepted(policy, &numbers), numbers);
         }
+
+        // Late and duplicate packets from before the wrap
+        let policy = PacketOrderPolicy::ReorderWindow(4);
+        assert_eq!(
+            accepted(policy, &[u32::MAX - 2, 1, u32::MAX, 0, u32::MAX, 2]),
+            [u32::MAX - 2, 1, u32::MAX, 0, 2]
+        );
+        assert_eq!(
+            accepted(PacketOrderPolicy::StrictDrop, &[u32::MAX, 1, u32::MAX]),
+            [u32::MAX, 1]
+        );
+    }
+
+    const ONE: [u8; 4] = [0x00, 0x00, 0x80, 0x3f];
+    const HALF: [u8; 4] = [0x00, 0x00, 0x00, 0x3f];
+    const MINUS_TWO: [u8; 4] = [0x00, 0x00, 0x00, 0xc0];
+    const ZERO: [u8; 4] = [0x00; 4];
+    /// Packet number 0x01020304
+    const PACKET_NUMBER: [u8; 4] = [0x04, 0x03, 0x02, 0x01];
+
+    /// Parses a packet as if it came from a device that has handshaked
+    fn parse_from_device<R>(bytes: &[u8], check: impl FnOnce(UdpPacket) -> R) -> R {
+        let mut device = UdpDevice::new("10.0.0.2:5828".parse().unwrap(), "test".to_string());
+        let mut bytes = bytes.iter();
+        let packet = UdpPacket::parse(
+            &mut bytes,
+            Some(&mut device),
+            PacketOrderPolicy::StrictDrop,
+            &mut WarningAggregator::default(),
+        )
+        .expect("packet should parse");
+        check(packet)
+    }
+
+    #[test]
+    fn handshake_golden_vectors() {
+        let bytes = [
+            &[PACKET_HANDSHAKE][..],
+            b"MCDEV",
+            &[0x0a, 0x1b, 0x02, 0xc3, 0xd4, 0xe5],
+        ]
+        .concat();
+        let Some(UdpPacket::Handshake(handshake)) = UdpPacket::parse(
+            &mut bytes.iter(),
+            None,
+            PacketOrderPolicy::StrictDrop,
+            &mut WarningAggregator::default(),
+        ) else {
+            panic!("should parse as a handshake");
+        };
+        assert_eq!(handshake.mac_string, "a:1b:2:c3:d4:e5");
+        assert_eq!(handshake.variant, None);
+
+        let bytes = [
+            b"MCDEV-V2\0".as_slice(),
+            &[0x0a, 0x1b, 0x02, 0xc3, 0xd4, 0xe5],
+        ]
+        .concat();
+        let handshake = UdpPacketHandshake::from_bytes(&mut bytes.iter()).unwrap();
+        assert_eq!(handshake.mac_string, "a:1b:2:c3:d4:e5");
+        assert_eq!(handshake.variant.as_deref(), Some("V2"));
+
+        // Wrong magic, a truncated mac and an unterminated variant
+        for bytes in [
+            &b"MCDEW\x01\x02\x03\x04\x05\x06"[..],
+            b"MCDEV\x01\x02\x03\x04\x05",
+            b"MCDEV-V2\x01\x02\x03\x04\x05\x06",
+        ] {
+            assert!(UdpPacketHandshake::from_bytes(&mut bytes.iter()).is_none());
+        }
+
+        assert_eq!(UdpPacketHandshake::to_bytes(), *b"\x01MCSVR");
+    }
+
+    #[test]
+    fn tracker_data_golden_vectors() {
+        let bytes = [
+            &[PACKET_TRACKER_DATA][..],
+            &PACKET_NUMBER,
+            &[0],
+            &ZERO,
+            &ZERO,
+            &ZERO,
+            &ONE,
+            &HALF,
+            &MINUS_TWO,
+            &ZERO,
+            &[2],
+            &HALF,
+            &HALF,
+            &HALF,
+            &HALF,
+            &ZERO,
+            &ZERO,
+            &ONE,
+            &[0xff],
+        ]
+        .concat();
+        let data = parse_from_device(&bytes, |packet| {
+            let UdpPacket::TrackerData((mut packet, device)) = packet else {
+                panic!("should parse as tracker data");
+            };
+            assert_eq!(device.packet_numbers.latest(), 0x01020304);
+            std::iter::from_fn(|| packet.next()).collect::<Vec<_>>()
+        });
+
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].tracker_index, 0);
+        assert_eq!(data[0].orientation.0, glam::Quat::IDENTITY);
+        assert_eq!(data[0].acceleration.0, glam::Vec3A::new(0.5, -2., 0.));
+        assert_eq!(data[1].tracker_index, 2);
+        assert_eq!(
+            data[1].orientation.0,
+            glam::Quat::from_xyzw(0.5, 0.5, 0.5, 0.5)
+        );
+        assert_eq!(data[1].acceleration.0, glam::Vec3A::Z);
+    }
+
+    #[test]
+    fn ping_pong_golden_vectors() {
+        let time_us = 0x0102030405060708;
+        let bytes = UdpPacketPingPong::to_bytes(7, time_us);
+        assert_eq!(
+            bytes,
+            [
+                PACKET_PING_PONG,
+                7,
+                0x08,
+                0x07,
+                0x06,
+                0x05,
+                0x04,
+                0x03,
+                0x02,
+                0x01
+            ]
+        );
+
+        // The device echoes it back with its own clock in the same layout
+        parse_from_device(&bytes, |packet| {
+            let UdpPacket::PingPong((pong, _)) = packet else {
+                panic!("should parse as a ping pong");
+            };
+            assert_eq!(pong.id, 7);
+            assert_eq!(pong.device_time_us, Some(time_us));
+        });
+
+        // Older firmware only sends the id
+        parse_from_device(&[PACKET_PING_PONG, 7], |packet| {
+            let UdpPacket::PingPong((pong, _)) = packet else {
+                panic!("should parse as a ping pong");
+            };
+            assert_eq!(pong.device_time_us, None);
+        });
+    }
+
+    #[test]
+    fn device_error_golden_vectors() {
+        let bytes = [
+            &[PACKET_DEVICE_ERROR][..],
+            &PACKET_NUMBER,
+            &[0x04, 5],
+            b"nvs 0",
+        ]
+        .concat();
+        parse_from_device(&bytes, |packet| {
+            let UdpPacket::DeviceError((error, _)) = packet else {
+                panic!("should parse as a device error");
+            };
+            assert_eq!(error.code, DeviceErrorCode::FlashFull);
+            assert_eq!(error.detail.as_deref(), Some("nvs 0"));
+        });
+
+        let error = UdpPacketDeviceError::from_bytes(&mut [0x7f, 0].iter()).unwrap();
+        assert_eq!(error.code, DeviceErrorCode::Unknown(0x7f));
+        assert_eq!(error.detail, None);
+
+        // Detail longer than the firmware can send and one that is cut off
+        let mut too_long = vec![0x01, MAX_DEVICE_ERROR_DETAIL_LENGTH as u8 + 1];
+        too_long.resize(too_long.len() + MAX_DEVICE_ERROR_DETAIL_LENGTH + 1, b'a');
+        assert!(UdpPacketDeviceError::from_bytes(&mut too_long.iter()).is_none());
+        assert!(UdpPacketDeviceError::from_bytes(&mut [0x01, 3, b'a'].iter()).is_none());
+    }
+
+    #[test]
+    fn raw_sensor_golden_vectors() {
+        let bytes = [
+            &[PACKET_RAW_SENSOR_DATA][..],
+            &PACKET_NUMBER,
+            &[1, RawSensorSample::ACCEL | RawSensorSample::MAG],
+            &ONE,
+            &ZERO,
+            &HALF,
+            &MINUS_TWO,
+            &MINUS_TWO,
+            &ONE,
+            &[3, RawSensorSample::GYRO],
+            &HALF,
+            &ONE,
+            &ZERO,
+            &[0xff],
+        ]
+        .concat();
+        let samples = parse_from_device(&bytes, |packet| {
+            let UdpPacket::RawSensorData((data, _)) = packet else {
+                panic!("should parse as raw sensor data");
+            };
+            data.samples
+        });
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].tracker_index, 1);
+        assert_eq!(samples[0].accel, Some(glam::Vec3::new(1., 0., 0.5)));
+        assert_eq!(samples[0].gyro, None);
+        assert_eq!(samples[0].mag, Some(glam::Vec3::new(-2., -2., 1.)));
+        assert_eq!(
+            samples[0].sensors(),
+            RawSensorSample::ACCEL | RawSensorSample::MAG
+        );
+        assert_eq!(samples[1].tracker_index, 3);
+        assert_eq!(samples[1].gyro, Some(glam::Vec3::new(0.5, 1., 0.)));
+        assert_eq!(samples[1].sensors(), RawSensorSample::GYRO);
+
+        // Missing the end marker
+        assert!(
+            UdpPacketRawSensorData::from_bytes(&mut bytes[5..bytes.len() - 1].iter()).is_none()
+        );
+    }
+
+    #[test]
+    fn server_info_golden_vectors() {
+        let info = UdpPacketServerInfo {
+            instance_id: 0x0102030405060708,
+            websocket_port: 8298,
+            udp_port: 5828,
+            tls: true,
+            version: "1.2.0".to_string(),
+        };
+        let bytes = [
+            &[PACKET_SERVER_INFO][..],
+            b"MCSVR",
+            &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+            &[0x6a, 0x20],
+            &[0xc4, 0x16],
+            &[1, 5],
+            b"1.2.0",
+        ]
+        .concat();
+        assert_eq!(info.to_bytes(), bytes);
+        assert_eq!(UdpPacketServerInfo::from_bytes(&bytes), Some(info));
+
+        // Versions too long for the packet get cut off
+        let info = UdpPacketServerInfo {
+            version: "v".repeat(MAX_SERVER_VERSION_LENGTH + 1),
+            ..UdpPacketServerInfo::from_bytes(&bytes).unwrap()
+        };
+        let parsed = UdpPacketServerInfo::from_bytes(&info.to_bytes()).unwrap();
+        assert_eq!(parsed.version, "v".repeat(MAX_SERVER_VERSION_LENGTH));
+
+        assert!(UdpPacketServerInfo::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(UdpPacketServerInfo::from_bytes(&UdpPacketServerProbe::to_bytes()).is_none());
     }
 
-    true
+    #[test]
+    fn device_config_round_trips() {
+        let set = UdpPacketSetConfigKv {
+            key: "wifi_ssid",
+            value: "home network",
+        };
+        let bytes = set.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                &[PACKET_SET_CONFIG_KV, 9][..],
+                b"wifi_ssid",
+                &[12],
+                b"home network"
+            ]
+            .concat()
+        );
+
+        // The device replies with its entries in the same layout the key and value were sent in
+        let reply = [
+            &[PACKET_GET_CONFIG][..],
+            &PACKET_NUMBER,
+            &[2],
+            &bytes[1..],
+            &[4],
+            b"rate",
+            &[0],
+        ]
+        .concat();
+        let entries = parse_from_device(&reply, |packet| {
+            let UdpPacket::DeviceConfig((config, _)) = packet else {
+                panic!("should parse as a device config");
+            };
+            config.entries
+        });
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["wifi_ssid"], "home network");
+        assert_eq!(entries["rate"], "");
+
+        assert_eq!(UdpPacketDeviceConfig::request_bytes(), [PACKET_GET_CONFIG]);
+    }
+
+    #[test]
+    fn device_configs_the_firmware_cant_send_are_rejected() {
+        let entry = |key: usize, value: usize| {
+            [
+                &[1, key as u8][..],
+                &vec![b'k'; key],
+                &[value as u8],
+                &vec![b'v'; value],
+            ]
+            .concat()
+        };
+        let parses = |bytes: &[u8]| UdpPacketDeviceConfig::from_bytes(&mut bytes.iter()).is_some();
+
+        assert!(parses(&entry(
+            MAX_CONFIG_KEY_LENGTH,
+            MAX_CONFIG_VALUE_LENGTH
+        )));
+        assert!(!parses(&entry(MAX_CONFIG_KEY_LENGTH + 1, 1)));
+        assert!(!parses(&entry(1, MAX_CONFIG_VALUE_LENGTH + 1)));
+
+        // More entries than the device can store and fewer than the count says
+        assert!(!parses(&[MAX_CONFIG_ENTRIES as u8 + 1]));
+        assert!(!parses(&[2, 1, b'k', 1, b'v']));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn device_configs_that_dont_fit_on_the_device_are_rejected() {
+        let code = |key: &str, value: &str| {
+            UdpPacketSetConfigKv { key, value }
+                .validate()
+                .err()
+                .map(|error| error.downcast_ref::<CodedMessage>().unwrap().code)
+        };
+        let longest_key = "k".repeat(MAX_CONFIG_KEY_LENGTH);
+        let longest_value = "v".repeat(MAX_CONFIG_VALUE_LENGTH);
+
+        assert_eq!(code(&longest_key, &longest_value), None);
+        assert_eq!(code(&longest_key, ""), None);
+        assert_eq!(
+            code(&format!("{longest_key}k"), "v"),
+            Some("device_config_key_too_long")
+        );
+        assert_eq!(
+            code("rate", &format!("{longest_value}v")),
+            Some("device_config_value_too_long")
+        );
+        for key in ["", "wifi ssid", "ключ"] {
+            assert_eq!(code(key, "v"), Some("device_config_key_invalid"));
+        }
+    }
 }
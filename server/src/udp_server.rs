@@ -1,47 +1,194 @@
 use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     time::{Duration, Instant},
 };
+
+#[cfg(feature = "websocket")]
+use anyhow::Context;
 use tokio::net::UdpSocket;
+use tracing::Instrument;
+
+#[cfg(feature = "recording")]
+use crate::packet_log::{LoggedPacket, PacketLogWriter, PacketReplay};
+#[cfg(all(feature = "recording", feature = "websocket"))]
+use crate::playback::PlaybackAction;
+#[cfg(feature = "recording")]
+use std::path::Path;
 
 use crate::{
-    main_server::MainServer,
-    tracker::{TrackerConfig, TrackerData, TrackerStatus},
-    udp_packet::{UdpPacket, UdpPacketHandshake, UdpPacketPingPong},
+    battery::{BatteryEstimator, BatteryStatus},
+    blocklist::Blocklist,
+    config::{DiscoveryConfig, DiscoveryMode, ServerConfig},
+    connection_history::{ConnectionHistory, DisconnectCause},
+    device_error::{DeviceError, DeviceErrorLog},
+    early_packets::EarlyPacketBuffer,
+    extension::{is_extension_packet, PacketHandlers},
+    fast_hash::FastHashMap,
+    firewall::{remediation_hint, FirewallProbe},
+    input::{InputFilter, InputKind},
+    main_server::{MainServer, ServerMessage},
+    network_test::{NetworkTest, NETWORK_TEST_PING_ID_START},
+    send_queue::{OutgoingPacket, SendPriority, SendQueue},
+    tracker::{RawTrackerData, TrackerConfig, TrackerData, TrackerStatus},
+    udp_packet::{
+        PacketNumbers, UdpPacket, UdpPacketDeviceConfig, UdpPacketDeviceError, UdpPacketHandshake,
+        UdpPacketHandshakeRequest, UdpPacketPingPong, UdpPacketRequestStatus,
+        UdpPacketServerAnnounce, UdpPacketServerFull, UdpPacketServerInfo, UdpPacketServerProbe,
+        UdpPacketServerShutdown, UdpPacketSetConfigKv, PACKET_HANDSHAKE,
+    },
+    units::AccelMps2,
+    warning_aggregator::WarningAggregator,
+    SPAN_TARGET,
 };
 
+/// Port the devices listen on and the server's default port
 pub const UDP_PORT: u16 = 5828;
 pub const MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(239, 255, 0, 123);
 
 const DEVICE_TIMEOUT: Duration = Duration::from_millis(4000);
 const UPKEEP_INTERVAL: Duration = Duration::from_millis(1000);
+/// Keep receiving on the old port for this long after changing ports so devices can move over
+#[cfg(feature = "websocket")]
+const PORT_CHANGE_GRACE: Duration = Duration::from_secs(10);
+/// A timed out device has to have sent a packet within this long for this many upkeeps in a row
+/// before it's Ok again, so a device on the edge of the timeout doesn't keep flapping
+const DEVICE_RECOVERY_SILENCE: Duration = Duration::from_millis(1000);
+const DEVICE_RECOVERY_UPKEEPS: u32 = 3;
+/// Warn about the device's connection if it disconnects more than this many times in an hour
+const FLAPPY_EPISODES_PER_HOUR: usize = 5;
+/// A mac handshaking from a different address more than this many times within the window is
+/// assumed to be multiple devices with the same mac
+const DUPLICATE_MAC_SWITCHES: usize = 4;
+const DUPLICATE_MAC_WINDOW: Duration = Duration::from_secs(30);
+/// Warn about the responses not reaching the device after it handshakes this many times in a row
+const UNANSWERED_HANDSHAKE_WARNING: u32 = 3;
+/// Minimum time between handshake requests to an unknown address sending data
+const HANDSHAKE_REQUEST_INTERVAL: Duration = Duration::from_millis(1000);
+/// Log when the device's clock offset moves by more than this since it could be drifting
+const CLOCK_OFFSET_LOG_THRESHOLD_US: i64 = 1000;
+const CONFIG_RESEND_INTERVAL: Duration = Duration::from_millis(1000);
+/// Give up on setting a config value if the device hasn't acknowledged it after this many sends
+const MAX_CONFIG_SEND_ATTEMPTS: u32 = 5;
+
+/// Commands from clients for the devices that get handled on the next tick
+#[cfg(feature = "websocket")]
+pub enum DeviceCommand {
+    GetConfig {
+        mac: String,
+    },
+    SetConfigValue {
+        mac: String,
+        key: String,
+        value: String,
+    },
+    /// The blocklist in the config was changed
+    UpdateBlocklist,
+    RunNetworkTest {
+        mac: String,
+    },
+    #[cfg(feature = "recording")]
+    ControlPlayback {
+        action: PlaybackAction,
+    },
+    /// Move the server to another port while keeping the devices connected
+    SetUdpPort {
+        port: u16,
+    },
+    /// The trackers were given new indices, from their old index
+    RemapTrackerIndices {
+        new_indices: HashMap<usize, usize>,
+    },
+}
+
+/// A config value sent to the device that it hasn't acknowledged yet
+struct PendingConfigValue {
+    key: String,
+    value: String,
+    last_sent_time: Instant,
+    attempts: u32,
+}
 
 pub struct UdpDevice {
-    pub(super) index: usize,
     pub(super) last_packet_received_time: Instant,
-    pub(super) last_packet_number: u32,
+    pub(super) packet_numbers: PacketNumbers,
+    /// Handshakes received since the device last sent any other packet
+    pub(super) unanswered_handshakes: u32,
     /// Maps the udp device's tracker index to the tracker's global index
     tracker_indexs: Vec<usize>,
     timed_out: bool,
-    mac: String,
+    /// Upkeeps in a row that a timed out device has been sending packets again
+    recovering_upkeeps: u32,
+    pub(super) mac: String,
+    /// Used to make the tracker ids, usually the same as the mac
+    id: String,
     address: SocketAddr,
+    /// When the device handshaked from a different address to detect duplicate macs
+    address_changes: VecDeque<Instant>,
     current_ping_start_time: Option<Instant>,
     current_ping_id: u8,
+    /// Server time minus device time in microseconds, measured from the pongs
+    clock_offset_us: Option<i64>,
+    connection_history: ConnectionHistory,
+    errors: DeviceErrorLog,
+    inputs: InputFilter,
+    variant: Option<String>,
+    /// Last known settings on the device
+    config: Option<BTreeMap<String, String>>,
+    /// Compare the next config from the device with the cached one to detect resets
+    check_config: bool,
+    pending_config_values: Vec<PendingConfigValue>,
+    network_test: Option<NetworkTest>,
+    /// Started once the device sends its battery level
+    battery: Option<BatteryEstimator>,
+    battery_status: Option<BatteryStatus>,
+    /// Already warned about the battery running low, until it charges
+    low_battery_warned: bool,
+}
+
+/// Summary of a device for bug reports
+#[derive(Clone, serde::Serialize)]
+pub struct DeviceInfo {
+    pub mac: String,
+    pub address: SocketAddr,
+    pub variant: Option<String>,
+    pub timed_out: bool,
+    /// Global indices of the device's trackers
+    pub trackers: Vec<usize>,
+    pub last_packet_number: u32,
+    pub clock_offset_us: Option<i64>,
+    pub disconnects: usize,
+    pub config: Option<BTreeMap<String, String>>,
+    pub battery: Option<BatteryStatus>,
 }
 
 impl UdpDevice {
-    fn new(index: usize, address: SocketAddr, mac: String) -> Self {
+    pub(super) fn new(address: SocketAddr, mac: String) -> Self {
         Self {
             tracker_indexs: Vec::default(),
-            index,
             address,
+            id: mac.clone(),
             mac,
+            address_changes: VecDeque::new(),
             last_packet_received_time: Instant::now(),
-            last_packet_number: 0,
+            packet_numbers: PacketNumbers::default(),
+            unanswered_handshakes: 0,
             timed_out: false,
+            recovering_upkeeps: 0,
             current_ping_id: 0,
             current_ping_start_time: None,
+            clock_offset_us: None,
+            connection_history: ConnectionHistory::default(),
+            errors: DeviceErrorLog::default(),
+            inputs: InputFilter::default(),
+            variant: None,
+            config: None,
+            check_config: false,
+            pending_config_values: Vec::new(),
+            network_test: None,
+            battery: None,
+            battery_status: None,
+            low_battery_warned: false,
         }
     }
 
@@ -59,7 +206,7 @@ impl UdpDevice {
             Some(index) => *index,
             None => {
                 // Register the tracker and add the index into the udp device array to know
-                let id = format!("{}/{}", self.mac, local_index);
+                let id = format!("{}/{}", self.id, local_index);
                 let name = format!("UDP Tracker {}", self.address);
                 let index = main.register_tracker(
                     id,
@@ -74,69 +221,522 @@ impl UdpDevice {
         }
     }
 
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            mac: self.mac.clone(),
+            address: self.address,
+            variant: self.variant.clone(),
+            timed_out: self.timed_out,
+            trackers: self.tracker_indexs.clone(),
+            last_packet_number: self.packet_numbers.latest(),
+            clock_offset_us: self.clock_offset_us,
+            disconnects: self.connection_history.episode_count,
+            config: self.config.clone(),
+            battery: self.battery_status,
+        }
+    }
+
+    /// Times the device out once it's been silent for too long but only brings it back after it's
+    /// been sending packets for a few upkeeps
+    fn update_timed_out(&mut self, main: &mut MainServer) {
+        let silence = self.last_packet_received_time.elapsed();
+        if !self.timed_out {
+            if silence > DEVICE_TIMEOUT {
+                self.set_timed_out(main, true);
+            }
+            return;
+        }
+
+        if silence > DEVICE_RECOVERY_SILENCE {
+            self.recovering_upkeeps = 0;
+            return;
+        }
+
+        self.recovering_upkeeps += 1;
+        if self.recovering_upkeeps >= DEVICE_RECOVERY_UPKEEPS {
+            self.set_timed_out(main, false);
+        }
+    }
+
     fn set_timed_out(&mut self, main: &mut MainServer, timed_out: bool) {
         if timed_out == self.timed_out {
             return;
         }
 
         self.timed_out = timed_out;
+        self.recovering_upkeeps = 0;
+
+        if timed_out {
+            self.connection_history
+                .disconnected(self.last_packet_received_time, DisconnectCause::Timeout);
+            // It's probably been turned off so this is the end of its session
+            self.save_discharge_rate(main);
+        } else {
+            self.reconnected(main);
+        }
 
         for global_index in &self.tracker_indexs {
-            let info = &main.trackers[*global_index].info;
+            let Some(tracker) = main.trackers.get_mut(*global_index) else {
+                continue;
+            };
 
             // Only allow changing status to TimedOut if tracker is Ok and vice-versa
+            let info = &mut tracker.info;
             if timed_out && info.status == TrackerStatus::Ok {
-                main.trackers[*global_index].info.status = TrackerStatus::TimedOut;
+                info.status = TrackerStatus::TimedOut;
                 main.tracker_info_updated(*global_index);
             } else if !timed_out && info.status == TrackerStatus::TimedOut {
-                main.trackers[*global_index].info.status = TrackerStatus::Ok;
+                info.status = TrackerStatus::Ok;
                 main.tracker_info_updated(*global_index);
             };
         }
     }
+
+    fn update_battery(&mut self, main: &mut MainServer, percent: f32) {
+        let config = &main.config.battery;
+        let timestamp_us = main.clock.timestamp_us(self.last_packet_received_time);
+        let status = (self.battery)
+            .get_or_insert_with(|| {
+                BatteryEstimator::new(config.discharge_rates.get(&self.mac).copied())
+            })
+            .add_reading(timestamp_us, percent, config.window_us());
+
+        if status.charging && self.battery_status.is_some_and(|old| !old.charging) {
+            tracing::info!("Device {} started charging", self.mac);
+            self.save_discharge_rate(main);
+        }
+
+        let low = match status.estimated_minutes_remaining {
+            Some(minutes) => minutes < main.config.battery.low_warning_mins,
+            None => false,
+        };
+        if low && !self.low_battery_warned {
+            let minutes = status.estimated_minutes_remaining.unwrap_or_default();
+            let warning = format!(
+                "Device {} has about {minutes} minutes of battery left",
+                self.mac
+            );
+            tracing::warn!("{warning}");
+            main.notify_warning(&warning);
+            main.send_to_clients(ServerMessage::LowBattery {
+                mac: self.mac.clone(),
+                trackers: self.tracker_indexs.clone(),
+                estimated_minutes_remaining: minutes,
+            });
+        }
+        self.low_battery_warned = (self.low_battery_warned || low) && !status.charging;
+
+        self.battery_status = Some(status);
+        for global_index in &self.tracker_indexs {
+            let Some(tracker) = main.trackers.get_mut(*global_index) else {
+                continue;
+            };
+            if tracker.info.battery != Some(status) {
+                tracker.info.battery = Some(status);
+                main.tracker_info_updated(*global_index);
+            }
+        }
+    }
+
+    /// Keeps the discharge rate from this session for the first estimate next time
+    fn save_discharge_rate(&self, main: &mut MainServer) {
+        let Some(rate) = self
+            .battery
+            .as_ref()
+            .and_then(BatteryEstimator::measured_rate)
+        else {
+            return;
+        };
+
+        if rate > 0. {
+            (main.config.battery.discharge_rates).insert(self.mac.clone(), rate);
+            main.save_config();
+        }
+    }
+
+    /// Returns true if the address has changed often enough to be multiple devices
+    fn record_address_change(&mut self) -> bool {
+        let now = Instant::now();
+        self.address_changes.push_back(now);
+        while let Some(time) = self.address_changes.front() {
+            if now.duration_since(*time) <= DUPLICATE_MAC_WINDOW {
+                break;
+            }
+
+            self.address_changes.pop_front();
+        }
+
+        self.address_changes.len() > DUPLICATE_MAC_SWITCHES
+    }
+
+    fn reconnected(&mut self, main: &mut MainServer) {
+        let now = Instant::now();
+        let Some(episode) = self.connection_history.reconnected(now) else {
+            return;
+        };
+
+        let downtime = episode.duration();
+        tracing::info!(
+            "Device {} was disconnected for {downtime:?} ({:?})",
+            self.mac,
+            episode.cause
+        );
+
+        let history = &self.connection_history;
+        main.send_to_clients(ServerMessage::DeviceReconnected {
+            mac: self.mac.clone(),
+            cause: episode.cause,
+            downtime_ms: downtime.as_millis() as u64,
+            episodes_today: history.episodes_within(Duration::from_secs(24 * 60 * 60), now),
+            total_downtime_ms: history.total_downtime.as_millis() as u64,
+        });
+
+        let episodes_last_hour = history.episodes_within(Duration::from_secs(60 * 60), now);
+        if episodes_last_hour > FLAPPY_EPISODES_PER_HOUR {
+            let warning = format!(
+                "Device {} disconnected {episodes_last_hour} times in the last hour, try moving it closer to the router or using a less congested wifi channel",
+                self.mac
+            );
+            tracing::warn!("{warning}");
+            main.notify_warning(&warning);
+        }
+    }
+}
+
+/// Devices with the same mac address
+enum MacDevices {
+    Single(usize),
+    /// Multiple physical devices are using the same mac so they get keyed by address instead
+    Duplicated(HashMap<SocketAddr, usize>),
+}
+
+/// Doesn't send anything while replaying packets since the addresses are from the recording
+struct PacketSocket {
+    socket: UdpSocket,
+    replaying: bool,
+    queue: SendQueue,
+}
+
+impl PacketSocket {
+    /// Queues the packet to be sent at the end of the tick
+    fn send_to(&mut self, bytes: &[u8], address: impl Into<SocketAddr>, priority: SendPriority) {
+        if self.replaying {
+            return;
+        }
+
+        let packet = OutgoingPacket {
+            bytes: bytes.to_vec(),
+            address: address.into(),
+        };
+        self.queue.push(packet, priority);
+    }
+
+    /// Sends the queued packets highest priority first without waiting, the rest are left for the
+    /// next tick if the socket's send buffer fills up
+    fn flush(&mut self) {
+        while let Some((packet, priority)) = self.queue.pop() {
+            match self.socket.try_send_to(&packet.bytes, packet.address) {
+                Ok(_) => (),
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.queue.unpop(packet, priority);
+                    return;
+                }
+                // Some interfaces might not allow broadcasting so this can happen normally
+                Err(error) => tracing::debug!("Failed to send to {}: {error}", packet.address),
+            }
+        }
+    }
 }
 
 pub struct UdpServer {
     devices: Vec<UdpDevice>,
-    mac_to_device_index: HashMap<String, usize>,
-    address_to_device_index: HashMap<SocketAddr, usize>,
+    mac_to_device_index: FastHashMap<String, MacDevices>,
+    /// Looked up for every packet so it uses the faster hasher
+    address_to_device_index: FastHashMap<SocketAddr, usize>,
+    /// When a handshake request was last sent to addresses that sent data without handshaking
+    handshake_requests: FastHashMap<SocketAddr, Instant>,
+    /// Packets that arrived before their device's handshake, handled once it arrives
+    early_packets: EarlyPacketBuffer,
+    blocklist: Blocklist,
+    /// Warnings that devices can trigger on every packet
+    warnings: WarningAggregator,
 
-    socket: UdpSocket,
+    socket: PacketSocket,
+    /// Socket on the previous port that's still received from until the time
+    old_socket: Option<(UdpSocket, Instant)>,
+    #[cfg(feature = "recording")]
+    raw_recorder: Option<PacketLogWriter>,
+    #[cfg(feature = "recording")]
+    replay: Option<PacketReplay>,
+    firewall_probe: Option<FirewallProbe>,
+    packet_handlers: PacketHandlers,
     last_upkeep_time: Instant,
+    /// Do the upkeep on the next tick without waiting for the interval
+    upkeep_now: bool,
+    start_time: Instant,
+    discovery_mode: DiscoveryMode,
+    /// Sent to UIs that probe for servers so they can tell servers apart
+    instance_id: u64,
 }
 
 impl UdpServer {
-    pub async fn new() -> anyhow::Result<Self> {
-        let socket = tokio::net::UdpSocket::bind(("0.0.0.0", UDP_PORT)).await?;
-        socket.join_multicast_v4(MULTICAST_IP, Ipv4Addr::UNSPECIFIED)?;
-        log::info!("Started UDP server on {}", socket.local_addr()?);
+    /// Reserves space for the expected number of devices so the maps don't grow while running
+    pub async fn new(config: &DiscoveryConfig, expected_devices: usize) -> anyhow::Result<Self> {
+        let socket = bind_socket(config).await?;
+        tracing::info!("Started UDP server on {}", socket.local_addr()?);
 
         Ok(Self {
-            devices: Default::default(),
-            mac_to_device_index: Default::default(),
-            address_to_device_index: Default::default(),
+            devices: Vec::with_capacity(expected_devices),
+            mac_to_device_index: FastHashMap::with_capacity_and_hasher(
+                expected_devices,
+                Default::default(),
+            ),
+            address_to_device_index: FastHashMap::with_capacity_and_hasher(
+                expected_devices,
+                Default::default(),
+            ),
+            handshake_requests: Default::default(),
+            early_packets: EarlyPacketBuffer::default(),
+            blocklist: Blocklist::default(),
+            warnings: WarningAggregator::default(),
             last_upkeep_time: Instant::now(),
-            socket,
+            upkeep_now: false,
+            start_time: Instant::now(),
+            discovery_mode: config.mode,
+            instance_id: new_instance_id(),
+            socket: PacketSocket {
+                socket,
+                replaying: false,
+                queue: SendQueue::default(),
+            },
+            old_socket: None,
+            #[cfg(feature = "recording")]
+            raw_recorder: None,
+            #[cfg(feature = "recording")]
+            replay: None,
+            firewall_probe: None,
+            packet_handlers: PacketHandlers::default(),
         })
     }
 
+    /// Replaces the socket with a new one while keeping the devices so they can carry on
+    pub async fn rebind(&mut self, config: &DiscoveryConfig) -> anyhow::Result<()> {
+        // The old socket needs to be closed before binding to the same port again
+        let placeholder = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        drop(std::mem::replace(&mut self.socket.socket, placeholder));
+
+        self.socket.socket = bind_socket(config).await?;
+        tracing::info!(
+            "Restarted UDP server on {}",
+            self.socket.socket.local_addr()?
+        );
+        Ok(())
+    }
+
+    /// Moves the server to another port without dropping the devices, they get pinged from the new
+    /// port straight away so they start sending to it and the announcements tell the rest
+    #[cfg(feature = "websocket")]
+    pub async fn change_port(&mut self, port: u16, main: &mut MainServer) -> anyhow::Result<()> {
+        if port == 0 {
+            anyhow::bail!("UDP port must not be 0");
+        }
+
+        // Devices that haven't connected yet send their handshakes to the multicast group on the
+        // port they were configured with so they would never find the new one
+        if self.discovery_mode == DiscoveryMode::Multicast {
+            anyhow::bail!(
+                "The UDP port can only be changed while using broadcast discovery, change it in the config and restart instead"
+            );
+        }
+
+        let old_port = self.socket.socket.local_addr()?.port();
+        if port == old_port {
+            return Ok(());
+        }
+
+        let config = DiscoveryConfig {
+            udp_port: port,
+            ..main.config.discovery.clone()
+        };
+        let socket = bind_socket(&config)
+            .await
+            .with_context(|| format!("Failed to bind UDP port {port}"))?;
+
+        let old_socket = std::mem::replace(&mut self.socket.socket, socket);
+        self.old_socket = Some((old_socket, Instant::now() + PORT_CHANGE_GRACE));
+        self.upkeep_now = true;
+
+        main.config.discovery = config;
+        main.save_config();
+        tracing::info!("Moved UDP server from port {old_port} to {port}");
+        Ok(())
+    }
+
+    /// Receives from the current socket then the old one while changing ports
+    fn try_recv_from(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self.socket.socket.try_recv_from(buffer) {
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+
+        match &self.old_socket {
+            // The old socket failing shouldn't stop the server so treat it as empty
+            Some((socket, _)) => socket
+                .try_recv_from(buffer)
+                .map_err(|_| std::io::ErrorKind::WouldBlock.into()),
+            None => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Check the device timeouts straight away since devices could've gone while the server was
+    /// asleep
+    pub fn resumed(&mut self) {
+        self.upkeep_now = true;
+    }
+
+    pub fn set_packet_handlers(&mut self, packet_handlers: PacketHandlers) {
+        self.packet_handlers = packet_handlers;
+    }
+
+    /// Sends a packet to the server's own LAN address to check that it arrives
+    pub async fn probe_firewall(&mut self) {
+        if self.socket.replaying {
+            return;
+        }
+
+        let Some((address, _)) = interface_addresses()
+            .into_iter()
+            .find(|(ip, _)| !ip.is_loopback())
+        else {
+            tracing::info!("Skipping firewall probe since there's no LAN address");
+            return;
+        };
+
+        let port = match self.socket.socket.local_addr() {
+            Ok(address) => address.port(),
+            Err(error) => {
+                tracing::warn!("Skipping firewall probe since the port is unknown: {error}");
+                return;
+            }
+        };
+
+        match FirewallProbe::send(address, port).await {
+            Ok(probe) => self.firewall_probe = Some(probe),
+            Err(error) => tracing::warn!("Failed to send firewall probe to {address}: {error}"),
+        }
+    }
+
+    /// Appends every received packet to the file to be replayed later
+    #[cfg(feature = "recording")]
+    pub fn record_raw(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.raw_recorder = Some(PacketLogWriter::create(path)?);
+        Ok(())
+    }
+
+    /// Handles the packets from the file instead of ones from the network, at the speed and
+    /// looping if set
+    #[cfg(feature = "recording")]
+    pub fn replay_raw(
+        &mut self,
+        path: &Path,
+        speed: Option<f32>,
+        looping: bool,
+    ) -> anyhow::Result<()> {
+        let mut replay = PacketReplay::open(path)?;
+        if let Some(speed) = speed {
+            replay.set_speed(speed)?;
+        }
+        if looping {
+            replay.loop_recording();
+        }
+
+        self.replay = Some(replay);
+        self.socket.replaying = true;
+        Ok(())
+    }
+
+    /// Tells every connected device that the server is stopping, it's only tried once since the
+    /// server is going either way
+    pub async fn shutdown(&mut self) {
+        if self.socket.replaying {
+            return;
+        }
+
+        self.socket.flush();
+        let bytes = UdpPacketServerShutdown::to_bytes();
+        let mut told = 0;
+        for device in self.devices.iter().filter(|device| !device.timed_out) {
+            match self.socket.socket.send_to(&bytes, device.address).await {
+                Ok(_) => told += 1,
+                Err(error) => {
+                    tracing::debug!("Failed to tell {} about the shutdown: {error}", device.mac)
+                }
+            }
+        }
+
+        tracing::info!("Told {told} devices that the server is shutting down");
+    }
+
     pub async fn tick(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
-        if self.last_upkeep_time.elapsed() > UPKEEP_INTERVAL {
+        let result = self.receive(main).await;
+        self.socket.flush();
+        result
+    }
+
+    async fn receive(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
+        // Before anything uses the tracker indices since they could've been remapped
+        #[cfg(feature = "websocket")]
+        for command in main.take_device_commands() {
+            self.handle_device_command(command, main).await?;
+        }
+
+        if self.upkeep_now || self.last_upkeep_time.elapsed() > UPKEEP_INTERVAL {
+            self.upkeep_now = false;
             self.upkeep(main).await?;
         }
 
-        let mut buffer = [0_u8; 256];
+        self.update_network_tests(main).await?;
+        self.send_held_back_inputs(main);
+
+        #[cfg(feature = "recording")]
+        if self.socket.replaying {
+            return self.tick_replay(main).await;
+        }
+
+        let mut buffer = [0_u8; 1024];
         loop {
             // Try and get all the packets that were received
-            match self.socket.try_recv_from(&mut buffer) {
+            match self.try_recv_from(&mut buffer) {
                 Ok((amount, peer_addr)) => {
-                    // log::trace!(
+                    #[cfg(feature = "recording")]
+                    if let Some(recorder) = &mut self.raw_recorder {
+                        let packet = LoggedPacket {
+                            timestamp_us: main.clock.now_us(),
+                            address: peer_addr,
+                            bytes: buffer[0..amount].to_vec(),
+                        };
+
+                        if let Err(error) = recorder.write(&packet) {
+                            tracing::error!("Failed to record packet, stopping recording: {error}");
+                            self.raw_recorder = None;
+                        }
+                    }
+
+                    if let Some(probe) = &self.firewall_probe {
+                        if probe.is_probe(&buffer[0..amount]) {
+                            tracing::info!("Firewall probe to {} arrived", probe.address);
+                            self.firewall_probe = None;
+                            continue;
+                        }
+                    }
+
+                    // tracing::trace!(
                     //     "Received {amount} bytes from {peer_addr} ({:#02x})",
                     //     buffer[0]
                     // );
 
                     // Only pass through the amount received
-                    self.handle_packet(&buffer[0..amount], peer_addr, main)
+                    self.handle_packet_in_span(&buffer[0..amount], peer_addr, main)
                         .await?;
                 }
                 // No more packets
@@ -148,67 +748,411 @@ impl UdpServer {
         }
     }
 
+    #[cfg(feature = "recording")]
+    async fn tick_replay(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
+        let Some(replay) = &mut self.replay else {
+            return Ok(());
+        };
+
+        replay.advance();
+        while let Some(replay) = &mut self.replay {
+            let Some(packet) = replay.next_due()? else {
+                break;
+            };
+
+            // The packet numbers go back after seeking or looping so start them again
+            if replay.take_jumped() {
+                for device in &mut self.devices {
+                    device.packet_numbers.reset();
+                }
+            }
+
+            main.replay_timestamp_us = Some(packet.timestamp_us);
+            self.handle_packet_in_span(&packet.bytes, packet.address, main)
+                .await?;
+        }
+
+        main.replay_timestamp_us = None;
+        main.playback = self.replay.as_ref().map(PacketReplay::state);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "recording", feature = "websocket"))]
+    fn control_playback(&mut self, action: PlaybackAction, main: &mut MainServer) {
+        let Some(replay) = &mut self.replay else {
+            main.notify_error("Not replaying a recording");
+            return;
+        };
+
+        if let Err(error) = replay.apply(action) {
+            main.notify_error(&error.to_string());
+        }
+
+        let state = replay.state();
+        main.playback = Some(state);
+        main.send_to_clients(ServerMessage::PlaybackState { state });
+    }
+
     async fn upkeep(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
+        #[cfg(feature = "recording")]
+        if let Some(recorder) = &mut self.raw_recorder {
+            if let Err(error) = recorder.flush() {
+                tracing::error!("Failed to flush recorded packets: {error}");
+            }
+        }
+
+        #[cfg(feature = "recording")]
+        if let Some(replay) = &self.replay {
+            let state = replay.state();
+            if !state.paused {
+                main.send_to_clients(ServerMessage::PlaybackState { state });
+            }
+        }
+
+        // Nothing gets sent while the replay is paused so don't time the devices out
+        #[cfg(feature = "recording")]
+        let paused = main.playback.is_some_and(|state| state.paused);
+        #[cfg(not(feature = "recording"))]
+        let paused = false;
         for device in &mut self.devices {
-            if device.last_packet_received_time.elapsed() > DEVICE_TIMEOUT {
-                device.set_timed_out(main, true);
-            } else {
-                device.set_timed_out(main, false);
+            if !paused {
+                let was_timed_out = device.timed_out;
+                device.update_timed_out(main);
+
+                // The device isn't listening so there's no point sending what was queued for it
+                if device.timed_out && !was_timed_out {
+                    self.socket.queue.purge(device.address);
+                }
             }
 
             // Ping has been acknowledge so start a new ping id
             if device.current_ping_start_time.is_none() {
                 device.current_ping_start_time = Some(Instant::now());
-                device.current_ping_id = device.current_ping_id.wrapping_add(1);
+                device.current_ping_id = (device.current_ping_id + 1) % NETWORK_TEST_PING_ID_START;
+            }
+
+            let ping_packet =
+                UdpPacketPingPong::to_bytes(device.current_ping_id, main.clock.now_us());
+            self.socket
+                .send_to(&ping_packet, device.address, SendPriority::Heartbeat);
+
+            let mut failed_keys = Vec::new();
+            device.pending_config_values.retain(|pending| {
+                let keep = pending.attempts < MAX_CONFIG_SEND_ATTEMPTS
+                    || pending.last_sent_time.elapsed() < CONFIG_RESEND_INTERVAL;
+                if !keep {
+                    failed_keys.push(pending.key.clone());
+                }
+                keep
+            });
+
+            for key in failed_keys {
+                let error = format!("Device {} didn't acknowledge setting {key}", device.mac);
+                tracing::error!("{error}");
+                main.notify_error(&error);
             }
 
-            let ping_packet = UdpPacketPingPong::to_bytes(device.current_ping_id);
-            self.socket.send_to(&ping_packet, device.address).await?;
+            for pending in &mut device.pending_config_values {
+                if pending.last_sent_time.elapsed() >= CONFIG_RESEND_INTERVAL {
+                    let packet = UdpPacketSetConfigKv {
+                        key: &pending.key,
+                        value: &pending.value,
+                    };
+                    self.socket
+                        .send_to(&packet.to_bytes(), device.address, SendPriority::Command);
+                    pending.last_sent_time = Instant::now();
+                    pending.attempts += 1;
+                }
+            }
+        }
+
+        let mut blocked_packets = 0;
+        for (address, count) in self.blocklist.drain_counts() {
+            tracing::debug!("Dropped {count} packets from blocked address {address}");
+            blocked_packets += count;
+        }
+
+        let dropped_outgoing_packets = self.socket.queue.take_dropped();
+        if dropped_outgoing_packets > 0 {
+            tracing::warn!(
+                "Dropped {dropped_outgoing_packets} outgoing packets since the send queue was full"
+            );
+        }
+
+        if blocked_packets > 0 || dropped_outgoing_packets > 0 {
+            main.blocked_packets += blocked_packets;
+            main.dropped_outgoing_packets += dropped_outgoing_packets;
+            main.server_status_updated();
+        }
+
+        self.warnings.flush();
+
+        let unhandled_count = self.packet_handlers.take_unhandled_count();
+        if unhandled_count > 0 {
+            tracing::debug!("Ignored {unhandled_count} extension packets without a handler");
+        }
+
+        self.handshake_requests
+            .retain(|_, time| time.elapsed() < HANDSHAKE_REQUEST_INTERVAL);
+        self.early_packets.evict(Instant::now());
+
+        if let Some(probe) = self.firewall_probe.take_if(|probe| probe.timed_out()) {
+            let warning = format!(
+                "Packets sent to {}:{} never arrived so a firewall is probably blocking devices from connecting. {}",
+                probe.address,
+                probe.port,
+                remediation_hint(probe.port)
+            );
+            tracing::warn!("{warning}");
+            main.notify_warning(&warning);
+        }
+
+        if self
+            .old_socket
+            .take_if(|(_, close_time)| Instant::now() >= *close_time)
+            .is_some()
+        {
+            tracing::info!("Stopped receiving on the old UDP port");
         }
 
+        self.update_discovery(main).await;
+        main.devices = self.devices.iter().map(UdpDevice::info).collect();
         self.last_upkeep_time = Instant::now();
         Ok(())
     }
 
+    async fn update_discovery(&mut self, main: &mut MainServer) {
+        let discovery_mode = next_discovery_mode(
+            &main.config.discovery,
+            self.discovery_mode,
+            self.start_time.elapsed(),
+            !self.devices.is_empty(),
+        );
+
+        if discovery_mode != self.discovery_mode {
+            tracing::info!(
+                "Switching to {discovery_mode:?} discovery since no devices have connected"
+            );
+            self.discovery_mode = discovery_mode;
+        }
+
+        if main.discovery_mode != discovery_mode {
+            main.discovery_mode = discovery_mode;
+            main.server_status_updated();
+        }
+
+        if discovery_mode != DiscoveryMode::Broadcast {
+            return;
+        }
+
+        let packet = UdpPacketServerAnnounce::to_bytes();
+        for address in broadcast_addresses(&interface_addresses()) {
+            self.socket.send_to(
+                &packet,
+                SocketAddrV4::new(address, UDP_PORT),
+                SendPriority::Telemetry,
+            );
+        }
+    }
+
+    /// Handles the packet inside a span so whatever it logs says where it came from
+    pub(crate) async fn handle_packet_in_span(
+        &mut self,
+        bytes: &[u8],
+        peer_addr: SocketAddr,
+        main: &mut MainServer,
+    ) -> tokio::io::Result<()> {
+        // The mac gets filled in once the packet's device is known
+        let span = tracing::trace_span!(
+            target: SPAN_TARGET,
+            "packet",
+            %peer_addr,
+            packet_type = bytes.first().copied(),
+            mac = tracing::field::Empty,
+        );
+        self.handle_packet(bytes, peer_addr, main)
+            .instrument(span)
+            .await
+    }
+
     async fn handle_packet(
         &mut self,
         bytes: &[u8],
         peer_addr: SocketAddr,
         main: &mut MainServer,
     ) -> tokio::io::Result<()> {
+        if self.blocklist.check_packet(peer_addr) {
+            return Ok(());
+        }
+
+        // Extensions are handled separately so they can't mess with the packet numbers
+        if bytes
+            .first()
+            .is_some_and(|packet_type| is_extension_packet(*packet_type))
+        {
+            self.packet_handlers.handle(bytes, peer_addr, main);
+            return Ok(());
+        }
+
+        if UdpPacketServerProbe::is_probe(bytes) {
+            return self.reply_to_probe(peer_addr).await;
+        }
+
+        if !self.address_to_device_index.contains_key(&peer_addr)
+            && bytes
+                .first()
+                .is_some_and(|packet_type| *packet_type != PACKET_HANDSHAKE)
+        {
+            // The handshake could still be on its way
+            self.early_packets.push(peer_addr, bytes, Instant::now());
+            return self.request_handshake(peer_addr).await;
+        }
+
         let mut byte_iter = bytes.iter();
         let device = self
             .address_to_device_index
             .get(&peer_addr)
             .and_then(|i| self.devices.get_mut(*i));
+        if let Some(device) = &device {
+            tracing::Span::current().record("mac", device.mac.as_str());
+        }
 
-        match UdpPacket::parse(&mut byte_iter, device) {
+        match UdpPacket::parse(
+            &mut byte_iter,
+            device,
+            main.config.packet_order,
+            &mut self.warnings,
+        ) {
             Some(UdpPacket::PingPong((packet, device))) => {
                 Self::handle_pong(main, packet, device);
             }
             Some(UdpPacket::Handshake(packet)) => {
-                self.socket
-                    .send_to(&UdpPacketHandshake::to_bytes(), peer_addr)
-                    .await?;
-                if let Some(device) = self.handle_handshake(packet, peer_addr) {
-                    device.last_packet_number = 0;
+                if self
+                    .blocklist
+                    .check_handshake(&packet.mac_string, peer_addr)
+                {
+                    return Ok(());
+                }
+
+                // Devices that aren't allowed don't get told anything, not even that it's full
+                let allowed = main.config.is_device_allowed(&packet.mac_string);
+                if !allowed && !main.is_pairing_window_open() {
+                    tracing::warn!(
+                        "Ignoring handshake from {peer_addr} since {} is not in the allowlist",
+                        packet.mac_string
+                    );
+                    return Ok(());
+                }
+
+                let max_devices = main.config.max_devices;
+                if max_devices != 0
+                    && self.devices.len() >= max_devices
+                    && !self.is_known_device(&packet.mac_string, peer_addr)
+                {
+                    tracing::warn!(
+                        "Rejecting handshake from {} at {peer_addr} since the server is full with {max_devices} devices",
+                        packet.mac_string
+                    );
+                    self.socket.send_to(
+                        &UdpPacketServerFull::to_bytes(),
+                        peer_addr,
+                        SendPriority::Handshake,
+                    );
+                    return Ok(());
+                }
+
+                // Only once it's let in so a device that gets turned away isn't added
+                if !allowed {
+                    main.pair_device(&packet.mac_string);
+                }
+
+                self.socket.send_to(
+                    &UdpPacketHandshake::to_bytes(),
+                    peer_addr,
+                    SendPriority::Handshake,
+                );
+                if let Some(device) = self.handle_handshake(packet, peer_addr, main) {
+                    device.packet_numbers.reset();
+                    device.reconnected(main);
+
+                    // Make sure the device still has the same settings as before
+                    if device.config.is_some() {
+                        device.check_config = true;
+                        self.socket.send_to(
+                            &UdpPacketDeviceConfig::request_bytes(),
+                            peer_addr,
+                            SendPriority::Command,
+                        );
+                    }
+
+                    // The statuses could've been sent before the handshake got here, or not at all
+                    // for trackers that are off
+                    self.socket.send_to(
+                        &UdpPacketRequestStatus::to_bytes(),
+                        peer_addr,
+                        SendPriority::Handshake,
+                    );
+                    for bytes in self.early_packets.take(peer_addr, Instant::now()) {
+                        Box::pin(self.handle_packet(&bytes, peer_addr, main)).await?;
+                    }
                 }
             }
             Some(UdpPacket::TrackerData((mut packet, device))) => {
                 while let Some(data) = packet.next() {
                     let global_index = device.get_global_tracker_index(main, data.tracker_index);
-                    main.update_tracker_data(global_index, data.accleration, data.orientation);
+                    let span = tracing::trace_span!(
+                        target: SPAN_TARGET,
+                        "tracker_data",
+                        tracker_index = global_index
+                    );
+                    let _span = span.entered();
+                    main.update_tracker_data(
+                        global_index,
+                        data.acceleration,
+                        data.orientation,
+                        device.last_packet_received_time,
+                    );
                 }
             }
             Some(UdpPacket::TrackerStatus((packet, device))) => {
-                log::trace!("Got status: {:?}", packet);
+                tracing::trace!("Got status: {:?}", packet);
 
-                self.socket.send_to(&packet.to_bytes(), peer_addr).await?;
+                self.socket
+                    .send_to(&packet.to_bytes(), peer_addr, SendPriority::Handshake);
                 let global_index = device.get_global_tracker_index(main, packet.tracker_index);
 
-                main.trackers[global_index].info.status = packet.tracker_status;
-                main.trackers[global_index].data = TrackerData::default();
-                main.tracker_info_updated(global_index);
+                if let Some(tracker) = main.trackers.get_mut(global_index) {
+                    tracker.info.status = packet.tracker_status;
+                    tracker.data = TrackerData::default();
+                    tracker.raw_data = RawTrackerData::default();
+                    tracker.acceleration_input = AccelMps2::ZERO;
+                    main.tracker_info_updated(global_index);
+                }
+            }
+            Some(UdpPacket::DeviceConfig((packet, device))) => {
+                Self::handle_device_config(main, packet, device);
+            }
+            Some(UdpPacket::DeviceError((packet, device))) => {
+                Self::handle_device_error(main, packet, device);
+            }
+            Some(UdpPacket::RawSensorData((packet, device))) => {
+                for sample in &packet.samples {
+                    let global_index = device.get_global_tracker_index(main, sample.tracker_index);
+                    main.record_raw_sensors(global_index, device.last_packet_received_time, sample);
+                }
+            }
+            Some(UdpPacket::BatteryLevel((packet, device))) => {
+                device.update_battery(main, packet.percent);
+            }
+            Some(UdpPacket::InputEvent((packet, device))) => {
+                let axis_interval = main.config.input.axis_interval();
+                if let Some(value) =
+                    device
+                        .inputs
+                        .filter(packet.input, packet.kind, packet.value, axis_interval)
+                {
+                    main.handle_input(&device.mac, packet.input, packet.kind, value);
+                }
             }
             None => (),
         }
@@ -216,56 +1160,1503 @@ impl UdpServer {
         Ok(())
     }
 
+    async fn reply_to_probe(&mut self, peer_addr: SocketAddr) -> tokio::io::Result<()> {
+        let info = UdpPacketServerInfo {
+            instance_id: self.instance_id,
+            #[cfg(feature = "websocket")]
+            websocket_port: crate::WEBSOCKET_PORT,
+            #[cfg(not(feature = "websocket"))]
+            websocket_port: 0,
+            udp_port: self.socket.socket.local_addr()?.port(),
+            tls: false,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+
+        tracing::trace!("Replying to server probe from {peer_addr}");
+        self.socket
+            .send_to(&info.to_bytes(), peer_addr, SendPriority::Telemetry);
+        Ok(())
+    }
+
+    /// The device probably still thinks it's connected from before the server restarted so get it
+    /// to handshake again instead of dropping its data
+    async fn request_handshake(&mut self, peer_addr: SocketAddr) -> tokio::io::Result<()> {
+        if self
+            .handshake_requests
+            .get(&peer_addr)
+            .is_some_and(|time| time.elapsed() < HANDSHAKE_REQUEST_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        tracing::info!("Requesting handshake from unknown address {peer_addr}");
+        self.handshake_requests.insert(peer_addr, Instant::now());
+        self.socket.send_to(
+            &UdpPacketHandshakeRequest::to_bytes(),
+            peer_addr,
+            SendPriority::Handshake,
+        );
+        Ok(())
+    }
+
+    fn is_known_device(&self, mac: &str, address: SocketAddr) -> bool {
+        match self.mac_to_device_index.get(mac) {
+            Some(MacDevices::Single(_)) => true,
+            Some(MacDevices::Duplicated(addresses)) => addresses.contains_key(&address),
+            None => false,
+        }
+    }
+
     fn handle_handshake(
         &mut self,
         packet: UdpPacketHandshake,
         peer_addr: SocketAddr,
+        main: &MainServer,
     ) -> Option<&mut UdpDevice> {
         // Check if the device already has connected with a mac address
-        if let Some(index) = self.mac_to_device_index.get(&packet.mac_string) {
-            let device = &mut self.devices[*index];
-            let index = device.index;
-            let old_address = device.address;
-
-            // Move over to the new address if the device has a new ip
-            if device.address != peer_addr {
-                self.address_to_device_index.remove(&old_address);
-                self.address_to_device_index.insert(peer_addr, index);
-                device.address = peer_addr;
-                log::info!("Reconnected from {peer_addr} from old: {old_address}");
-                return Some(device);
-            } else if device.timed_out {
-                log::info!("Reconnected from {peer_addr}");
-                return Some(device);
-            } else {
-                log::warn!("Received handshake packet while already connected");
-                return None;
-            }
-        }
-
-        // Create a new udp device
-        let index = self.devices.len();
-        let device = UdpDevice::new(index, peer_addr, packet.mac_string.clone());
-        self.mac_to_device_index.insert(packet.mac_string, index);
-        self.address_to_device_index.insert(peer_addr, index);
-        self.devices.push(device);
-        log::info!("New device connected from {peer_addr}");
-        self.devices.get_mut(index)
-    }
+        let index = match self.mac_to_device_index.get(&packet.mac_string) {
+            Some(MacDevices::Single(index)) => *index,
+            Some(MacDevices::Duplicated(addresses)) => match addresses.get(&peer_addr) {
+                Some(index) => *index,
+                None => return Some(self.add_device(packet, peer_addr)),
+            },
+            None => return Some(self.add_device(packet, peer_addr)),
+        };
 
-    fn handle_pong(main: &mut MainServer, packet: UdpPacketPingPong, device: &mut UdpDevice) {
-        if packet.id != device.current_ping_id {
-            return;
+        let device = &mut self.devices[index];
+        device.unanswered_handshakes += 1;
+        if device.unanswered_handshakes == UNANSWERED_HANDSHAKE_WARNING {
+            let warning = format!(
+                "Device {} keeps handshaking without sending data so the server's responses probably aren't reaching it, check outbound firewall rules and that the device isn't behind NAT",
+                packet.mac_string
+            );
+            tracing::warn!("{warning}");
+            main.notify_warning(&warning);
         }
 
-        if let Some(start_time) = device.current_ping_start_time {
-            for global_index in &device.tracker_indexs {
-                let latency = start_time.elapsed() / 2;
-                main.trackers[*global_index].info.latency_ms = Some(latency.as_millis() as u32);
-                main.tracker_info_updated(*global_index);
-            }
+        let old_address = device.address;
+        if old_address != peer_addr && device.record_address_change() {
+            let warning = format!(
+                "Multiple devices are using the MAC address {}. This happens when the same firmware image with a hardcoded MAC address is flashed onto multiple boards, reflash them so that each uses its own MAC address",
+                packet.mac_string
+            );
+            tracing::warn!("{warning}");
+            main.notify_warning(&warning);
+
+            // Keep the existing device at its address and give the new address its own device
+            self.mac_to_device_index.insert(
+                packet.mac_string.clone(),
+                MacDevices::Duplicated(HashMap::from([(old_address, index)])),
+            );
+            return Some(self.add_device(packet, peer_addr));
+        }
+
+        let device = &mut self.devices[index];
+
+        // Move over to the new address if the device has a new ip
+        if old_address != peer_addr {
+            // The firmware could've been updated since the last handshake
+            device.variant = packet.variant;
+            self.address_to_device_index.remove(&old_address);
+            self.address_to_device_index.insert(peer_addr, index);
+            device.address = peer_addr;
+            device.connection_history.disconnected(
+                device.last_packet_received_time,
+                DisconnectCause::AddressChange,
+            );
+            tracing::info!("Reconnected from {peer_addr} from old: {old_address}");
+            return Some(device);
+        }
+
+        device.variant = packet.variant;
+        if device.timed_out {
+            tracing::info!("Reconnected from {peer_addr}");
+            Some(device)
+        } else if device.packet_numbers.latest() != 0 {
+            // The device must have restarted since it's handshaking after sending packets
+            device
+                .connection_history
+                .disconnected(device.last_packet_received_time, DisconnectCause::Reboot);
+            tracing::info!("Reconnected from {peer_addr} after restarting");
+            Some(device)
+        } else {
+            self.warnings.warn(
+                "Received handshake packet while already connected",
+                peer_addr,
+            );
+            None
+        }
+    }
+
+    fn add_device(&mut self, packet: UdpPacketHandshake, peer_addr: SocketAddr) -> &mut UdpDevice {
+        let index = self.devices.len();
+        let mut device = UdpDevice::new(peer_addr, packet.mac_string.clone());
+        device.variant = packet.variant;
+
+        match self.mac_to_device_index.get_mut(&packet.mac_string) {
+            Some(MacDevices::Duplicated(addresses)) => {
+                // Trackers need a different id from the other devices with the same mac
+                device.id = format!("{}@{}", packet.mac_string, peer_addr.ip());
+                addresses.insert(peer_addr, index);
+            }
+            _ => {
+                self.mac_to_device_index
+                    .insert(packet.mac_string, MacDevices::Single(index));
+            }
+        }
+
+        self.address_to_device_index.insert(peer_addr, index);
+        match &device.variant {
+            Some(variant) => tracing::info!("New device ({variant}) connected from {peer_addr}"),
+            None => tracing::info!("New device connected from {peer_addr}"),
+        }
+
+        self.devices.push(device);
+        &mut self.devices[index]
+    }
+
+    #[cfg(feature = "websocket")]
+    async fn handle_device_command(
+        &mut self,
+        command: DeviceCommand,
+        main: &mut MainServer,
+    ) -> anyhow::Result<()> {
+        let mac = match &command {
+            DeviceCommand::GetConfig { mac }
+            | DeviceCommand::SetConfigValue { mac, .. }
+            | DeviceCommand::RunNetworkTest { mac } => mac,
+            DeviceCommand::UpdateBlocklist => {
+                self.update_blocklist(main);
+                return Ok(());
+            }
+            #[cfg(feature = "recording")]
+            DeviceCommand::ControlPlayback { action } => {
+                self.control_playback(action.clone(), main);
+                return Ok(());
+            }
+            DeviceCommand::SetUdpPort { port } => {
+                if let Err(error) = self.change_port(*port, main).await {
+                    let error = format!("{error:#}");
+                    tracing::error!("{error}");
+                    main.notify_error(&error);
+                }
+                return Ok(());
+            }
+            DeviceCommand::RemapTrackerIndices { new_indices } => {
+                for device in &mut self.devices {
+                    for index in &mut device.tracker_indexs {
+                        if let Some(new_index) = new_indices.get(index) {
+                            *index = *new_index;
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        let indices = match self.mac_to_device_index.get(mac) {
+            Some(MacDevices::Single(index)) => vec![*index],
+            Some(MacDevices::Duplicated(addresses)) => addresses.values().copied().collect(),
+            None => {
+                main.notify_error(&format!(
+                    "No device with the MAC address {mac} has connected"
+                ));
+                return Ok(());
+            }
+        };
+
+        for index in indices {
+            let device = &mut self.devices[index];
+            match &command {
+                DeviceCommand::GetConfig { mac } => {
+                    // Send the cached config straight away then refresh it from the device
+                    if let Some(entries) = &device.config {
+                        main.send_to_clients(ServerMessage::DeviceConfig {
+                            mac: mac.clone(),
+                            entries: entries.clone(),
+                        });
+                    }
+
+                    self.socket.send_to(
+                        &UdpPacketDeviceConfig::request_bytes(),
+                        device.address,
+                        SendPriority::Command,
+                    );
+                }
+                DeviceCommand::RunNetworkTest { .. } => {
+                    tracing::info!(
+                        "Running network test on {} at {}",
+                        device.mac,
+                        device.address
+                    );
+                    device.network_test = Some(NetworkTest::default());
+                }
+                DeviceCommand::UpdateBlocklist
+                | DeviceCommand::SetUdpPort { .. }
+                | DeviceCommand::RemapTrackerIndices { .. } => {
+                    unreachable!("handled before finding the devices")
+                }
+                #[cfg(feature = "recording")]
+                DeviceCommand::ControlPlayback { .. } => {
+                    unreachable!("handled before finding the devices")
+                }
+                DeviceCommand::SetConfigValue { key, value, .. } => {
+                    let packet = UdpPacketSetConfigKv { key, value };
+                    self.socket
+                        .send_to(&packet.to_bytes(), device.address, SendPriority::Command);
+
+                    // Only the latest value for a key needs to be acknowledged
+                    device
+                        .pending_config_values
+                        .retain(|pending| pending.key != *key);
+                    device.pending_config_values.push(PendingConfigValue {
+                        key: key.clone(),
+                        value: value.clone(),
+                        last_sent_time: Instant::now(),
+                        attempts: 1,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the network test probes that are due and reports the finished tests
+    async fn update_network_tests(&mut self, main: &mut MainServer) -> anyhow::Result<()> {
+        for device in &mut self.devices {
+            let Some(test) = &mut device.network_test else {
+                continue;
+            };
+
+            if let Some(id) = test.next_probe() {
+                self.socket.send_to(
+                    &UdpPacketPingPong::to_bytes(id, main.clock.now_us()),
+                    device.address,
+                    SendPriority::Telemetry,
+                );
+            }
+
+            if let Some(test) = device.network_test.take_if(|test| test.is_finished()) {
+                let result = test.finish(device.mac.clone());
+                tracing::info!(
+                    "Network test on {} lost {:.1}% with an average round trip of {}us",
+                    result.mac,
+                    result.loss_percent,
+                    result.avg_rtt_us
+                );
+                main.send_to_clients(ServerMessage::NetworkTestResult { result });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn load_blocklist(&mut self, config: &ServerConfig) {
+        self.blocklist = Blocklist::from_config(config);
+    }
+
+    /// Reloads the blocklist and removes any connected devices that are now blocked
+    #[cfg(feature = "websocket")]
+    fn update_blocklist(&mut self, main: &mut MainServer) {
+        self.load_blocklist(&main.config);
+
+        // Go backwards so removing a device doesn't change the indices still to be checked
+        for index in (0..self.devices.len()).rev() {
+            let device = &self.devices[index];
+            if self.blocklist.is_blocked(&device.mac, device.address) {
+                tracing::info!(
+                    "Removing blocked device {} at {}",
+                    device.mac,
+                    device.address
+                );
+                self.remove_device(index, main);
+            }
+        }
+    }
+
+    /// Removes the device along with its trackers
+    #[cfg(feature = "websocket")]
+    fn remove_device(&mut self, index: usize, main: &mut MainServer) {
+        let device = self.devices.remove(index);
+        for global_index in device.tracker_indexs {
+            main.remove_tracker(global_index);
+        }
+
+        // Shift down the indices of the devices after the removed one
+        let fix_index = |device_index: &mut usize| {
+            if *device_index > index {
+                *device_index -= 1;
+            }
+        };
+
+        self.address_to_device_index
+            .retain(|_, device_index| *device_index != index);
+        self.address_to_device_index
+            .values_mut()
+            .for_each(fix_index);
+
+        self.mac_to_device_index.retain(|_, devices| match devices {
+            MacDevices::Single(device_index) => *device_index != index,
+            MacDevices::Duplicated(addresses) => {
+                addresses.retain(|_, device_index| *device_index != index);
+                addresses.values_mut().for_each(fix_index);
+                !addresses.is_empty()
+            }
+        });
+        for devices in self.mac_to_device_index.values_mut() {
+            if let MacDevices::Single(device_index) = devices {
+                fix_index(device_index);
+            }
+        }
+    }
+
+    fn handle_device_config(
+        main: &mut MainServer,
+        packet: UdpPacketDeviceConfig,
+        device: &mut UdpDevice,
+    ) {
+        let entries = packet.entries;
+        if std::mem::take(&mut device.check_config) {
+            if let Some(cached) = &device.config {
+                let changed_keys: BTreeSet<&String> = cached
+                    .keys()
+                    .chain(entries.keys())
+                    .filter(|key| cached.get(*key) != entries.get(*key))
+                    .collect();
+
+                if !changed_keys.is_empty() {
+                    let keys: Vec<&str> = changed_keys.into_iter().map(String::as_str).collect();
+                    let warning = format!(
+                        "Settings on device {} changed while it was disconnected ({}), it may have been reset",
+                        device.mac,
+                        keys.join(", ")
+                    );
+                    tracing::warn!("{warning}");
+                    main.notify_warning(&warning);
+                }
+            }
+        }
+
+        // The device sends its config after setting a value which acts as the acknowledgement
+        device
+            .pending_config_values
+            .retain(|pending| entries.get(&pending.key) != Some(&pending.value));
+
+        main.send_to_clients(ServerMessage::DeviceConfig {
+            mac: device.mac.clone(),
+            entries: entries.clone(),
+        });
+        device.config = Some(entries);
+    }
+
+    /// Sends the latest axis values that the rate limit held back
+    fn send_held_back_inputs(&mut self, main: &mut MainServer) {
+        let axis_interval = main.config.input.axis_interval();
+        for device in &mut self.devices {
+            for (input, value) in device.inputs.take_due_axes(axis_interval) {
+                main.handle_input(&device.mac, input, InputKind::Axis, value);
+            }
+        }
+    }
+
+    fn handle_device_error(
+        main: &mut MainServer,
+        packet: UdpPacketDeviceError,
+        device: &mut UdpDevice,
+    ) {
+        let detail = packet.detail.as_deref().unwrap_or("");
+        tracing::error!("Device {} reported {:?} {detail}", device.mac, packet.code);
+
+        let now = Instant::now();
+        let error = DeviceError {
+            code: packet.code,
+            detail: packet.detail,
+            time: now,
+        };
+        let Some(suppressed) = device.errors.push(error.clone()) else {
+            return;
+        };
+
+        main.send_to_clients(ServerMessage::DeviceError {
+            mac: device.mac.clone(),
+            code: error.code,
+            detail: error.detail,
+            advice: error.code.advice(),
+            suppressed,
+            errors_last_hour: device
+                .errors
+                .errors_within(Duration::from_secs(60 * 60), now),
+        });
+    }
+
+    fn handle_pong(main: &mut MainServer, packet: UdpPacketPingPong, device: &mut UdpDevice) {
+        if packet.id >= NETWORK_TEST_PING_ID_START {
+            if let Some(test) = &mut device.network_test {
+                test.handle_pong(packet.id);
+            }
+            return;
+        }
+
+        if packet.id != device.current_ping_id {
+            return;
+        }
+
+        if let Some(start_time) = device.current_ping_start_time {
+            if let Some(device_time_us) = packet.device_time_us {
+                Self::update_clock_offset(main, device, start_time, device_time_us);
+            }
+
+            for global_index in &device.tracker_indexs {
+                let latency = start_time.elapsed() / 2;
+                if let Some(tracker) = main.trackers.get_mut(*global_index) {
+                    tracker.info.latency_ms = Some(latency.as_millis() as u32);
+                    main.tracker_info_updated(*global_index);
+                }
+            }
 
             device.current_ping_start_time = None;
         }
     }
+
+    fn update_clock_offset(
+        main: &MainServer,
+        device: &mut UdpDevice,
+        ping_start_time: Instant,
+        device_time_us: u64,
+    ) {
+        let offset_us =
+            (main.clock).device_offset_us(ping_start_time, Instant::now(), device_time_us);
+
+        // Smooth it out since the round trips aren't always symmetric
+        let smoothed_us = match device.clock_offset_us {
+            Some(previous_us) => {
+                if (offset_us - previous_us).abs() > CLOCK_OFFSET_LOG_THRESHOLD_US {
+                    tracing::debug!(
+                        "Clock offset of {} moved from {previous_us}us to {offset_us}us",
+                        device.mac
+                    );
+                }
+                previous_us + (offset_us - previous_us) / 8
+            }
+            None => offset_us,
+        };
+        device.clock_offset_us = Some(smoothed_us);
+    }
+}
+
+async fn bind_socket(config: &DiscoveryConfig) -> anyhow::Result<UdpSocket> {
+    let socket = UdpSocket::bind(("0.0.0.0", config.udp_port)).await?;
+    socket.set_broadcast(true)?;
+
+    // Devices can still be reached over broadcast discovery so not being able to join isn't fatal
+    if let Err(error) = socket.join_multicast_v4(config.multicast_ip, Ipv4Addr::UNSPECIFIED) {
+        tracing::warn!(
+            "Failed to join multicast group {}: {error}",
+            config.multicast_ip
+        );
+    }
+
+    if let Err(error) = socket.set_multicast_ttl_v4(config.multicast_ttl) {
+        tracing::warn!("Failed to set multicast TTL: {error}");
+    }
+
+    Ok(socket)
+}
+
+/// Decides whether to keep using multicast or fallback to announcing over broadcast
+fn next_discovery_mode(
+    config: &DiscoveryConfig,
+    current: DiscoveryMode,
+    elapsed: Duration,
+    has_devices: bool,
+) -> DiscoveryMode {
+    if config.mode == DiscoveryMode::Broadcast || current == DiscoveryMode::Broadcast {
+        return DiscoveryMode::Broadcast;
+    }
+
+    let fallback_window = Duration::from_secs(config.broadcast_fallback_secs);
+    if config.broadcast_fallback_secs != 0 && !has_devices && elapsed > fallback_window {
+        DiscoveryMode::Broadcast
+    } else {
+        DiscoveryMode::Multicast
+    }
+}
+
+/// Random enough to tell apart servers that were started at the same time
+fn new_instance_id() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+/// Gets the ip and netmask of every ipv4 interface
+pub fn interface_addresses() -> Vec<(Ipv4Addr, Ipv4Addr)> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter_map(|interface| match interface.addr {
+                if_addrs::IfAddr::V4(addr) => Some((addr.ip, addr.netmask)),
+                _ => None,
+            })
+            .collect(),
+        Err(error) => {
+            tracing::warn!("Failed to get network interfaces: {error}");
+            Vec::new()
+        }
+    }
+}
+
+/// Gets the addresses to send announcements to from a list of interface ips and netmasks
+pub fn broadcast_addresses(interfaces: &[(Ipv4Addr, Ipv4Addr)]) -> Vec<Ipv4Addr> {
+    let mut addresses = vec![Ipv4Addr::BROADCAST];
+    for (ip, netmask) in interfaces {
+        if ip.is_loopback() {
+            continue;
+        }
+
+        let address = Ipv4Addr::from(u32::from(*ip) | !u32::from(*netmask));
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        discovery_client::{DiscoveryClient, DiscoveryEvent, SERVER_EXPIRY},
+        span_recorder::SpanRecorder,
+        udp_packet::{
+            format_mac, PACKET_HANDSHAKE_REQUEST, PACKET_SERVER_FULL, PACKET_TRACKER_DATA,
+        },
+    };
+
+    async fn test_server() -> UdpServer {
+        let config = DiscoveryConfig {
+            udp_port: 0,
+            ..Default::default()
+        };
+        let mut server = UdpServer::new(&config, 4).await.unwrap();
+        server.socket.socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server
+    }
+
+    fn handshake(mac: &str) -> UdpPacketHandshake {
+        UdpPacketHandshake {
+            mac_string: mac.to_string(),
+            variant: None,
+        }
+    }
+
+    fn address(ip: &str) -> SocketAddr {
+        format!("{ip}:5828").parse().unwrap()
+    }
+
+    fn handshake_bytes(mac: [u8; 6]) -> Vec<u8> {
+        [&[PACKET_HANDSHAKE][..], b"MCDEV", &mac].concat()
+    }
+
+    fn tracker_data_bytes(packet_number: u32, orientation: glam::Quat) -> Vec<u8> {
+        let mut bytes = vec![PACKET_TRACKER_DATA];
+        bytes.extend(packet_number.to_le_bytes());
+        bytes.push(0);
+        for value in orientation.to_array().into_iter().chain([0.; 3]) {
+            bytes.extend(f32::to_le_bytes(value));
+        }
+        bytes.push(0xff);
+        bytes
+    }
+
+    #[cfg(feature = "websocket")]
+    impl UdpServer {
+        /// Adds a device with a tracker that's sent data, for tests of the main server's loop
+        pub(crate) async fn add_test_device(&mut self, main: &mut MainServer) {
+            let peer = address("10.0.0.2");
+            let bytes = handshake_bytes([1, 2, 3, 4, 5, 6]);
+            self.handle_packet(&bytes, peer, main).await.unwrap();
+            let bytes = tracker_data_bytes(1, glam::Quat::IDENTITY);
+            self.handle_packet(&bytes, peer, main).await.unwrap();
+        }
+
+        /// Pretends the devices last sent a packet this long ago, like when the computer slept
+        pub(crate) fn age_devices(&mut self, age: Duration) {
+            for device in &mut self.devices {
+                device.last_packet_received_time -= age;
+            }
+        }
+    }
+
+    /// Takes the packets waiting to be sent, returning their packet types
+    fn sent_packet_types(server: &mut UdpServer) -> Vec<u8> {
+        std::iter::from_fn(|| server.socket.queue.pop())
+            .map(|(packet, _)| packet.bytes[0])
+            .collect()
+    }
+
+    /// Every address points to the device that's at that address and the other way around
+    fn assert_addresses_in_sync(server: &UdpServer) {
+        assert_eq!(server.address_to_device_index.len(), server.devices.len());
+        for (address, index) in &server.address_to_device_index {
+            assert_eq!(server.devices[*index].address, *address);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discovery_clients_find_the_server_then_lose_it_once_it_stops() {
+        let mut server = test_server().await;
+        let port = server.socket.socket.local_addr().unwrap().port();
+        let responder = tokio::spawn(async move {
+            let mut main = MainServer::default();
+            loop {
+                server.tick(&mut main).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        let mut client = DiscoveryClient::new(port).await.unwrap();
+        let event = client.next_event().await.unwrap();
+        let DiscoveryEvent::Found(found) = event else {
+            panic!("expected the server to be found, got {event:?}");
+        };
+        assert_eq!(found.udp_port, port);
+
+        // Replies to the later probes don't find it again
+        tokio::time::timeout(SERVER_EXPIRY / 2, client.next_event())
+            .await
+            .expect_err("found the same server twice");
+
+        responder.abort();
+        responder.await.ok();
+        let stop_time = tokio::time::Instant::now();
+        let event = client.next_event().await.unwrap();
+        let DiscoveryEvent::Lost(lost) = event else {
+            panic!("expected the server to be lost, got {event:?}");
+        };
+        assert_eq!(lost, found);
+        assert!(stop_time.elapsed() <= SERVER_EXPIRY);
+        assert_eq!(client.servers().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn known_mac_from_new_ip_moves_the_device() {
+        let mut server = test_server().await;
+        let main = MainServer::default();
+        let (old, new) = (address("10.0.0.2"), address("10.0.0.3"));
+
+        assert!(server
+            .handle_handshake(handshake("AA:BB"), old, &main)
+            .is_some());
+        let device = server
+            .handle_handshake(handshake("AA:BB"), new, &main)
+            .unwrap();
+        assert_eq!(device.address, new);
+
+        assert_eq!(server.devices.len(), 1);
+        assert!(!server.address_to_device_index.contains_key(&old));
+        assert_eq!(server.address_to_device_index.get(&new), Some(&0));
+        assert_addresses_in_sync(&server);
+    }
+
+    #[tokio::test]
+    async fn same_ip_rehandshake_only_reconnects_after_a_restart() {
+        let mut server = test_server().await;
+        let main = MainServer::default();
+        let peer = address("10.0.0.2");
+
+        server.handle_handshake(handshake("AA:BB"), peer, &main);
+        // The response didn't reach the device so it's still handshaking
+        assert!(server
+            .handle_handshake(handshake("AA:BB"), peer, &main)
+            .is_none());
+        assert_eq!(server.devices[0].unanswered_handshakes, 1);
+
+        // Handshaking after sending data means it restarted
+        server.devices[0]
+            .packet_numbers
+            .accept(10, crate::config::PacketOrderPolicy::StrictDrop);
+        assert!(server
+            .handle_handshake(handshake("AA:BB"), peer, &main)
+            .is_some());
+        assert_eq!(server.devices.len(), 1);
+        assert_addresses_in_sync(&server);
+    }
+
+    #[tokio::test]
+    async fn addresses_stay_in_sync_with_duplicate_macs() {
+        let mut server = test_server().await;
+        let main = MainServer::default();
+        let (first, second) = (address("10.0.0.2"), address("10.0.0.3"));
+
+        // Two boards with the same mac keep taking the device from each other
+        for i in 0..=DUPLICATE_MAC_SWITCHES + 1 {
+            let peer = if i % 2 == 0 { first } else { second };
+            server.handle_handshake(handshake("AA:BB"), peer, &main);
+            assert_addresses_in_sync(&server);
+        }
+        server.handle_handshake(handshake("CC:DD"), address("10.0.0.4"), &main);
+
+        assert_eq!(server.devices.len(), 3);
+        assert!(matches!(
+            server.mac_to_device_index.get("AA:BB"),
+            Some(MacDevices::Duplicated(addresses)) if addresses.len() == 2
+        ));
+        assert_ne!(server.devices[0].id, server.devices[1].id);
+        assert_addresses_in_sync(&server);
+
+        // Each board keeps its own device from now on
+        assert_eq!(server.devices[0].address, first);
+        assert!(server
+            .handle_handshake(handshake("AA:BB"), first, &main)
+            .is_none());
+        assert!(server
+            .handle_handshake(handshake("AA:BB"), second, &main)
+            .is_none());
+        assert_eq!(server.devices.len(), 3);
+        assert_addresses_in_sync(&server);
+    }
+
+    #[test]
+    fn only_frequent_address_changes_are_duplicate_macs() {
+        let mut device = UdpDevice::new(address("10.0.0.2"), "AA:BB".to_string());
+        let long_ago = Instant::now() - DUPLICATE_MAC_WINDOW - Duration::from_secs(1);
+        device.address_changes =
+            std::iter::repeat_n(long_ago, DUPLICATE_MAC_SWITCHES * 2).collect();
+
+        // A device moving between networks every so often isn't a duplicate
+        assert!(!device.record_address_change());
+        assert_eq!(device.address_changes.len(), 1);
+
+        for _ in 1..DUPLICATE_MAC_SWITCHES {
+            assert!(!device.record_address_change());
+        }
+        assert!(device.record_address_change());
+    }
+
+    /// Warnings sent to the clients that contain the text
+    #[cfg(feature = "websocket")]
+    fn warnings_containing(
+        messages: &mut tokio::sync::broadcast::Receiver<crate::main_server::ChannelMessage>,
+        text: &str,
+    ) -> usize {
+        std::iter::from_fn(|| messages.try_recv().ok())
+            .filter(|message| {
+                matches!(&message.message, ServerMessage::Warning { warning } if warning.contains(text))
+            })
+            .count()
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test(start_paused = true)]
+    async fn firewall_probes_that_never_arrive_are_warned_about() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        // Bound so the probe goes somewhere but never read
+        let elsewhere = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = elsewhere.local_addr().unwrap().port();
+        server.firewall_probe = Some(
+            FirewallProbe::send(Ipv4Addr::LOCALHOST, port)
+                .await
+                .unwrap(),
+        );
+
+        server.upkeep_now = true;
+        server.tick(&mut main).await.unwrap();
+        assert_eq!(warnings_containing(&mut messages, "firewall"), 0);
+
+        tokio::time::advance(crate::firewall::PROBE_TIMEOUT + Duration::from_millis(1)).await;
+        server.upkeep_now = true;
+        server.tick(&mut main).await.unwrap();
+        assert_eq!(
+            warnings_containing(&mut messages, &remediation_hint(port)),
+            1
+        );
+        assert!(server.firewall_probe.is_none());
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test(start_paused = true)]
+    async fn firewall_probes_that_arrive_are_not_warned_about() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let port = server.socket.socket.local_addr().unwrap().port();
+        server.firewall_probe = Some(
+            FirewallProbe::send(Ipv4Addr::LOCALHOST, port)
+                .await
+                .unwrap(),
+        );
+
+        // Gives the socket a chance to see the probe
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        server.tick(&mut main).await.unwrap();
+        assert!(server.firewall_probe.is_none());
+        // Not handled as a packet from a device either
+        assert!(server.devices.is_empty());
+
+        tokio::time::advance(crate::firewall::PROBE_TIMEOUT * 2).await;
+        server.upkeep_now = true;
+        server.tick(&mut main).await.unwrap();
+        assert_eq!(warnings_containing(&mut messages, "firewall"), 0);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn devices_that_only_handshake_are_warned_about_once() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let peer = address("10.0.0.2");
+        let mac = [1, 2, 3, 4, 5, 6];
+        let warning = "keeps handshaking without sending data";
+
+        let mut warnings = Vec::new();
+        for _ in 0..UNANSWERED_HANDSHAKE_WARNING + 2 {
+            server
+                .handle_packet(&handshake_bytes(mac), peer, &mut main)
+                .await
+                .unwrap();
+            warnings.push(warnings_containing(&mut messages, warning));
+        }
+        // The first handshake adds the device so it isn't unanswered
+        let mut expected = vec![0; UNANSWERED_HANDSHAKE_WARNING as usize + 2];
+        expected[UNANSWERED_HANDSHAKE_WARNING as usize] = 1;
+        assert_eq!(warnings, expected);
+
+        // Sending data means the responses are getting through, so it starts counting again
+        let bytes = tracker_data_bytes(1, glam::Quat::IDENTITY);
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        assert_eq!(server.devices[0].unanswered_handshakes, 0);
+        for _ in 0..UNANSWERED_HANDSHAKE_WARNING - 1 {
+            server
+                .handle_packet(&handshake_bytes(mac), peer, &mut main)
+                .await
+                .unwrap();
+        }
+        assert_eq!(warnings_containing(&mut messages, warning), 0);
+
+        // Other devices aren't counted together with it
+        let other = address("10.0.0.3");
+        for _ in 0..UNANSWERED_HANDSHAKE_WARNING {
+            server
+                .handle_packet(&handshake_bytes([2; 6]), other, &mut main)
+                .await
+                .unwrap();
+        }
+        assert_eq!(warnings_containing(&mut messages, warning), 0);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn devices_that_keep_disconnecting_are_warned_about() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let mut device = UdpDevice::new(address("10.0.0.2"), format_mac([1; 6]));
+
+        let mut flappy_warnings = Vec::new();
+        for _ in 0..FLAPPY_EPISODES_PER_HOUR + 2 {
+            (device.connection_history).disconnected(Instant::now(), DisconnectCause::Timeout);
+            device.reconnected(&mut main);
+            flappy_warnings.push(warnings_containing(&mut messages, "times in the last hour"));
+        }
+
+        let mut expected = vec![0; FLAPPY_EPISODES_PER_HOUR];
+        expected.extend([1, 1]);
+        assert_eq!(flappy_warnings, expected);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn devices_sharing_a_mac_get_their_own_trackers() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        let (first, second) = (address("10.0.0.2"), address("10.0.0.3"));
+        let mac = [1, 2, 3, 4, 5, 6];
+
+        for i in 0..=DUPLICATE_MAC_SWITCHES + 1 {
+            let peer = if i % 2 == 0 { first } else { second };
+            let bytes = handshake_bytes(mac);
+            server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        }
+        for peer in [first, second] {
+            let bytes = tracker_data_bytes(1, glam::Quat::IDENTITY);
+            server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        }
+
+        let ids: Vec<_> = main
+            .trackers
+            .iter()
+            .map(|tracker| &tracker.info.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+
+        let warnings = std::iter::from_fn(|| messages.try_recv().ok())
+            .filter(|message| {
+                matches!(&message.message, ServerMessage::Warning { warning } if warning.contains("MAC address"))
+            })
+            .count();
+        assert_eq!(warnings, 1);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn blocking_a_connected_device_removes_it() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let (blocked, other) = (address("10.0.0.2"), address("10.0.0.3"));
+        for (peer, mac) in [(blocked, [1; 6]), (other, [2; 6])] {
+            let bytes = handshake_bytes(mac);
+            server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+            let bytes = tracker_data_bytes(1, glam::Quat::IDENTITY);
+            server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        }
+        assert_eq!(main.trackers.iter().count(), 2);
+
+        let mac = server.devices[0].mac.clone();
+        main.config.blocked_macs.push(mac);
+        server.update_blocklist(&mut main);
+        assert_eq!(server.devices.len(), 1);
+        assert_eq!(server.devices[0].address, other);
+        assert!(main.trackers.get(0).is_none());
+        assert_addresses_in_sync(&server);
+
+        // Handshaking again doesn't bring it back
+        sent_packet_types(&mut server);
+        let bytes = handshake_bytes([1; 6]);
+        server
+            .handle_packet(&bytes, blocked, &mut main)
+            .await
+            .unwrap();
+        let bytes = tracker_data_bytes(2, glam::Quat::IDENTITY);
+        server
+            .handle_packet(&bytes, blocked, &mut main)
+            .await
+            .unwrap();
+        assert_eq!(server.devices.len(), 1);
+        assert_eq!(main.trackers.iter().count(), 1);
+        assert!(sent_packet_types(&mut server).is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_devices_need_the_pairing_window_with_an_allowlist() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        main.config.allowlist_enabled = true;
+        let peer = address("10.0.0.2");
+
+        let bytes = handshake_bytes([1; 6]);
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        assert!(server.devices.is_empty());
+        assert!(sent_packet_types(&mut server).is_empty());
+
+        main.open_pairing_window(Duration::from_secs(60));
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        assert_eq!(server.devices.len(), 1);
+        assert!(sent_packet_types(&mut server).contains(&PACKET_HANDSHAKE));
+        assert_eq!(main.config.allowlist, [server.devices[0].mac.clone()]);
+    }
+
+    #[tokio::test]
+    async fn devices_not_in_the_allowlist_arent_told_the_server_is_full() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        main.config.allowlist_enabled = true;
+        main.config.max_devices = 1;
+        main.config.allowlist.push(format_mac([1; 6]));
+        let bytes = handshake_bytes([1; 6]);
+        server
+            .handle_packet(&bytes, address("10.0.0.2"), &mut main)
+            .await
+            .unwrap();
+        assert_eq!(server.devices.len(), 1);
+        sent_packet_types(&mut server);
+
+        let bytes = handshake_bytes([2; 6]);
+        let peer = address("10.0.0.3");
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        assert!(sent_packet_types(&mut server).is_empty());
+
+        // Could be let in through the pairing window but doesn't take an entry when turned away
+        main.open_pairing_window(Duration::from_secs(60));
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        assert_eq!(sent_packet_types(&mut server), [PACKET_SERVER_FULL]);
+        assert_eq!(server.devices.len(), 1);
+        assert_eq!(main.config.allowlist, [format_mac([1; 6])]);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn raw_sensor_data_is_recorded_by_tracker_id() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let path = std::env::temp_dir().join(format!("mycap-raw-udp-{}.csv", std::process::id()));
+        main.start_raw_sensor_recording(path.clone());
+        let peer = address("10.0.0.2");
+        server
+            .handle_packet(&handshake_bytes([1; 6]), peer, &mut main)
+            .await
+            .unwrap();
+
+        let mut bytes = vec![crate::udp_packet::PACKET_RAW_SENSOR_DATA];
+        bytes.extend(1_u32.to_le_bytes());
+        bytes.extend([0, crate::udp_packet::RawSensorSample::GYRO]);
+        for value in [0.5_f32, 1., 2.] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.push(0xff);
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        main.stop_raw_sensor_recording();
+
+        let id = &main.trackers.get(0).unwrap().info.id;
+        let mut contents = String::new();
+        for _ in 0..50 {
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines[0], "timestamp_us,tracker,gx,gy,gz");
+        assert!(
+            lines[1].ends_with(&format!(",{id},0.5,1,2")),
+            "{}",
+            lines[1]
+        );
+    }
+
+    /// Rotation around z of each recorded packet times its number, to tell which one was sent
+    #[cfg(all(feature = "recording", feature = "osc"))]
+    const REPLAY_ANGLE_STEP: f32 = 0.002;
+
+    /// Packet number of the latest orientation sent to the socket over OSC
+    #[cfg(all(feature = "recording", feature = "osc"))]
+    fn latest_osc_packet_number(receiver: &std::net::UdpSocket) -> Option<u32> {
+        const ADDRESS: &[u8] = b"/tracker/orientation\0\0\0\0,ffff\0\0\0";
+        let mut buffer = [0; 256];
+        let mut latest = None;
+        while let Ok(length) = receiver.recv(&mut buffer) {
+            if let Some(values) = buffer[..length].strip_prefix(ADDRESS) {
+                let value =
+                    |i: usize| f32::from_be_bytes(values[i * 4..i * 4 + 4].try_into().unwrap());
+                let angle = 2. * value(2).atan2(value(3));
+                latest = Some((angle / REPLAY_ANGLE_STEP).round() as u32);
+            }
+        }
+        latest
+    }
+
+    #[cfg(all(feature = "recording", feature = "osc"))]
+    #[tokio::test(start_paused = true)]
+    async fn replay_drives_the_osc_routes_at_the_recorded_rate() {
+        const START_US: u64 = 1_000_000;
+        const INTERVAL_US: u64 = 10_000;
+        const PACKETS: u32 = 50;
+        const TICK: Duration = Duration::from_millis(20);
+
+        let path =
+            std::env::temp_dir().join(format!("mycap-replay-osc-{}.log", std::process::id()));
+        let mut writer = PacketLogWriter::create(&path).unwrap();
+        let peer = address("10.0.0.2");
+        let handshake = handshake_bytes([1, 2, 3, 4, 5, 6]);
+        let data = (1..=PACKETS).map(|number| {
+            let orientation = glam::Quat::from_rotation_z(number as f32 * REPLAY_ANGLE_STEP);
+            tracker_data_bytes(number, orientation)
+        });
+        for (i, bytes) in std::iter::once(handshake).chain(data).enumerate() {
+            let timestamp_us = START_US + i as u64 * INTERVAL_US;
+            let packet = LoggedPacket {
+                timestamp_us,
+                address: peer,
+                bytes,
+            };
+            writer.write(&packet).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut server = test_server().await;
+        server.replay_raw(&path, Some(2.), true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let mut main = MainServer::default();
+        main.config.routes.push(crate::routing::OutputRoute {
+            name: "test".to_string(),
+            enabled: true,
+            destination: crate::routing::RouteDestination::Osc {
+                target: receiver.local_addr().unwrap(),
+                address: "/tracker".to_string(),
+            },
+            selector: crate::routing::RouteSelector::Indices(vec![0]),
+        });
+
+        // The loop starts again one microsecond after the last packet
+        let loop_us = PACKETS as u64 * INTERVAL_US + 1;
+        let tick_us = TICK.as_micros() as u64;
+        for tick in 1..=30 {
+            tokio::time::advance(TICK).await;
+            server.tick_replay(&mut main).await.unwrap();
+            main.tick(TICK);
+
+            // Twice as fast so each tick covers two ticks of the recording
+            let position_us = (tick * tick_us * 2) % loop_us;
+            let expected = (position_us / INTERVAL_US) as u32;
+            let sent = latest_osc_packet_number(&receiver);
+            assert_eq!(sent, Some(expected), "tick {tick}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tracker_timestamps_only_go_forward() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let peer = address("10.0.0.2");
+        let orientation = glam::Quat::IDENTITY;
+
+        // Including the data that's held back until the handshake
+        let bytes = tracker_data_bytes(1, orientation);
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let bytes = handshake_bytes([1, 2, 3, 4, 5, 6]);
+        server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+
+        let mut timestamps_us = vec![main.trackers.get(0).unwrap().data.timestamp_us];
+        for packet_number in 2..10 {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            let bytes = tracker_data_bytes(packet_number, orientation);
+            server.handle_packet(&bytes, peer, &mut main).await.unwrap();
+            let tracker = main.trackers.get(0).unwrap();
+            assert_eq!(tracker.data.timestamp_us, tracker.raw_data.timestamp_us);
+            timestamps_us.push(tracker.data.timestamp_us);
+        }
+
+        assert!(
+            timestamps_us.windows(2).all(|pair| pair[0] < pair[1]),
+            "{timestamps_us:?}"
+        );
+        assert!(timestamps_us.last().unwrap() <= &main.clock.now_us());
+    }
+
+    #[tokio::test]
+    async fn packets_before_the_handshake_are_replayed_after_it() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let peer = address("10.0.0.2");
+        let orientation = glam::Quat::from_rotation_z(1.);
+
+        server
+            .handle_packet(&tracker_data_bytes(1, orientation), peer, &mut main)
+            .await
+            .unwrap();
+        assert!(main.trackers.get(0).is_none());
+        assert_eq!(sent_packet_types(&mut server), [PACKET_HANDSHAKE_REQUEST]);
+
+        server
+            .handle_packet(&handshake_bytes([1, 2, 3, 4, 5, 6]), peer, &mut main)
+            .await
+            .unwrap();
+        let tracker = main.trackers.get(0).unwrap();
+        assert_eq!(tracker.raw_data.orientation.0, orientation);
+        assert_eq!(server.devices[0].packet_numbers.latest(), 1);
+
+        // Replayed once only
+        assert!(server.early_packets.take(peer, Instant::now()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn tracker_data_is_traced_inside_the_packet_span() {
+        let recorder = SpanRecorder::default();
+        let _guard = recorder.install();
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let peer = address("10.0.0.2");
+
+        server
+            .handle_packet_in_span(&handshake_bytes([1, 2, 3, 4, 5, 6]), peer, &mut main)
+            .await
+            .unwrap();
+        server
+            .handle_packet_in_span(
+                &tracker_data_bytes(1, glam::Quat::IDENTITY),
+                peer,
+                &mut main,
+            )
+            .await
+            .unwrap();
+
+        let packets = recorder.named("packet");
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].fields["peer_addr"], peer.to_string());
+        assert_eq!(
+            packets[1].fields["packet_type"],
+            PACKET_TRACKER_DATA.to_string()
+        );
+        assert_eq!(packets[1].fields["mac"], server.devices[0].mac);
+
+        let tracker_data = recorder.named("tracker_data");
+        assert_eq!(tracker_data.len(), 1);
+        assert_eq!(tracker_data[0].parent, Some("packet"));
+        assert_eq!(tracker_data[0].fields["tracker_index"], "0");
+    }
+
+    /// Acts as a device, sending its packets to the server's port
+    #[cfg(feature = "websocket")]
+    struct SimulatedDevice {
+        socket: UdpSocket,
+        server_port: u16,
+        packet_number: u32,
+    }
+
+    #[cfg(feature = "websocket")]
+    impl SimulatedDevice {
+        async fn connect(server: &mut UdpServer, main: &mut MainServer) -> Self {
+            let device = Self {
+                socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                server_port: server.socket.socket.local_addr().unwrap().port(),
+                packet_number: 0,
+            };
+            device.send(&handshake_bytes([1, 2, 3, 4, 5, 6])).await;
+            receive(server, main).await;
+            device
+        }
+
+        async fn send(&self, bytes: &[u8]) {
+            let address = SocketAddr::from((Ipv4Addr::LOCALHOST, self.server_port));
+            self.socket.send_to(bytes, address).await.unwrap();
+        }
+
+        async fn send_orientation(&mut self, orientation: glam::Quat) {
+            self.packet_number += 1;
+            self.send(&tracker_data_bytes(self.packet_number, orientation))
+                .await;
+        }
+
+        /// Follows the server to the port its packets come from like the firmware does
+        async fn follow_server(&mut self) {
+            let mut buffer = [0; 1024];
+            let (_, address) =
+                tokio::time::timeout(Duration::from_secs(1), self.socket.recv_from(&mut buffer))
+                    .await
+                    .expect("server should send to the device")
+                    .unwrap();
+            self.server_port = address.port();
+        }
+    }
+
+    /// Gives the packets time to arrive over loopback then handles them
+    #[cfg(feature = "websocket")]
+    async fn receive(server: &mut UdpServer, main: &mut MainServer) {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        server.tick(main).await.unwrap();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn devices_keep_sending_across_a_port_change() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let mut device = SimulatedDevice::connect(&mut server, &mut main).await;
+        let old_port = device.server_port;
+        let new_port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // Unconnected devices in multicast discovery wouldn't find the new port
+        server.discovery_mode = DiscoveryMode::Multicast;
+        assert!(server.change_port(new_port, &mut main).await.is_err());
+        assert_eq!(server.socket.socket.local_addr().unwrap().port(), old_port);
+
+        server.discovery_mode = DiscoveryMode::Broadcast;
+        server.change_port(new_port, &mut main).await.unwrap();
+        assert_eq!(main.config.discovery.udp_port, new_port);
+
+        // Still received on the old port until the device moves
+        let orientations = [0.5, 1., 1.5].map(glam::Quat::from_rotation_z);
+        device.send_orientation(orientations[0]).await;
+        receive(&mut server, &mut main).await;
+        assert_eq!(
+            main.trackers.get(0).unwrap().raw_data.orientation.0,
+            orientations[0]
+        );
+
+        while device.server_port != new_port {
+            device.follow_server().await;
+        }
+
+        for orientation in &orientations[1..] {
+            device.send_orientation(*orientation).await;
+            receive(&mut server, &mut main).await;
+            assert_eq!(
+                main.trackers.get(0).unwrap().raw_data.orientation.0,
+                *orientation
+            );
+        }
+
+        // The same device and tracker the whole time
+        assert_eq!(server.devices.len(), 1);
+        assert_eq!(server.devices[0].tracker_indexs, [0]);
+        assert_eq!(server.devices[0].packet_numbers.latest(), 3);
+        assert!(main.trackers.get(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn handshake_requests_are_rate_limited_per_address() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let (first, second) = (address("10.0.0.2"), address("10.0.0.3"));
+
+        for packet_number in 1..=5 {
+            let bytes = tracker_data_bytes(packet_number, glam::Quat::IDENTITY);
+            server
+                .handle_packet(&bytes, first, &mut main)
+                .await
+                .unwrap();
+            server
+                .handle_packet(&bytes, second, &mut main)
+                .await
+                .unwrap();
+        }
+        let requests = vec![PACKET_HANDSHAKE_REQUEST; 2];
+        assert_eq!(sent_packet_types(&mut server), requests);
+
+        // Asked again once the interval has passed
+        for time in server.handshake_requests.values_mut() {
+            *time -= HANDSHAKE_REQUEST_INTERVAL;
+        }
+        let bytes = tracker_data_bytes(6, glam::Quat::IDENTITY);
+        server
+            .handle_packet(&bytes, first, &mut main)
+            .await
+            .unwrap();
+        assert_eq!(sent_packet_types(&mut server), [PACKET_HANDSHAKE_REQUEST]);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn extension_packets_go_to_their_handler() {
+        let mut server = test_server().await;
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+
+        // A glove that sends its orientation as 4 floats without handshaking
+        let mut handlers = PacketHandlers::default();
+        let handler: crate::extension::PacketHandler = Box::new(|payload, address, context| {
+            let values: Vec<f32> = payload
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            let index = context.register_tracker(address.to_string(), Default::default());
+            let orientation = glam::Quat::from_slice(&values);
+            context.update_tracker_data(
+                index,
+                AccelMps2::ZERO,
+                crate::units::SensorQuat(orientation),
+            );
+            context.send_custom("glove", serde_json::json!({ "index": index }));
+        });
+        handlers.register(0x81, handler).unwrap();
+        assert!(handlers.register(0x81, Box::new(|_, _, _| {})).is_err());
+        assert!(handlers.register(0x10, Box::new(|_, _, _| {})).is_err());
+        server.set_packet_handlers(handlers);
+
+        let orientation = glam::Quat::from_rotation_z(1.);
+        let mut bytes = vec![0x81];
+        for value in orientation.to_array() {
+            bytes.extend(value.to_le_bytes());
+        }
+        let glove = address("10.0.0.2");
+        server
+            .handle_packet(&bytes, glove, &mut main)
+            .await
+            .unwrap();
+
+        let tracker = main.trackers.get(0).unwrap();
+        assert_eq!(tracker.info.id, glove.to_string());
+        assert_eq!(tracker.info.status, TrackerStatus::Ok);
+        assert_eq!(tracker.raw_data.orientation.0, orientation);
+        // Not treated as a device that needs to handshake
+        assert!(server.devices.is_empty());
+        assert!(sent_packet_types(&mut server).is_empty());
+
+        let custom = std::iter::from_fn(|| messages.try_recv().ok())
+            .find_map(|message| match message.message {
+                ServerMessage::Custom { kind, payload } => Some((kind, payload)),
+                _ => None,
+            })
+            .expect("clients should get the custom message");
+        assert_eq!(
+            custom,
+            ("glove".to_string(), serde_json::json!({ "index": 0 }))
+        );
+
+        // Extension packets without a handler are only counted
+        server
+            .handle_packet(&[0x90, 1, 2], glove, &mut main)
+            .await
+            .unwrap();
+        assert_eq!(server.packet_handlers.take_unhandled_count(), 1);
+        assert_eq!(main.trackers.iter().count(), 1);
+    }
+
+    #[test]
+    fn discovery_falls_back_to_broadcast_when_nothing_connects() {
+        use DiscoveryMode::{Broadcast, Multicast};
+
+        let config = DiscoveryConfig {
+            broadcast_fallback_secs: 30,
+            ..Default::default()
+        };
+        let (early, late) = (Duration::from_secs(10), Duration::from_secs(31));
+        let cases = [
+            (Multicast, early, false, Multicast),
+            (Multicast, late, false, Broadcast),
+            // A device has already found the server through multicast
+            (Multicast, late, true, Multicast),
+            // Devices connecting after the fallback don't switch back
+            (Broadcast, early, true, Broadcast),
+        ];
+        for (current, elapsed, has_devices, expected) in cases {
+            let mode = next_discovery_mode(&config, current, elapsed, has_devices);
+            assert_eq!(mode, expected, "{current:?} after {elapsed:?}");
+        }
+
+        let never = DiscoveryConfig {
+            broadcast_fallback_secs: 0,
+            ..Default::default()
+        };
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            next_discovery_mode(&never, Multicast, hour, false),
+            Multicast
+        );
+
+        let broadcast = DiscoveryConfig {
+            mode: Broadcast,
+            ..Default::default()
+        };
+        assert_eq!(
+            next_discovery_mode(&broadcast, Multicast, early, true),
+            Broadcast
+        );
+    }
 }
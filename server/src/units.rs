@@ -0,0 +1,116 @@
+use crate::{fusion, gravity::STANDARD_GRAVITY};
+
+/// Orientation as the tracker's own fusion reports it, its yaw is relative to wherever the tracker
+/// happened to be facing when it started
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SensorQuat(pub glam::Quat);
+
+impl SensorQuat {
+    /// Turns it into the world frame with the tracker's yaw offset from the calibration
+    pub fn to_world(self, yaw_offset: f32) -> WorldQuat {
+        WorldQuat(glam::Quat::from_rotation_z(yaw_offset) * self.0)
+    }
+
+    /// Yaw around the z (up) axis in radians
+    pub fn yaw(self) -> f32 {
+        fusion::yaw(self.0)
+    }
+}
+
+/// Orientation in the server's world frame with z up, the same for every tracker
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct WorldQuat(pub glam::Quat);
+
+impl WorldQuat {
+    /// Rotates it around the world's up axis by the angle in radians
+    pub fn rotate_yaw(self, angle: f32) -> Self {
+        Self(glam::Quat::from_rotation_z(angle) * self.0)
+    }
+
+    /// Yaw around the z (up) axis in radians
+    pub fn yaw(self) -> f32 {
+        fusion::yaw(self.0)
+    }
+}
+
+/// Acceleration in m/s², what everything on the server works in
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct AccelMps2(pub glam::Vec3A);
+
+impl AccelMps2 {
+    pub const ZERO: Self = Self(glam::Vec3A::ZERO);
+
+    pub fn to_g(self) -> AccelG {
+        AccelG(self.0 / STANDARD_GRAVITY)
+    }
+
+    /// Magnitude in m/s²
+    pub fn length(self) -> f32 {
+        self.0.length()
+    }
+}
+
+/// Acceleration in multiples of standard gravity
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct AccelG(pub glam::Vec3A);
+
+impl AccelG {
+    pub fn to_mps2(self) -> AccelMps2 {
+        AccelMps2(self.0 * STANDARD_GRAVITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaw_offset_turns_sensor_into_world() {
+        let sensor = SensorQuat(glam::Quat::from_rotation_z(0.5));
+        let world = sensor.to_world(0.25);
+        assert!((world.yaw() - 0.75).abs() < 1e-5);
+        assert!((sensor.yaw() - 0.5).abs() < 1e-5);
+
+        // Turning around the up axis afterwards is the same as a different offset
+        let rotated = world.rotate_yaw(-0.75);
+        assert!(rotated.yaw().abs() < 1e-5);
+        assert!(rotated.0.abs_diff_eq(sensor.to_world(-0.5).0, 1e-5));
+    }
+
+    #[test]
+    fn yaw_offset_keeps_tilt() {
+        let tilt = glam::Quat::from_rotation_x(0.3);
+        let world = SensorQuat(tilt).to_world(1.0);
+        let up = world.0 * glam::Vec3::Z;
+        assert!((up.z - tilt.mul_vec3(glam::Vec3::Z).z).abs() < 1e-5);
+    }
+
+    #[test]
+    fn acceleration_converts_between_units() {
+        let gravity = AccelMps2(glam::Vec3A::new(0.0, 0.0, STANDARD_GRAVITY));
+        assert_eq!(gravity.to_g(), AccelG(glam::Vec3A::Z));
+        assert!((gravity.length() - STANDARD_GRAVITY).abs() < 1e-5);
+
+        let accel = AccelMps2(glam::Vec3A::new(1.0, -2.0, 3.5));
+        assert!(accel.to_g().to_mps2().0.abs_diff_eq(accel.0, 1e-5));
+        assert_eq!(AccelMps2::ZERO.length(), 0.0);
+    }
+
+    #[test]
+    fn units_serialize_as_their_inner_value() {
+        let accel = AccelMps2(glam::Vec3A::new(1.0, 2.0, 3.0));
+        assert_eq!(
+            serde_json::to_string(&accel).unwrap(),
+            serde_json::to_string(&accel.0).unwrap()
+        );
+
+        let quat = WorldQuat(glam::Quat::from_rotation_y(0.2));
+        let json = serde_json::to_string(&quat).unwrap();
+        assert_eq!(json, serde_json::to_string(&quat.0).unwrap());
+        assert_eq!(serde_json::from_str::<WorldQuat>(&json).unwrap(), quat);
+    }
+}
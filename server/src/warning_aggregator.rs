@@ -0,0 +1,64 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Repeated warnings are summarised at most this often
+pub const WARNING_SUMMARY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counts warnings that can fire many times a second so only the first one gets logged straight
+/// away and the rest get logged as a single summary line
+#[derive(Default)]
+pub struct WarningAggregator {
+    /// Keyed by the warning and where it came from
+    entries: HashMap<(&'static str, String), RepeatedWarning>,
+}
+
+struct RepeatedWarning {
+    /// Repeats since the warning or the last summary was logged
+    count: u64,
+    logged_time: Instant,
+}
+
+impl WarningAggregator {
+    pub fn warn(&mut self, warning: &'static str, source: impl ToString) {
+        let source = source.to_string();
+        match self.entries.get_mut(&(warning, source.clone())) {
+            Some(entry) => entry.count += 1,
+            None => {
+                tracing::warn!("{warning} from {source}");
+                self.entries.insert(
+                    (warning, source),
+                    RepeatedWarning {
+                        count: 0,
+                        logged_time: Instant::now(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Logs the summaries that are due, the warnings that stopped repeating get logged straight away
+    /// again next time
+    pub fn flush(&mut self) {
+        self.entries.retain(|(warning, source), entry| {
+            let elapsed = entry.logged_time.elapsed();
+            if elapsed < WARNING_SUMMARY_INTERVAL {
+                return true;
+            }
+
+            if entry.count == 0 {
+                return false;
+            }
+
+            tracing::warn!(
+                "{warning} x{} in the last {}s from {source}",
+                entry.count,
+                elapsed.as_secs()
+            );
+            entry.count = 0;
+            entry.logged_time = Instant::now();
+            true
+        });
+    }
+}
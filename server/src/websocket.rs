@@ -1,63 +1,524 @@
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast::error::RecvError, mpsc, watch, RwLock};
 use warp::{filters::ws::WebSocket, Filter};
 
-use crate::{main_server::ServerMessage, serial::write_serial, MainServer};
+#[cfg(feature = "recording")]
+use crate::playback::PlaybackAction;
+#[cfg(feature = "osc")]
+use crate::routing::OutputRoute;
+use crate::{
+    calibration::{CalibrationKind, MAX_CALIBRATION_DELAY_SECS, MAX_SIDE_CHECK_SECS},
+    config::{ConfigError, WebsocketConfig, MAX_HISTORY_SECS},
+    exporter::ExportConfig,
+    latency_test::LatencyStage,
+    log_forward,
+    main_server::{ticks_in, ServerMessage},
+    messages::CodedMessage,
+    prediction::{OrientationPredictor, MAX_PREDICTION_MS},
+    serial::{write_serial, StaticIpConfig, WifiCredentials},
+    snapshot::{Snapshot, SnapshotSubscriber},
+    tracker::{RawTrackerData, TrackerData, TrackerSide, TrackerStatus},
+    udp_packet::{format_mac, parse_mac, UdpPacketSetConfigKv},
+    udp_server::DeviceCommand,
+    MainServer,
+};
 
 pub const WEBSOCKET_PORT: u16 = 8298;
+/// Clients have to offer this subprotocol, it changes when the messages do in a way that would
+/// break older clients
+pub const WEBSOCKET_PROTOCOL: &str = "mycap.v1";
+/// Number of messages in each chunk of the initial sync
+const SYNC_CHUNK_SIZE: usize = 32;
+const MAX_PAIRING_WINDOW_SECS: u64 = 600;
 
 // Receieved from client
 #[derive(Clone, serde::Deserialize)]
 #[serde(tag = "type")]
 enum WebsocketClientMessage {
-    Wifi { ssid: String, password: String },
+    Wifi {
+        ssid: String,
+        password: String,
+        #[serde(default)]
+        hidden: bool,
+        bssid: Option<String>,
+        static_ip: Option<StaticIpConfig>,
+    },
     FactoryReset,
+    /// Send the wifi credentials to every mycap device plugged in over USB until stopped, the
+    /// credentials are only kept in memory
+    #[cfg(feature = "serial")]
+    StartProvisioning {
+        ssid: String,
+        password: String,
+        #[serde(default)]
+        hidden: bool,
+        bssid: Option<String>,
+        static_ip: Option<StaticIpConfig>,
+    },
+    #[cfg(feature = "serial")]
+    StopProvisioning,
+    AddToAllowlist {
+        mac: String,
+    },
+    /// Add new devices to the allowlist when they handshake for a while
+    OpenPairingWindow {
+        seconds: u64,
+    },
+    ClosePairingWindow,
+    /// Control the replay when replaying a recording
+    #[cfg(feature = "recording")]
+    PlaybackControl {
+        action: PlaybackAction,
+    },
+    /// Drop all packets from the device and disconnect it if connected
+    #[serde(alias = "ForgetDevice")]
+    BlockDevice {
+        mac: String,
+    },
+    #[serde(alias = "UnforgetDevice")]
+    UnblockDevice {
+        mac: String,
+    },
+    /// Drop all packets from the ip address
+    BlockAddress {
+        addr: IpAddr,
+    },
+    UnblockAddress {
+        addr: IpAddr,
+    },
+    /// Get the blocked devices and addresses, also sent to every client when it changes
+    #[serde(alias = "GetForgottenDevices")]
+    GetBlocklist,
+    Subscribe {
+        stream: DataStream,
+    },
+    /// Get the server's logs at or above the level, "off" to stop getting them
+    SubscribeLogs {
+        level: String,
+    },
+    /// Measure the latency of the data for some seconds
+    RunLatencyTest {
+        seconds: f32,
+    },
+    /// Start exporting tracker data, using the saved export config if none is given
+    StartExport {
+        config: Option<ExportConfig>,
+    },
+    StopExport,
+    /// Record the sensor readings from before the fusion on the devices to a CSV file, only for
+    /// firmware that sends them
+    StartRawSensorRecording {
+        path: PathBuf,
+    },
+    StopRawSensorRecording,
+    GetDeviceConfig {
+        mac: String,
+    },
+    SetDeviceConfigValue {
+        mac: String,
+        key: String,
+        value: String,
+    },
+    /// Get the whole config as JSON to back it up or share it
+    ExportConfig,
+    /// Write the trackers, devices and config to a JSON file for a bug report
+    DumpState {
+        path: String,
+    },
+    /// Replace the whole config with one from ExportConfig
+    ImportConfig {
+        json: String,
+    },
+    /// Send a burst of pings to the device to measure its packet loss and round trip times
+    RunNetworkTest {
+        mac: String,
+    },
+    /// Translate the tracker's position, saved in its config
+    SetPositionOffset {
+        index: usize,
+        offset: glam::Vec3A,
+    },
+    /// Move the UDP server to another port while using broadcast discovery, connected devices
+    /// follow it
+    SetUdpPort {
+        port: u16,
+    },
+    /// Add an output route or replace the one with the same name
+    #[cfg(feature = "osc")]
+    SetRoute {
+        route: OutputRoute,
+    },
+    #[cfg(feature = "osc")]
+    RemoveRoute {
+        name: String,
+    },
+    /// Get the output routes and how much they're sending
+    #[cfg(feature = "osc")]
+    GetRoutes,
+    /// Force the tracker into the status to test how it's shown, needs --debug-commands
+    /// This is only a manual override so the device's next packet or the upkeep can change it back
+    SetStatus {
+        index: usize,
+        status: TrackerStatus,
+    },
+    /// Use the current height of the foot tracker as the floor for all foot trackers
+    SetFloor {
+        index: usize,
+    },
+    /// Move the tracker at mapping[i] to index i, such as [2, 0, 1] to move tracker 2 to the
+    /// start, the mapping has to have every current index once
+    RemapIndices {
+        mapping: Vec<usize>,
+    },
+    /// Get the saved profiles and which one is active
+    ListProfiles,
+    /// Switch the filter, VRChat OSC and route settings to the ones in the profile
+    ApplyProfile {
+        name: String,
+    },
+    /// Save the current filter, VRChat OSC and route settings as a profile
+    SaveCurrentAsProfile {
+        name: String,
+    },
+    /// Keep the tracker still for some seconds to find out how its IMU reports gravity
+    CalibrateGravity {
+        index: usize,
+        seconds: f32,
+    },
+    /// Rest the tracker on each of its faces in turn to correct its accelerometer's scale and bias,
+    /// the server sends AccelScaleProgress with the face to rest it on
+    CalibrateAccelScale {
+        index: usize,
+    },
+    /// The tracker is resting still on the face from AccelScaleProgress
+    NextAccelScaleFace,
+    /// Get the tracker's data from the last seconds, needs history_secs in the config
+    GetHistory {
+        index: usize,
+        seconds: f32,
+    },
+    /// Stop sending updates about trackers that are in the config but have never been seen, they're
+    /// still in the initial sync with the Unknown status
+    SetHideUnknown {
+        hide: bool,
+    },
+    /// Send coded errors in this locale, such as es or en-GB, falling back to English
+    SetLocale {
+        locale: String,
+    },
+    /// Count down then run the calibration so there's time to get into pose
+    StartCalibration {
+        delay_secs: u64,
+        kind: CalibrationKind,
+    },
+    /// Swap the sides of the left and right tracker, such as after a SwapSuggestion
+    SwapSides {
+        a: usize,
+        b: usize,
+    },
+    /// Rotate the processed orientations sent to this client forward by this many milliseconds to
+    /// make up for its render latency, 0 to turn it off
+    SetPrediction {
+        ms: u32,
+    },
+}
+
+/// Which tracker data the client wants to receive
+#[derive(Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DataStream {
+    Raw,
+    #[default]
+    Processed,
+    /// Processed data along with the raw data in the same message
+    Both,
+}
+
+/// Options that the client can change that affect what gets sent to it
+#[derive(Clone, Default)]
+struct ClientOptions {
+    stream: DataStream,
+    log_level: Option<tracing::Level>,
+    /// Coded errors get sent in this locale if the catalog has it
+    locale: Option<String>,
+    /// Don't send updates about trackers that have never been seen
+    hide_unknown: bool,
+    /// How far ahead to predict the processed orientations
+    prediction_ms: u32,
+}
+
+impl ClientOptions {
+    /// Modifies the message to what the client wants or None if it shouldn't be sent
+    fn filter_message(&self, message: ServerMessage) -> Option<ServerMessage> {
+        Some(match message {
+            ServerMessage::TrackerData {
+                index,
+                data,
+                raw_data,
+            } => match self.stream {
+                DataStream::Raw => match raw_data {
+                    Some(data) => ServerMessage::RawTrackerData { index, data },
+                    None => ServerMessage::TrackerData {
+                        index,
+                        data,
+                        raw_data: None,
+                    },
+                },
+                DataStream::Processed => ServerMessage::TrackerData {
+                    index,
+                    data,
+                    raw_data: None,
+                },
+                DataStream::Both => ServerMessage::TrackerData {
+                    index,
+                    data,
+                    raw_data,
+                },
+            },
+            ServerMessage::TrackerInfo { info }
+                if self.hide_unknown && info.status == TrackerStatus::Unknown =>
+            {
+                return None;
+            }
+            ServerMessage::Error {
+                coded: Some(coded),
+                error,
+            } => ServerMessage::Error {
+                error: match &self.locale {
+                    Some(locale) => coded.format(locale),
+                    None => error,
+                },
+                coded: Some(coded),
+            },
+            message => message,
+        })
+    }
+}
+
+/// Server message with the number of messages sent to the client before it so the client can tell
+/// if any were missed or came out of order
+#[derive(serde::Serialize)]
+struct SequencedMessage<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    message: &'a ServerMessage,
 }
 
 async fn send_websocket_message(
     ws_tx: &mut SplitSink<WebSocket, warp::ws::Message>,
+    seq: &mut u64,
     message: ServerMessage,
 ) {
-    if let Ok(string) = serde_json::to_string(&message) {
-        ws_tx.send(warp::ws::Message::text(string)).await.ok();
+    let sequenced = SequencedMessage {
+        seq: *seq,
+        message: &message,
+    };
+    match serde_json::to_string(&sequenced) {
+        Ok(string) => {
+            *seq += 1;
+            ws_tx.send(warp::ws::Message::text(string)).await.ok();
+        }
+        Err(error) => match message.tracker_index() {
+            Some(index) => {
+                tracing::error!("Failed to serialize message for tracker {index}: {error}")
+            }
+            None => tracing::error!("Failed to serialize message: {error}"),
+        },
     }
 }
 
-pub async fn start_server(main: Arc<RwLock<MainServer>>) -> anyhow::Result<()> {
-    let websocket = warp::ws()
-        .and(warp::any().map(move || main.clone()))
-        .map(|ws: warp::ws::Ws, main| ws.on_upgrade(|ws| on_connect(ws, main)));
-
+pub async fn start_server(
+    main: Arc<RwLock<MainServer>>,
+    snapshots: SnapshotSubscriber,
+) -> anyhow::Result<()> {
+    let config = main.read().await.config.websocket.clone();
+    let websocket = websocket_filter(main, snapshots, config);
     let address = SocketAddr::from((Ipv4Addr::LOCALHOST, WEBSOCKET_PORT));
-    log::info!("Started websocket server on {address}");
+    tracing::info!("Started websocket server on {address}");
     warp::serve(websocket).run(address).await;
     Ok(())
 }
 
-async fn on_connect(ws: WebSocket, main: Arc<RwLock<MainServer>>) {
-    log::info!("Websocket client connected");
-    let (mut ws_tx, mut ws_rx) = ws.split();
+fn websocket_filter(
+    main: Arc<RwLock<MainServer>>,
+    snapshots: SnapshotSubscriber,
+    config: WebsocketConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    check_handshake(config)
+        .and(warp::ws())
+        .and(warp::any().map(move || (main.clone(), snapshots.clone())))
+        .map(|ws: warp::ws::Ws, (main, snapshots)| {
+            let reply = ws.on_upgrade(|ws| on_connect(ws, main, snapshots));
+            warp::reply::with_header(reply, "sec-websocket-protocol", WEBSOCKET_PROTOCOL)
+        })
+        .recover(reject_handshake)
+}
+
+#[derive(Debug)]
+enum HandshakeRejection {
+    Origin(Option<String>),
+    Subprotocol,
+}
 
-    let mut server_rx = main.write().await.new_message_channel();
+impl warp::reject::Reject for HandshakeRejection {}
 
-    for tracker in &main.read().await.trackers {
-        send_websocket_message(
-            &mut ws_tx,
-            ServerMessage::TrackerInfo {
-                info: tracker.info.clone(),
-            },
-        )
-        .await;
+/// Rejects before upgrading so the client gets a proper HTTP error instead of a closed websocket
+fn check_handshake(
+    config: WebsocketConfig,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and_then(move |origin: Option<String>, protocols: Option<String>| {
+            let result = if !config.is_origin_allowed(origin.as_deref()) {
+                Err(warp::reject::custom(HandshakeRejection::Origin(origin)))
+            } else if !protocols.is_some_and(|protocols| {
+                (protocols.split(',')).any(|protocol| protocol.trim() == WEBSOCKET_PROTOCOL)
+            }) {
+                Err(warp::reject::custom(HandshakeRejection::Subprotocol))
+            } else {
+                Ok(())
+            };
+            async move { result }
+        })
+        .untuple_one()
+}
+
+async fn reject_handshake(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    match rejection.find::<HandshakeRejection>() {
+        Some(HandshakeRejection::Origin(origin)) => {
+            let origin = origin.as_deref().unwrap_or("no origin");
+            tracing::warn!("Rejected websocket client from {origin}");
+            Ok(warp::reply::with_status(
+                "Origin not allowed".to_string(),
+                warp::http::StatusCode::FORBIDDEN,
+            ))
+        }
+        Some(HandshakeRejection::Subprotocol) => Ok(warp::reply::with_status(
+            format!("Needs the {WEBSOCKET_PROTOCOL} websocket subprotocol"),
+            warp::http::StatusCode::BAD_REQUEST,
+        )),
+        None => Err(rejection),
     }
+}
+
+async fn on_connect(ws: WebSocket, main: Arc<RwLock<MainServer>>, snapshots: SnapshotSubscriber) {
+    tracing::info!("Websocket client connected");
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let mut message_seq = 0;
+
+    // Synced from the snapshot published each tick so connecting doesn't block the main server
+    let (mut server_rx, snapshot) = snapshots.subscribe().await;
+    let mut next_message_id = snapshot.next_message_id;
+    // Updates that happen during the sync get queued in the channel
+    for message in sync_messages(&snapshot) {
+        send_websocket_message(&mut ws_tx, &mut message_seq, message).await;
+    }
+
+    let (options_tx, options_rx) = watch::channel(ClientOptions::default());
+    // Replies to messages from this client that don't go to every client
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    let (latency_recorder, clock) = {
+        let main = main.read().await;
+        (main.latency_recorder(), main.clock)
+    };
+
+    let mut log_rx = log_forward::subscribe();
 
     // Spawn seperate task for listening to server messages
     let server_messages_task = tokio::spawn(async move {
-        while let Some(message) = server_rx.recv().await {
-            send_websocket_message(&mut ws_tx, message).await;
+        // Timestamps of the last data sent for each tracker to only measure latency of new data
+        let mut last_timestamps = Vec::new();
+        let mut predictor = OrientationPredictor::default();
+
+        loop {
+            let message = tokio::select! {
+                Some(message) = reply_rx.recv() => message,
+                result = server_rx.recv() => match result {
+                    // Already part of the synced snapshot
+                    Ok(message) if message.id < next_message_id => continue,
+                    Ok(message) => message.message,
+                    Err(RecvError::Lagged(count)) => {
+                        // The missed messages could've changed anything so start it again
+                        tracing::warn!(
+                            "Websocket client fell behind and missed {count} messages, syncing again"
+                        );
+                        let snapshot;
+                        (server_rx, snapshot) = snapshots.subscribe().await;
+                        next_message_id = snapshot.next_message_id;
+                        for message in sync_messages(&snapshot) {
+                            send_websocket_message(&mut ws_tx, &mut message_seq, message).await;
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                result = log_rx.recv() => match result {
+                    Ok(record) if options_rx.borrow().log_level.is_some_and(|level| record.level <= level) => {
+                        ServerMessage::LogRecord(record)
+                    }
+                    // Logging about missed records would only make more records
+                    _ => continue,
+                },
+            };
+
+            // Otherwise the state of one tracker would carry over to the one that took its index
+            if let ServerMessage::TrackerIndicesRemapped { .. } = message {
+                predictor = OrientationPredictor::default();
+                last_timestamps.clear();
+            }
+
+            let (message, prediction_ms) = {
+                let options = options_rx.borrow();
+                // The raw data is left alone so it stays what the tracker sent
+                let prediction_ms = match options.stream {
+                    DataStream::Raw => 0,
+                    _ => options.prediction_ms,
+                };
+                (options.filter_message(message), prediction_ms)
+            };
+            let Some(mut message) = message else {
+                continue;
+            };
+
+            if let ServerMessage::TrackerData { index, data, .. } = &mut message {
+                if prediction_ms > 0 {
+                    data.orientation = predictor.predict(*index, data, prediction_ms);
+                }
+            }
+
+            let new_timestamp = match &message {
+                ServerMessage::TrackerData {
+                    index,
+                    data: TrackerData { timestamp_us, .. },
+                    ..
+                }
+                | ServerMessage::RawTrackerData {
+                    index,
+                    data: RawTrackerData { timestamp_us, .. },
+                } if latency_recorder.is_active() => {
+                    if *index >= last_timestamps.len() {
+                        last_timestamps.resize(*index + 1, 0);
+                    }
+
+                    let is_new = last_timestamps[*index] != *timestamp_us;
+                    last_timestamps[*index] = *timestamp_us;
+                    is_new.then_some(*timestamp_us)
+                }
+                _ => None,
+            };
+
+            send_websocket_message(&mut ws_tx, &mut message_seq, message).await;
+
+            if let Some(timestamp_us) = new_timestamp {
+                let latency_us = clock.now_us().saturating_sub(timestamp_us);
+                latency_recorder.record(LatencyStage::ReceiveToWebsocket, latency_us);
+            }
         }
     });
 
@@ -65,38 +526,830 @@ async fn on_connect(ws: WebSocket, main: Arc<RwLock<MainServer>>) {
         let msg = match ws_result {
             Ok(msg) => msg,
             Err(e) => {
-                log::error!("Websocket error: {e}");
+                tracing::error!("Websocket error: {e}");
                 break;
             }
         };
 
         if let Ok(string) = msg.to_str() {
-            log::info!("Got from websocket: {string}");
-            if let Err(error) = handle_websocket_message(string) {
-                log::error!("{error}");
-                main.write().await.notify_error(&error.to_string());
+            if let Err(error) =
+                handle_websocket_message(string, &main, &options_tx, &reply_tx).await
+            {
+                tracing::error!("{error}");
+                match coded_error(&error) {
+                    Some(coded) => main.read().await.notify_coded_error(coded),
+                    None => main.read().await.notify_error(&error.to_string()),
+                }
             }
         }
     }
 
-    log::info!("Websocket client disconnected");
+    tracing::info!("Websocket client disconnected");
     server_messages_task.abort();
     server_messages_task.await.ok();
 }
 
-fn handle_websocket_message(message: &str) -> anyhow::Result<()> {
-    match serde_json::from_str(message)? {
-        WebsocketClientMessage::Wifi { ssid, password } => {
-            if ssid.len() > 32 || password.len() > 64 {
-                anyhow::bail!("SSID or password too long");
+/// Replaces every password in the message so it can be logged, the wifi credentials are only
+/// meant to be kept in memory
+fn redact_credentials(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                if key == "password" {
+                    *value = "<redacted>".into();
+                } else {
+                    redact_credentials(value);
+                }
             }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_credentials),
+        _ => (),
+    }
+}
+
+/// The snapshot split into chunks followed by SyncComplete
+fn sync_messages(snapshot: &Snapshot) -> Vec<ServerMessage> {
+    let messages = snapshot.to_messages();
+    let total = messages.len().div_ceil(SYNC_CHUNK_SIZE);
+    let chunks = messages.chunks(SYNC_CHUNK_SIZE).enumerate();
+    (chunks.map(|(seq, payload)| ServerMessage::SyncChunk {
+        seq,
+        total,
+        payload: payload.to_vec(),
+    }))
+    .chain([ServerMessage::SyncComplete])
+    .collect()
+}
+
+/// Gets the code for errors that have one so clients can show them in their own language
+fn coded_error(error: &anyhow::Error) -> Option<CodedMessage> {
+    if let Some(coded) = error.downcast_ref::<CodedMessage>() {
+        return Some(coded.clone());
+    }
 
-            write_serial(format!("Wifi\0{ssid}\0{password}\n").as_bytes())?;
+    let error = error.downcast_ref::<ConfigError>()?;
+    Some(
+        CodedMessage::new("invalid_config_value")
+            .param("field", &error.field)
+            .param("message", &error.message),
+    )
+}
+
+async fn handle_websocket_message(
+    message: &str,
+    main: &Arc<RwLock<MainServer>>,
+    options_tx: &watch::Sender<ClientOptions>,
+    reply_tx: &mpsc::UnboundedSender<ServerMessage>,
+) -> anyhow::Result<()> {
+    let invalid = |error| CodedMessage::new("invalid_message").param("details", error);
+    let message: serde_json::Value = serde_json::from_str(message).map_err(invalid)?;
+    let mut logged = message.clone();
+    redact_credentials(&mut logged);
+    tracing::info!("Got from websocket: {logged}");
+
+    let message = serde_json::from_value(message).map_err(invalid)?;
+    match message {
+        WebsocketClientMessage::Wifi {
+            ssid,
+            password,
+            hidden,
+            bssid,
+            static_ip,
+        } => {
+            let credentials = WifiCredentials {
+                ssid,
+                password,
+                hidden,
+                bssid,
+                static_ip,
+            };
+
+            let protocol = main.read().await.config.serial_protocol;
+            write_serial(&credentials.to_command(protocol)?)?;
+        }
+        #[cfg(feature = "serial")]
+        WebsocketClientMessage::StartProvisioning {
+            ssid,
+            password,
+            hidden,
+            bssid,
+            static_ip,
+        } => {
+            let credentials = WifiCredentials {
+                ssid,
+                password,
+                hidden,
+                bssid,
+                static_ip,
+            };
+            main.write().await.start_provisioning(credentials)?;
+        }
+        #[cfg(feature = "serial")]
+        WebsocketClientMessage::StopProvisioning => {
+            main.write().await.stop_provisioning();
         }
         WebsocketClientMessage::FactoryReset => {
             write_serial(b"FactoryReset\n")?;
         }
+        WebsocketClientMessage::AddToAllowlist { mac } => {
+            let mac = parse_mac(&mac)
+                .ok_or_else(|| CodedMessage::new("invalid_mac").param("mac", &mac))?;
+            let mac = format_mac(mac);
+
+            let mut main = main.write().await;
+            if !main.config.allowlist.contains(&mac) {
+                tracing::info!("Added {mac} to the allowlist");
+                main.config.allowlist.push(mac);
+                main.save_config();
+            }
+        }
+        WebsocketClientMessage::OpenPairingWindow { seconds } => {
+            if !(1..=MAX_PAIRING_WINDOW_SECS).contains(&seconds) {
+                return Err(CodedMessage::new("pairing_window_out_of_range")
+                    .param("max", MAX_PAIRING_WINDOW_SECS)
+                    .into());
+            }
+
+            main.write()
+                .await
+                .open_pairing_window(Duration::from_secs(seconds));
+        }
+        WebsocketClientMessage::SetPositionOffset { index, offset } => {
+            main.write().await.set_position_offset(index, offset)?;
+        }
+        WebsocketClientMessage::SetUdpPort { port } => {
+            main.write()
+                .await
+                .queue_device_command(DeviceCommand::SetUdpPort { port });
+        }
+        #[cfg(feature = "osc")]
+        WebsocketClientMessage::SetRoute { route } => {
+            main.write().await.set_route(route)?;
+        }
+        #[cfg(feature = "osc")]
+        WebsocketClientMessage::RemoveRoute { name } => {
+            main.write().await.remove_route(&name)?;
+        }
+        #[cfg(feature = "osc")]
+        WebsocketClientMessage::GetRoutes => {
+            let routes = main.write().await.route_stats();
+            reply_tx.send(ServerMessage::Routes { routes }).ok();
+        }
+        WebsocketClientMessage::SetStatus { index, status } => {
+            let mut main = main.write().await;
+            if !main.debug_commands {
+                return Err(CodedMessage::new("debug_commands_disabled").into());
+            }
+
+            main.update_tracker_status(index, status)?;
+        }
+        WebsocketClientMessage::SetFloor { index } => {
+            main.write().await.set_floor(index)?;
+        }
+        WebsocketClientMessage::RemapIndices { mapping } => {
+            main.write().await.remap_tracker_indices(mapping)?;
+        }
+        WebsocketClientMessage::ListProfiles => {
+            let main = main.read().await;
+            reply_tx
+                .send(ServerMessage::Profiles {
+                    profiles: main.config.profiles.clone(),
+                    active_profile: main.config.active_profile.clone(),
+                })
+                .ok();
+        }
+        WebsocketClientMessage::ApplyProfile { name } => {
+            main.write().await.apply_profile(&name)?;
+        }
+        WebsocketClientMessage::SaveCurrentAsProfile { name } => {
+            main.write().await.save_current_as_profile(name)?;
+        }
+        WebsocketClientMessage::ClosePairingWindow => {
+            main.write().await.close_pairing_window();
+        }
+        #[cfg(feature = "recording")]
+        WebsocketClientMessage::PlaybackControl { action } => {
+            main.write()
+                .await
+                .queue_device_command(DeviceCommand::ControlPlayback { action });
+        }
+        WebsocketClientMessage::BlockDevice { mac } => {
+            let mac = parse_mac(&mac)
+                .ok_or_else(|| CodedMessage::new("invalid_mac").param("mac", &mac))?;
+            let mac = format_mac(mac);
+
+            let mut main = main.write().await;
+            if !main.config.blocked_macs.contains(&mac) {
+                tracing::info!("Blocked {mac}");
+                main.config.blocked_macs.push(mac);
+                main.blocklist_updated();
+            }
+        }
+        WebsocketClientMessage::UnblockDevice { mac } => {
+            let mac = parse_mac(&mac)
+                .ok_or_else(|| CodedMessage::new("invalid_mac").param("mac", &mac))?;
+            let mac = format_mac(mac);
+
+            let mut main = main.write().await;
+            if main.config.blocked_macs.contains(&mac) {
+                tracing::info!("Unblocked {mac}");
+                main.config.blocked_macs.retain(|blocked| *blocked != mac);
+                main.blocklist_updated();
+            }
+        }
+        WebsocketClientMessage::BlockAddress { addr } => {
+            let mut main = main.write().await;
+            if !main.config.blocked_addresses.contains(&addr) {
+                tracing::info!("Blocked {addr}");
+                main.config.blocked_addresses.push(addr);
+                main.blocklist_updated();
+            }
+        }
+        WebsocketClientMessage::UnblockAddress { addr } => {
+            let mut main = main.write().await;
+            if main.config.blocked_addresses.contains(&addr) {
+                tracing::info!("Unblocked {addr}");
+                main.config
+                    .blocked_addresses
+                    .retain(|blocked| *blocked != addr);
+                main.blocklist_updated();
+            }
+        }
+        WebsocketClientMessage::GetBlocklist => {
+            let message = main.read().await.blocklist_message();
+            reply_tx.send(message).ok();
+        }
+        WebsocketClientMessage::Subscribe { stream } => {
+            options_tx.send_modify(|options| options.stream = stream);
+        }
+        WebsocketClientMessage::GetHistory { index, seconds } => {
+            if !(seconds > 0. && seconds <= MAX_HISTORY_SECS as f32) {
+                return Err(CodedMessage::new("history_out_of_range")
+                    .param("max", MAX_HISTORY_SECS)
+                    .into());
+            }
+
+            let history = main.read().await.tracker_history(index)?;
+            // Copying a long history takes a while so the tick isn't held up waiting for it
+            let samples = history.latest(ticks_in(seconds));
+            reply_tx
+                .send(ServerMessage::TrackerHistory { index, samples })
+                .ok();
+        }
+        WebsocketClientMessage::SetHideUnknown { hide } => {
+            options_tx.send_modify(|options| options.hide_unknown = hide);
+        }
+        WebsocketClientMessage::SetLocale { locale } => {
+            options_tx.send_modify(|options| options.locale = Some(locale));
+        }
+        WebsocketClientMessage::SetPrediction { ms } => {
+            if ms > MAX_PREDICTION_MS {
+                return Err(CodedMessage::new("prediction_out_of_range")
+                    .param("max", MAX_PREDICTION_MS)
+                    .into());
+            }
+
+            options_tx.send_modify(|options| options.prediction_ms = ms);
+        }
+        WebsocketClientMessage::SubscribeLogs { level } => {
+            let level = level
+                .parse::<tracing::level_filters::LevelFilter>()
+                .map_err(|_| CodedMessage::new("invalid_log_level").param("level", &level))?;
+            options_tx.send_modify(|options| options.log_level = level.into_level());
+        }
+        WebsocketClientMessage::RunLatencyTest { seconds } => {
+            if !(seconds > 0. && seconds <= 300.) {
+                return Err(CodedMessage::new("latency_test_out_of_range")
+                    .param("max", 300)
+                    .into());
+            }
+
+            main.write()
+                .await
+                .start_latency_test(Duration::from_secs_f32(seconds));
+        }
+        WebsocketClientMessage::StartExport { config } => {
+            main.write().await.start_export(config)?;
+        }
+        WebsocketClientMessage::StopExport => {
+            main.write().await.stop_export();
+        }
+        WebsocketClientMessage::StartRawSensorRecording { path } => {
+            main.write().await.start_raw_sensor_recording(path);
+        }
+        WebsocketClientMessage::StopRawSensorRecording => {
+            main.write().await.stop_raw_sensor_recording();
+        }
+        WebsocketClientMessage::GetDeviceConfig { mac } => {
+            let mac = parse_mac(&mac)
+                .ok_or_else(|| CodedMessage::new("invalid_mac").param("mac", &mac))?;
+            let mac = format_mac(mac);
+            main.write()
+                .await
+                .queue_device_command(DeviceCommand::GetConfig { mac });
+        }
+        WebsocketClientMessage::RunNetworkTest { mac } => {
+            let mac = parse_mac(&mac)
+                .ok_or_else(|| CodedMessage::new("invalid_mac").param("mac", &mac))?;
+            let mac = format_mac(mac);
+            main.write()
+                .await
+                .queue_device_command(DeviceCommand::RunNetworkTest { mac });
+        }
+        WebsocketClientMessage::SetDeviceConfigValue { mac, key, value } => {
+            let mac = parse_mac(&mac)
+                .ok_or_else(|| CodedMessage::new("invalid_mac").param("mac", &mac))?;
+            let mac = format_mac(mac);
+            UdpPacketSetConfigKv {
+                key: &key,
+                value: &value,
+            }
+            .validate()?;
+
+            main.write()
+                .await
+                .queue_device_command(DeviceCommand::SetConfigValue { mac, key, value });
+        }
+        WebsocketClientMessage::ExportConfig => {
+            let json = serde_json::to_string_pretty(&main.read().await.config)?;
+            reply_tx.send(ServerMessage::ConfigBlob { json }).ok();
+        }
+        WebsocketClientMessage::DumpState { path } => {
+            let json = main.read().await.dump_state()?;
+            // Only the client that asked needs to know if it couldn't be written
+            let reply = match tokio::fs::write(&path, json).await {
+                Ok(()) => {
+                    tracing::info!("Dumped the state to {path}");
+                    ServerMessage::StateDumped { path }
+                }
+                Err(error) => {
+                    tracing::error!("Failed to dump the state to {path}: {error}");
+                    let coded = CodedMessage::new("state_dump_failed").param("path", &path);
+                    ServerMessage::Error {
+                        error: coded.to_string(),
+                        coded: Some(coded),
+                    }
+                }
+            };
+            reply_tx.send(reply).ok();
+        }
+        WebsocketClientMessage::ImportConfig { json } => {
+            let config = serde_json::from_str(&json)
+                .map_err(|error| CodedMessage::new("invalid_config").param("details", error))?;
+            main.write().await.import_config(config)?;
+        }
+        WebsocketClientMessage::CalibrateGravity { index, seconds } => {
+            if !(seconds > 0. && seconds <= 60.) {
+                return Err(CodedMessage::new("gravity_calibration_out_of_range")
+                    .param("max", 60)
+                    .into());
+            }
+
+            let mut main = main.write().await;
+            if main.trackers.get(index).is_none() {
+                return Err(CodedMessage::new("tracker_not_found")
+                    .param("index", index)
+                    .into());
+            }
+
+            main.start_gravity_calibration(index, Duration::from_secs_f32(seconds));
+        }
+        WebsocketClientMessage::CalibrateAccelScale { index } => {
+            main.write().await.start_accel_scale_calibration(index)?;
+        }
+        WebsocketClientMessage::NextAccelScaleFace => {
+            main.write().await.next_accel_scale_face()?;
+        }
+        WebsocketClientMessage::StartCalibration { delay_secs, kind } => {
+            if delay_secs > MAX_CALIBRATION_DELAY_SECS {
+                return Err(CodedMessage::new("calibration_delay_out_of_range")
+                    .param("max", MAX_CALIBRATION_DELAY_SECS)
+                    .into());
+            }
+
+            let mut main = main.write().await;
+            match kind {
+                CalibrationKind::Gravity { index, seconds } => {
+                    if !(seconds > 0. && seconds <= 60.) {
+                        return Err(CodedMessage::new("gravity_calibration_out_of_range")
+                            .param("max", 60)
+                            .into());
+                    }
+
+                    if main.trackers.get(index).is_none() {
+                        return Err(CodedMessage::new("tracker_not_found")
+                            .param("index", index)
+                            .into());
+                    }
+                }
+                CalibrationKind::SideCheck {
+                    location,
+                    side,
+                    seconds,
+                } => {
+                    if !(seconds > 0. && seconds <= MAX_SIDE_CHECK_SECS) {
+                        return Err(CodedMessage::new("side_check_out_of_range")
+                            .param("max", MAX_SIDE_CHECK_SECS)
+                            .into());
+                    }
+
+                    if side == TrackerSide::None {
+                        return Err(CodedMessage::new("side_check_needs_side").into());
+                    }
+
+                    // Fail now instead of after the countdown
+                    main.side_pair(location)?;
+                }
+                CalibrationKind::ResetYaw => (),
+            }
+
+            main.start_calibration(kind, Duration::from_secs(delay_secs));
+        }
+        WebsocketClientMessage::SwapSides { a, b } => {
+            main.write().await.swap_sides(a, b)?;
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        main_server::MESSAGE_CHANNEL_CAPACITY,
+        tracker::{Tracker, TrackerConfig},
+    };
+    use warp::http::StatusCode;
+
+    async fn handshake_status(origin: Option<&str>, protocols: Option<&str>) -> StatusCode {
+        let config = WebsocketConfig {
+            allowed_origins: vec!["http://localhost".to_string()],
+            allow_missing_origin: true,
+        };
+        let filter = check_handshake(config)
+            .map(warp::reply)
+            .recover(reject_handshake);
+
+        let mut request = warp::test::request();
+        if let Some(origin) = origin {
+            request = request.header("origin", origin);
+        }
+        if let Some(protocols) = protocols {
+            request = request.header("sec-websocket-protocol", protocols);
+        }
+        request.reply(&filter).await.status()
+    }
+
+    #[test]
+    fn passwords_are_left_out_of_the_logged_message() {
+        let mut message = serde_json::json!({
+            "type": "StartProvisioning",
+            "ssid": "home",
+            "password": "password123",
+            "nested": [{ "password": "hunter22" }],
+        });
+        redact_credentials(&mut message);
+        assert_eq!(message["ssid"], "home");
+        assert_eq!(message["password"], "<redacted>");
+        assert_eq!(message["nested"][0]["password"], "<redacted>");
+    }
+
+    #[test]
+    fn sync_is_chunked_and_then_completed() {
+        let mut main = MainServer::default();
+        for i in 0..20 {
+            main.register_tracker(
+                format!("tracker {i}"),
+                crate::tracker::TrackerConfig::default(),
+            );
+        }
+        let snapshot = main.snapshot(0);
+        let expected = snapshot.to_messages();
+
+        let mut messages = sync_messages(&snapshot);
+        assert!(matches!(messages.pop(), Some(ServerMessage::SyncComplete)));
+        let total = expected.len().div_ceil(SYNC_CHUNK_SIZE);
+        assert_eq!(messages.len(), total);
+
+        let mut payloads = Vec::new();
+        for (i, message) in messages.into_iter().enumerate() {
+            let ServerMessage::SyncChunk {
+                seq,
+                total: chunk_total,
+                payload,
+            } = message
+            else {
+                panic!("expected a sync chunk");
+            };
+            assert_eq!((seq, chunk_total), (i, total));
+            payloads.extend(payload);
+        }
+        assert_eq!(payloads.len(), expected.len());
+    }
+
+    #[test]
+    fn each_stream_gets_its_own_data() {
+        let mut main = MainServer::default();
+        let mut messages = main.new_message_channel();
+        // Turning from the default orientation this soon after starting would look like a glitch
+        main.config.anomaly.enabled = false;
+        let index = main.register_tracker("test".to_string(), TrackerConfig::default());
+        let orientation = crate::SensorQuat(glam::Quat::from_rotation_x(0.5));
+        let acceleration = crate::AccelMps2(glam::Vec3A::new(0.1, 0.2, 9.8));
+        main.update_tracker_data(index, acceleration, orientation, std::time::Instant::now());
+        main.tick(Duration::from_millis(20));
+        let message = std::iter::from_fn(|| messages.try_recv().ok())
+            .map(|message| message.message)
+            .find(|message| matches!(message, ServerMessage::TrackerData { .. }))
+            .unwrap();
+        let filter = |stream| {
+            let options = ClientOptions {
+                stream,
+                ..Default::default()
+            };
+            let message = options.filter_message(message.clone()).unwrap();
+            serde_json::to_value(message).unwrap()
+        };
+
+        let both = filter(DataStream::Both);
+        assert_eq!(both["type"], "TrackerData");
+        assert_eq!(
+            both["raw_data"]["timestamp_us"],
+            both["data"]["timestamp_us"]
+        );
+        assert_ne!(both["raw_data"]["timestamp_us"], 0);
+
+        // The same processed data as before raw data was added
+        let processed = filter(DataStream::Processed);
+        assert_eq!(processed["type"], "TrackerData");
+        assert_eq!(processed["data"], both["data"]);
+        assert!(processed["raw_data"].is_null());
+
+        // Under the same type with the raw data in place of the processed data
+        let raw = filter(DataStream::Raw);
+        assert_eq!(raw["type"], "TrackerData");
+        assert_eq!(raw["data"], both["raw_data"]);
+        assert!(raw["raw_data"].is_null());
+
+        // Data without the raw part, like in the sync, is sent as it is
+        let ServerMessage::TrackerData { index, data, .. } = message else {
+            unreachable!();
+        };
+        let options = ClientOptions {
+            stream: DataStream::Raw,
+            ..Default::default()
+        };
+        let without_raw = ServerMessage::TrackerData {
+            index,
+            data,
+            raw_data: None,
+        };
+        let message = options.filter_message(without_raw).unwrap();
+        assert!(matches!(
+            message,
+            ServerMessage::TrackerData { raw_data: None, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_trackers_can_be_hidden() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        let (options_tx, options_rx) = watch::channel(ClientOptions::default());
+        let (reply_tx, _) = mpsc::unbounded_channel();
+        let tracker_info = |status| {
+            let mut tracker = Tracker::new("test".to_string(), 0, TrackerConfig::default());
+            tracker.info.status = status;
+            ServerMessage::TrackerInfo { info: tracker.info }
+        };
+        let passes = |status| {
+            (options_rx.borrow())
+                .filter_message(tracker_info(status))
+                .is_some()
+        };
+        assert!(passes(TrackerStatus::Unknown));
+
+        let message = r#"{"type":"SetHideUnknown","hide":true}"#;
+        handle_websocket_message(message, &main, &options_tx, &reply_tx)
+            .await
+            .unwrap();
+        assert!(!passes(TrackerStatus::Unknown));
+        assert!(passes(TrackerStatus::Ok));
+        assert!(passes(TrackerStatus::TimedOut));
+        // Removing it still gets through so it isn't left in the client's list
+        let removed = ServerMessage::TrackerRemoved { index: 0 };
+        assert!(options_rx.borrow().filter_message(removed).is_some());
+
+        let message = r#"{"type":"SetHideUnknown","hide":false}"#;
+        handle_websocket_message(message, &main, &options_tx, &reply_tx)
+            .await
+            .unwrap();
+        assert!(passes(TrackerStatus::Unknown));
+    }
+
+    #[tokio::test]
+    async fn history_has_to_be_in_range() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        let (options_tx, _) = watch::channel(ClientOptions::default());
+        let (reply_tx, _) = mpsc::unbounded_channel();
+        for seconds in [0., -1., 1000.] {
+            let message = format!(r#"{{"type":"GetHistory","index":0,"seconds":{seconds}}}"#);
+            let error = handle_websocket_message(&message, &main, &options_tx, &reply_tx)
+                .await
+                .unwrap_err();
+            let coded = error.downcast_ref::<CodedMessage>().unwrap();
+            assert_eq!(coded.code, "history_out_of_range");
+            assert_eq!(coded.params["max"], MAX_HISTORY_SECS.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn state_dumps_are_replied_to_the_client_that_asked() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        let (mut others, _) = main.read().await.message_channels.subscribe();
+        let (options_tx, _) = watch::channel(ClientOptions::default());
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        let dump = |path: &std::path::Path| {
+            serde_json::json!({ "type": "DumpState", "path": path }).to_string()
+        };
+
+        let path = std::env::temp_dir().join(format!("mycap-dump-{}.json", std::process::id()));
+        handle_websocket_message(&dump(&path), &main, &options_tx, &reply_tx)
+            .await
+            .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, main.read().await.dump_state().unwrap());
+        assert!(matches!(
+            reply_rx.try_recv(),
+            Ok(ServerMessage::StateDumped { path: dumped }) if dumped == path.to_str().unwrap()
+        ));
+
+        let path = std::env::temp_dir()
+            .join("mycap-missing-dir")
+            .join("state.json");
+        handle_websocket_message(&dump(&path), &main, &options_tx, &reply_tx)
+            .await
+            .unwrap();
+        let Ok(ServerMessage::Error {
+            coded: Some(coded), ..
+        }) = reply_rx.try_recv()
+        else {
+            panic!("the client should be told it failed");
+        };
+        assert_eq!(coded.code, "state_dump_failed");
+        assert_eq!(coded.params["path"], path.to_str().unwrap());
+        assert!(others.try_recv().is_err());
+    }
+
+    /// Type and the whole message as JSON of the next message the client gets
+    async fn next_message(client: &mut warp::test::WsClient) -> (String, serde_json::Value) {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("no message")
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        (value["type"].as_str().unwrap().to_string(), value)
+    }
+
+    /// Ids of the trackers in the sync up to and including SyncComplete
+    async fn synced_tracker_ids(client: &mut warp::test::WsClient) -> Vec<String> {
+        let mut ids = Vec::new();
+        loop {
+            match next_message(client).await {
+                (kind, _) if kind == "SyncComplete" => return ids,
+                (kind, chunk) if kind == "SyncChunk" => {
+                    let payload = chunk["payload"].as_array().unwrap();
+                    ids.extend(
+                        (payload.iter())
+                            .filter(|message| message["type"] == "TrackerInfo")
+                            .map(|message| message["info"]["id"].as_str().unwrap().to_string()),
+                    );
+                }
+                (kind, _) => panic!("got {kind} during the sync"),
+            }
+        }
+    }
+
+    async fn connect(main: &Arc<RwLock<MainServer>>) -> warp::test::WsClient {
+        let snapshots = main.read().await.snapshot_subscriber();
+        let filter = websocket_filter(main.clone(), snapshots, WebsocketConfig::default());
+        (warp::test::ws())
+            .header("sec-websocket-protocol", WEBSOCKET_PROTOCOL)
+            .handshake(filter)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_sync_while_the_main_server_is_locked() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        {
+            let mut main = main.write().await;
+            main.register_tracker("hip".to_string(), TrackerConfig::default());
+            main.publish_snapshot();
+        }
+
+        let locked = main.write().await;
+        let snapshots = locked.snapshot_subscriber();
+        let filter = websocket_filter(main.clone(), snapshots, WebsocketConfig::default());
+        let mut client = (warp::test::ws())
+            .header("sec-websocket-protocol", WEBSOCKET_PROTOCOL)
+            .handshake(filter)
+            .await
+            .unwrap();
+        assert_eq!(synced_tracker_ids(&mut client).await, ["hip"]);
+    }
+
+    #[tokio::test]
+    async fn clients_wait_for_a_snapshot_with_every_message_they_miss() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        main.write()
+            .await
+            .register_tracker("hip".to_string(), TrackerConfig::default());
+        main.read().await.publish_snapshot();
+        // Sent before the client subscribes but after the published snapshot
+        main.write()
+            .await
+            .register_tracker("foot".to_string(), TrackerConfig::default());
+
+        let mut client = connect(&main).await;
+        let waiting = tokio::time::timeout(Duration::from_millis(50), client.recv()).await;
+        assert!(waiting.is_err(), "synced from a snapshot without foot");
+
+        main.read().await.publish_snapshot();
+        assert_eq!(synced_tracker_ids(&mut client).await, ["hip", "foot"]);
+    }
+
+    #[tokio::test]
+    async fn clients_that_fall_behind_are_synced_again() {
+        let main = Arc::new(RwLock::new(MainServer::default()));
+        {
+            let mut main = main.write().await;
+            main.register_tracker("hip".to_string(), TrackerConfig::default());
+            main.publish_snapshot();
+        }
+        let mut client = connect(&main).await;
+        assert_eq!(synced_tracker_ids(&mut client).await, ["hip"]);
+
+        // The new tracker gets pushed out of the channel before the client gets to it
+        {
+            let mut main = main.write().await;
+            main.register_tracker("foot".to_string(), TrackerConfig::default());
+            for _ in 0..MESSAGE_CHANNEL_CAPACITY * 2 {
+                main.notify_warning("flood");
+            }
+            main.publish_snapshot();
+        }
+        assert_eq!(synced_tracker_ids(&mut client).await, ["hip", "foot"]);
+
+        // Live updates carry on after the new sync
+        main.read().await.notify_warning("after");
+        let (kind, warning) = next_message(&mut client).await;
+        assert_eq!(
+            (kind.as_str(), &warning["warning"]),
+            ("Warning", &"after".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_needs_an_allowed_origin() {
+        let protocol = Some(WEBSOCKET_PROTOCOL);
+        assert_eq!(
+            handshake_status(Some("http://localhost:5173"), protocol).await,
+            StatusCode::OK
+        );
+        assert_eq!(handshake_status(None, protocol).await, StatusCode::OK);
+        assert_eq!(
+            handshake_status(Some("https://evil.example"), protocol).await,
+            StatusCode::FORBIDDEN
+        );
+        // The origin is checked first
+        assert_eq!(
+            handshake_status(Some("https://evil.example"), None).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_needs_the_subprotocol() {
+        let origin = Some("http://localhost");
+        let offered = format!("other, {WEBSOCKET_PROTOCOL}");
+        assert_eq!(
+            handshake_status(origin, Some(&offered)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            handshake_status(origin, None).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            handshake_status(origin, Some("mycap.v0")).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            handshake_status(origin, Some("mycap.v1x")).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}